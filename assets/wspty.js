@@ -0,0 +1,52 @@
+// Minimal client for the wspty binary protocol.
+(function () {
+  const INPUT = 0;
+  const RESIZE = 1;
+  const OUTPUT = 0;
+
+  const term = new Terminal({ cursorBlink: true });
+  const fit = new FitAddon.FitAddon();
+  term.loadAddon(fit);
+  term.open(document.getElementById("terminal"));
+
+  // Server provided settings, see `UiConfig::page_hook`.
+  const config = window.WSPTY_CONFIG || {};
+  const proto = location.protocol === "https:" ? "wss:" : "ws:";
+  let url = `${proto}//${location.host}${location.pathname}`;
+  if (config.token) {
+    url += `?token=${encodeURIComponent(config.token)}`;
+  }
+  const ws = new WebSocket(url);
+  ws.binaryType = "arraybuffer";
+
+  const encoder = new TextEncoder();
+  function send(opcode, payload) {
+    const msg = new Uint8Array(payload.length + 1);
+    msg[0] = opcode;
+    msg.set(payload, 1);
+    ws.send(msg);
+  }
+
+  function resize() {
+    fit.fit();
+    const size = JSON.stringify({ cols: term.cols, rows: term.rows });
+    send(RESIZE, encoder.encode(size));
+  }
+
+  ws.onopen = () => {
+    // Empty command: let the server pick its default one.
+    ws.send("");
+    resize();
+    term.focus();
+  };
+  ws.onmessage = (event) => {
+    const data = new Uint8Array(event.data);
+    if (data[0] === OUTPUT) {
+      term.write(data.subarray(1));
+    }
+  };
+  ws.onclose = () => term.write("\r\n[connection closed]\r\n");
+
+  term.onData((data) => send(INPUT, encoder.encode(data)));
+  window.addEventListener("resize", resize);
+})();
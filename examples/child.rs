@@ -11,7 +11,7 @@ async fn run() -> Result<(), anyhow::Error> {
         "PATH".to_owned(),
         "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".to_owned(),
     );
-    cmd.envs(&envs).args(&["-", "jason"]);
+    cmd.envs(&envs).args(["-", "jason"]);
 
     let mut pty_cmd = PtyCommand::from(cmd);
     let (_stop_sender, stop_receiver) = mpsc::unbounded_channel();
@@ -22,7 +22,7 @@ async fn run() -> Result<(), anyhow::Error> {
 
     let fut = async move {
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-        wh.write(&[113]).await?;
+        wh.write_all(&[113]).await?;
         Ok::<(), anyhow::Error>(())
     };
     tokio::spawn(fut);
@@ -0,0 +1,76 @@
+// Measures how fast pty output can be drained, to decide whether the
+// io_uring backend is worth enabling on a given machine:
+//
+//   cargo run --release --example pty_throughput --features io-uring [MiB]
+
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use wspty::{PtyCommand, PtyMaster};
+
+async fn drain<R: AsyncRead + Unpin>(mut reader: R) -> Result<usize, anyhow::Error> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(s) => total += s,
+            // EIO once the child is gone.
+            Err(_) => break,
+        }
+    }
+    Ok(total)
+}
+
+// The child gets killed once the returned sender is dropped.
+async fn spawn(mib: usize) -> Result<(PtyMaster, mpsc::UnboundedSender<()>), anyhow::Error> {
+    let mut cmd = Command::new("head");
+    cmd.args(["-c", &format!("{}M", mib), "/dev/zero"]);
+    let (stop_sender, stop_receiver) = mpsc::unbounded_channel();
+    let master = PtyCommand::from(cmd).run(stop_receiver).await?;
+    Ok((master, stop_sender))
+}
+
+fn report(backend: &str, bytes: usize, start: Instant) {
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "{:>6}: {} bytes in {:.2}s, {:.1} MiB/s",
+        backend,
+        bytes,
+        secs,
+        bytes as f64 / secs / (1024.0 * 1024.0)
+    );
+}
+
+async fn run() -> Result<(), anyhow::Error> {
+    let mib = std::env::args()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(256);
+
+    let (master, _stop) = spawn(mib).await?;
+    let start = Instant::now();
+    let bytes = drain(master).await?;
+    report("epoll", bytes, start);
+
+    #[cfg(feature = "io-uring")]
+    {
+        let (master, _stop) = spawn(mib).await?;
+        let start = Instant::now();
+        let bytes = drain(wspty::UringReader::new(&master)?).await?;
+        report("uring", bytes, start);
+    }
+    Ok(())
+}
+
+fn main() {
+    env_logger::init();
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    if let Err(e) = rt.block_on(run()) {
+        eprintln!("{:?}", e);
+    }
+}
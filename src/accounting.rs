@@ -0,0 +1,230 @@
+use crate::ratelimit::{MemoryRateLimiter, RateLimit, RateLimiter};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BandwidthCaps {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+    // Sustained rate in bytes per second, traffic above it gets delayed.
+    // Enforced by the ledger's `RateLimiter`.
+    pub rate: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TenantUsage {
+    pub total: u64,
+    pub today: u64,
+    pub this_month: u64,
+}
+
+struct TenantState {
+    usage: TenantUsage,
+    day: u64,
+    month: u64,
+}
+
+#[derive(Default)]
+struct Ledger {
+    default_caps: BandwidthCaps,
+    caps: HashMap<String, BandwidthCaps>,
+    tenants: HashMap<String, TenantState>,
+    limiter: Option<Arc<dyn RateLimiter>>,
+    local_limiter: Arc<MemoryRateLimiter>,
+}
+
+// Bytes transferred per tenant, shared between the server and whoever
+// wants to read the usage statistics.
+#[derive(Clone, Default)]
+pub struct BandwidthLedger {
+    inner: Arc<Mutex<Ledger>>,
+}
+
+impl BandwidthLedger {
+    pub fn new(default_caps: BandwidthCaps) -> Self {
+        BandwidthLedger {
+            inner: Arc::new(Mutex::new(Ledger {
+                default_caps,
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn set_caps(&self, tenant: &str, caps: BandwidthCaps) {
+        self.inner
+            .lock()
+            .unwrap()
+            .caps
+            .insert(tenant.to_owned(), caps);
+    }
+
+    // Replaces the in-memory token buckets, e.g. with a `RedisRateLimiter`
+    // for rates to hold across servers.
+    pub fn set_rate_limiter(&self, limiter: Arc<dyn RateLimiter>) {
+        self.inner.lock().unwrap().limiter = Some(limiter);
+    }
+
+    fn rate_limiter(&self) -> Arc<dyn RateLimiter> {
+        let ledger = self.inner.lock().unwrap();
+        match ledger.limiter {
+            Some(ref limiter) => limiter.clone(),
+            None => ledger.local_limiter.clone(),
+        }
+    }
+
+    pub fn usage(&self, tenant: &str) -> Option<TenantUsage> {
+        let (day, month) = today();
+        let mut ledger = self.inner.lock().unwrap();
+        ledger.tenants.get_mut(tenant).map(|state| {
+            state.roll(day, month);
+            state.usage.clone()
+        })
+    }
+
+    pub fn stats(&self) -> HashMap<String, TenantUsage> {
+        let (day, month) = today();
+        let mut ledger = self.inner.lock().unwrap();
+        ledger
+            .tenants
+            .iter_mut()
+            .map(|(tenant, state)| {
+                state.roll(day, month);
+                (tenant.clone(), state.usage.clone())
+            })
+            .collect()
+    }
+
+    // Fails if `tenant` has already used up one of its quotas.
+    pub(crate) fn check(&self, tenant: &str) -> Result<(), anyhow::Error> {
+        let (day, month) = today();
+        let mut guard = self.inner.lock().unwrap();
+        let ledger = &mut *guard;
+        let caps = ledger
+            .caps
+            .get(tenant)
+            .copied()
+            .unwrap_or(ledger.default_caps);
+        let usage = match ledger.tenants.get_mut(tenant) {
+            Some(state) => {
+                state.roll(day, month);
+                state.usage.clone()
+            }
+            None => TenantUsage::default(),
+        };
+        if caps.daily.is_some_and(|cap| usage.today >= cap) {
+            anyhow::bail!("daily bandwidth cap exceeded for {}", tenant);
+        }
+        if caps.monthly.is_some_and(|cap| usage.this_month >= cap) {
+            anyhow::bail!("monthly bandwidth cap exceeded for {}", tenant);
+        }
+        Ok(())
+    }
+
+    // Accounts for `bytes` and returns the rate cap of the tenant, or an
+    // error once a quota is exhausted.
+    pub(crate) fn record(&self, tenant: &str, bytes: u64) -> Result<Option<u64>, anyhow::Error> {
+        let (day, month) = today();
+        let mut guard = self.inner.lock().unwrap();
+        let ledger = &mut *guard;
+        let caps = ledger
+            .caps
+            .get(tenant)
+            .copied()
+            .unwrap_or(ledger.default_caps);
+        let state = ledger
+            .tenants
+            .entry(tenant.to_owned())
+            .or_insert_with(|| TenantState {
+                usage: TenantUsage::default(),
+                day,
+                month,
+            });
+        state.roll(day, month);
+
+        state.usage.total += bytes;
+        state.usage.today += bytes;
+        state.usage.this_month += bytes;
+
+        if caps.daily.is_some_and(|cap| state.usage.today > cap) {
+            anyhow::bail!("daily bandwidth cap exceeded for {}", tenant);
+        }
+        if caps.monthly.is_some_and(|cap| state.usage.this_month > cap) {
+            anyhow::bail!("monthly bandwidth cap exceeded for {}", tenant);
+        }
+
+        Ok(caps.rate.filter(|&rate| rate > 0))
+    }
+}
+
+impl TenantState {
+    fn roll(&mut self, day: u64, month: u64) {
+        if self.day != day {
+            self.day = day;
+            self.usage.today = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.usage.this_month = 0;
+        }
+    }
+}
+
+// Current UTC day number and `year * 12 + month` index.
+fn today() -> (u64, u64) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let day = secs / 86400;
+
+    // Gregorian calendar from days since epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = day as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (day, (year * 12 + month - 1) as u64)
+}
+
+// Per connection view on the ledger.
+#[derive(Clone)]
+pub(crate) struct Meter {
+    ledger: BandwidthLedger,
+    tenant: String,
+}
+
+impl Meter {
+    // For the client with that identity, or from that address for clients
+    // without one.
+    pub(crate) fn new(ledger: BandwidthLedger, identity: Option<&str>, peer: SocketAddr) -> Self {
+        let tenant = match identity {
+            Some(identity) => identity.to_owned(),
+            None => peer.ip().to_string(),
+        };
+        Meter { ledger, tenant }
+    }
+
+    pub(crate) async fn account(&self, bytes: usize) -> Result<(), anyhow::Error> {
+        let rate = match self.ledger.record(&self.tenant, bytes as u64)? {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        let limit = RateLimit { rate, burst: rate };
+        let delay = self
+            .ledger
+            .rate_limiter()
+            .acquire(&self.tenant, limit, bytes as u64)
+            .await;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+// Reverse connection mode, for hosts behind NAT or a firewall: instead of
+// waiting for clients, the server dials out to a broker and registers under
+// its device id with a control WebSocket to `<broker>/agents/<device>`.
+// For each client the broker wants to connect, it sends an `Offer` as a
+// JSON text message, and the agent opens a WebSocket to
+// `<broker>/agents/<device>/sessions/<id>` which the broker splices with
+// the client's. That one then carries the usual protocol, served as if
+// the client had connected directly, with the broker as the peer. The
+// registration is retried with exponential backoff whenever it fails or
+// gets lost, sessions already running are not affected. With `offline`
+// set, the sessions also outlive their own connection to the broker: their
+// output gets buffered while the uplink is down, for the client to resume
+// through the broker once it's back.
+
+use crate::auth::Handshake;
+use crate::server::serve_session;
+#[cfg(feature = "device-keys")]
+use crate::{DeviceKey, PublicKey};
+use crate::{Dialer, Persistence, ServerConfig};
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::client::Request;
+use tungstenite::http::header::AUTHORIZATION;
+use tungstenite::Message;
+
+type Ws = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Clone, Debug)]
+pub struct AgentConfig {
+    // WebSocket URL of the broker, e.g. `ws://broker.example:7800/`.
+    pub broker: String,
+    pub device_id: String,
+    // Sent to the broker as a bearer token.
+    pub token: Option<String>,
+    pub dialer: Dialer,
+    // Longest wait between two registration attempts, which starts at a
+    // second and doubles.
+    pub max_backoff: Duration,
+    // Persistence of the brokered sessions, in place of the server's.
+    pub offline: Option<Persistence>,
+    // Proves the device to brokers pinning its public key, see the `keys`
+    // module.
+    #[cfg(feature = "device-keys")]
+    pub key: Option<DeviceKey>,
+    // Only trust a broker proving this key.
+    #[cfg(feature = "device-keys")]
+    pub broker_key: Option<PublicKey>,
+}
+
+impl AgentConfig {
+    pub fn new(broker: &str, device_id: &str) -> Self {
+        AgentConfig {
+            broker: broker.to_owned(),
+            device_id: device_id.to_owned(),
+            token: None,
+            dialer: Dialer::default(),
+            max_backoff: Duration::from_secs(60),
+            offline: None,
+            #[cfg(feature = "device-keys")]
+            key: None,
+            #[cfg(feature = "device-keys")]
+            broker_key: None,
+        }
+    }
+
+    // The broker URL with `path` appended.
+    fn request(&self, path: &str) -> Result<Request, anyhow::Error> {
+        let url = format!("{}/{}", self.broker.trim_end_matches('/'), path);
+        let mut request = url.into_client_request()?;
+        if let Some(ref token) = self.token {
+            let value = format!("Bearer {}", token).parse()?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        Ok(request)
+    }
+
+    // Opens a WebSocket to the broker at `path`, the keys checked.
+    async fn connect(&self, path: &str) -> Result<Ws, anyhow::Error> {
+        let request = self.request(path)?;
+        #[cfg(feature = "device-keys")]
+        let (request, nonce) = crate::keys::challenge_broker(self, request)?;
+        let (ws, response) = self.dialer.connect_websocket(request).await?;
+        #[cfg(feature = "device-keys")]
+        let ws = {
+            let mut ws = ws;
+            crate::keys::authenticate_broker(self, nonce, &response, &mut ws).await?;
+            ws
+        };
+        let _ = response;
+        Ok(ws)
+    }
+}
+
+// A client waiting for the agent, as sent by the broker.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Offer {
+    pub(crate) session: String,
+    // As authenticated by the broker.
+    #[serde(default)]
+    pub(crate) identity: Option<String>,
+    #[serde(default)]
+    pub(crate) correlation_id: Option<String>,
+    // Whether the client negotiated the `wspty.v1` subprotocol.
+    #[serde(default)]
+    pub(crate) envelope: bool,
+}
+
+pub(crate) fn control_path(device_id: &str) -> String {
+    format!("agents/{}", device_id)
+}
+
+pub(crate) fn session_path(device_id: &str, session: &str) -> String {
+    format!("agents/{}/sessions/{}", device_id, session)
+}
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+// Stays registered with the broker, forever.
+pub(crate) async fn run(agent: AgentConfig, config: Arc<ServerConfig>) {
+    let config = match agent.offline {
+        Some(ref offline) => {
+            let mut config = (*config).clone();
+            config.persistence = Some(offline.clone());
+            Arc::new(config)
+        }
+        None => config,
+    };
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match register(&agent, &config).await {
+            Ok(()) => {
+                info!("connection to broker {} lost", agent.broker);
+                backoff = MIN_BACKOFF;
+            }
+            Err(e) => warn!("failed to register with {}: {:?}", agent.broker, e),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(agent.max_backoff);
+    }
+}
+
+// Serves the offers of one control connection, returns once it is closed.
+async fn register(agent: &AgentConfig, config: &Arc<ServerConfig>) -> Result<(), anyhow::Error> {
+    let ws = agent.connect(&control_path(&agent.device_id)).await?;
+    info!("registered with {} as {}", agent.broker, agent.device_id);
+    // Only read from, pings get answered along.
+    let (_ws_outgoing, mut ws_incoming) = ws.split();
+    while let Some(msg) = ws_incoming.next().await {
+        match msg? {
+            Message::Text(text) => match serde_json::from_str::<Offer>(&text) {
+                Ok(offer) => {
+                    let (agent, config) = (agent.clone(), config.clone());
+                    tokio::spawn(async move {
+                        let session = offer.session.clone();
+                        let _ = accept(agent, config, offer)
+                            .await
+                            .map_err(|e| error!("brokered session {} failed: {:?}", session, e));
+                    });
+                }
+                Err(e) => warn!("invalid offer from {}: {}", agent.broker, e),
+            },
+            Message::Close(_) => break,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+async fn accept(
+    agent: AgentConfig,
+    config: Arc<ServerConfig>,
+    offer: Offer,
+) -> Result<(), anyhow::Error> {
+    debug!("accepting brokered session {}", offer.session);
+    let ws = agent
+        .connect(&session_path(&agent.device_id, &offer.session))
+        .await?;
+    let peer: SocketAddr = match ws.get_ref() {
+        MaybeTlsStream::Plain(stream) => stream.peer_addr()?,
+        _ => return Err(IoError::new(ErrorKind::Unsupported, "not a plain stream").into()),
+    };
+    let handshake = Handshake {
+        identity: offer.identity,
+        envelope: offer.envelope,
+        correlation_id: offer.correlation_id,
+        ..Default::default()
+    };
+    let limits = config.message_limits;
+    let (ws_outgoing, ws_incoming) = ws.split();
+    let ws_outgoing = limits.fragmenting(ws_outgoing);
+    if handshake.envelope {
+        serve_session(
+            crate::framing::outgoing(ws_outgoing),
+            crate::framing::incoming(ws_incoming),
+            peer,
+            handshake,
+            config,
+            None,
+        )
+        .await
+    } else {
+        serve_session(ws_outgoing, ws_incoming, peer, handshake, config, None).await
+    }
+}
@@ -0,0 +1,283 @@
+// Alerts from sessions, for long-running jobs to tell someone they are done
+// even with no client to see it. With `ServerConfig::alerts`, the bells the
+// pty output rings (not the BELs ending title sequences, see the
+// `sequencer` module), output after `activity` of quiet and `silence`
+// without output are POSTed as JSON to the `webhook`, with the session's id,
+// identity, profile and correlation id, and passed to
+// `EventHandler::on_alert`. Their `text` suits chat services taking Slack's
+// incoming webhook payloads. With `detached_only`, only parked sessions
+// alert (see the `persist` module). Alerts of a kind come at most once per
+// `cooldown` per session. Webhooks are plain HTTP, as in the `commands`
+// module.
+
+use crate::clock::Clock;
+use crate::sequencer::Bells;
+use crate::{EventHandler, ServerConfig, SessionHandle};
+use log::info;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+const COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct Alerts {
+    // Where alerts are POSTed, only passed to the event handler if unset.
+    pub webhook: Option<String>,
+    pub bell: bool,
+    // Alert on output after this long without any.
+    pub activity: Option<Duration>,
+    // Alert once there was no output for this long.
+    pub silence: Option<Duration>,
+    // Only alert while no client is attached.
+    pub detached_only: bool,
+    // Between two alerts of the same kind.
+    pub cooldown: Duration,
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Alerts {
+            webhook: None,
+            bell: true,
+            activity: None,
+            silence: None,
+            detached_only: false,
+            cooldown: COOLDOWN,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertKind {
+    Bell,
+    Activity,
+    Silence,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub session_id: String,
+    pub identity: Option<String>,
+    pub profile: Option<String>,
+    pub correlation_id: Option<String>,
+    // Whether no client was attached.
+    pub detached: bool,
+    pub at: SystemTime,
+    // A line for people, e.g. `bell in session 01HZX (alice)`.
+    pub text: String,
+}
+
+// What triggers alerts, on the session's clock.
+struct Watch {
+    bells: Bells,
+    last_output: Instant,
+    // Whether the quiet since the last output was alerted about.
+    silent: bool,
+    detached: bool,
+    // The last alert of each kind.
+    alerted: [Option<Instant>; 3],
+}
+
+impl Watch {
+    fn new(now: Instant) -> Self {
+        Watch {
+            bells: Bells::default(),
+            last_output: now,
+            silent: false,
+            detached: false,
+            alerted: [None; 3],
+        }
+    }
+
+    // Takes the alert unless it is not to be sent.
+    fn alert(&mut self, alerts: &Alerts, kind: AlertKind, now: Instant) -> bool {
+        if alerts.detached_only && !self.detached {
+            return false;
+        }
+        let alerted = &mut self.alerted[kind as usize];
+        if alerted.is_some_and(|alerted| now.saturating_duration_since(alerted) < alerts.cooldown) {
+            return false;
+        }
+        *alerted = Some(now);
+        true
+    }
+
+    fn output(&mut self, alerts: &Alerts, data: &[u8], now: Instant) -> Vec<AlertKind> {
+        let mut kinds = vec![];
+        if self.bells.count(data) > 0 && alerts.bell && self.alert(alerts, AlertKind::Bell, now) {
+            kinds.push(AlertKind::Bell);
+        }
+        let quiet = now.saturating_duration_since(self.last_output);
+        if alerts.activity.is_some_and(|activity| quiet >= activity)
+            && self.alert(alerts, AlertKind::Activity, now)
+        {
+            kinds.push(AlertKind::Activity);
+        }
+        self.last_output = now;
+        self.silent = false;
+        kinds
+    }
+
+    // When to check for silence next.
+    fn next_check(&self, silence: Duration, now: Instant) -> Instant {
+        if self.silent {
+            now + silence
+        } else {
+            self.last_output + silence
+        }
+    }
+
+    fn silence(&mut self, alerts: &Alerts, silence: Duration, now: Instant) -> bool {
+        if self.silent || now.saturating_duration_since(self.last_output) < silence {
+            return false;
+        }
+        self.silent = true;
+        self.alert(alerts, AlertKind::Silence, now)
+    }
+}
+
+// The alerts of a session.
+pub(crate) struct Monitor {
+    alerts: Alerts,
+    events: Option<Arc<dyn EventHandler>>,
+    clock: Arc<dyn Clock>,
+    watch: Mutex<Watch>,
+}
+
+impl Monitor {
+    pub(crate) fn new(config: &ServerConfig, clock: Arc<dyn Clock>) -> Option<Arc<Self>> {
+        let alerts = config.alerts.clone()?;
+        let watch = Mutex::new(Watch::new(clock.now()));
+        Some(Arc::new(Monitor {
+            alerts,
+            events: config.events.clone(),
+            clock,
+            watch,
+        }))
+    }
+
+    // Follows the pty output, parked sessions' too.
+    pub(crate) fn output(&self, handle: &SessionHandle, data: &[u8]) {
+        let kinds = self
+            .watch
+            .lock()
+            .unwrap()
+            .output(&self.alerts, data, self.clock.now());
+        for kind in kinds {
+            self.send(handle, kind);
+        }
+    }
+
+    pub(crate) fn detached(&self, detached: bool) {
+        self.watch.lock().unwrap().detached = detached;
+    }
+
+    // Checks for silence until the session is over.
+    pub(crate) async fn run(self: Arc<Self>, handle: SessionHandle) {
+        let silence = match self.alerts.silence {
+            Some(silence) => silence,
+            None => return,
+        };
+        let mut done = handle.done.subscribe();
+        loop {
+            let next = self
+                .watch
+                .lock()
+                .unwrap()
+                .next_check(silence, self.clock.now());
+            tokio::select! {
+                _ = self.clock.sleep_until(next) => {}
+                _ = done.wait_for(|done| *done) => return,
+            }
+            let silent =
+                self.watch
+                    .lock()
+                    .unwrap()
+                    .silence(&self.alerts, silence, self.clock.now());
+            if silent {
+                self.send(&handle, AlertKind::Silence);
+            }
+        }
+    }
+
+    fn send(&self, handle: &SessionHandle, kind: AlertKind) {
+        let detached = self.watch.lock().unwrap().detached;
+        let mut text = format!("{} in session {}", name(kind), handle.session_id());
+        if let Some(ref identity) = handle.identity {
+            text += &format!(" ({})", identity);
+        }
+        info!("session {}: {}", handle, name(kind));
+        let alert = Alert {
+            kind,
+            session_id: handle.session_id().to_owned(),
+            identity: handle.identity.clone(),
+            profile: handle.profile.clone(),
+            correlation_id: handle.correlation_id.clone(),
+            detached,
+            at: SystemTime::now(),
+            text,
+        };
+        if let Some(ref events) = self.events {
+            events.on_alert(handle, &alert);
+        }
+        if let Some(ref webhook) = self.alerts.webhook {
+            let body = serde_json::to_vec(&alert).unwrap_or_default();
+            crate::commands::spawn_post(webhook.clone(), body, "alert");
+        }
+    }
+}
+
+fn name(kind: AlertKind) -> &'static str {
+    match kind {
+        AlertKind::Bell => "bell",
+        AlertKind::Activity => "activity",
+        AlertKind::Silence => "silence",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triggers() {
+        let alerts = Alerts {
+            activity: Some(Duration::from_secs(30)),
+            silence: Some(Duration::from_secs(60)),
+            ..Alerts::default()
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut watch = Watch::new(start);
+        assert_eq!(watch.output(&alerts, b"make\x07", at(1)), [AlertKind::Bell]);
+        // Within the cooldown.
+        assert_eq!(watch.output(&alerts, b"\x07", at(2)), []);
+        assert_eq!(watch.output(&alerts, b"\x1b]0;make\x07", at(10)), []);
+        let silence = Duration::from_secs(60);
+        assert!(!watch.silence(&alerts, silence, at(50)));
+        assert_eq!(watch.next_check(silence, at(50)), at(70));
+        assert!(watch.silence(&alerts, silence, at(70)));
+        assert!(!watch.silence(&alerts, silence, at(200)));
+        assert_eq!(
+            watch.output(&alerts, b"done", at(300)),
+            [AlertKind::Activity]
+        );
+    }
+
+    #[test]
+    fn detached_only() {
+        let alerts = Alerts {
+            detached_only: true,
+            ..Alerts::default()
+        };
+        let start = Instant::now();
+        let mut watch = Watch::new(start);
+        assert_eq!(watch.output(&alerts, b"\x07", start), []);
+        watch.detached = true;
+        assert_eq!(watch.output(&alerts, b"\x07", start), [AlertKind::Bell]);
+    }
+}
@@ -0,0 +1,88 @@
+// Who can type in sessions shared between their client and the embedder's
+// attachments, with `ServerConfig::input_arbitration`. The client is
+// participant 0 and starts out with write access, attachments only get it
+// once the client sends a `proto::Grant` for their id, see
+// `Attachment::id`. The client can also take its own back, to hand the
+// keyboard over. Input without write access is dropped, signals and
+// environment updates included. The client gets a `proto::Participants`
+// message after each change so that it can show who has the keyboard.
+
+use crate::outbox::Outbox;
+use crate::SessionHandle;
+use log::debug;
+use std::collections::BTreeMap;
+use tungstenite::Message;
+use wspty_proto::{Participant, Participants, ServerMessage};
+
+pub(crate) const CLIENT: u64 = 0;
+
+pub(crate) struct Floor {
+    enabled: bool,
+    // Whether each participant can write.
+    participants: BTreeMap<u64, bool>,
+    next: u64,
+}
+
+impl Default for Floor {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+impl Floor {
+    pub(crate) fn new(enabled: bool) -> Self {
+        Floor {
+            enabled,
+            participants: std::iter::once((CLIENT, true)).collect(),
+            next: CLIENT + 1,
+        }
+    }
+
+    pub(crate) fn can_write(&self, participant: u64) -> bool {
+        !self.enabled || self.participants.get(&participant) == Some(&true)
+    }
+
+    // Returns the id of the new participant.
+    pub(crate) fn join(&mut self) -> u64 {
+        let participant = self.next;
+        self.next += 1;
+        self.participants.insert(participant, false);
+        participant
+    }
+
+    pub(crate) fn leave(&mut self, participant: u64) {
+        self.participants.remove(&participant);
+    }
+
+    // Returns whether that changed anything.
+    pub(crate) fn grant(&mut self, participant: u64, write: bool) -> bool {
+        match self.participants.get_mut(&participant) {
+            Some(access) if self.enabled && *access != write => {
+                *access = write;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn message(&self) -> Message {
+        let participants = self
+            .participants
+            .iter()
+            .map(|(&id, &write)| Participant { id, write })
+            .collect();
+        Message::Binary(ServerMessage::Participants(Participants { participants }).encode())
+    }
+}
+
+// Tells the client about each change, until the session is over.
+pub(crate) async fn watch(handle: SessionHandle, sender: Outbox) {
+    let mut floor = handle.floor.subscribe();
+    loop {
+        let msg = floor.borrow_and_update().message();
+        if sender.send(msg).is_err() || floor.changed().await.is_err() {
+            break;
+        }
+    }
+    debug!("session {} input arbitration done", handle);
+}
@@ -0,0 +1,383 @@
+use crate::bind::BindOptions;
+use crate::colors::ColorLevel;
+use crate::tokens::{Capabilities, TokenClaims};
+use crate::ServerConfig;
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tungstenite::http::header::{AUTHORIZATION, CONTENT_TYPE, ORIGIN, SEC_WEBSOCKET_PROTOCOL};
+use tungstenite::http::{HeaderValue, StatusCode};
+use wspty_proto::protocol::{CloseReason, SUBPROTOCOL};
+use wspty_proto::Decision;
+
+pub type UpgradeRequest = Request;
+
+// Decides who may open sessions, from the WebSocket upgrade request (its
+// headers and URI). Returns the client identity, or why it was refused.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, request: &UpgradeRequest, peer: SocketAddr) -> Result<String, String>;
+}
+
+// Validates the bearer tokens of `ServerConfig::token_auth`, returns the
+// client identity and what it may do, or why the token was refused. See
+// `TokenPolicy` for short-lived tokens.
+pub type TokenValidator =
+    Arc<dyn Fn(&str, SocketAddr) -> Result<TokenClaims, String> + Send + Sync>;
+
+// Settings clients may pick with the query string of the upgrade request,
+// for web embeddings that can only change the URL, e.g.
+// `?profile=logs&cols=200&rows=50`. Parameters not allowed here are
+// ignored, the `token` one is always accepted with `token_auth`.
+#[derive(Clone, Debug, Default)]
+pub struct QueryOverrides {
+    // `profile`, one of `ServerConfig::profiles`.
+    pub profile: bool,
+    // `cols` and `rows`, the size of the terminal until the client resizes
+    // it, within `ServerConfig::max_size`.
+    pub size: bool,
+    // `theme`, one of `ServerConfig::themes`.
+    pub theme: bool,
+}
+
+// The first `name` parameter of the request's query string, taken as is.
+fn query_param<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request.uri().query()?.split('&').find_map(|param| {
+        param
+            .strip_prefix(name)
+            .and_then(|rest| rest.strip_prefix('='))
+    })
+}
+
+// The bearer token of an upgrade request, from the `Authorization` header
+// or the `token` query parameter, taken as is.
+pub(crate) fn bearer_token(request: &Request) -> Option<&str> {
+    let header = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    header.or_else(|| query_param(request, "token"))
+}
+
+// Listener besides the default one, with its own authentication realm. For
+// instance an admin port with a stricter authenticator than the user one.
+#[derive(Clone)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    // Interface and dual-stack options, see the `bind` module.
+    pub bind: BindOptions,
+    // Only upgrade requests for this path are accepted.
+    pub path: Option<String>,
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    // Serve over TLS, see the `tls` module.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+// Why a client was turned away.
+#[derive(Debug)]
+pub(crate) enum Refusal {
+    NotFound,
+    // An `Origin` not in `ServerConfig::allowed_origins`.
+    Origin,
+    Unauthorized(String),
+    Banned,
+    Quota(String),
+    Capacity(String),
+    // No agent registered under the device id, see the `broker` module.
+    Offline,
+    Command(String),
+    // An invalid `proto::SpawnRequest`, see the `spawn` module.
+    Spawn(String),
+    LegacyFraming,
+}
+
+impl Refusal {
+    fn status(&self) -> StatusCode {
+        match self {
+            Refusal::NotFound => StatusCode::NOT_FOUND,
+            Refusal::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Refusal::Origin | Refusal::Banned | Refusal::Command(_) => StatusCode::FORBIDDEN,
+            Refusal::Quota(_) => StatusCode::TOO_MANY_REQUESTS,
+            Refusal::Capacity(_) | Refusal::Offline => StatusCode::SERVICE_UNAVAILABLE,
+            Refusal::Spawn(_) | Refusal::LegacyFraming => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    // The HTTP response turning the upgrade request down.
+    pub(crate) fn response(self) -> ErrorResponse {
+        let status = self.status();
+        let body = serde_json::to_string(&Decision::from(self)).unwrap_or_default();
+        let mut response = ErrorResponse::new(Some(body));
+        *response.status_mut() = status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        response
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::NotFound => write!(f, "no such path"),
+            Refusal::Origin => write!(f, "origin not allowed"),
+            Refusal::Unauthorized(reason)
+            | Refusal::Quota(reason)
+            | Refusal::Capacity(reason)
+            | Refusal::Command(reason)
+            | Refusal::Spawn(reason) => write!(f, "{}", reason),
+            Refusal::Banned => write!(f, "banned"),
+            Refusal::Offline => write!(f, "device offline"),
+            Refusal::LegacyFraming => write!(f, "the {} subprotocol is required", SUBPROTOCOL),
+        }
+    }
+}
+
+impl From<Refusal> for Decision {
+    fn from(refusal: Refusal) -> Self {
+        Decision {
+            allowed: false,
+            reason: Some(refusal.to_string()),
+        }
+    }
+}
+
+impl From<Refusal> for CloseReason {
+    fn from(refusal: Refusal) -> Self {
+        match refusal {
+            Refusal::Unauthorized(reason) => CloseReason::Unauthorized(reason),
+            Refusal::Banned => CloseReason::Banned,
+            Refusal::Quota(reason) => CloseReason::Quota(reason),
+            Refusal::Capacity(reason) => CloseReason::Capacity(reason),
+            Refusal::Offline => CloseReason::DeviceOffline,
+            refusal => CloseReason::Forbidden(refusal.to_string()),
+        }
+    }
+}
+
+// What the upgrade request told about the client.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Handshake {
+    // As returned by the authenticator.
+    pub(crate) identity: Option<String>,
+    // Set once the token required by `token_auth` was checked.
+    pub(crate) authenticated: bool,
+    // Set when the client negotiated `SUBPROTOCOL`, see the `framing` module.
+    pub(crate) envelope: bool,
+    // See `SessionHandle::correlation_id`.
+    pub(crate) correlation_id: Option<String>,
+    // Picked in the query string, see `QueryOverrides`.
+    pub(crate) profile: Option<String>,
+    pub(crate) size: Option<(u16, u16)>,
+    pub(crate) theme: Option<String>,
+    // What the client can show, see the `colors` module.
+    pub(crate) colors: Option<ColorLevel>,
+    // Granted by its token, see the `tokens` module.
+    pub(crate) capabilities: Capabilities,
+    // Asked for and granted, see the `power` module.
+    pub(crate) low_power: bool,
+    // Asked for and granted, see the `control` module.
+    pub(crate) split: bool,
+    // Frames per second the client declared it renders, with
+    // `ServerConfig::render_hints`, see the `render` module.
+    pub(crate) render_rate: Option<u32>,
+}
+
+// Checks an upgrade request against a listener's realm and the admission
+// policies, filling `handshake`. Refused clients get an HTTP error with a
+// JSON `proto::Decision` body.
+pub(crate) struct Upgrade<'a> {
+    pub(crate) config: &'a ServerConfig,
+    pub(crate) listener: &'a Listener,
+    pub(crate) peer: SocketAddr,
+    pub(crate) handshake: &'a mut Handshake,
+}
+
+const CORRELATION_ID: &str = "x-correlation-id";
+const COLOR_LEVEL: &str = "x-color-level";
+const POWER_MODE: &str = "x-power-mode";
+const CONTROL_CHANNEL: &str = "x-control-channel";
+const RENDER_RATE: &str = "x-render-rate";
+
+// The client's correlation id, from the `X-Correlation-Id` header or the
+// `correlation_id` query parameter. Up to 128 printable ASCII characters,
+// others are ignored.
+pub(crate) fn correlation_id(request: &Request) -> Option<String> {
+    let header = request
+        .headers()
+        .get(CORRELATION_ID)
+        .and_then(|value| value.to_str().ok());
+    let id = header.or_else(|| query_param(request, "correlation_id"))?;
+    let valid = (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic());
+    valid.then(|| id.to_owned())
+}
+
+// From the `X-Color-Level` header or the `colors` query parameter, unknown
+// levels are ignored.
+fn color_level(request: &Request) -> Option<ColorLevel> {
+    let header = request
+        .headers()
+        .get(COLOR_LEVEL)
+        .and_then(|value| value.to_str().ok());
+    ColorLevel::parse(header.or_else(|| query_param(request, "colors"))?)
+}
+
+// From the `X-Power-Mode` header or the `power` query parameter.
+fn wants_low_power(request: &Request) -> bool {
+    let header = request
+        .headers()
+        .get(POWER_MODE)
+        .and_then(|value| value.to_str().ok());
+    header.or_else(|| query_param(request, "power")) == Some("low")
+}
+
+// From the `X-Control-Channel` header or the `control` query parameter.
+fn wants_split(request: &Request) -> bool {
+    let header = request
+        .headers()
+        .get(CONTROL_CHANNEL)
+        .and_then(|value| value.to_str().ok());
+    header.or_else(|| query_param(request, "control")) == Some("split")
+}
+
+// From the `X-Render-Rate` header or the `fps` query parameter, rates that
+// aren't positive integers are ignored.
+fn render_rate(request: &Request) -> Option<u32> {
+    let header = request
+        .headers()
+        .get(RENDER_RATE)
+        .and_then(|value| value.to_str().ok());
+    let rate = header.or_else(|| query_param(request, "fps"))?;
+    rate.parse().ok().filter(|&rate| rate > 0)
+}
+
+// Whether the request lists `SUBPROTOCOL` in `Sec-WebSocket-Protocol`.
+pub(crate) fn wants_envelope(request: &Request) -> bool {
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == SUBPROTOCOL)
+}
+
+impl Upgrade<'_> {
+    fn check(&mut self, request: &Request) -> Result<(), Refusal> {
+        if let Some(ref path) = self.listener.path {
+            if request.uri().path() != path {
+                return Err(Refusal::NotFound);
+            }
+        }
+        if let Some(ref origins) = self.config.allowed_origins {
+            if let Some(origin) = request.headers().get(ORIGIN) {
+                let origin = origin.to_str().unwrap_or_default();
+                if !origins.iter().any(|allowed| allowed == origin) {
+                    return Err(Refusal::Origin);
+                }
+            }
+        }
+        let anonymous = self.listener.authenticator.is_none() && !self.config.authenticates();
+        if self.config.require_auth && anonymous {
+            return Err(Refusal::Unauthorized("authentication required".to_owned()));
+        }
+        if let Some(ref authenticator) = self.listener.authenticator {
+            let identity = authenticator
+                .authenticate(request, self.peer)
+                .map_err(Refusal::Unauthorized)?;
+            self.handshake.identity = Some(identity);
+        }
+        if let Some(ref validate) = self.config.token_auth {
+            if let Some(token) = bearer_token(request) {
+                let claims = validate(token, self.peer).map_err(Refusal::Unauthorized)?;
+                self.handshake.identity.get_or_insert(claims.identity);
+                self.handshake.capabilities = claims.capabilities;
+                self.handshake.authenticated = true;
+            }
+        }
+        self.handshake.envelope = wants_envelope(request);
+        if self.config.reject_legacy_framing && !self.handshake.envelope {
+            return Err(Refusal::LegacyFraming);
+        }
+        self.handshake.correlation_id = correlation_id(request);
+        self.handshake.colors = color_level(request);
+        self.handshake.low_power = self.config.low_power.is_some() && wants_low_power(request);
+        self.handshake.split = self.config.control_channels.is_some()
+            && !self.handshake.envelope
+            && wants_split(request);
+        if self.config.render_hints.is_some() {
+            self.handshake.render_rate = render_rate(request);
+        }
+        self.overrides(request)?;
+        self.config
+            .check_peer(self.peer, self.handshake.identity.as_deref())
+    }
+}
+
+impl Upgrade<'_> {
+    fn overrides(&mut self, request: &Request) -> Result<(), Refusal> {
+        let allowed = &self.config.query_overrides;
+        if let Some(name) = query_param(request, "profile").filter(|_| allowed.profile) {
+            self.config.resolve_profile(name).map_err(Refusal::Spawn)?;
+            self.handshake.profile = Some(name.to_owned());
+        }
+        if allowed.size {
+            let dimension = |name| {
+                query_param(request, name)
+                    .and_then(|value| value.parse::<u16>().ok())
+                    .filter(|&value| value > 0)
+            };
+            if let (Some(cols), Some(rows)) = (dimension("cols"), dimension("rows")) {
+                self.handshake.size = Some((cols, rows));
+            }
+        }
+        if let Some(name) = query_param(request, "theme").filter(|_| allowed.theme) {
+            if !self.config.themes.contains_key(name) {
+                return Err(Refusal::Spawn(format!("no such theme: {}", name)));
+            }
+            self.handshake.theme = Some(name.to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Callback for Upgrade<'_> {
+    fn on_request(
+        mut self,
+        request: &Request,
+        response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        match self.check(request) {
+            Ok(()) => {
+                let mut response = response;
+                if self.handshake.envelope {
+                    response.headers_mut().insert(
+                        SEC_WEBSOCKET_PROTOCOL,
+                        HeaderValue::from_static(SUBPROTOCOL),
+                    );
+                }
+                if self.handshake.low_power {
+                    response
+                        .headers_mut()
+                        .insert(POWER_MODE, HeaderValue::from_static("low"));
+                }
+                if self.handshake.split {
+                    response
+                        .headers_mut()
+                        .insert(CONTROL_CHANNEL, HeaderValue::from_static("split"));
+                }
+                if let Some(rate) = self.handshake.render_rate {
+                    response
+                        .headers_mut()
+                        .insert(RENDER_RATE, HeaderValue::from(rate));
+                }
+                Ok(response)
+            }
+            Err(refusal) => Err(refusal.response()),
+        }
+    }
+}
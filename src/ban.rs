@@ -0,0 +1,110 @@
+use crate::SessionHandle;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tungstenite::protocol::frame::coding::CloseCode;
+use wspty_proto::protocol::CloseReason;
+
+#[derive(Default, Serialize, Deserialize)]
+struct Bans {
+    ips: BTreeSet<IpAddr>,
+    identities: BTreeSet<String>,
+}
+
+#[derive(Default)]
+struct State {
+    bans: Bans,
+    // Saved there on every change when set.
+    path: Option<PathBuf>,
+    sessions: HashMap<u64, SessionHandle>,
+}
+
+// Clients kept out by IP address or identity. Banning one terminates its
+// live sessions and refuses its next handshakes.
+#[derive(Clone, Default)]
+pub struct BanList {
+    inner: Arc<Mutex<State>>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Loads the bans saved in `path` if it exists, and keeps it up to date.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let bans = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Bans::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(BanList {
+            inner: Arc::new(Mutex::new(State {
+                bans,
+                path: Some(path.to_owned()),
+                sessions: HashMap::new(),
+            })),
+        })
+    }
+
+    pub fn ban_ip(&self, ip: IpAddr) -> Result<(), anyhow::Error> {
+        self.update(|bans| bans.ips.insert(ip))
+    }
+
+    pub fn ban_identity(&self, identity: &str) -> Result<(), anyhow::Error> {
+        self.update(|bans| bans.identities.insert(identity.to_owned()))
+    }
+
+    pub fn unban_ip(&self, ip: IpAddr) -> Result<(), anyhow::Error> {
+        self.update(|bans| bans.ips.remove(&ip))
+    }
+
+    pub fn unban_identity(&self, identity: &str) -> Result<(), anyhow::Error> {
+        self.update(|bans| bans.identities.remove(identity))
+    }
+
+    pub fn is_banned(&self, ip: IpAddr, identity: Option<&str>) -> bool {
+        self.inner.lock().unwrap().bans.matches(ip, identity)
+    }
+
+    pub(crate) fn register(&self, handle: &SessionHandle) {
+        let mut state = self.inner.lock().unwrap();
+        if state.bans.matches(handle.peer().ip(), handle.identity()) {
+            handle.close(CloseCode::Policy, CloseReason::Banned);
+        }
+        state.sessions.insert(handle.id(), handle.clone());
+    }
+
+    pub(crate) fn unregister(&self, id: u64) {
+        self.inner.lock().unwrap().sessions.remove(&id);
+    }
+
+    // Applies `change`, then saves the list and terminates the sessions
+    // now banned if it did change anything.
+    fn update<F: FnOnce(&mut Bans) -> bool>(&self, change: F) -> Result<(), anyhow::Error> {
+        let mut state = self.inner.lock().unwrap();
+        if !change(&mut state.bans) {
+            return Ok(());
+        }
+        for handle in state.sessions.values() {
+            if state.bans.matches(handle.peer().ip(), handle.identity()) {
+                info!("terminating banned session {}", handle);
+                handle.close(CloseCode::Policy, CloseReason::Banned);
+            }
+        }
+        if let Some(ref path) = state.path {
+            std::fs::write(path, serde_json::to_vec(&state.bans)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Bans {
+    fn matches(&self, ip: IpAddr, identity: Option<&str>) -> bool {
+        self.ips.contains(&ip) || identity.is_some_and(|id| self.identities.contains(id))
+    }
+}
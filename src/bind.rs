@@ -0,0 +1,91 @@
+// Socket options of the TCP listeners, for hosts where the terminal must
+// only be reachable from some network, a management VLAN on an appliance
+// for instance. With `interface` the socket is bound to that device
+// (`SO_BINDTODEVICE`, on Linux) and connections coming through others are
+// never accepted, whatever the address. Link-local IPv6 addresses given
+// without a scope id, `fe80::1` rather than `fe80::1%2`, get the device's.
+// `v6only` picks whether a listener on an IPv6 address, `[::]` for
+// instance, also takes IPv4 clients as v4-mapped addresses rather than
+// leaving it to the system default.
+
+use nix::sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn, SockaddrIn6};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+const BACKLOG: usize = 128;
+
+#[derive(Clone, Debug, Default)]
+pub struct BindOptions {
+    // Network device name, `eth0.100` for instance.
+    pub interface: Option<String>,
+    // `IPV6_V6ONLY` for IPv6 addresses, the system default if unset.
+    pub v6only: Option<bool>,
+}
+
+impl BindOptions {
+    // `addr` with the interface's scope id if it is link-local and has none.
+    fn scoped(&self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        match (addr, self.interface.as_deref()) {
+            (SocketAddr::V6(mut v6), Some(interface))
+                if v6.scope_id() == 0 && v6.ip().is_unicast_link_local() =>
+            {
+                v6.set_scope_id(nix::net::if_::if_nametoindex(interface)?);
+                Ok(SocketAddr::V6(v6))
+            }
+            _ => Ok(addr),
+        }
+    }
+}
+
+pub(crate) fn bind(addr: SocketAddr, options: &BindOptions) -> io::Result<TcpListener> {
+    let addr = options.scoped(addr)?;
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket::socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    // Owned right away, for it to be closed on errors.
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    socket::setsockopt(fd, sockopt::ReuseAddr, &true)?;
+    if let (SocketAddr::V6(_), Some(v6only)) = (addr, options.v6only) {
+        socket::setsockopt(fd, sockopt::Ipv6V6Only, &v6only)?;
+    }
+    if let Some(ref interface) = options.interface {
+        bind_to_device(fd, interface)?;
+    }
+    match addr {
+        SocketAddr::V4(v4) => socket::bind(fd, &SockaddrIn::from(v4))?,
+        SocketAddr::V6(v6) => socket::bind(fd, &SockaddrIn6::from(v6))?,
+    }
+    socket::listen(fd, BACKLOG)?;
+    Ok(listener)
+}
+
+// For the tokio runtime. Listeners inherited for `addr` are used as they
+// are, see the `handover` module.
+pub(crate) fn listen(
+    addr: SocketAddr,
+    options: &BindOptions,
+) -> io::Result<tokio::net::TcpListener> {
+    let listener = match crate::handover::take_listener(addr) {
+        Some(listener) => listener,
+        None => bind(addr, options)?,
+    };
+    listener.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(listener)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_to_device(fd: RawFd, interface: &str) -> io::Result<()> {
+    socket::setsockopt(fd, sockopt::BindToDevice, &interface.into())?;
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_to_device(_fd: RawFd, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
@@ -0,0 +1,484 @@
+// Rendezvous server for the agents of the `agent` module, for consoles on
+// hosts clients can't reach. Agents register with a control WebSocket to
+// `/agents/<device>`, clients connect to `/devices/<device>`. The broker
+// then sends the agent an `Offer`, waits for it to connect back to
+// `/agents/<device>/sessions/<id>`, and splices both WebSockets message by
+// message. It never looks into the sessions, which are authorized by the
+// agent with the client identity passed along in the offer.
+//
+// A device registering again replaces its previous control connection,
+// spliced sessions are not affected.
+
+use crate::agent::Offer;
+use crate::auth::{bearer_token, correlation_id, wants_envelope, Refusal};
+use crate::Authenticator;
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+#[cfg(feature = "device-keys")]
+use crate::{DeviceKey, PublicKey};
+use futures::{SinkExt, Stream, StreamExt};
+use log::{debug, error, info};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio_tungstenite::{accept_hdr_async, WebSocketStream};
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+use tungstenite::http::HeaderValue;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::protocol::{CloseReason, SUBPROTOCOL};
+
+// Checks the bearer token of an agent for the device id it registers as.
+pub type AgentValidator = Arc<dyn Fn(&str, &str) -> Result<(), String> + Send + Sync>;
+
+// Whether a client, with the identity its authenticator returned, may reach
+// a device.
+pub type DeviceAccess = Arc<dyn Fn(Option<&str>, &str) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct BrokerConfig {
+    pub addr: SocketAddr,
+    // Serve agents and clients over TLS, see the `tls` module.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    // Any agent can register if unset.
+    pub agent_auth: Option<AgentValidator>,
+    // Checks the upgrade requests of clients, see `Authenticator`.
+    pub client_auth: Option<Arc<dyn Authenticator>>,
+    // Any client can reach any device if unset.
+    pub device_access: Option<DeviceAccess>,
+    // How long clients wait for the agent to connect back.
+    pub answer_timeout: Duration,
+    // Proves the broker to agents pinning its public key, see the `keys`
+    // module.
+    #[cfg(feature = "device-keys")]
+    pub key: Option<DeviceKey>,
+    // Only devices listed here may register, proving their key.
+    #[cfg(feature = "device-keys")]
+    pub device_keys: Option<HashMap<String, PublicKey>>,
+}
+
+impl BrokerConfig {
+    pub fn new(addr: SocketAddr) -> Self {
+        BrokerConfig {
+            addr,
+            #[cfg(feature = "tls")]
+            tls: None,
+            agent_auth: None,
+            client_auth: None,
+            device_access: None,
+            answer_timeout: Duration::from_secs(10),
+            #[cfg(feature = "device-keys")]
+            key: None,
+            #[cfg(feature = "device-keys")]
+            device_keys: None,
+        }
+    }
+}
+
+// Keeps the control connections of agents open through NATs.
+const CONTROL_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+type Ws = WebSocketStream<Box<dyn Io>>;
+
+struct Agent {
+    // Tells a replaced registration from the current one.
+    generation: u64,
+    offers: UnboundedSender<Offer>,
+}
+
+struct Pending {
+    device: String,
+    answer: oneshot::Sender<Ws>,
+}
+
+#[derive(Default)]
+struct State {
+    agents: Mutex<HashMap<String, Agent>>,
+    pending: Mutex<HashMap<String, Pending>>,
+    counter: AtomicU64,
+    hasher: RandomState,
+}
+
+impl State {
+    fn next(&self) -> u64 {
+        self.counter.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Not guessable, though answers are authenticated anyway.
+    fn session_id(&self) -> String {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write_u64(self.next());
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+enum Route {
+    Control(String),
+    Answer(String, String),
+    Client(String),
+}
+
+impl Route {
+    fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match segments[..] {
+            ["agents", device] if !device.is_empty() => Some(Route::Control(device.to_owned())),
+            ["agents", device, "sessions", session] if !device.is_empty() => {
+                Some(Route::Answer(device.to_owned(), session.to_owned()))
+            }
+            ["devices", device] if !device.is_empty() => Some(Route::Client(device.to_owned())),
+            _ => None,
+        }
+    }
+}
+
+// What the upgrade request turned out to be.
+enum Accepted {
+    Control(String),
+    Answer(String, String),
+    Client(String, Offer),
+}
+
+struct Upgrade<'a> {
+    config: &'a BrokerConfig,
+    state: &'a State,
+    peer: SocketAddr,
+    accepted: &'a mut Option<Accepted>,
+    #[cfg(feature = "device-keys")]
+    challenge: &'a mut Option<crate::keys::Challenge>,
+}
+
+impl Upgrade<'_> {
+    fn check_agent(
+        &mut self,
+        request: &Request,
+        response: &mut Response,
+        device: &str,
+    ) -> Result<(), Refusal> {
+        if let Some(ref validate) = self.config.agent_auth {
+            let token = bearer_token(request)
+                .ok_or_else(|| Refusal::Unauthorized("no token".to_owned()))?;
+            validate(device, token).map_err(Refusal::Unauthorized)?;
+        }
+        #[cfg(feature = "device-keys")]
+        {
+            *self.challenge = crate::keys::answer_agent(self.config, device, request, response)
+                .map_err(Refusal::Unauthorized)?;
+        }
+        let _ = response;
+        Ok(())
+    }
+
+    fn check(&mut self, request: &Request, response: &mut Response) -> Result<(), Refusal> {
+        let route = Route::parse(request.uri().path()).ok_or(Refusal::NotFound)?;
+        let accepted = match route {
+            Route::Control(device) => {
+                self.check_agent(request, response, &device)?;
+                Accepted::Control(device)
+            }
+            Route::Answer(device, session) => {
+                self.check_agent(request, response, &device)?;
+                match self.state.pending.lock().unwrap().get(&session) {
+                    Some(waiting) if waiting.device == device => Accepted::Answer(device, session),
+                    _ => return Err(Refusal::NotFound),
+                }
+            }
+            Route::Client(device) => {
+                let identity = match self.config.client_auth {
+                    Some(ref authenticator) => Some(
+                        authenticator
+                            .authenticate(request, self.peer)
+                            .map_err(Refusal::Unauthorized)?,
+                    ),
+                    None => None,
+                };
+                if let Some(ref allowed) = self.config.device_access {
+                    if !allowed(identity.as_deref(), &device) {
+                        return Err(Refusal::Unauthorized(format!("not allowed on {}", device)));
+                    }
+                }
+                if !self.state.agents.lock().unwrap().contains_key(&device) {
+                    return Err(Refusal::Offline);
+                }
+                let envelope = wants_envelope(request);
+                if envelope {
+                    // The agent speaks the same framing for the session.
+                    response.headers_mut().insert(
+                        SEC_WEBSOCKET_PROTOCOL,
+                        HeaderValue::from_static(SUBPROTOCOL),
+                    );
+                }
+                let offer = Offer {
+                    session: self.state.session_id(),
+                    identity,
+                    correlation_id: correlation_id(request),
+                    envelope,
+                };
+                Accepted::Client(device, offer)
+            }
+        };
+        self.accepted.replace(accepted);
+        Ok(())
+    }
+}
+
+impl Callback for Upgrade<'_> {
+    fn on_request(
+        mut self,
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        match self.check(request, &mut response) {
+            Ok(()) => Ok(response),
+            Err(refusal) => {
+                debug!("refused {} from {}: {}", request.uri(), self.peer, refusal);
+                Err(refusal.response())
+            }
+        }
+    }
+}
+
+pub async fn start_broker(config: BrokerConfig) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(config.addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to listen on {}: {:?}", config.addr, e))?;
+    #[cfg(feature = "tls")]
+    let tls = match config.tls {
+        Some(ref tls) => Some(tls.load()?),
+        None => None,
+    };
+    let config = Arc::new(config);
+    let state = Arc::new(State::default());
+    while let Ok((stream, peer)) = listener.accept().await {
+        let (config, state) = (config.clone(), state.clone());
+        #[cfg(feature = "tls")]
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            #[cfg(feature = "tls")]
+            let stream: Box<dyn Io> = match tls {
+                Some(tls) => match crate::tls::accept(stream, tls).await {
+                    Ok(stream) => Box::new(stream),
+                    Err(e) => return debug!("TLS handshake with {} failed: {}", peer, e),
+                },
+                None => Box::new(stream),
+            };
+            #[cfg(not(feature = "tls"))]
+            let stream: Box<dyn Io> = Box::new(stream);
+            let _ = handle_connection(stream, peer, config, state)
+                .await
+                .map_err(|e| error!("broker connection from {} failed: {:?}", peer, e));
+        });
+    }
+    Ok(())
+}
+
+async fn handle_connection(
+    stream: Box<dyn Io>,
+    peer: SocketAddr,
+    config: Arc<BrokerConfig>,
+    state: Arc<State>,
+) -> Result<(), anyhow::Error> {
+    let mut accepted = None;
+    #[cfg(feature = "device-keys")]
+    let mut challenge = None;
+    let upgrade = Upgrade {
+        config: &config,
+        state: &state,
+        peer,
+        accepted: &mut accepted,
+        #[cfg(feature = "device-keys")]
+        challenge: &mut challenge,
+    };
+    let ws = accept_hdr_async(stream, upgrade).await?;
+    #[cfg(feature = "device-keys")]
+    let ws = match challenge {
+        Some(challenge) => {
+            let mut ws = ws;
+            if let Err(e) = crate::keys::authenticate_agent(&config, challenge, &mut ws).await {
+                let _ = ws
+                    .close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: CloseReason::KeyNotProven.to_string().into(),
+                    }))
+                    .await;
+                return Err(e);
+            }
+            ws
+        }
+        None => ws,
+    };
+    match accepted {
+        Some(Accepted::Control(device)) => control(ws, device, peer, &state).await,
+        Some(Accepted::Answer(device, session)) => {
+            let mut pending = state.pending.lock().unwrap();
+            match pending.get(&session) {
+                Some(waiting) if waiting.device == device => {
+                    // The client gave up otherwise.
+                    let _ = pending.remove(&session).unwrap().answer.send(ws);
+                }
+                _ => debug!("session {} of {} no longer pending", session, device),
+            }
+            Ok(())
+        }
+        Some(Accepted::Client(device, offer)) => client(ws, device, offer, &config, &state).await,
+        None => Ok(()),
+    }
+}
+
+// Passes offers along until the agent goes away or registers again.
+async fn control(
+    ws: Ws,
+    device: String,
+    peer: SocketAddr,
+    state: &State,
+) -> Result<(), anyhow::Error> {
+    let generation = state.next();
+    let (sender, mut offers) = unbounded_channel();
+    let agent = Agent {
+        generation,
+        offers: sender,
+    };
+    state.agents.lock().unwrap().insert(device.clone(), agent);
+    info!("agent {} registered from {}", device, peer);
+
+    let (mut ws_outgoing, mut ws_incoming) = ws.split();
+    let mut ping = tokio::time::interval(CONTROL_PING_INTERVAL);
+    let res = loop {
+        tokio::select! {
+            offer = offers.recv() => match offer {
+                Some(offer) => {
+                    let offer = serde_json::to_string(&offer)?;
+                    if let Err(e) = ws_outgoing.send(Message::Text(offer)).await {
+                        break Err(e.into());
+                    }
+                }
+                // Replaced.
+                None => break Ok(()),
+            },
+            msg = ws_incoming.next() => match msg {
+                Some(Ok(Message::Close(_))) | None => break Ok(()),
+                Some(Ok(_)) => (),
+                Some(Err(e)) => break Err(e.into()),
+            },
+            _ = ping.tick() => {
+                if let Err(e) = ws_outgoing.send(Message::Ping(vec![])).await {
+                    break Err(e.into());
+                }
+            }
+        }
+    };
+
+    let mut agents = state.agents.lock().unwrap();
+    if agents.get(&device).map(|agent| agent.generation) == Some(generation) {
+        agents.remove(&device);
+        info!("agent {} unregistered", device);
+    }
+    res
+}
+
+async fn client(
+    mut ws: Ws,
+    device: String,
+    offer: Offer,
+    config: &BrokerConfig,
+    state: &State,
+) -> Result<(), anyhow::Error> {
+    let session = offer.session.clone();
+    let (answer, answered) = oneshot::channel();
+    let pending = Pending {
+        device: device.clone(),
+        answer,
+    };
+    state
+        .pending
+        .lock()
+        .unwrap()
+        .insert(session.clone(), pending);
+    let offered = match state.agents.lock().unwrap().get(&device) {
+        Some(agent) => agent.offers.send(offer).is_ok(),
+        None => false,
+    };
+    let agent = match offered {
+        true => tokio::time::timeout(config.answer_timeout, answered)
+            .await
+            .ok()
+            .and_then(Result::ok),
+        false => None,
+    };
+    let agent = match agent {
+        Some(agent) => agent,
+        None => {
+            state.pending.lock().unwrap().remove(&session);
+            let reason = match offered {
+                true => CloseReason::DeviceUnresponsive,
+                false => CloseReason::DeviceOffline,
+            };
+            let _ = ws
+                .close(Some(CloseFrame {
+                    code: CloseCode::Again,
+                    reason: reason.to_string().into(),
+                }))
+                .await;
+            return Ok(());
+        }
+    };
+    debug!("splicing session {} with {}", session, device);
+
+    let (mut client_outgoing, client_incoming) = ws.split();
+    let (agent_outgoing, agent_incoming) = agent.split();
+    // Either side ending ends both.
+    let lost = tokio::select! {
+        res = forward(client_incoming, agent_outgoing) => {
+            res?;
+            false
+        }
+        res = forward(agent_incoming, &mut client_outgoing) => !matches!(res, Ok(true)),
+    };
+    if lost {
+        // The session may still be running on the device, see
+        // `AgentConfig::offline`, for the client to resume once it's back.
+        let _ = client_outgoing
+            .send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                reason: CloseReason::DeviceLost.to_string().into(),
+            })))
+            .await;
+    }
+    debug!("session {} with {} ended", session, device);
+    Ok(())
+}
+
+// Pings are answered by each side's WebSocket on its own. Returns whether
+// the connection was closed rather than lost.
+async fn forward<I, O>(mut incoming: I, mut outgoing: O) -> Result<bool, WsError>
+where
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+    O: SinkExt<Message, Error = WsError> + Unpin,
+{
+    while let Some(msg) = incoming.next().await {
+        match msg? {
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => (),
+            Message::Close(frame) => {
+                let _ = outgoing.send(Message::Close(frame)).await;
+                return Ok(true);
+            }
+            msg => outgoing.send(msg).await?,
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,81 @@
+// Challenge-response authentication with pre-shared keys, for deployments
+// that can't put secrets in URLs or headers, which proxies may log. With
+// `ServerConfig::challenge_auth` the server sends a `proto::Challenge`
+// holding a random nonce as soon as the connection is up, and the client
+// answers with a `proto::ChallengeResponse` before anything else: the
+// HMAC-SHA256 of the nonce under one of the keys, named by its id. The key
+// id is the client identity unless the listener's authenticator returned
+// one, and `token_auth` isn't asked for then. Clients answering wrong, or
+// not in time, are closed with a policy violation.
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::time::Duration;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::{Challenge, ClientMessage, ServerMessage};
+
+const NONCE_LEN: usize = 32;
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Default)]
+pub struct ChallengeAuth {
+    // Pre-shared keys by id.
+    pub keys: HashMap<String, Vec<u8>>,
+}
+
+impl ChallengeAuth {
+    // Challenges the client, returns its identity or why it failed.
+    pub(crate) async fn authenticate<O, I>(
+        &self,
+        ws_outgoing: &mut O,
+        ws_incoming: &mut I,
+    ) -> Result<String, String>
+    where
+        O: Sink<Message, Error = WsError> + Unpin,
+        I: Stream<Item = Result<Message, WsError>> + Unpin,
+    {
+        let challenge = Challenge { nonce: nonce()? };
+        let msg = ServerMessage::Challenge(challenge.clone()).encode();
+        ws_outgoing
+            .send(Message::Binary(msg))
+            .await
+            .map_err(|e| e.to_string())?;
+        let answer = tokio::time::timeout(RESPONSE_TIMEOUT, ws_incoming.next())
+            .await
+            .map_err(|_| "no challenge response".to_owned())?;
+        let response = match answer {
+            Some(Ok(Message::Binary(ref data))) => match ClientMessage::decode(data) {
+                Ok(ClientMessage::ChallengeResponse(response)) => response,
+                _ => return Err("challenge response required".to_owned()),
+            },
+            _ => return Err("challenge response required".to_owned()),
+        };
+        // The same for unknown keys, not to tell which exist.
+        let failed = || "challenge failed".to_owned();
+        let key = self.keys.get(&response.key_id).ok_or_else(failed)?;
+        let mac = decode(&response.mac).ok_or_else(failed)?;
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+        hmac::verify(&key, challenge.nonce.as_bytes(), &mac).map_err(|_| failed())?;
+        Ok(response.key_id)
+    }
+}
+
+fn nonce() -> Result<String, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| "no randomness".to_owned())?;
+    Ok(nonce.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,151 @@
+// The client half, for Rust programs driving sessions of a server, e.g.
+// automation or tests. It speaks the binary protocol with the types of
+// `wspty_proto`: `spawn` sends the command message, then the output of the
+// session comes out of `on_output` until the server closes the connection,
+// and `wait_exit` tells how the command ended. Connections are made with a
+// default `Dialer`, plain `ws://` URLs only.
+
+use crate::Dialer;
+use futures::stream::{SplitSink, Stream, StreamExt};
+use futures::SinkExt;
+use log::debug;
+use std::io::{Error as IoError, ErrorKind};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::{
+    ClientMessage, Exit, ServerMessage, Signal, SignalRequest, SpawnRequest, WindowSize,
+};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// How the session ended, as the reader saw it.
+#[derive(Clone, Debug, Default)]
+struct Ending {
+    exit: Option<Exit>,
+    // The reason of the close frame, or why the connection was lost.
+    reason: Option<String>,
+    done: bool,
+}
+
+pub struct WsPtyClient {
+    sink: SplitSink<Socket, Message>,
+    output: mpsc::UnboundedReceiver<Vec<u8>>,
+    ending: watch::Receiver<Ending>,
+    reader: JoinHandle<()>,
+}
+
+impl WsPtyClient {
+    pub async fn connect<R: IntoClientRequest>(request: R) -> Result<Self, WsError> {
+        let (ws, _) = Dialer::default().connect_websocket(request).await?;
+        let (sink, stream) = ws.split();
+        let (output_sender, output) = mpsc::unbounded_channel();
+        let (ending_sender, ending) = watch::channel(Ending::default());
+        let reader = tokio::spawn(read(stream, output_sender, ending_sender));
+        Ok(WsPtyClient {
+            sink,
+            output,
+            ending,
+            reader,
+        })
+    }
+
+    // Runs `request`, it must be the first message of the connection.
+    pub async fn spawn(&mut self, request: &SpawnRequest) -> Result<(), WsError> {
+        self.sink.send(Message::Text(request.encode())).await
+    }
+
+    pub async fn write(&mut self, input: &[u8]) -> Result<(), WsError> {
+        self.send(ClientMessage::Input(input)).await
+    }
+
+    pub async fn resize(&mut self, cols: u16, rows: u16) -> Result<(), WsError> {
+        let size = WindowSize {
+            cols,
+            rows,
+            xpixel: 0,
+            ypixel: 0,
+        };
+        self.send(ClientMessage::Resize(size)).await
+    }
+
+    pub async fn signal(&mut self, signal: Signal) -> Result<(), WsError> {
+        self.send(ClientMessage::Signal(SignalRequest { signal }))
+            .await
+    }
+
+    // Any other message of the protocol.
+    pub async fn send(&mut self, message: ClientMessage<'_>) -> Result<(), WsError> {
+        self.sink.send(Message::Binary(message.encode())).await
+    }
+
+    // The output of the session as it comes, ending with the connection,
+    // stderr included. Output not taken is kept until it is.
+    pub fn on_output(&mut self) -> impl Stream<Item = Vec<u8>> + '_ {
+        futures::stream::poll_fn(move |cx| self.output.poll_recv(cx))
+    }
+
+    // Waits for the connection to end, then how the command exited, or why
+    // the server closed without it exiting, e.g. a refused command.
+    pub async fn wait_exit(&mut self) -> Result<Exit, IoError> {
+        let ending = self
+            .ending
+            .wait_for(|ending| ending.done)
+            .await
+            .map(|ending| ending.clone())
+            .unwrap_or_default();
+        ending.exit.ok_or_else(|| {
+            let reason = ending.reason.unwrap_or_else(|| "closed".to_owned());
+            IoError::new(ErrorKind::ConnectionAborted, reason)
+        })
+    }
+
+    // Closes the connection, which the server takes as the client leaving.
+    pub async fn close(&mut self) -> Result<(), WsError> {
+        self.sink.close().await
+    }
+}
+
+impl Drop for WsPtyClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read<S>(
+    mut stream: S,
+    output: mpsc::UnboundedSender<Vec<u8>>,
+    ending: watch::Sender<Ending>,
+) where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let mut ended = Ending::default();
+    while let Some(msg) = stream.next().await {
+        let data = match msg {
+            Ok(Message::Binary(data)) => data,
+            // Read on, for tungstenite to answer it.
+            Ok(Message::Close(frame)) => {
+                ended.reason = frame.map(|frame| frame.reason.into_owned());
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                ended.reason = Some(e.to_string());
+                break;
+            }
+        };
+        match ServerMessage::decode(&data) {
+            Ok(ServerMessage::Output(data)) | Ok(ServerMessage::StreamOutput { data, .. }) => {
+                let _ = output.send(data.to_vec());
+            }
+            Ok(ServerMessage::Exit(exit)) => ended.exit = Some(exit),
+            Ok(_) => (),
+            Err(e) => debug!("undecodable message from the server: {:?}", e),
+        }
+    }
+    ended.done = true;
+    ending.send_replace(ended);
+}
@@ -0,0 +1,63 @@
+// Time as sessions see it, for tests and simulations to drive the idle and
+// absolute timeouts (see the `timeout` module), the pings, the frame
+// interval, the output batching of low power mode, the end of repeated line
+// runs, the sequences held by the sequencer and the persistence ttl
+// deterministically.
+// `ServerConfig::clock` replaces the default `TokioClock`, which already
+// follows `tokio::time::pause` and `tokio::time::advance`. Other delays,
+// e.g. teardown and dial timeouts, are left to tokio's timers.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    // Resolves once `now()` is past `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+// Like `tokio::time::Interval`, ticks missed while busy are skipped. Waiting
+// for a tick can be cancelled.
+pub(crate) struct Ticker {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    next: Instant,
+}
+
+impl Ticker {
+    // The first tick is at `start`.
+    pub(crate) fn new(clock: Arc<dyn Clock>, start: Instant, period: Duration) -> Self {
+        Ticker {
+            clock,
+            period,
+            next: start,
+        }
+    }
+
+    pub(crate) async fn tick(&mut self) {
+        self.clock.sleep_until(self.next).await;
+        let now = self.clock.now();
+        self.next += self.period;
+        if self.next <= now {
+            self.next = now + self.period;
+        }
+    }
+}
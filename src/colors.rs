@@ -0,0 +1,331 @@
+// Output filter bringing colors down to what the client can show, for log
+// viewers, e-ink or old terminals, when it declares less than 24 bit color
+// with the `X-Color-Level` header or the `colors` query parameter of the
+// upgrade request (`truecolor`, `256`, `16` or `none`), or
+// `ServerConfig::color_level` says so for clients declaring nothing. Only
+// SGR sequences are rewritten: 24 bit colors become the closest of the 256
+// or 16 color palette, 256 colors the closest of the 16, and without colors
+// they are dropped, other attributes being kept. Sequences split across
+// reads are held back until complete.
+
+// Longest sequence held back before giving up and letting it through.
+const MAX_HELD: usize = 64;
+
+// From the least to the most colorful.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorLevel {
+    None,
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+impl ColorLevel {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" | "mono" => Some(ColorLevel::None),
+            "16" => Some(ColorLevel::Ansi16),
+            "256" => Some(ColorLevel::Ansi256),
+            "truecolor" | "24bit" => Some(ColorLevel::TrueColor),
+            _ => None,
+        }
+    }
+}
+
+// The xterm defaults, as most terminals have them.
+const ANSI16: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub(crate) struct ColorFilter {
+    level: ColorLevel,
+    state: State,
+    held: Vec<u8>,
+}
+
+impl ColorFilter {
+    // `None` if there is nothing to bring down.
+    pub(crate) fn new(level: ColorLevel) -> Option<Self> {
+        (level < ColorLevel::TrueColor).then(|| ColorFilter {
+            level,
+            state: State::Ground,
+            held: vec![],
+        })
+    }
+
+    pub(crate) fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            self.state = match std::mem::replace(&mut self.state, State::Ground) {
+                State::Ground if b == 0x1b => {
+                    self.held.push(b);
+                    State::Escape
+                }
+                State::Ground => {
+                    out.push(b);
+                    State::Ground
+                }
+                State::Escape if b == b'[' => {
+                    self.held.push(b);
+                    State::Csi
+                }
+                State::Escape if b == 0x1b => {
+                    out.push(b);
+                    State::Escape
+                }
+                State::Escape => {
+                    self.held.push(b);
+                    self.release(&mut out);
+                    State::Ground
+                }
+                State::Csi => {
+                    self.held.push(b);
+                    if (0x40..0x7f).contains(&b) {
+                        let params = &self.held[2..self.held.len() - 1];
+                        let sgr = b == b'm' && params.iter().all(|b| (0x30..0x3c).contains(b));
+                        if sgr {
+                            let rewritten = self.rewrite(params);
+                            out.extend_from_slice(&rewritten);
+                            self.held.clear();
+                        } else {
+                            self.release(&mut out);
+                        }
+                        State::Ground
+                    } else if self.held.len() > MAX_HELD {
+                        self.release(&mut out);
+                        State::Ground
+                    } else {
+                        State::Csi
+                    }
+                }
+            };
+        }
+        out
+    }
+
+    fn release(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.held);
+    }
+
+    // The SGR sequence with `params` as the client can show it, nothing if
+    // only colors it can't show were set.
+    fn rewrite(&self, params: &[u8]) -> Vec<u8> {
+        let params: Vec<&[u8]> = params.split(|&b| b == b';').collect();
+        let mut kept: Vec<String> = vec![];
+        let mut i = 0;
+        while i < params.len() {
+            let param = std::str::from_utf8(params[i]).unwrap_or_default();
+            i += 1;
+            let (base, color) = match param.split_once(':') {
+                // Colons keep the whole color in one parameter.
+                Some((base, rest)) if matches!(base, "38" | "48" | "58") => {
+                    let rest: Vec<&str> = rest.split(':').collect();
+                    (base, parse_color(&rest, true))
+                }
+                None if matches!(param, "38" | "48" | "58") => {
+                    let rest: Vec<&str> = params[i..]
+                        .iter()
+                        .map(|p| std::str::from_utf8(p).unwrap_or_default())
+                        .collect();
+                    let color = parse_color(&rest, false);
+                    i += color.map_or(rest.len(), |(_, used)| used);
+                    (param, color)
+                }
+                _ => {
+                    if self.level == ColorLevel::None && is_basic_color(param) {
+                        continue;
+                    }
+                    kept.push(param.to_owned());
+                    continue;
+                }
+            };
+            let color = match color {
+                Some((color, _)) => color,
+                None => continue,
+            };
+            if let Some(param) = self.downgrade(base, color) {
+                kept.push(param);
+            }
+        }
+        if kept.is_empty() && !params.iter().all(|p| p.is_empty()) {
+            return vec![];
+        }
+        format!("\x1b[{}m", kept.join(";")).into_bytes()
+    }
+
+    // `base` being 38 for the foreground, 48 for the background or 58 for
+    // underlines.
+    fn downgrade(&self, base: &str, color: Color) -> Option<String> {
+        let index = match (self.level, color) {
+            (ColorLevel::None, _) => return None,
+            (ColorLevel::TrueColor, Color::Rgb(r, g, b)) => {
+                return Some(format!("{};2;{};{};{}", base, r, g, b))
+            }
+            (ColorLevel::Ansi256, Color::Rgb(r, g, b)) => nearest256(r, g, b),
+            (ColorLevel::Ansi16, Color::Rgb(r, g, b)) => nearest16(r, g, b),
+            (ColorLevel::Ansi16, Color::Indexed(index)) if index >= 16 => {
+                let (r, g, b) = rgb(index);
+                nearest16(r, g, b)
+            }
+            (_, Color::Indexed(index)) => index,
+        };
+        match (self.level, base) {
+            (ColorLevel::Ansi256 | ColorLevel::TrueColor, _) => {
+                Some(format!("{};5;{}", base, index))
+            }
+            // Terminals with 16 colors mostly have no underline color.
+            (_, "58") => None,
+            (_, base) => {
+                let offset = if base == "48" { 10 } else { 0 };
+                let code = match index {
+                    0..=7 => 30 + index,
+                    _ => 90 + index - 8,
+                };
+                Some((code + offset).to_string())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Color {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+// What follows 38, 48 or 58 (`5;n` or `2;r;g;b`), and how many parameters
+// it took. With colons, `2` may be followed by a colorspace id.
+fn parse_color(rest: &[&str], colons: bool) -> Option<(Color, usize)> {
+    let number = |i: usize| rest.get(i).and_then(|p| p.parse::<u8>().ok());
+    match rest.first().copied() {
+        Some("5") => Some((Color::Indexed(number(1)?), 2)),
+        Some("2") => {
+            let skip = colons && rest.len() > 4;
+            let at = if skip { 2 } else { 1 };
+            let color = Color::Rgb(number(at)?, number(at + 1)?, number(at + 2)?);
+            Some((color, at + 3))
+        }
+        _ => None,
+    }
+}
+
+fn is_basic_color(param: &str) -> bool {
+    matches!(
+        param.parse::<u8>(),
+        Ok(30..=37 | 40..=47 | 90..=97 | 100..=107)
+    )
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+fn nearest16(r: u8, g: u8, b: u8) -> u8 {
+    (0..16u8)
+        .min_by_key(|&i| distance(ANSI16[i as usize], (r, g, b)))
+        .unwrap_or(0)
+}
+
+// The closest of the color cube and the gray ramp.
+fn nearest256(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| match c {
+        0..=47 => 0,
+        48..=114 => 1,
+        c => (c - 35) / 40,
+    };
+    let (cr, cg, cb) = (level(r), level(g), level(b));
+    let cube = 16 + 36 * cr + 6 * cg + cb;
+    let average = ((r as u32 + g as u32 + b as u32) / 3) as u8;
+    let gray = 232 + (average.saturating_sub(3) / 10).min(23);
+    if distance(rgb(gray), (r, g, b)) < distance(rgb(cube), (r, g, b)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+pub(crate) fn rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI16[index as usize],
+        16..=231 => {
+            let i = index - 16;
+            (
+                CUBE[(i / 36) as usize],
+                CUBE[(i / 6 % 6) as usize],
+                CUBE[(i % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + (index - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(level: ColorLevel, data: &[u8]) -> String {
+        let mut filter = ColorFilter::new(level).unwrap();
+        String::from_utf8(filter.filter(data)).unwrap()
+    }
+
+    #[test]
+    fn truecolor_becomes_256_colors() {
+        let out = filter(ColorLevel::Ansi256, b"\x1b[1;38;2;255;0;0mred\x1b[m");
+        assert_eq!(out, "\x1b[1;38;5;196mred\x1b[m");
+        let out = filter(ColorLevel::Ansi256, b"\x1b[48:2::128:128:128mgray");
+        assert_eq!(out, "\x1b[48;5;244mgray");
+    }
+
+    #[test]
+    fn colors_become_16_colors() {
+        let out = filter(ColorLevel::Ansi16, b"\x1b[38;5;196;48;2;0;0;0mx");
+        assert_eq!(out, "\x1b[91;40mx");
+        let out = filter(ColorLevel::Ansi16, b"\x1b[38;5;3mx");
+        assert_eq!(out, "\x1b[33mx");
+    }
+
+    #[test]
+    fn colors_are_dropped() {
+        let out = filter(
+            ColorLevel::None,
+            b"\x1b[31mred\x1b[4:3;38;5;1mu\x1b[38;2;1;2;3mx\x1b[0m",
+        );
+        assert_eq!(out, "red\x1b[4:3mux\x1b[0m");
+    }
+
+    #[test]
+    fn split_sequences_are_held_back() {
+        let mut filter = ColorFilter::new(ColorLevel::Ansi16).unwrap();
+        let mut out = filter.filter(b"a\x1b[38;2;2");
+        assert_eq!(out, b"a");
+        out.extend(filter.filter(b"55;255;255mb\x1b[2J"));
+        assert_eq!(out, b"a\x1b[97mb\x1b[2J");
+    }
+}
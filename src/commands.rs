@@ -0,0 +1,203 @@
+// Per-command execution metrics from the shell integration, for platform
+// teams to see what users run in their web shells. With both
+// `ServerConfig::command_sentinels` and `command_metrics`, each command
+// finishing in a session (see the `sentinel` module) is counted in
+// `SessionHandle::command_stats`, passed to `EventHandler::on_command` and
+// POSTed as JSON to the `webhook` if there is one. Command lines can hold
+// secrets, they are only kept with `command_lines`. Webhooks are plain HTTP,
+// `http://collector:8080/commands` for instance, with a request for each
+// command: those failing are logged and dropped.
+
+use crate::sentinel::Finished;
+use crate::{EventHandler, ServerConfig, SessionHandle};
+use log::warn;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Commands kept in `CommandStats::recent`.
+const RECENT: usize = 32;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RESPONSE: usize = 8192;
+
+#[derive(Clone, Debug, Default)]
+pub struct CommandMetrics {
+    pub command_lines: bool,
+    pub webhook: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CommandRecord {
+    pub session_id: String,
+    pub identity: Option<String>,
+    // As typed, with `CommandMetrics::command_lines`.
+    pub command: Option<String>,
+    pub exit_code: Option<i32>,
+    // In milliseconds.
+    pub duration: u64,
+    pub finished: SystemTime,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CommandStats {
+    pub commands: u64,
+    // Those exiting with another code than 0.
+    pub failed: u64,
+    // In milliseconds.
+    pub total_duration: u64,
+    pub longest: u64,
+    // The last ones, oldest first.
+    pub recent: VecDeque<CommandRecord>,
+}
+
+impl CommandStats {
+    fn add(&mut self, record: CommandRecord) {
+        self.commands += 1;
+        if record.exit_code.is_some_and(|code| code != 0) {
+            self.failed += 1;
+        }
+        self.total_duration += record.duration;
+        self.longest = self.longest.max(record.duration);
+        if self.recent.len() == RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(record);
+    }
+}
+
+// The metrics of a session.
+pub(crate) struct Commands {
+    metrics: CommandMetrics,
+    events: Option<Arc<dyn EventHandler>>,
+    pub(crate) stats: Mutex<CommandStats>,
+}
+
+impl Commands {
+    pub(crate) fn new(config: &ServerConfig) -> Option<Arc<Self>> {
+        let metrics = config
+            .command_metrics
+            .clone()
+            .filter(|_| config.command_sentinels)?;
+        Some(Arc::new(Commands {
+            metrics,
+            events: config.events.clone(),
+            stats: Mutex::new(CommandStats::default()),
+        }))
+    }
+
+    pub(crate) fn record(&self, handle: &SessionHandle, finished: Finished) {
+        let record = CommandRecord {
+            session_id: handle.session_id().to_owned(),
+            identity: handle.identity.clone(),
+            command: finished.command.filter(|_| self.metrics.command_lines),
+            exit_code: finished.exit_code,
+            duration: finished.duration,
+            finished: SystemTime::now(),
+        };
+        self.stats.lock().unwrap().add(record.clone());
+        if let Some(ref events) = self.events {
+            events.on_command(handle, &record);
+        }
+        if let Some(ref webhook) = self.metrics.webhook {
+            let body = serde_json::to_vec(&record).unwrap_or_default();
+            spawn_post(webhook.clone(), body, "command");
+        }
+    }
+}
+
+// POSTs `body` in a task of its own, logging what fails as failing to post
+// `what`. Also used by the `alerts` module.
+pub(crate) fn spawn_post(webhook: String, body: Vec<u8>, what: &'static str) {
+    tokio::spawn(async move {
+        let posted = tokio::time::timeout(WEBHOOK_TIMEOUT, post(&webhook, &body));
+        let res = match posted.await {
+            Ok(res) => res,
+            Err(_) => Err(IoError::new(ErrorKind::TimedOut, "timed out")),
+        };
+        if let Err(e) = res {
+            warn!("failed to post {} to {}: {}", what, webhook, e);
+        }
+    });
+}
+
+// The `host:port` to connect to and the path of an `http://` URL.
+pub(crate) fn parse_webhook(url: &str) -> Result<(String, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("{:?} is not an http:// URL", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(format!("{:?} has no host", url));
+    }
+    let port = authority
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    if port {
+        Ok((authority.to_owned(), path))
+    } else {
+        Ok((format!("{}:80", authority), path))
+    }
+}
+
+async fn post(url: &str, body: &[u8]) -> Result<(), IoError> {
+    let (addr, path) = parse_webhook(url).map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+    let mut stream = TcpStream::connect(&addr).await?;
+    let request = format!(
+        concat!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n",
+            "Content-Length: {}\r\nConnection: close\r\n\r\n"
+        ),
+        path,
+        addr,
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE {
+            return Err(IoError::new(ErrorKind::InvalidData, "response too large"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    match parsed.parse(&response) {
+        Ok(httparse::Status::Complete(_)) if parsed.code.is_some_and(|code| code / 100 == 2) => {
+            Ok(())
+        }
+        Ok(httparse::Status::Complete(_)) => Err(IoError::other(format!(
+            "{} {}",
+            parsed.code.unwrap_or_default(),
+            parsed.reason.unwrap_or_default()
+        ))),
+        _ => Err(IoError::new(ErrorKind::InvalidData, "invalid response")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_urls() {
+        assert_eq!(
+            parse_webhook("http://collector:8080/commands"),
+            Ok(("collector:8080".to_owned(), "/commands"))
+        );
+        assert_eq!(
+            parse_webhook("http://[::1]"),
+            Ok(("[::1]:80".to_owned(), "/"))
+        );
+        assert!(parse_webhook("https://collector/commands").is_err());
+        assert!(parse_webhook("http:///commands").is_err());
+    }
+}
@@ -0,0 +1,588 @@
+use crate::auth::Refusal;
+use crate::user::Account;
+#[cfg(feature = "challenge-auth")]
+use crate::ChallengeAuth;
+#[cfg(feature = "fault-injection")]
+use crate::Faults;
+#[cfg(feature = "mdns")]
+use crate::MdnsConfig;
+#[cfg(feature = "quic")]
+use crate::QuicConfig;
+#[cfg(feature = "resume-routing")]
+use crate::ResumeRouting;
+#[cfg(feature = "tls")]
+use crate::TlsConfig;
+use crate::{
+    AccountingExport, AgentConfig, Alerts, Authenticator, BanList, BandwidthLedger, BindOptions,
+    Clock, ColorLevel, CommandMetrics, CommandPolicy, ConfigProblem, ContainerExec,
+    ControlChannels, CpuBudget, EnvironmentPolicy, EventHandler, FallbackShell, FileTransfer,
+    FrameDumps, GarbageCollection, HandoffPolicy, JobControl, Listener, LowPower, MalformedPolicy,
+    MemoryBudget, MessageLimits, MirrorConfig, OutputCompression, PeerLimit, Persistence, Probe,
+    ProxyRoute, QueryOverrides, RecordingConfig, RenderHints, RepeatLimit, ResourceLimits,
+    SecurityPreset, SessionHandle, SessionIdGenerator, SessionLimit, SessionPool, Sharing,
+    SizePolicy, SpawnPolicy, SshRoute, Teardown, Theme, Timeouts, TogglePolicy, TokenValidator,
+    TokioClock, UiConfig, UserMapping, Workspace,
+};
+use std::collections::HashMap;
+use std::io::Error as IoError;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::process::Command;
+use wspty_proto::{Decision, SpawnRequest, WindowSize};
+
+#[derive(Clone, Default)]
+pub struct ServerConfig {
+    // Address of the default listener, 127.0.0.1:7703 if unset.
+    pub addr: Option<SocketAddr>,
+    // Socket options of the default listener, see the `bind` module.
+    pub bind: BindOptions,
+    // Only listen on `unix_socket`, for servers behind a reverse proxy.
+    pub tcp_disabled: bool,
+    // Also listen on this Unix domain socket, with the authenticator of the
+    // default listener but not its TLS configuration. Peers show up as
+    // `UNIX_PEER` and their sessions can't be migrated.
+    pub unix_socket: Option<PathBuf>,
+    // Also dial out to a broker and serve the clients it passes along, for
+    // hosts clients can't reach, see the `agent` module. Enough on its own
+    // with `tcp_disabled`.
+    pub agent: Option<AgentConfig>,
+    // Checks upgrade requests on the default listener, see `Authenticator`.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    // Serve the default listener over TLS, see the `tls` module.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    // Bearer token required on every listener, with the upgrade request or
+    // in a `proto::Auth` message before the command. Its identity is used
+    // unless the listener's authenticator returned one.
+    pub token_auth: Option<TokenValidator>,
+    // Authenticate clients with pre-shared keys before the command instead,
+    // see the `challenge` module.
+    #[cfg(feature = "challenge-auth")]
+    pub challenge_auth: Option<ChallengeAuth>,
+    // Refuse the clients of listeners with neither an authenticator nor
+    // `token_auth` or `challenge_auth`, rather than serving them anonymously.
+    pub require_auth: bool,
+    // Origins browsers may open sessions from, e.g.
+    // `https://console.example.com`, any if unset. Requests without an
+    // `Origin` header, from other clients, are not checked.
+    pub allowed_origins: Option<Vec<String>>,
+    // More listeners, each with its own authenticator.
+    pub listeners: Vec<Listener>,
+    // Advertise the listener as `_wspty._tcp` on the local network.
+    #[cfg(feature = "mdns")]
+    pub mdns: Option<MdnsConfig>,
+    // Commands clients may run, all of them if unset.
+    pub command_policy: Option<CommandPolicy>,
+    // Checks and rewrites spawn requests, see `SpawnPolicy` and its
+    // `Allowlist` implementation.
+    pub spawn_policy: Option<Arc<dyn SpawnPolicy>>,
+    // Environment variables clients may update mid-session, none if unset.
+    // See the `env` module.
+    pub environment_policy: Option<EnvironmentPolicy>,
+    // Start commands with only `PATH`, `LANG` and the account variables of
+    // the server's environment, plus what the configuration sets, rather
+    // than all of it.
+    pub clear_env: bool,
+    // Who clients may hand their sessions to, see the `handoff` module.
+    pub handoff_policy: Option<HandoffPolicy>,
+    // Spawned for commands refused by `command_policy` instead of closing
+    // the connection.
+    pub fallback_shell: Option<FallbackShell>,
+    // Clients to keep out, see `BanList`.
+    pub bans: Option<BanList>,
+    // Per tenant traffic accounting and caps. Tenants are identified by the
+    // identity of the client, by its IP address without one.
+    pub bandwidth: Option<BandwidthLedger>,
+    // Where completed sessions are accounted for, see the `usage` module.
+    pub accounting: Option<AccountingExport>,
+    // Memory buffered for connections and what is shed when it runs out,
+    // see the `memory` module.
+    pub memory_budget: Option<MemoryBudget>,
+    // Experimental QUIC listener, see the `quic` module for the framing.
+    #[cfg(feature = "quic")]
+    pub quic: Option<QuicConfig>,
+    // Read pty output through io_uring instead of epoll, see the `uring`
+    // module. Only worth it for high throughput sessions.
+    #[cfg(feature = "io-uring")]
+    pub io_uring: bool,
+    // Fragmentation of outgoing messages and size limits of incoming ones,
+    // on WebSocket transports.
+    pub message_limits: MessageLimits,
+    // What to do with client messages that don't decode or are too large,
+    // see the `malformed` module.
+    pub malformed: MalformedPolicy,
+    // Unix socket where other instances can hand over their live sessions,
+    // see `SessionHandle::migrate`.
+    pub migration_socket: Option<PathBuf>,
+    // Stream every session's output to a supervision endpoint, see the
+    // `mirror` module.
+    pub mirror: Option<MirrorConfig>,
+    // Sessions forwarded to other wspty servers, see the `proxy` module.
+    // The first matching route is used.
+    pub proxy_routes: Vec<ProxyRoute>,
+    // Resume tokens naming the server holding the session, for gateways to
+    // forward resumes to it, see the `routing` module.
+    #[cfg(feature = "resume-routing")]
+    pub resume_routing: Option<ResumeRouting>,
+    // Sessions opened on other hosts over SSH, see the `ssh` module. The
+    // first matching route is used, proxy routes going first.
+    pub ssh_routes: Vec<SshRoute>,
+    // Let clients run commands in containers, see the `container` module.
+    // Requests naming a container are refused if unset.
+    pub container_exec: Option<ContainerExec>,
+    // Commands run with plain pipes instead of a pty, for sessions that only
+    // stream output. See the `pipe` module.
+    pub output_only: Vec<String>,
+    // Tag their output with the stream it came from, for clients to tell
+    // stderr apart.
+    pub separate_stderr: bool,
+    // Let clients upload and download files, see the `transfer` module.
+    pub file_transfer: Option<FileTransfer>,
+    // Give local sessions a directory of their own, removed when they end,
+    // see the `workspace` module.
+    pub workspace: Option<Workspace>,
+    // Record sessions into a directory, see the `recording` module.
+    pub recording: Option<RecordingConfig>,
+    // What clients can switch at runtime, see the `toggles` module.
+    pub toggle_policy: Option<TogglePolicy>,
+    // Drop escape sequences that could be used to attack the client
+    // terminal, see the `sanitize` module.
+    pub sanitize_output: bool,
+    // Drop lines repeated too fast, see the `repeats` module.
+    pub repeated_lines: Option<RepeatLimit>,
+    // Bring colors down to that for clients not declaring what they can
+    // show, see the `colors` module.
+    pub color_level: Option<ColorLevel>,
+    // Only end output frames on UTF-8 codepoint boundaries, see the `utf8`
+    // module.
+    pub utf8_frames: bool,
+    // Let clients ask for compressed output, see the `deflate` module.
+    pub compression: Option<OutputCompression>,
+    // CPU time each session's processes may use, see the `cpu` module.
+    pub cpu_budget: Option<CpuBudget>,
+    // Send `proto::JobEvent`s as jobs get stopped and continued, see the
+    // `jobs` module.
+    pub job_control: Option<JobControl>,
+    // Keep sessions running when their client's connection is lost, for it
+    // to resume them. See the `persist` module.
+    pub persistence: Option<Persistence>,
+    // Let more clients follow sessions, see the `sharing` module.
+    pub sharing: Option<Sharing>,
+    // Clean up after sessions, see the `gc` module.
+    pub gc: Option<GarbageCollection>,
+    // Let clients move control traffic to a connection of its own, see the
+    // `control` module.
+    pub control_channels: Option<ControlChannels>,
+    // Refuse clients not negotiating the `wspty.v1` subprotocol, see the
+    // `protocol` module of wspty-proto.
+    pub reject_legacy_framing: bool,
+    // Maximum number of concurrent sessions, see `SessionLimit`.
+    pub session_limit: Option<SessionLimit>,
+    // Maximum number of concurrent sessions per client IP address.
+    pub peer_limit: Option<PeerLimit>,
+    // Maximum number of concurrent sessions per profile, for those backed
+    // by scarce resources: by the name of one of `profiles`, or else of the
+    // program run, e.g. "htop" for `/usr/bin/htop -d 10`. Counted along
+    // with `session_limit`, each with its own queue.
+    pub profile_limits: HashMap<String, SessionLimit>,
+    // Close reason for clients turned away by `session_limit`,
+    // `peer_limit` or `profile_limits`, instead of saying which one was hit.
+    pub limit_message: Option<String>,
+    // Set on the commands run for clients, see `ResourceLimits`.
+    pub resource_limits: Option<ResourceLimits>,
+    // Account the commands run as, the server's if unset. See the `user`
+    // module.
+    pub run_as: Option<String>,
+    // Picks the account per client identity instead, falling back to
+    // `run_as`.
+    pub user_mapping: Option<UserMapping>,
+    // Size of new terminals as (cols, rows), otherwise 80x24.
+    pub default_size: Option<(u16, u16)>,
+    // Largest terminal clients can resize to, as (cols, rows).
+    pub max_size: Option<(u16, u16)>,
+    // The only devices commands see besides the basics, e.g. `/dev/dri` or
+    // `/dev/nvidia*`, the host's /dev being left as is if unset. See the
+    // `devices` module.
+    pub devices: Option<Vec<String>>,
+    // Who sets the terminal size when sessions have attachments besides
+    // their client.
+    pub size_policy: SizePolicy,
+    // Only let attachments type once the client grants them, see the
+    // `arbitration` module.
+    pub input_arbitration: bool,
+    // Tell everyone following a session who else does, see the `presence`
+    // module.
+    pub presence: bool,
+    // Send `proto::CommandEvent` frames for the shell prompts and commands,
+    // see the `sentinel` module. Bash is set up to mark them through `PS0`
+    // and `PROMPT_COMMAND`.
+    pub command_sentinels: bool,
+    // Durations and exit codes of the commands run, with
+    // `command_sentinels`, see the `commands` module.
+    pub command_metrics: Option<CommandMetrics>,
+    // Tell a webhook about bells, activity and silence, see the `alerts`
+    // module.
+    pub alerts: Option<Alerts>,
+    // Answer `proto::Trace` marks with latency reports, see the `trace`
+    // module. Meant for debugging.
+    pub latency_tracing: bool,
+    // Count messages, bytes and wakeups per session, see the `instrument`
+    // module. Meant for benchmarks.
+    pub instrumentation: bool,
+    // Write the frames of sessions to files, see the `dump` module. Meant
+    // for debugging clients.
+    pub frame_dumps: Option<FrameDumps>,
+    // Drop and delay output, stall clients and kill commands, see the
+    // `faults` module. For testing clients.
+    #[cfg(feature = "fault-injection")]
+    pub faults: Option<Faults>,
+    // What is still sent to clients when sessions end, see the `teardown`
+    // module.
+    pub teardown: Teardown,
+    // Pings and ending sessions left idle or open too long, see the
+    // `timeout` module.
+    pub timeouts: Timeouts,
+    // What the timeouts, pings and output coalescing go by, tokio's if
+    // unset. See the `clock` module.
+    pub clock: Option<Arc<dyn Clock>>,
+    // Granted to the clients asking for it, see the `power` module.
+    pub low_power: Option<LowPower>,
+    // Pace output to the frame rates clients declare, see the `render`
+    // module.
+    pub render_hints: Option<RenderHints>,
+    // Run for clients not asking for a command, the login shell of the
+    // account commands run as or `/usr/bin/bash` if unset.
+    pub default_command: Option<String>,
+    // Working directory of the commands, the home of the account they run
+    // as or `$HOME` if unset.
+    pub working_dir: Option<PathBuf>,
+    // Set for the commands on top of the server's own environment, `TERM`
+    // and `COLORTERM` included.
+    pub env: HashMap<String, String>,
+    // Size of the reads from ptys, 1024 bytes if unset. The io_uring reader
+    // has its own.
+    pub read_buffer_size: Option<usize>,
+    // Bytes queued for a client before its pty stops being read, 256KiB if
+    // unset, see the `outbox` module.
+    pub output_backlog: Option<usize>,
+    // Bytes of output kept per session for clients to replay, none if
+    // unset, see the `scrollback` module.
+    pub scrollback: Option<usize>,
+    // Written to the pty of new sessions, e.g. `cd /workspace && clear`.
+    pub startup: Option<String>,
+    // Checks of the environment before new sessions start, see the
+    // `probes` module.
+    pub probes: Vec<Probe>,
+    // Pre-spawned shells for the most common command.
+    pub pool: Option<SessionPool>,
+    // Colors applied to every client terminal before the shell output.
+    pub theme: Option<Theme>,
+    // Themes clients can pick by name.
+    pub themes: HashMap<String, Theme>,
+    // Profiles clients can pick by name, see `QueryOverrides`.
+    pub profiles: HashMap<String, Profile>,
+    // What clients may pick with the query string of the upgrade request.
+    pub query_overrides: QueryOverrides,
+    // Serve the xterm.js page to plain HTTP requests.
+    pub ui: Option<UiConfig>,
+    // Called with a handle on each new session, for embedders that need to
+    // drive it from their side (e.g. resizing from a native UI).
+    pub on_session: Option<Arc<dyn Fn(SessionHandle) + Send + Sync>>,
+    // Told about connections and sessions as they come and go, see
+    // `EventHandler`.
+    pub events: Option<Arc<dyn EventHandler>>,
+    // Names the sessions in logs, recordings and mirrors instead of their
+    // numeric id, see `SessionHandle::session_id`.
+    pub session_ids: Option<SessionIdGenerator>,
+}
+
+// How sessions are set up, the `ServerConfig` fields of the same names as
+// one value, e.g. to share them between servers.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    // The profile this one builds on, see the `profiles` module.
+    pub base: Option<String>,
+    pub default_command: Option<String>,
+    pub working_dir: Option<PathBuf>,
+    pub env: HashMap<String, String>,
+    pub startup: Option<String>,
+    pub default_size: Option<(u16, u16)>,
+    pub devices: Option<Vec<String>>,
+    pub probes: Vec<Probe>,
+}
+
+const DEFAULT_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7703));
+
+// The peer address of clients connected through `ServerConfig::unix_socket`,
+// for policies and logs.
+pub const UNIX_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+const DEFAULT_COMMAND: &str = "/usr/bin/bash";
+// What commands get of the server's environment with `clear_env`.
+const KEPT_ENV: [&str; 6] = ["HOME", "LANG", "LOGNAME", "PATH", "SHELL", "USER"];
+
+impl ServerConfig {
+    pub fn profile(&self) -> Profile {
+        Profile {
+            base: None,
+            default_command: self.default_command.clone(),
+            working_dir: self.working_dir.clone(),
+            env: self.env.clone(),
+            startup: self.startup.clone(),
+            default_size: self.default_size,
+            devices: self.devices.clone(),
+            probes: self.probes.clone(),
+        }
+    }
+
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.default_command = profile.default_command;
+        self.working_dir = profile.working_dir;
+        self.env = profile.env;
+        self.startup = profile.startup;
+        self.default_size = profile.default_size;
+        self.devices = profile.devices;
+        self.probes = profile.probes;
+    }
+
+    // Applies a bundle of settings, see the `preset` module.
+    pub fn with_preset(mut self, preset: SecurityPreset) -> Self {
+        preset.apply(&mut self);
+        self
+    }
+
+    // Whether clients get authenticated on every listener, by `token_auth`
+    // or `challenge_auth`.
+    pub(crate) fn authenticates(&self) -> bool {
+        #[cfg(feature = "challenge-auth")]
+        if self.challenge_auth.is_some() {
+            return true;
+        }
+        self.token_auth.is_some()
+    }
+
+    // Replaces the environment `cmd` inherits from the server with
+    // `KEPT_ENV`, keeping what was set on it.
+    fn scrub_env(cmd: &mut Command) {
+        let set: Vec<_> = cmd
+            .as_std()
+            .get_envs()
+            .filter_map(|(name, value)| Some((name.to_owned(), value?.to_owned())))
+            .collect();
+        cmd.env_clear();
+        for name in KEPT_ENV {
+            if let Some(value) = std::env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+        cmd.envs(set);
+    }
+
+    // The profile `name` with what it inherits.
+    pub(crate) fn resolve_profile(&self, name: &str) -> Result<Profile, String> {
+        crate::profiles::resolve(&self.profiles, name)
+    }
+
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(TokioClock))
+    }
+
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr.unwrap_or(DEFAULT_ADDR)
+    }
+
+    // What runs for `command` as asked by the client.
+    pub(crate) fn resolve_command<'a>(&'a self, command: &'a str) -> &'a str {
+        match command {
+            "" => self.default_command.as_deref().unwrap_or(DEFAULT_COMMAND),
+            command => command,
+        }
+    }
+
+    // Who the commands of a client with `identity` run as, `None` for the
+    // server's own account.
+    pub(crate) fn account(&self, identity: Option<&str>) -> Result<Option<Account>, IoError> {
+        let name = self
+            .user_mapping
+            .as_ref()
+            .and_then(|mapping| mapping(identity))
+            .or_else(|| self.run_as.clone());
+        name.map(|name| Account::lookup(&name)).transpose()
+    }
+
+    // Applies `clear_env`, `working_dir` and `env` to a command about to be
+    // spawned, run as `account`.
+    pub(crate) fn prepare(&self, cmd: &mut Command, account: Option<&Account>) {
+        if self.clear_env {
+            Self::scrub_env(cmd);
+        }
+        match (&self.working_dir, account) {
+            (Some(dir), _) => {
+                cmd.current_dir(dir);
+            }
+            (None, Some(account)) => {
+                cmd.current_dir(&account.home);
+            }
+            (None, None) => {
+                if let Ok(home) = std::env::var("HOME") {
+                    cmd.current_dir(home);
+                }
+            }
+        }
+        if let Some(account) = account {
+            account.env(cmd);
+        }
+        cmd.envs(&self.env);
+        if let Some(limits) = self.resource_limits {
+            limits.apply(cmd);
+        }
+    }
+
+    // Applies `max_size` to a client resize, scaling the pixel size along.
+    pub(crate) fn clamp_size(&self, size: WindowSize) -> WindowSize {
+        let (max_cols, max_rows) = match self.max_size {
+            Some(max) => max,
+            None => return size,
+        };
+        let scale = |pixels: u16, cells: u16, max: u16| {
+            if cells <= max {
+                pixels
+            } else {
+                (pixels as u32 * max as u32 / cells as u32) as u16
+            }
+        };
+        WindowSize {
+            cols: size.cols.min(max_cols),
+            rows: size.rows.min(max_rows),
+            xpixel: scale(size.xpixel, size.cols, max_cols),
+            ypixel: scale(size.ypixel, size.rows, max_rows),
+        }
+    }
+
+    // Checks what can be checked before serving, see the `validate` module.
+    // Meant for startup and CI, it binds the listening addresses for a
+    // moment and loads the TLS files.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        crate::validate::check(self)
+    }
+
+    // The configuration as JSON, defaults filled in and secrets redacted,
+    // see the `introspect` module.
+    pub fn effective(&self) -> serde_json::Value {
+        crate::introspect::effective(self)
+    }
+
+    // Runs the admission checks of a handshake from `peer` asking for
+    // `command` (a program name or a JSON `proto::SpawnRequest`), without
+    // spawning anything. `identity` is what the
+    // listener's authenticator returned. Clients can get the same answer
+    // with a `proto::DryRun` message.
+    pub fn admit(&self, peer: SocketAddr, identity: Option<&str>, command: &str) -> Decision {
+        match self
+            .check_peer(peer, identity)
+            .and_then(|()| {
+                SpawnRequest::parse(command)
+                    .map_err(|e| Refusal::Spawn(format!("invalid spawn request: {}", e)))
+            })
+            .and_then(|mut request| self.check_spawn(&mut request, peer, identity))
+        {
+            Ok(()) => Decision {
+                allowed: true,
+                reason: None,
+            },
+            Err(refusal) => refusal.into(),
+        }
+    }
+
+    pub(crate) fn check_command(
+        &self,
+        command: &str,
+        identity: Option<&str>,
+    ) -> Result<(), Refusal> {
+        match self.command_policy {
+            Some(ref policy) => policy(command, identity).map_err(Refusal::Command),
+            None => Ok(()),
+        }
+    }
+
+    // The spawn policy, which may rewrite `request`, the command policy on
+    // its command line, then the checks of the `spawn` module.
+    pub(crate) fn check_spawn(
+        &self,
+        request: &mut SpawnRequest,
+        peer: SocketAddr,
+        identity: Option<&str>,
+    ) -> Result<(), Refusal> {
+        if let Some(ref policy) = self.spawn_policy {
+            policy
+                .check(request, peer, identity)
+                .map_err(Refusal::Command)?;
+        }
+        self.check_command(&crate::spawn::command_line(request), identity)?;
+        crate::spawn::check(request, self, identity)
+    }
+
+    // The checks not depending on the command, which can be run during the
+    // WebSocket upgrade.
+    pub(crate) fn check_peer(
+        &self,
+        peer: SocketAddr,
+        identity: Option<&str>,
+    ) -> Result<(), Refusal> {
+        if let Some(ref bans) = self.bans {
+            if bans.is_banned(peer.ip(), identity) {
+                return Err(Refusal::Banned);
+            }
+        }
+        if let Some(ref ledger) = self.bandwidth {
+            ledger
+                .check(&peer.ip().to_string())
+                .map_err(|e| Refusal::Quota(e.to_string()))?;
+        }
+        if self
+            .memory_budget
+            .as_ref()
+            .is_some_and(|budget| budget.rejects())
+        {
+            return Err(self.capacity("server out of memory"));
+        }
+        if self
+            .session_limit
+            .as_ref()
+            .is_some_and(|limit| limit.rejects())
+        {
+            return Err(self.capacity("server at capacity"));
+        }
+        if self
+            .peer_limit
+            .as_ref()
+            .is_some_and(|limit| limit.rejects(peer.ip()))
+        {
+            return Err(self.capacity("too many sessions from this address"));
+        }
+        Ok(())
+    }
+
+    // The one of `profile_limits` for a session of `profile` running
+    // `program`, with its name.
+    pub(crate) fn profile_limit<'a>(
+        &'a self,
+        profile: Option<&'a str>,
+        program: &'a str,
+    ) -> Option<(&'a str, &'a SessionLimit)> {
+        let name = match profile {
+            Some(profile) => profile,
+            None => {
+                let program = self.resolve_command(program);
+                Path::new(program).file_name()?.to_str()?
+            }
+        };
+        self.profile_limits.get(name).map(|limit| (name, limit))
+    }
+
+    // Turns a client away for `session_limit`, `peer_limit` or
+    // `profile_limits`.
+    pub(crate) fn capacity(&self, reason: &str) -> Refusal {
+        Refusal::Capacity(self.limit_message.as_deref().unwrap_or(reason).to_owned())
+    }
+}
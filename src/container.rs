@@ -0,0 +1,87 @@
+// Sessions attached to running containers: spawn requests naming a
+// `container` run `docker exec -it` (or podman's) in the session's pty in
+// place of the command, when `ServerConfig::container_exec` lets the client
+// in. The runtime's client forwards the window size it gets from the pty to
+// the runtime's resize API on every SIGWINCH, so resizes go through the same
+// `TIOCSWINSZ` as for local commands and the rest of the session stays as
+// it is. Signals are sent as the characters the container's terminal turns
+// into them, the exec client would swallow them otherwise, see
+// `SessionHandle::signal`.
+//
+// The request's working directory and variables are applied in the
+// container, so they aren't checked against the local file system.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::process::Command;
+use wspty_proto::SpawnRequest;
+
+// Whether a client, with the identity its authenticator returned, may exec
+// into a container.
+pub type ContainerAccess = Arc<dyn Fn(Option<&str>, &str) -> bool + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Runtime {
+    #[default]
+    Docker,
+    Podman,
+}
+
+#[derive(Clone, Default)]
+pub struct ContainerExec {
+    pub runtime: Runtime,
+    // The client binary, the runtime's from `PATH` if unset.
+    pub program: Option<PathBuf>,
+    // Requests are refused unless it says yes.
+    pub access: Option<ContainerAccess>,
+    // The container user, the image's if unset.
+    pub user: Option<String>,
+    // Run when the request has no program, `/bin/sh` if unset.
+    pub shell: Option<String>,
+}
+
+impl Runtime {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+        }
+    }
+}
+
+impl ContainerExec {
+    pub(crate) fn program(&self) -> PathBuf {
+        self.program
+            .clone()
+            .unwrap_or_else(|| self.runtime.name().into())
+    }
+
+    pub(crate) fn allows(&self, identity: Option<&str>, container: &str) -> bool {
+        self.access
+            .as_ref()
+            .is_some_and(|access| access(identity, container))
+    }
+
+    // The exec client running `request` in `container`.
+    pub(crate) fn command(&self, container: &str, request: &SpawnRequest) -> Command {
+        let mut cmd = Command::new(self.program());
+        cmd.args(["exec", "-it"]);
+        if let Some(ref user) = self.user {
+            cmd.arg("-u").arg(user);
+        }
+        if let Some(ref cwd) = request.cwd {
+            cmd.arg("-w").arg(cwd);
+        }
+        // The container doesn't inherit the variables set for the client.
+        cmd.args(["-e", "TERM=xterm-256color", "-e", "COLORTERM=truecolor"]);
+        for (name, value) in request.env.iter() {
+            cmd.arg("-e").arg(format!("{}={}", name, value));
+        }
+        cmd.arg("--").arg(container);
+        match request.cmd.is_empty() {
+            true => cmd.arg(self.shell.as_deref().unwrap_or("/bin/sh")),
+            false => cmd.args(request.cmd.iter()),
+        };
+        cmd
+    }
+}
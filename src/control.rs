@@ -0,0 +1,262 @@
+// Control traffic on a WebSocket of its own, for frontends and proxies
+// handling a plain byte stream better than mixed framing. Clients ask for it
+// with the `X-Control-Channel: split` header or the `control=split` query
+// parameter of the upgrade request, servers with
+// `ServerConfig::control_channels` set grant it by sending the header back.
+// The session's first message is then a `proto::ControlChannel` with a
+// token, binary messages after it being only the pty's output and the
+// client's input, without opcodes. Everything else, resizes, signals,
+// stats and notifications included, goes both ways over a second connection
+// sent a `proto::Control` with the token in place of the command. Up to
+// `BACKLOG` messages wait for it to connect, or come back, and a new one
+// replaces it. Integrity mode and compression don't apply to the plain
+// stream, nor is it offered with the envelope framing (see the `framing`
+// module). Sessions migrated to another server aren't split anymore.
+
+use crate::{ServerConfig, SessionHandle};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::protocol::CloseReason;
+use wspty_proto::{self as proto, ControlChannel, ServerMessage};
+
+// Messages kept for a control connection to come.
+pub(crate) const BACKLOG: usize = 256;
+
+#[derive(Clone, Default)]
+pub struct ControlChannels {
+    // By token.
+    sessions: Arc<Mutex<HashMap<String, Arc<Control>>>>,
+}
+
+impl ControlChannels {
+    pub(crate) fn register(&self, handle: &SessionHandle) -> Result<Arc<Control>, IoError> {
+        let control = Arc::new(Control {
+            token: crate::persist::token()?,
+            identity: handle.identity.clone(),
+            channels: self.clone(),
+            outgoing: Mutex::new(Outgoing::default()),
+            incoming: Mutex::new(None),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(control.token.clone(), control.clone());
+        Ok(control)
+    }
+}
+
+#[derive(Default)]
+struct Outgoing {
+    // Until a control connection takes them.
+    pending: VecDeque<Message>,
+    connection: Option<UnboundedSender<Message>>,
+}
+
+// The control side of a split session.
+pub(crate) struct Control {
+    token: String,
+    identity: Option<String>,
+    channels: ControlChannels,
+    outgoing: Mutex<Outgoing>,
+    // To the input task of the data connection, while there is one.
+    incoming: Mutex<Option<UnboundedSender<Message>>>,
+}
+
+impl Control {
+    // The first message of the data connection.
+    pub(crate) fn message(&self) -> Message {
+        let channel = ControlChannel {
+            token: self.token.clone(),
+        };
+        Message::Binary(ServerMessage::ControlChannel(channel).encode())
+    }
+
+    // What of a message for the client goes out on the data connection,
+    // the rest being sent over the control one.
+    pub(crate) fn route(&self, msg: Message) -> Option<Message> {
+        match msg {
+            Message::Binary(ref data) if data.first() == Some(&proto::OUTPUT) => {
+                Some(Message::Binary(data[1..].to_vec()))
+            }
+            Message::Binary(ref data) if data.first() == Some(&proto::CONTROL_CHANNEL) => Some(msg),
+            Message::Binary(_) => {
+                self.send(msg);
+                None
+            }
+            msg => Some(msg),
+        }
+    }
+
+    fn send(&self, msg: Message) {
+        let mut outgoing = self.outgoing.lock().unwrap();
+        let msg = match outgoing.connection {
+            Some(ref connection) => match connection.send(msg) {
+                Ok(()) => return,
+                Err(e) => e.0,
+            },
+            None => msg,
+        };
+        outgoing.connection = None;
+        if outgoing.pending.len() == BACKLOG {
+            outgoing.pending.pop_front();
+        }
+        outgoing.pending.push_back(msg);
+    }
+
+    // Takes the messages for the client from the last control connection,
+    // which gets closed.
+    fn connect(&self) -> UnboundedReceiver<Message> {
+        let (sender, receiver) = unbounded_channel();
+        let mut outgoing = self.outgoing.lock().unwrap();
+        if let Some(last) = outgoing.connection.take() {
+            let _ = last.send(close_message(CloseReason::HandedOver));
+        }
+        for msg in outgoing.pending.drain(..) {
+            let _ = sender.send(msg);
+        }
+        outgoing.connection = Some(sender);
+        receiver
+    }
+
+    // What the control connection gets from now on, for a new data
+    // connection.
+    pub(crate) fn incoming(&self) -> UnboundedReceiver<Message> {
+        let (sender, receiver) = unbounded_channel();
+        self.incoming.lock().unwrap().replace(sender);
+        receiver
+    }
+
+    // The session is over.
+    pub(crate) fn close(&self) {
+        self.channels.sessions.lock().unwrap().remove(&self.token);
+        let mut outgoing = self.outgoing.lock().unwrap();
+        if let Some(connection) = outgoing.connection.take() {
+            let _ = connection.send(close_message(CloseReason::SessionEnded));
+        }
+    }
+}
+
+fn close_message(reason: CloseReason) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::Normal,
+        reason: reason.to_string().into(),
+    }))
+}
+
+// The data connection's messages, input framed again, along with the
+// control connection's. Ends with the data connection.
+pub(crate) struct Merged<I> {
+    data: I,
+    control: Option<UnboundedReceiver<Message>>,
+}
+
+pub(crate) fn merge<I>(data: I, control: Option<UnboundedReceiver<Message>>) -> Merged<I> {
+    Merged { data, control }
+}
+
+impl<I> Stream for Merged<I>
+where
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let split = self.control.is_some();
+        if let Some(ref mut control) = self.control {
+            if let Poll::Ready(Some(msg)) = control.poll_recv(cx) {
+                return Poll::Ready(Some(Ok(msg)));
+            }
+        }
+        match self.data.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(Message::Binary(data)))) if split => {
+                let input = proto::frame(proto::INPUT, &data);
+                Poll::Ready(Some(Ok(Message::Binary(input))))
+            }
+            poll => poll,
+        }
+    }
+}
+
+// Serves a `proto::Control` request from `peer`, until the session is over
+// or another connection takes over.
+pub(crate) async fn serve<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    peer: SocketAddr,
+    identity: Option<String>,
+    token: &str,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let control = config
+        .control_channels
+        .as_ref()
+        .and_then(|channels| channels.sessions.lock().unwrap().get(token).cloned())
+        .filter(|control| control.identity == identity);
+    let control = match control {
+        Some(control) => control,
+        None => {
+            warn!("refusing control connection of {:?}: unknown session", peer);
+            let farewell = CloseFrame {
+                code: CloseCode::Policy,
+                reason: CloseReason::UnknownSession.to_string().into(),
+            };
+            crate::teardown::refuse(
+                &mut ws_outgoing,
+                &mut ws_incoming,
+                farewell,
+                config.teardown,
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    info!("{:?} ({:?}) connected a control channel", peer, identity);
+    let mut outgoing = control.connect();
+    loop {
+        tokio::select! {
+            msg = outgoing.recv() => match msg {
+                Some(msg) if msg.is_close() => {
+                    ws_outgoing.send(msg).await?;
+                    break;
+                }
+                Some(msg) => ws_outgoing.send(msg).await?,
+                None => break,
+            },
+            msg = ws_incoming.next() => match msg {
+                Some(Ok(msg @ Message::Binary(_))) => {
+                    let incoming = control.incoming.lock().unwrap();
+                    match *incoming {
+                        Some(ref incoming) if incoming.send(msg).is_ok() => (),
+                        _ => debug!("dropped control message of {:?}, no data connection", peer),
+                    }
+                }
+                Some(Ok(Message::Ping(data))) => ws_outgoing.send(Message::Pong(data)).await?,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    debug!("{:?} closed its control channel", peer);
+                    return Ok(());
+                }
+                _ => (),
+            },
+        }
+    }
+    crate::teardown::linger(
+        &mut ws_incoming,
+        tokio::time::Instant::now() + config.teardown.timeout,
+    )
+    .await;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+// CPU time budget of sessions. The time used by every process of the
+// child's session is summed from `/proc`, including the children they
+// already waited for. Clients get a `proto::CpuUsage` warning once most of
+// the budget is used, then the processes get SIGXCPU and the session is
+// terminated. Processes that left the session (e.g. through `setsid`) are
+// not accounted. Only implemented on Linux, elsewhere nothing is enforced.
+
+use crate::outbox::Outbox;
+use crate::procfs::session_processes;
+use crate::SessionHandle;
+use log::{info, warn};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message;
+use wspty_proto::protocol::CloseReason;
+use wspty_proto::{CpuUsage, ServerMessage};
+
+#[derive(Clone, Debug)]
+pub struct CpuBudget {
+    pub limit: Duration,
+    // Clients are warned once this much is used.
+    pub warning: Duration,
+    // Time between two checks of the usage.
+    pub interval: Duration,
+}
+
+impl CpuBudget {
+    // Warns at 90% of `limit`, checking every second.
+    pub fn new(limit: Duration) -> Self {
+        CpuBudget {
+            limit,
+            warning: limit.mul_f64(0.9),
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+// Runs until the budget of the session is exhausted.
+pub(crate) async fn enforce(budget: CpuBudget, handle: SessionHandle, websocket_sender: Outbox) {
+    let sid = unsafe { libc::tcgetsid(handle.master.as_raw_fd()) };
+    if sid <= 0 {
+        warn!("no session to account CPU time for in {}", handle);
+        return;
+    }
+    let mut ticker = tokio::time::interval(budget.interval);
+    let mut warned = false;
+    // Dead processes reparented away take their time along, never go back.
+    let mut used = Duration::ZERO;
+    loop {
+        ticker.tick().await;
+        let usage = match tokio::task::spawn_blocking(move || session_cpu_time(sid)).await {
+            Ok(Some(usage)) => usage,
+            _ => {
+                warn!("failed to read the CPU time of session {}", handle);
+                return;
+            }
+        };
+        used = used.max(usage);
+
+        if used >= budget.limit {
+            info!(
+                "session {} exhausted its CPU time budget ({:?})",
+                handle, used
+            );
+            unsafe {
+                let foreground = libc::tcgetpgrp(handle.master.as_raw_fd());
+                if foreground > 0 && foreground != sid {
+                    libc::killpg(foreground, libc::SIGXCPU);
+                }
+                libc::killpg(sid, libc::SIGXCPU);
+            }
+            handle.close(CloseCode::Policy, CloseReason::CpuBudget);
+            return;
+        }
+        if !warned && used >= budget.warning {
+            warned = true;
+            let usage = CpuUsage {
+                used: used.as_millis() as u64,
+                limit: budget.limit.as_millis() as u64,
+            };
+            let frame = ServerMessage::CpuUsage(usage).encode();
+            if websocket_sender.send(Message::Binary(frame)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// User and system time of the processes in session `sid`, and of their
+// children already waited for.
+fn session_cpu_time(sid: libc::pid_t) -> Option<Duration> {
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    let ticks: u64 = session_processes(sid)?
+        .iter()
+        .map(|process| process.ticks)
+        .sum();
+    Some(Duration::from_millis(
+        ticks * 1000 / ticks_per_second as u64,
+    ))
+}
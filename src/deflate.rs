@@ -0,0 +1,255 @@
+// Compressed output for clients asking with `proto::Compression`, on
+// servers with `ServerConfig::compression`. Output goes out as one raw
+// DEFLATE stream (RFC 1951) per connection, the way permessage-deflate
+// does it: each message is a block with the fixed Huffman codes, ended by
+// a sync flush, and matches can refer to the last 32 KiB of output from
+// earlier messages. That is where the gain is, full screen apps redraw
+// with the same escape sequences over and over. The extension itself
+// can't be negotiated, tungstenite doesn't implement it. Frames under
+// `min_size`, keystroke echoes for the most part, are sent as plain output
+// and left out of the stream, as the client's inflater never sees them. The
+// `level` is how hard matches are looked for, from 0 for none to 9.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// Candidates tried for each match, by level.
+const MAX_CHAIN: [usize; 10] = [0, 2, 4, 8, 16, 32, 64, 128, 512, 4096];
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+#[derive(Clone, Debug)]
+pub struct OutputCompression {
+    // Of the output in a frame, in bytes.
+    pub min_size: usize,
+    pub level: u32,
+}
+
+impl Default for OutputCompression {
+    fn default() -> Self {
+        OutputCompression {
+            min_size: 64,
+            level: 6,
+        }
+    }
+}
+
+pub(crate) struct Deflater {
+    min_size: usize,
+    max_chain: usize,
+    // What was compressed so far, the last window of it at least.
+    history: Vec<u8>,
+    // Position of `history[0]` in the stream.
+    base: usize,
+    // Positions are kept in the hash chains plus one, zero ends them.
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    // Next position to add to the chains.
+    hashed: usize,
+}
+
+impl Deflater {
+    pub(crate) fn new(config: &OutputCompression) -> Self {
+        Deflater {
+            min_size: config.min_size,
+            max_chain: MAX_CHAIN[(config.level as usize).min(MAX_CHAIN.len() - 1)],
+            history: vec![],
+            base: 0,
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW],
+            hashed: 0,
+        }
+    }
+
+    // About what it holds on to, in bytes.
+    pub(crate) fn footprint(&self) -> usize {
+        self.history.capacity() + (self.head.len() + self.prev.len()) * std::mem::size_of::<usize>()
+    }
+
+    // The next part of the stream, ending on a byte boundary, unless the
+    // data is too small to be worth it.
+    pub(crate) fn compress(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < self.min_size {
+            return None;
+        }
+        let mut pos = self.base + self.history.len();
+        self.history.extend_from_slice(data);
+        let end = self.base + self.history.len();
+        let mut out = BitWriter::default();
+        // Not the last block, fixed codes.
+        out.bits(0b010, 3);
+        while pos < end {
+            self.hash_up_to(pos);
+            match self.longest_match(pos, end) {
+                Some((len, distance)) => {
+                    out.length(len);
+                    out.distance(distance);
+                    pos += len;
+                }
+                None => {
+                    out.symbol(self.history[pos - self.base] as u16);
+                    pos += 1;
+                }
+            }
+        }
+        out.symbol(256);
+        // Sync flush, an empty stored block.
+        out.bits(0, 3);
+        let mut out = out.finish();
+        out.extend_from_slice(&[0, 0, 0xff, 0xff]);
+
+        if self.history.len() > 2 * WINDOW {
+            let drop = self.history.len() - WINDOW;
+            self.history.drain(..drop);
+            self.base += drop;
+        }
+        Some(out)
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let i = pos - self.base;
+        let bytes = &self.history[i..i + MIN_MATCH];
+        let hash = (bytes[0] as usize) << 10 ^ (bytes[1] as usize) << 5 ^ bytes[2] as usize;
+        hash & ((1 << HASH_BITS) - 1)
+    }
+
+    // Adds the positions before `pos` with enough bytes after them.
+    fn hash_up_to(&mut self, pos: usize) {
+        let end = self.base + self.history.len();
+        while self.hashed < pos && self.hashed + MIN_MATCH <= end {
+            let hash = self.hash(self.hashed);
+            self.prev[self.hashed % WINDOW] = self.head[hash];
+            self.head[hash] = self.hashed + 1;
+            self.hashed += 1;
+        }
+    }
+
+    // As (length, distance).
+    fn longest_match(&self, pos: usize, end: usize) -> Option<(usize, usize)> {
+        if pos + MIN_MATCH > end {
+            return None;
+        }
+        let max = (end - pos).min(MAX_MATCH);
+        let data = &self.history[pos - self.base..pos - self.base + max];
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..self.max_chain {
+            // Gone from the chain, the history or the window.
+            let from = match candidate.checked_sub(1) {
+                Some(from) if from < pos && from >= self.base && pos - from <= WINDOW => from,
+                _ => break,
+            };
+            let earlier = &self.history[from - self.base..];
+            let len = data.iter().zip(earlier).take_while(|(a, b)| a == b).count();
+            if len >= MIN_MATCH && best.is_none_or(|(best, _)| len > best) {
+                best = Some((len, pos - from));
+                if len == max {
+                    break;
+                }
+            }
+            let next = self.prev[from % WINDOW];
+            // Overwritten by a later position.
+            if next > from {
+                break;
+            }
+            candidate = next;
+        }
+        best
+    }
+}
+
+// Bits go in from the least significant, Huffman codes from their most
+// significant bit.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    len: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, len: u32) {
+        self.acc |= value << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    fn code(&mut self, code: u32, len: u32) {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    // A literal, the end of block or a length code, with the fixed codes.
+    fn symbol(&mut self, symbol: u16) {
+        let symbol = symbol as u32;
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, len: usize) {
+        let code = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base as usize <= len)
+            .unwrap();
+        self.symbol(257 + code as u16);
+        let extra = LENGTH_EXTRA[code] as u32;
+        self.bits((len - LENGTH_BASE[code] as usize) as u32, extra);
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let code = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| base as usize <= distance)
+            .unwrap();
+        self.code(code as u32, 5);
+        let extra = DISTANCE_EXTRA[code] as u32;
+        self.bits((distance - DISTANCE_BASE[code] as usize) as u32, extra);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let mut deflater = Deflater::new(&OutputCompression::default());
+        assert_eq!(deflater.compress(b"l"), None);
+        let redraw = b"\x1b[H\x1b[2J\x1b[1;1Hwspty ".repeat(8);
+        let compressed = deflater.compress(&redraw).unwrap();
+        let mut literals = Deflater::new(&OutputCompression {
+            level: 0,
+            ..OutputCompression::default()
+        });
+        assert!(compressed.len() < literals.compress(&redraw).unwrap().len());
+    }
+}
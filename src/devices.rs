@@ -0,0 +1,279 @@
+// Device access per profile: with `ServerConfig::devices` set, commands run
+// in a mount namespace of their own where /dev is a tmpfs holding only the
+// basics every program expects (`BASICS`, the ptys and an empty /dev/shm)
+// and the devices listed, bind mounted from the host's. Default sessions
+// can then be kept device-free while a profile for GPU debugging gets
+// `/dev/dri` or `/dev/nvidia*`. Globs are expanded as sessions start, devices
+// appearing later aren't seen. Setting the namespace up needs the server to
+// run as root. Sessions in containers or over SSH get the devices of the
+// other side.
+
+use std::ffi::CString;
+use std::io::{Error as IoError, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+const DEV: &str = "/dev";
+// Bind mounted when the host has them.
+const BASICS: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/tty",
+    "/dev/ptmx",
+    "/dev/pts",
+];
+const LINKS: &[(&str, &str)] = &[
+    ("/dev/fd", "/proc/self/fd"),
+    ("/dev/stdin", "/proc/self/fd/0"),
+    ("/dev/stdout", "/proc/self/fd/1"),
+    ("/dev/stderr", "/proc/self/fd/2"),
+];
+
+// Whether `pattern`, `*` and `?` being wildcards, matches all of `name`.
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (Some((b'*', rest)), _) => {
+            wildcard(rest, name) || (!name.is_empty() && wildcard(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((_, name))) => wildcard(rest, name),
+        (Some((p, rest)), Some((n, name))) if p == n => wildcard(rest, name),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+// The devices `pattern` names, with wildcards in its last component only.
+pub(crate) fn expand(pattern: &str) -> Result<Vec<PathBuf>, IoError> {
+    let path = Path::new(pattern);
+    let (parent, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if path.starts_with(DEV) && path != Path::new(DEV) => {
+            (parent, name)
+        }
+        _ => {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a device", pattern),
+            ))
+        }
+    };
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(IoError::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a device", pattern),
+        ));
+    }
+    let name = name.as_bytes();
+    if !name.contains(&b'*') && !name.contains(&b'?') {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(parent)? {
+        let entry = entry?;
+        if wildcard(name, entry.file_name().as_bytes()) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn c_path(path: &Path) -> Result<CString, IoError> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| IoError::new(ErrorKind::InvalidInput, e))
+}
+
+struct Node {
+    path: CString,
+    dir: bool,
+    // Of the host's, opened with O_PATH before /dev is covered.
+    fd: libc::c_int,
+}
+
+// What the child's /dev gets, worked out before forking so that setting it
+// up doesn't allocate.
+pub(crate) struct PrivateDev {
+    dirs: Vec<CString>,
+    nodes: Vec<Node>,
+    links: Vec<(CString, CString)>,
+}
+
+impl PrivateDev {
+    pub(crate) fn new(devices: &[String]) -> Result<Self, IoError> {
+        let mut paths: Vec<PathBuf> = BASICS
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.symlink_metadata().is_ok())
+            .collect();
+        for pattern in devices {
+            for path in expand(pattern)? {
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+        // Those in a directory listed come with it.
+        let listed = paths.clone();
+        paths.retain(|path| {
+            !listed
+                .iter()
+                .any(|dir| dir != path && path.starts_with(dir))
+        });
+        let mut dirs = vec![];
+        let mut nodes = vec![];
+        for path in paths {
+            // The directories leading to it, e.g. /dev/nvidia-caps.
+            for dir in path.ancestors().skip(1) {
+                if dir == Path::new(DEV) {
+                    break;
+                }
+                let dir = c_path(dir)?;
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+            nodes.push(Node {
+                dir: path.is_dir(),
+                path: c_path(&path)?,
+                fd: -1,
+            });
+        }
+        // Parents first.
+        dirs.sort_by_key(|dir| dir.as_bytes().len());
+        let links = LINKS
+            .iter()
+            .map(|&(link, target)| Ok((c_path(link.as_ref())?, c_path(target.as_ref())?)))
+            .collect::<Result<_, IoError>>()?;
+        Ok(PrivateDev { dirs, nodes, links })
+    }
+
+    // Runs in the child, so it must not allocate.
+    pub(crate) fn mount(&mut self) -> Result<(), IoError> {
+        let check = |res: libc::c_int| {
+            if res < 0 {
+                Err(IoError::last_os_error())
+            } else {
+                Ok(res)
+            }
+        };
+        let dev = b"/dev\0".as_ptr().cast();
+        let shm = b"/dev/shm\0".as_ptr().cast();
+        let tmpfs = b"tmpfs\0".as_ptr().cast();
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWNS))?;
+            // Host mounts still show up here, not the other way round.
+            check(libc::mount(
+                std::ptr::null(),
+                b"/\0".as_ptr().cast(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_SLAVE,
+                std::ptr::null(),
+            ))?;
+            // Mounts can only be bound from the namespace's own copies.
+            for node in self.nodes.iter_mut() {
+                node.fd = check(libc::open(
+                    node.path.as_ptr(),
+                    libc::O_PATH | libc::O_CLOEXEC,
+                ))?;
+            }
+            check(libc::mount(
+                tmpfs,
+                dev,
+                tmpfs,
+                libc::MS_NOSUID | libc::MS_NOEXEC,
+                b"mode=755\0".as_ptr().cast(),
+            ))?;
+            for dir in self.dirs.iter() {
+                check(libc::mkdir(dir.as_ptr(), 0o755))?;
+            }
+            for node in self.nodes.iter() {
+                if node.dir {
+                    check(libc::mkdir(node.path.as_ptr(), 0o755))?;
+                } else {
+                    let fd = check(libc::open(
+                        node.path.as_ptr(),
+                        libc::O_CREAT | libc::O_WRONLY | libc::O_CLOEXEC,
+                        0o644,
+                    ))?;
+                    libc::close(fd);
+                }
+                let mut source = [0u8; 32];
+                check(libc::mount(
+                    fd_path(node.fd, &mut source).as_ptr(),
+                    node.path.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND,
+                    std::ptr::null(),
+                ))?;
+                libc::close(node.fd);
+            }
+            check(libc::mkdir(shm, 0o1777))?;
+            check(libc::mount(
+                tmpfs,
+                shm,
+                tmpfs,
+                libc::MS_NOSUID | libc::MS_NODEV,
+                b"mode=1777\0".as_ptr().cast(),
+            ))?;
+            for (link, target) in self.links.iter() {
+                check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// `/proc/self/fd/<fd>`, NUL terminated, in `buf`.
+fn fd_path(fd: libc::c_int, buf: &mut [u8; 32]) -> &[libc::c_char] {
+    let prefix = b"/proc/self/fd/";
+    buf[..prefix.len()].copy_from_slice(prefix);
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    let mut n = fd as u32;
+    loop {
+        digits[len] = b'0' + (n % 10) as u8;
+        len += 1;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    for i in 0..len {
+        buf[prefix.len() + i] = digits[len - 1 - i];
+    }
+    buf[prefix.len() + len] = 0;
+    let buf = &buf[..prefix.len() + len + 1];
+    unsafe { std::slice::from_raw_parts(buf.as_ptr().cast(), buf.len()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard(b"nvidia*", b"nvidia0"));
+        assert!(wildcard(b"nvidia*", b"nvidia"));
+        assert!(wildcard(b"render?128", b"renderD128"));
+        assert!(!wildcard(b"nvidia*", b"dri"));
+        assert!(!wildcard(b"card?", b"card10"));
+    }
+
+    #[test]
+    fn devices_only() {
+        assert!(expand("/etc/passwd").is_err());
+        assert!(expand("/dev").is_err());
+        assert!(expand("/dev/../etc/shadow").is_err());
+        assert_eq!(expand("/dev/dri").unwrap(), vec![PathBuf::from("/dev/dri")]);
+    }
+
+    #[test]
+    fn fd_paths() {
+        let mut buf = [0u8; 32];
+        let path = fd_path(1023, &mut buf);
+        let path = unsafe { CStr::from_ptr(path.as_ptr()) };
+        assert_eq!(path.to_bytes(), b"/proc/self/fd/1023");
+    }
+}
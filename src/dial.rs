@@ -0,0 +1,132 @@
+// Outgoing connections, for clients and the `proxy` gateway. Host names
+// resolving to several addresses are raced as in RFC 8305 (Happy Eyeballs):
+// families are interleaved in the resolver's order of preference, a new
+// attempt starts every `attempt_delay` or as soon as the previous one
+// failed, and the first established connection wins. A dual-stack host
+// with a broken family then only costs `attempt_delay`. Only plain `ws://`
+// URLs can be reached, TLS isn't done on the client side.
+
+use crate::tunnel::Tunnel;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream};
+use tokio_tungstenite::{client_async, MaybeTlsStream, WebSocketStream};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::error::UrlError;
+use tungstenite::handshake::client::Response;
+use tungstenite::Error as WsError;
+
+#[derive(Clone, Debug)]
+pub struct Dialer {
+    // Connect through this proxy, which is then what gets dialed.
+    pub tunnel: Option<Tunnel>,
+    // Head start of each connection attempt on the next one, 250ms as
+    // recommended by RFC 8305.
+    pub attempt_delay: Duration,
+    // For the whole connection, tunnel handshake included.
+    pub timeout: Duration,
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Dialer {
+            tunnel: None,
+            attempt_delay: Duration::from_millis(250),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Dialer {
+    // A stream to `host:port`, through the tunnel if any.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, IoError> {
+        let connect = async {
+            match self.tunnel {
+                Some(ref tunnel) => {
+                    let mut stream = self.race(tunnel.proxy()).await?;
+                    tunnel.handshake(&mut stream, host, port).await?;
+                    Ok(stream)
+                }
+                None => self.race((host, port)).await,
+            }
+        };
+        tokio::time::timeout(self.timeout, connect)
+            .await
+            .map_err(|_| IoError::new(ErrorKind::TimedOut, "connection timed out"))?
+    }
+
+    // Opens a WebSocket to a URL, or for a request with more headers.
+    pub async fn connect_websocket<R: IntoClientRequest>(
+        &self,
+        request: R,
+    ) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), WsError> {
+        let request = request.into_client_request()?;
+        if request.uri().scheme_str() != Some("ws") {
+            return Err(WsError::Url(UrlError::TlsFeatureNotEnabled));
+        }
+        let host = request
+            .uri()
+            .host()
+            .ok_or(WsError::Url(UrlError::NoHostName))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = request.uri().port_u16().unwrap_or(80);
+        let stream = self.connect(&host, port).await?;
+        client_async(request, MaybeTlsStream::Plain(stream)).await
+    }
+
+    async fn race<A: tokio::net::ToSocketAddrs>(&self, target: A) -> Result<TcpStream, IoError> {
+        let mut addrs = interleave(lookup_host(target).await?.collect()).into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        loop {
+            if attempts.is_empty() {
+                match addrs.next() {
+                    Some(addr) => attempts.push(TcpStream::connect(addr)),
+                    None => {
+                        return Err(last_error.unwrap_or_else(|| {
+                            IoError::new(ErrorKind::NotFound, "no address to connect to")
+                        }))
+                    }
+                }
+            }
+            tokio::select! {
+                Some(res) = attempts.next() => match res {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => {
+                        last_error = Some(e);
+                        if let Some(addr) = addrs.next() {
+                            attempts.push(TcpStream::connect(addr));
+                        }
+                    }
+                },
+                _ = tokio::time::sleep(self.attempt_delay) => {
+                    if let Some(addr) = addrs.next() {
+                        attempts.push(TcpStream::connect(addr));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Alternates IPv6 and IPv4 addresses, starting with the family of the first
+// one and keeping the order within each family.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Frame dumps, for debugging client compatibility issues offline. With
+// `ServerConfig::frame_dumps`, every WebSocket frame of the sessions its
+// `filter` picks, all of them if unset, goes to `<dir>/<id>.frames.jsonl`
+// as it is received or sent: one JSON line with the time, the direction,
+// the frame type, the opcode of binary messages, the size and the first
+// `payload` bytes in hex. Outgoing frames are dumped as they go on the
+// wire, compressed or sealed. Like recordings, dumps hold what users type,
+// passwords included. Writing never holds the session back, frames are
+// queued.
+
+use crate::{Session, SessionHandle};
+use log::error;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tungstenite::Message;
+
+const DEFAULT_PAYLOAD: usize = 64;
+
+// Whether the frames of the session are dumped.
+pub type DumpFilter = Arc<dyn Fn(&Session) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct FrameDumps {
+    pub dir: PathBuf,
+    // Bytes of each payload written, 64 if unset.
+    pub payload: Option<usize>,
+    pub filter: Option<DumpFilter>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    // In seconds since the epoch.
+    at: f64,
+    direction: Direction,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opcode: Option<u8>,
+    size: usize,
+    payload: String,
+    truncated: bool,
+}
+
+fn line(direction: Direction, msg: &Message, payload: usize, at: SystemTime) -> String {
+    let (kind, data): (_, &[u8]) = match msg {
+        Message::Binary(data) => ("binary", data),
+        Message::Text(text) => ("text", text.as_bytes()),
+        Message::Ping(data) => ("ping", data),
+        Message::Pong(data) => ("pong", data),
+        Message::Close(Some(frame)) => ("close", frame.reason.as_bytes()),
+        Message::Close(None) => ("close", &[]),
+        Message::Frame(frame) => ("frame", frame.payload()),
+    };
+    let opcode = match msg {
+        Message::Binary(data) => data.first().copied(),
+        _ => None,
+    };
+    let kept = &data[..data.len().min(payload)];
+    let line = Line {
+        at: at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0),
+        direction,
+        kind,
+        opcode,
+        size: data.len(),
+        payload: kept.iter().map(|b| format!("{:02x}", b)).collect(),
+        truncated: kept.len() < data.len(),
+    };
+    serde_json::to_string(&line).unwrap_or_default() + "\n"
+}
+
+// The dump of a session.
+#[derive(Clone)]
+pub(crate) struct FrameDump {
+    payload: usize,
+    sender: UnboundedSender<String>,
+}
+
+impl FrameDump {
+    pub(crate) fn start(dumps: &FrameDumps, handle: &SessionHandle) -> Option<Self> {
+        if !dumps.filter.as_ref().is_none_or(|filter| filter(handle)) {
+            return None;
+        }
+        let dir = dumps.dir.clone();
+        let path = dir.join(format!("{}.frames.jsonl", handle.session_id()));
+        let (sender, mut receiver) = unbounded_channel::<String>();
+        tokio::task::spawn_blocking(move || {
+            let file = std::fs::create_dir_all(&dir).and_then(|()| File::create(&path));
+            let mut writer = match file {
+                Ok(file) => BufWriter::new(file),
+                Err(e) => {
+                    error!("failed to create frame dump {:?}: {}", path, e);
+                    return;
+                }
+            };
+            while let Some(line) = receiver.blocking_recv() {
+                if let Err(e) = writer.write_all(line.as_bytes()) {
+                    error!("failed to write frame dump {:?}: {}", path, e);
+                    return;
+                }
+                // Flush once caught up.
+                if receiver.is_empty() {
+                    let _ = writer.flush();
+                }
+            }
+        });
+        Some(FrameDump {
+            payload: dumps.payload.unwrap_or(DEFAULT_PAYLOAD),
+            sender,
+        })
+    }
+
+    pub(crate) fn record(&self, direction: Direction, msg: &Message) {
+        let _ = self
+            .sender
+            .send(line(direction, msg, self.payload, SystemTime::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn lines() {
+        let at = UNIX_EPOCH + Duration::from_millis(1500);
+        let msg = Message::Binary(vec![0, b'l', b's', b'\r']);
+        let json: serde_json::Value =
+            serde_json::from_str(&line(Direction::In, &msg, 3, at)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "at": 1.5,
+                "direction": "in",
+                "type": "binary",
+                "opcode": 0,
+                "size": 4,
+                "payload": "006c73",
+                "truncated": true,
+            })
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&line(Direction::Out, &Message::Text("ok".into()), 3, at))
+                .unwrap();
+        assert_eq!(json["payload"], "6f6b");
+        assert_eq!(json["truncated"], false);
+        assert!(json.get("opcode").is_none());
+    }
+}
@@ -0,0 +1,62 @@
+// Environment updates sent by clients mid-session, e.g. to refresh
+// short-lived credentials in a long-lived shell. The variables allowed by
+// `ServerConfig::environment_policy` are typed into the shell as a single
+// `export` line, so they reach the commands started after it. The line
+// starts with a space to stay out of the history with
+// `HISTCONTROL=ignorespace`, but it gets echoed like any input. Updates are
+// refused while a job runs in the foreground, it would read the line
+// instead of the shell.
+
+use crate::policy::EnvironmentPolicy;
+use crate::SessionHandle;
+use log::warn;
+use std::os::unix::io::AsRawFd;
+use wspty_proto::EnvVar;
+
+pub(crate) fn valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Values are single quoted. Control characters would be interpreted by the
+// line discipline (e.g. ^C) rather than reach the shell.
+fn quote(value: &str) -> Option<String> {
+    if value.chars().any(char::is_control) {
+        return None;
+    }
+    Some(format!("'{}'", value.replace('\'', r"'\''")))
+}
+
+// The line to write to the pty of `handle`, `None` if no variable is
+// allowed. Fails if the shell isn't in the foreground.
+pub(crate) fn export_line(
+    vars: &[EnvVar],
+    policy: &EnvironmentPolicy,
+    handle: &SessionHandle,
+) -> Result<Option<String>, String> {
+    let mut line = String::from(" export");
+    for var in vars {
+        let value = match quote(&var.value) {
+            Some(value) if valid_name(&var.name) && policy(&var.name, handle.identity()) => value,
+            _ => {
+                warn!("refused to set {:?} in session {}", var.name, handle);
+                continue;
+            }
+        };
+        line.push_str(&format!(" {}={}", var.name, value));
+    }
+    if line.len() == " export".len() {
+        return Ok(None);
+    }
+    line.push('\n');
+
+    let fd = handle.master.as_raw_fd();
+    let (sid, foreground) = unsafe { (libc::tcgetsid(fd), libc::tcgetpgrp(fd)) };
+    if sid <= 0 || foreground != sid {
+        return Err("the shell is not in the foreground".into());
+    }
+    Ok(Some(line))
+}
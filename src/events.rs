@@ -0,0 +1,53 @@
+// Callbacks as sessions come and go, for operators wiring them into their
+// own logging or auditing, see `ServerConfig::events`. They are called from
+// the server's tasks, anything slow belongs in a task of its own.
+
+use crate::{Alert, CommandRecord, Session};
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::process::ExitStatus;
+use wspty_proto::{ProbeFailure, WindowSize};
+
+pub trait EventHandler: Send + Sync {
+    // A client connected, once authenticated, before it asks for anything.
+    fn on_connect(&self, _peer: SocketAddr, _identity: Option<&str>) {}
+
+    fn on_spawn(&self, _session: &Session) {}
+
+    fn on_spawn_failure(&self, _peer: SocketAddr, _error: &IoError) {}
+
+    // The environment of a session failed a startup probe, see the
+    // `probes` module.
+    fn on_probe_failure(&self, _peer: SocketAddr, _failure: &ProbeFailure) {}
+
+    // The client resized the terminal, to `size` after the size policy.
+    fn on_resize(&self, _session: &Session, _size: WindowSize) {}
+
+    // The client connection is over, the session may still be parked for
+    // it to resume.
+    fn on_disconnect(&self, _session: &Session) {}
+
+    // A command the shell ran is done, see the `commands` module.
+    fn on_command(&self, _session: &Session, _command: &CommandRecord) {}
+
+    // The output of the session rang the bell, resumed or stopped, see the
+    // `alerts` module.
+    fn on_alert(&self, _session: &Session, _alert: &Alert) {}
+
+    // The session went from its owner `from` to `to`, see the `handoff`
+    // module.
+    fn on_handoff(&self, _session: &Session, _from: Option<&str>, _to: &str) {}
+
+    // The child exited, its session is about to end.
+    fn on_exit(&self, _session: &Session, _status: ExitStatus) {}
+
+    // A bug of the server ended the session, see the `panics` module.
+    fn on_session_error(&self, _session: &Session, _error: &SessionError) {}
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionError {
+    // Where it happened: `input`, `output`, `pty` or `resize`.
+    pub task: &'static str,
+    pub message: String,
+}
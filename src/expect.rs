@@ -0,0 +1,129 @@
+// Waiting for output, for orchestration code sequencing actions against
+// interactive programs (see `SessionHandle::wait_for`), e.g. typing a
+// password once `Password:` shows. What the pty prints from the call on,
+// escape sequences included, is matched against bytes or a regex, over the
+// last `WINDOW` bytes so that matches can span reads. Waits fail with
+// `ErrorKind::TimedOut`, or `ErrorKind::BrokenPipe` once the session is over.
+// Output the waiter fell too far behind on is skipped.
+
+use crate::SessionHandle;
+use log::debug;
+use regex::bytes::Regex;
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+
+const WINDOW: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub enum OutputPattern {
+    Bytes(Vec<u8>),
+    Regex(Regex),
+}
+
+impl From<&str> for OutputPattern {
+    fn from(text: &str) -> Self {
+        OutputPattern::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for OutputPattern {
+    fn from(bytes: &[u8]) -> Self {
+        OutputPattern::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<Regex> for OutputPattern {
+    fn from(regex: Regex) -> Self {
+        OutputPattern::Regex(regex)
+    }
+}
+
+struct Matcher {
+    pattern: OutputPattern,
+    window: Vec<u8>,
+}
+
+impl Matcher {
+    // What matched, once it does.
+    fn push(&mut self, output: &[u8]) -> Option<Vec<u8>> {
+        self.window.extend_from_slice(output);
+        if self.window.len() > WINDOW {
+            self.window.drain(..self.window.len() - WINDOW);
+        }
+        match self.pattern {
+            OutputPattern::Bytes(ref bytes) if bytes.is_empty() => Some(vec![]),
+            OutputPattern::Bytes(ref bytes) => self
+                .window
+                .windows(bytes.len())
+                .find(|w| w == bytes)
+                .map(<[u8]>::to_vec),
+            OutputPattern::Regex(ref regex) => {
+                regex.find(&self.window).map(|m| m.as_bytes().to_vec())
+            }
+        }
+    }
+}
+
+pub(crate) async fn wait(
+    handle: SessionHandle,
+    mut output: Receiver<Vec<u8>>,
+    pattern: OutputPattern,
+    timeout: Duration,
+) -> Result<Vec<u8>, IoError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut matcher = Matcher {
+        pattern,
+        window: vec![],
+    };
+    loop {
+        // What was printed before the end still counts.
+        let res = tokio::select! {
+            biased;
+            res = tokio::time::timeout_at(deadline, output.recv()) => res,
+            _ = handle.closed() => Ok(Err(RecvError::Closed)),
+        };
+        match res {
+            Ok(Ok(data)) => {
+                if let Some(found) = matcher.push(&data) {
+                    return Ok(found);
+                }
+            }
+            Ok(Err(RecvError::Lagged(missed))) => {
+                debug!(
+                    "output waiter of session {} missed {} outputs",
+                    handle, missed
+                );
+                matcher.window.clear();
+            }
+            Ok(Err(RecvError::Closed)) => {
+                return Err(IoError::new(ErrorKind::BrokenPipe, "session over"))
+            }
+            Err(_) => return Err(IoError::new(ErrorKind::TimedOut, "no match")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_across_reads() {
+        let mut matcher = Matcher {
+            pattern: "Password:".into(),
+            window: vec![],
+        };
+        assert_eq!(matcher.push(b"sudo ls\r\n[sudo] Pass"), None);
+        assert_eq!(matcher.push(b"word: "), Some(b"Password:".to_vec()));
+        let mut matcher = Matcher {
+            pattern: Regex::new(r"exit=(\d+)").unwrap().into(),
+            window: vec![],
+        };
+        assert_eq!(matcher.push(b"\x1b[1mexit=4"), Some(b"exit=4".to_vec()));
+        matcher.window.clear();
+        assert_eq!(matcher.push(&vec![b'.'; WINDOW * 2]), None);
+        assert_eq!(matcher.window.len(), WINDOW);
+    }
+}
@@ -0,0 +1,111 @@
+// Misbehaving on purpose, for testing how clients cope with lossy links,
+// slow networks and commands dying mid-output. Enabled with the
+// `fault-injection` feature and set with `ServerConfig::faults`, never meant
+// for production servers. Only output frames are dropped or delayed, so that
+// sessions still get set up and torn down as usual.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    // Percentage of the output frames never sent to clients.
+    pub drop_output: u8,
+    // Waited for before sending each output frame.
+    pub output_delay: Duration,
+    // The sink stops taking frames for `stall.1` after every `stall.0`
+    // frames sent.
+    pub stall: Option<(u64, Duration)>,
+    // Kill the child once this many bytes were read from its pty, the output
+    // being cut at that offset.
+    pub kill_at: Option<u64>,
+    // Picks the dropped frames, the same ones from run to run unless 0.
+    pub seed: u64,
+}
+
+// Cuts the output of a session at `Faults::kill_at`.
+pub(crate) struct Cut {
+    left: Option<u64>,
+    done: bool,
+}
+
+impl Cut {
+    pub(crate) fn new(faults: &Faults) -> Self {
+        Cut {
+            left: faults.kill_at,
+            done: false,
+        }
+    }
+
+    // How much of the `n` bytes just read from the pty get through, and
+    // whether to kill the child now.
+    pub(crate) fn cut(&mut self, n: usize) -> (usize, bool) {
+        if self.done {
+            return (0, false);
+        }
+        match self.left {
+            Some(left) if n as u64 >= left => {
+                self.done = true;
+                (left as usize, true)
+            }
+            Some(ref mut left) => {
+                *left -= n as u64;
+                (n, false)
+            }
+            None => (n, false),
+        }
+    }
+}
+
+// The faults of one connection's writer.
+pub(crate) struct Injector {
+    faults: Faults,
+    rng: u64,
+    sent: u64,
+    // What `sent` was at the last stall.
+    stalled: u64,
+}
+
+impl Injector {
+    pub(crate) fn new(faults: &Faults) -> Self {
+        let seed = match faults.seed {
+            0 => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_nanos() as u64)
+                .unwrap_or(1),
+            seed => seed,
+        };
+        Injector {
+            faults: faults.clone(),
+            // Xorshift gets stuck on 0.
+            rng: seed.max(1),
+            sent: 0,
+            stalled: 0,
+        }
+    }
+
+    // How long to stall before taking the next frame.
+    pub(crate) fn stall(&mut self) -> Option<Duration> {
+        match self.faults.stall {
+            Some((every, pause)) if every > 0 && self.sent - self.stalled >= every => {
+                self.stalled = self.sent;
+                Some(pause)
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn drop_output(&mut self) -> bool {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng % 100 < self.faults.drop_output as u64
+    }
+
+    pub(crate) fn output_delay(&self) -> Duration {
+        self.faults.output_delay
+    }
+
+    pub(crate) fn sent(&mut self) {
+        self.sent += 1;
+    }
+}
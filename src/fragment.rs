@@ -0,0 +1,63 @@
+// Sizes of WebSocket messages. Outgoing binary messages larger than
+// `max_fragment` are sent as a sequence of frames of at most that size, so
+// no single frame of a huge output burst needs to be buffered whole by
+// proxies or the client. Incoming fragmented messages are reassembled by
+// tungstenite up to `max_message_size`, the connection is dropped beyond.
+
+use futures::{stream, Sink, SinkExt};
+use tungstenite::protocol::frame::coding::{Data, OpCode};
+use tungstenite::protocol::frame::Frame;
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Error as WsError, Message};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessageLimits {
+    // Largest frame payload sent, unlimited if unset.
+    pub max_fragment: Option<usize>,
+    // Largest message and frame accepted from clients, tungstenite's
+    // defaults (64 and 16 MiB) if unset.
+    pub max_message_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+}
+
+impl MessageLimits {
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig {
+        let defaults = WebSocketConfig::default();
+        WebSocketConfig {
+            max_message_size: self.max_message_size.or(defaults.max_message_size),
+            max_frame_size: self.max_frame_size.or(defaults.max_frame_size),
+            ..defaults
+        }
+    }
+
+    // Wraps the sending half of a WebSocket stream.
+    pub(crate) fn fragmenting<S>(self, sink: S) -> impl Sink<Message, Error = WsError> + Unpin
+    where
+        S: Sink<Message, Error = WsError> + Unpin,
+    {
+        sink.with_flat_map(move |msg| {
+            let frames = match (msg, self.max_fragment) {
+                (Message::Binary(data), Some(max)) if max > 0 && data.len() > max => {
+                    fragment(&data, max)
+                }
+                (msg, _) => vec![msg],
+            };
+            stream::iter(frames.into_iter().map(Ok))
+        })
+    }
+}
+
+fn fragment(data: &[u8], max: usize) -> Vec<Message> {
+    let count = data.len().div_ceil(max);
+    data.chunks(max)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let opcode = if index == 0 {
+                OpCode::Data(Data::Binary)
+            } else {
+                OpCode::Data(Data::Continue)
+            };
+            Message::Frame(Frame::message(chunk.to_vec(), opcode, index + 1 == count))
+        })
+        .collect()
+}
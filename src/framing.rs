@@ -0,0 +1,56 @@
+// Sessions of clients negotiating `proto::protocol::SUBPROTOCOL` are served
+// with the legacy framing internally, their messages converted at the edge:
+// text envelopes to legacy control messages and raw binary to input on the
+// way in, legacy messages to envelopes and raw binary output on the way
+// out. See `ServerConfig::reject_legacy_framing`.
+
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use log::debug;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::protocol::{ClientControl, ServerControl};
+use wspty_proto::{self as proto, ServerMessage};
+
+pub(crate) fn incoming<I>(incoming: I) -> impl Stream<Item = Result<Message, WsError>> + Unpin
+where
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    incoming.filter_map(|msg| {
+        let msg = match msg {
+            Ok(Message::Text(text)) => match ClientControl::decode(&text) {
+                Ok(ClientControl::Spawn { command }) => Some(Message::Text(command)),
+                Ok(control) => control.to_legacy().map(Message::Binary),
+                Err(e) => {
+                    debug!("invalid envelope: {}", e);
+                    None
+                }
+            },
+            Ok(Message::Binary(data)) => Some(Message::Binary(proto::frame(proto::INPUT, &data))),
+            Ok(msg) => Some(msg),
+            Err(e) => return future::ready(Some(Err(e))),
+        };
+        future::ready(msg.map(Ok))
+    })
+}
+
+pub(crate) fn outgoing<O>(outgoing: O) -> impl Sink<Message, Error = WsError> + Unpin
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+{
+    outgoing.with_flat_map(|msg| {
+        let msg = match msg {
+            Message::Binary(data) => match ServerMessage::decode(&data) {
+                Ok(ServerMessage::Output(output)) => Some(Message::Binary(output.to_vec())),
+                Ok(message) => match ServerControl::from_legacy(&message) {
+                    Some(control) => Some(Message::Text(control.encode())),
+                    None => {
+                        debug!("no envelope for opcode {}", data[0]);
+                        None
+                    }
+                },
+                Err(_) => None,
+            },
+            msg => Some(msg),
+        };
+        futures::stream::iter(msg.map(Ok))
+    })
+}
@@ -0,0 +1,320 @@
+// Collection of what sessions leave behind, for servers running for months
+// to stay clean when a teardown goes wrong. With `ServerConfig::gc`, a
+// background task sweeps every `interval` for orphans of each
+// `OrphanKind`: parked sessions without a client (see the `persist`
+// module), sessions whose command exited but that are still parked or
+// shared (see the `sharing` module), sharing entries of sessions that are
+// over, and the upgrade directories of servers no longer running (see the
+// `handover` module). Those found again `linger` after the sweep that first
+// found them are reaped, only reported or left alone as the policy of their
+// kind says: sessions are terminated, entries dropped and directories
+// removed. Orphans are logged, counted in `Metrics::orphans` and
+// `Metrics::reaped`, and those reaped passed to `on_reap`. Recordings are
+// the `retention` module's, and the server makes no cgroups of its own.
+
+use crate::handover::UPGRADE_PREFIX;
+use crate::{ServerConfig, SessionHandle};
+use futures::FutureExt;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub type ReapHook = Arc<dyn Fn(&Orphan) + Send + Sync>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    Ignore,
+    // Logged and counted.
+    Report,
+    Reap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OrphanKind {
+    Unattended,
+    Exited,
+    StaleEntry,
+    UpgradeDir,
+}
+
+pub(crate) const KINDS: [OrphanKind; 4] = [
+    OrphanKind::Unattended,
+    OrphanKind::Exited,
+    OrphanKind::StaleEntry,
+    OrphanKind::UpgradeDir,
+];
+
+impl OrphanKind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            OrphanKind::Unattended => "unattended",
+            OrphanKind::Exited => "exited",
+            OrphanKind::StaleEntry => "stale_entry",
+            OrphanKind::UpgradeDir => "upgrade_dir",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Orphan {
+    pub kind: OrphanKind,
+    // The session id, or the path of the directory.
+    pub id: String,
+}
+
+#[derive(Clone)]
+pub struct GarbageCollection {
+    // Time between two sweeps.
+    pub interval: Duration,
+    pub linger: Duration,
+    // Parked sessions, which otherwise wait for the persistence ttl.
+    pub unattended: OrphanPolicy,
+    pub exited: OrphanPolicy,
+    pub stale_entries: OrphanPolicy,
+    pub upgrade_dirs: OrphanPolicy,
+    pub on_reap: Option<ReapHook>,
+}
+
+impl Default for GarbageCollection {
+    fn default() -> Self {
+        GarbageCollection {
+            interval: Duration::from_secs(60),
+            linger: Duration::from_secs(10 * 60),
+            unattended: OrphanPolicy::Report,
+            exited: OrphanPolicy::Reap,
+            stale_entries: OrphanPolicy::Reap,
+            upgrade_dirs: OrphanPolicy::Reap,
+            on_reap: None,
+        }
+    }
+}
+
+impl GarbageCollection {
+    fn policy(&self, kind: OrphanKind) -> OrphanPolicy {
+        match kind {
+            OrphanKind::Unattended => self.unattended,
+            OrphanKind::Exited => self.exited,
+            OrphanKind::StaleEntry => self.stale_entries,
+            OrphanKind::UpgradeDir => self.upgrade_dirs,
+        }
+    }
+}
+
+// When each orphan was first found, for those to go `linger` after.
+#[derive(Default)]
+struct Sweeper {
+    // And whether it was handled.
+    seen: HashMap<(OrphanKind, String), (Instant, bool)>,
+}
+
+impl Sweeper {
+    // Of the orphans a sweep found, those to handle now. Orphans no longer
+    // found are forgotten.
+    fn due(&mut self, found: Vec<Orphan>, now: Instant, linger: Duration) -> Vec<Orphan> {
+        let mut seen = HashMap::new();
+        let mut due = vec![];
+        for orphan in found {
+            let key = (orphan.kind, orphan.id.clone());
+            let (since, mut handled) = self.seen.get(&key).copied().unwrap_or((now, false));
+            if !handled && now.saturating_duration_since(since) >= linger {
+                handled = true;
+                due.push(orphan);
+            }
+            seen.insert(key, (since, handled));
+        }
+        self.seen = seen;
+        due
+    }
+}
+
+// What a sweep finds, with the sessions to reap them.
+struct Found {
+    orphans: Vec<Orphan>,
+    sessions: HashMap<String, SessionHandle>,
+}
+
+fn exited(handle: &SessionHandle) -> bool {
+    matches!(handle.master.exit_status().now_or_never(), Some(Some(_)))
+}
+
+fn sweep(config: &ServerConfig) -> Found {
+    let mut found = Found {
+        orphans: vec![],
+        sessions: HashMap::new(),
+    };
+    let add = |found: &mut Found, kind, handle: &SessionHandle| {
+        let id = handle.session_id().to_owned();
+        found.orphans.push(Orphan {
+            kind,
+            id: id.clone(),
+        });
+        found.sessions.insert(id, handle.clone());
+    };
+    for handle in config.persistence.iter().flat_map(|p| p.parked_sessions()) {
+        if exited(&handle) {
+            add(&mut found, OrphanKind::Exited, &handle);
+        } else {
+            add(&mut found, OrphanKind::Unattended, &handle);
+        }
+    }
+    for handle in config.sharing.iter().flat_map(|s| s.shared_sessions()) {
+        if found.sessions.contains_key(handle.session_id()) {
+            continue;
+        }
+        if *handle.done.borrow() {
+            add(&mut found, OrphanKind::StaleEntry, &handle);
+        } else if exited(&handle) {
+            add(&mut found, OrphanKind::Exited, &handle);
+        }
+    }
+    found.orphans.extend(upgrade_dirs(&std::env::temp_dir()));
+    found
+}
+
+// Of servers no longer running, see `Handover::start`.
+fn upgrade_dirs(tmp: &Path) -> Vec<Orphan> {
+    let entries = match std::fs::read_dir(tmp) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let pid = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(UPGRADE_PREFIX))
+                .and_then(|pid| pid.parse::<i32>().ok());
+            pid.is_some_and(|pid| {
+                let pid = nix::unistd::Pid::from_raw(pid);
+                nix::sys::signal::kill(pid, None) == Err(nix::errno::Errno::ESRCH)
+            })
+        })
+        .map(|entry| Orphan {
+            kind: OrphanKind::UpgradeDir,
+            id: entry.path().to_string_lossy().into_owned(),
+        })
+        .collect()
+}
+
+fn reap(config: &ServerConfig, orphan: &Orphan, session: Option<&SessionHandle>) -> bool {
+    match (orphan.kind, session) {
+        (OrphanKind::Unattended, Some(session)) | (OrphanKind::Exited, Some(session)) => {
+            session.terminate("abandoned");
+            true
+        }
+        (OrphanKind::StaleEntry, _) => {
+            if let Some(ref sharing) = config.sharing {
+                sharing.unregister(&orphan.id);
+            }
+            true
+        }
+        (OrphanKind::UpgradeDir, _) => match std::fs::remove_dir_all(PathBuf::from(&orphan.id)) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("failed to remove {}: {}", orphan.id, e);
+                false
+            }
+        },
+        _ => false,
+    }
+}
+
+fn collect(config: &ServerConfig, gc: &GarbageCollection, sweeper: &mut Sweeper) {
+    let found = sweep(config);
+    let orphans = found
+        .orphans
+        .into_iter()
+        .filter(|orphan| gc.policy(orphan.kind) != OrphanPolicy::Ignore)
+        .collect();
+    for orphan in sweeper.due(orphans, Instant::now(), gc.linger) {
+        crate::metrics::orphan(orphan.kind, false);
+        if gc.policy(orphan.kind) == OrphanPolicy::Report {
+            warn!("orphaned {}: {}", orphan.kind.name(), orphan.id);
+            continue;
+        }
+        if !reap(config, &orphan, found.sessions.get(&orphan.id)) {
+            continue;
+        }
+        info!("reaped orphaned {}: {}", orphan.kind.name(), orphan.id);
+        crate::metrics::orphan(orphan.kind, true);
+        if let Some(ref on_reap) = gc.on_reap {
+            on_reap(&orphan);
+        }
+    }
+}
+
+pub(crate) async fn run(config: Arc<ServerConfig>, gc: GarbageCollection) {
+    let mut sweeper = Sweeper::default();
+    loop {
+        tokio::time::sleep(gc.interval).await;
+        let (config, gc) = (config.clone(), gc.clone());
+        let swept = tokio::task::spawn_blocking(move || {
+            collect(&config, &gc, &mut sweeper);
+            sweeper
+        });
+        sweeper = match swept.await {
+            Ok(sweeper) => sweeper,
+            Err(e) => {
+                error!("garbage collection task failed: {:?}", e);
+                Sweeper::default()
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lingering() {
+        let orphan = |kind, id: &str| Orphan {
+            kind,
+            id: id.to_owned(),
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let linger = Duration::from_secs(60);
+        let mut sweeper = Sweeper::default();
+        let found = || {
+            vec![
+                orphan(OrphanKind::Unattended, "1"),
+                orphan(OrphanKind::Exited, "2"),
+            ]
+        };
+        assert_eq!(sweeper.due(found(), at(0), linger), []);
+        assert_eq!(sweeper.due(found(), at(30), linger), []);
+        assert_eq!(sweeper.due(found(), at(60), linger), found());
+        // Handled once.
+        assert_eq!(sweeper.due(found(), at(90), linger), []);
+        // Found again after it went away, it starts over.
+        assert_eq!(sweeper.due(vec![], at(120), linger), []);
+        assert_eq!(sweeper.due(found(), at(150), linger), []);
+        assert_eq!(
+            sweeper.due(found()[1..].to_vec(), at(210), linger),
+            found()[1..]
+        );
+    }
+
+    #[test]
+    fn dead_servers() {
+        let tmp = std::env::temp_dir().join(format!("wspty-gc-{}", std::process::id()));
+        let live = tmp.join(format!("{}{}", UPGRADE_PREFIX, std::process::id()));
+        let dead = tmp.join(format!("{}{}", UPGRADE_PREFIX, i32::MAX));
+        std::fs::create_dir_all(&live).unwrap();
+        std::fs::create_dir_all(&dead).unwrap();
+        let found = upgrade_dirs(&tmp);
+        std::fs::remove_dir_all(&tmp).unwrap();
+        assert_eq!(
+            found,
+            [Orphan {
+                kind: OrphanKind::UpgradeDir,
+                id: dead.to_string_lossy().into_owned(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,86 @@
+// What becomes of the child of a `PtyCommand` once the last handle on its
+// `PtyMaster` is dropped, the watcher that `PtyCommand::run` leaves behind
+// aside. `PtyMaster::detach` opts a child out, for handing it over to some
+// other process for instance: it then also outlives the stopper given to
+// `run`. Children that already exited are left alone whatever the policy.
+
+use log::warn;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{watch, Notify};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    // Stopped as with the stopper, see `PtyCommand::stop_signal`.
+    #[default]
+    Kill,
+    // Left running, its pty is closed once it exits.
+    Detach,
+    // Left running, with a warning. For finding handles dropped too early.
+    Warn,
+}
+
+// Shared by the handles of a `PtyMaster`.
+pub(crate) struct Guard {
+    policy: DropPolicy,
+    pid: Option<u32>,
+    detached: Arc<AtomicBool>,
+    exit: watch::Receiver<Option<ExitStatus>>,
+    stop: Arc<Notify>,
+}
+
+// The watcher's side.
+pub(crate) struct Orphan {
+    detached: Arc<AtomicBool>,
+    // Asks for the child to be stopped, once orphaned under
+    // `DropPolicy::Kill` or with `PtyMaster::kill`.
+    pub(crate) stop: Arc<Notify>,
+}
+
+impl Orphan {
+    pub(crate) fn detached(&self) -> bool {
+        self.detached.load(Ordering::SeqCst)
+    }
+}
+
+impl Guard {
+    pub(crate) fn new(
+        policy: DropPolicy,
+        pid: Option<u32>,
+        exit: watch::Receiver<Option<ExitStatus>>,
+    ) -> (Self, Orphan) {
+        let detached = Arc::new(AtomicBool::new(false));
+        let stop = Arc::new(Notify::new());
+        let guard = Guard {
+            policy,
+            pid,
+            detached: detached.clone(),
+            exit,
+            stop: stop.clone(),
+        };
+        let orphan = Orphan { detached, stop };
+        (guard, orphan)
+    }
+
+    pub(crate) fn detach(&self) {
+        self.detached.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn kill(&self) {
+        self.stop.notify_one();
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.detached.load(Ordering::SeqCst) || self.exit.borrow().is_some() {
+            return;
+        }
+        match self.policy {
+            DropPolicy::Kill => self.kill(),
+            DropPolicy::Detach => (),
+            DropPolicy::Warn => warn!("pty child {:?} still running with no handle left", self.pid),
+        }
+    }
+}
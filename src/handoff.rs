@@ -0,0 +1,59 @@
+// Sessions changing hands, for support escalations where an engineer takes
+// over a customer's debugging shell. The client of a session sends a
+// `proto::Handoff` naming the new owner, which `ServerConfig::handoff_policy`
+// has to allow, none being allowed without one. The session then belongs to
+// that identity (see `SessionHandle::owner`): its client is closed with
+// `reassigned`, the session is parked as if the connection was lost (see
+// the `persist` module, required), and only the new owner can resume it,
+// with the token the former owner's client passes along, or follow it (see
+// the `sharing` module). The command keeps running as it was started.
+// Handoffs are logged, go in the timeline and to `EventHandler::on_handoff`.
+
+use crate::timeline::TimelineEvent;
+use crate::{ServerConfig, Session, SessionHandle};
+use log::info;
+use std::sync::Arc;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use wspty_proto::protocol::CloseReason;
+
+// Whether the session may go from its owner, the first identity, to the
+// second one.
+pub type HandoffPolicy = Arc<dyn Fn(&Session, Option<&str>, &str) -> bool + Send + Sync>;
+
+// Makes `to` the owner of the session, or tells why not. The client is to
+// be closed with the farewell left in the handle and the session parked.
+pub(crate) fn hand_off(
+    handle: &SessionHandle,
+    config: &ServerConfig,
+    to: &str,
+) -> Result<(), String> {
+    let from = handle.owner();
+    if to.is_empty() || Some(to) == from.as_deref() {
+        return Err(format!("can't hand the session to {:?}", to));
+    }
+    if config.persistence.is_none() {
+        return Err("sessions can't wait for their new owner".to_owned());
+    }
+    let allowed = config
+        .handoff_policy
+        .as_ref()
+        .is_some_and(|policy| policy(handle, from.as_deref(), to));
+    if !allowed {
+        return Err(format!("not allowed to hand the session to {}", to));
+    }
+    *handle.owner.lock().unwrap() = Some(to.to_owned());
+    info!("session {} handed from {:?} to {}", handle, from, to);
+    handle.record(TimelineEvent::HandedOff {
+        from: from.clone(),
+        to: to.to_owned(),
+    });
+    if let Some(ref events) = config.events {
+        events.on_handoff(handle, from.as_deref(), to);
+    }
+    handle.farewell.lock().unwrap().replace(CloseFrame {
+        code: CloseCode::Normal,
+        reason: CloseReason::Reassigned(to.to_owned()).to_string().into(),
+    });
+    Ok(())
+}
@@ -0,0 +1,270 @@
+// Listening sockets handed down by whoever started the server, for systemd
+// socket activation and for upgrading the server binary without dropping
+// sessions.
+//
+// Sockets are inherited as with `sd_listen_fds`: `LISTEN_FDS` descriptors
+// from 3 on, named in `LISTEN_FDNAMES`, meant for this process unless
+// `LISTEN_PID` tells another. TCP listeners are used instead of binding
+// the addresses they are bound to, the others are left alone.
+//
+// `ServerHandle::upgrade` starts the new binary with the TCP listeners, a
+// Unix listener it adopts migrated sessions from and a pipe to tell when
+// it is serving. The old server then stops accepting, migrates its
+// sessions over (see the `migrate` module) and tells systemd the new main
+// pid, for the caller to exit. Clients connecting meanwhile wait in the
+// listen backlog. Sessions that can't be migrated, over the Unix socket or
+// QUIC for instance, stay with the old server. Under systemd, upgrade from
+// `ExecReload` with `NotifyAccess=main`: restarting the service stops
+// every process in it.
+
+use log::{debug, warn};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sys::socket::{
+    getsockname, getsockopt, sockopt, AddressFamily, SockaddrLike, SockaddrStorage,
+};
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+const FIRST_FD: RawFd = 3;
+const LISTENER: &str = "wspty-listener";
+const MIGRATION: &str = "wspty-migration";
+const READY: &str = "wspty-ready";
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+// Of the temporary directories of upgrades, followed by the server's pid.
+pub(crate) const UPGRADE_PREFIX: &str = "wspty-upgrade-";
+
+#[derive(Default)]
+struct Inherited {
+    listeners: Vec<TcpListener>,
+    migration: Option<UnixListener>,
+    ready: Option<File>,
+}
+
+fn inherited() -> std::sync::MutexGuard<'static, Inherited> {
+    static INHERITED: OnceLock<Mutex<Inherited>> = OnceLock::new();
+    INHERITED
+        .get_or_init(|| Mutex::new(Inherited::from_env()))
+        .lock()
+        .unwrap()
+}
+
+impl Inherited {
+    fn from_env() -> Self {
+        let mut inherited = Inherited::default();
+        let for_us =
+            std::env::var("LISTEN_PID").map_or(true, |pid| pid == std::process::id().to_string());
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse::<RawFd>().ok());
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        // Otherwise the commands of the sessions would take the fds to be
+        // theirs, as `sd_listen_fds` does.
+        for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(var);
+        }
+        let count = match count {
+            Some(count) if for_us => count,
+            _ => return inherited,
+        };
+        let mut names = names.split(':');
+        for fd in FIRST_FD..FIRST_FD + count {
+            let name = names.next().unwrap_or_default();
+            // Not for children to inherit in turn.
+            if fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).is_err() {
+                continue;
+            }
+            match name {
+                MIGRATION => inherited.migration = Some(unsafe { UnixListener::from_raw_fd(fd) }),
+                READY => inherited.ready = Some(unsafe { File::from_raw_fd(fd) }),
+                _ if is_tcp_listener(fd) => {
+                    inherited
+                        .listeners
+                        .push(unsafe { TcpListener::from_raw_fd(fd) });
+                }
+                _ => debug!("ignoring inherited fd {} ({:?})", fd, name),
+            }
+        }
+        inherited
+    }
+}
+
+fn is_tcp_listener(fd: RawFd) -> bool {
+    let inet = getsockname::<SockaddrStorage>(fd).is_ok_and(|addr| {
+        matches!(
+            addr.family(),
+            Some(AddressFamily::Inet) | Some(AddressFamily::Inet6)
+        )
+    });
+    inet && getsockopt(fd, sockopt::AcceptConn).unwrap_or(false)
+}
+
+// Whether an inherited listener is bound to `addr`.
+pub(crate) fn inherits(addr: SocketAddr) -> bool {
+    inherited()
+        .listeners
+        .iter()
+        .any(|listener| listener.local_addr().ok() == Some(addr))
+}
+
+pub(crate) fn take_listener(addr: SocketAddr) -> Option<TcpListener> {
+    let mut inherited = inherited();
+    let pos = inherited
+        .listeners
+        .iter()
+        .position(|listener| listener.local_addr().ok() == Some(addr))?;
+    Some(inherited.listeners.remove(pos))
+}
+
+// Where the previous server sends its sessions.
+pub(crate) fn take_migration() -> Option<UnixListener> {
+    inherited().migration.take()
+}
+
+// Tells the previous server this one is serving.
+pub(crate) fn ready() {
+    if let Some(mut ready) = inherited().ready.take() {
+        let _ = ready.write_all(b"1");
+    }
+}
+
+// A new server being started with the listeners of this one.
+pub(crate) struct Handover {
+    child: Child,
+    ready: File,
+    dir: PathBuf,
+}
+
+impl Handover {
+    pub(crate) fn start(mut command: Command, listeners: &[OwnedFd]) -> Result<Self, IoError> {
+        let dir = std::env::temp_dir().join(format!("{}{}", UPGRADE_PREFIX, std::process::id()));
+        // Left over by a failed upgrade.
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let migration = UnixListener::bind(dir.join("sessions"))?;
+        let (ready_read, ready_write) = nix::unistd::pipe2(OFlag::O_CLOEXEC)?;
+        let ready = unsafe { File::from_raw_fd(ready_read) };
+        let ready_write = unsafe { OwnedFd::from_raw_fd(ready_write) };
+
+        let mut fds: Vec<(RawFd, &str)> = listeners
+            .iter()
+            .map(|fd| (fd.as_raw_fd(), LISTENER))
+            .collect();
+        fds.push((migration.as_raw_fd(), MIGRATION));
+        fds.push((ready_write.as_raw_fd(), READY));
+        // Out of the way of the descriptors they are moved to.
+        let count = fds.len() as RawFd;
+        let high = fds
+            .iter()
+            .map(|&(fd, _)| {
+                let high = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(FIRST_FD + count))?;
+                Ok(unsafe { OwnedFd::from_raw_fd(high) })
+            })
+            .collect::<Result<Vec<_>, IoError>>()?;
+        let raw: Vec<RawFd> = high.iter().map(AsRawFd::as_raw_fd).collect();
+        let names: Vec<&str> = fds.iter().map(|&(_, name)| name).collect();
+        command
+            .env("LISTEN_FDS", count.to_string())
+            .env("LISTEN_FDNAMES", names.join(":"))
+            .env_remove("LISTEN_PID");
+        unsafe {
+            command.pre_exec(move || {
+                for (i, &fd) in raw.iter().enumerate() {
+                    if libc::dup2(fd, FIRST_FD + i as RawFd) < 0 {
+                        return Err(IoError::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        Ok(Handover { child, ready, dir })
+    }
+
+    // Waits for the new server to serve, it is killed if it doesn't.
+    pub(crate) async fn ready(&mut self) -> Result<(), IoError> {
+        let mut ready = self.ready.try_clone()?;
+        let read = tokio::task::spawn_blocking(move || {
+            let mut byte = [0u8; 1];
+            ready.read(&mut byte)
+        });
+        let res = match tokio::time::timeout(READY_TIMEOUT, read).await {
+            Ok(Ok(Ok(1))) => Ok(()),
+            Ok(Ok(Ok(_))) => Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                "new server exited before serving",
+            )),
+            Ok(Ok(Err(e))) => Err(e),
+            Ok(Err(e)) => Err(IoError::other(e)),
+            Err(_) => Err(IoError::new(
+                ErrorKind::TimedOut,
+                "new server not serving in time",
+            )),
+        };
+        if res.is_err() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+        res
+    }
+
+    pub(crate) fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    // For `SessionHandle::migrate`.
+    pub(crate) fn sessions(&self) -> PathBuf {
+        self.dir.join("sessions")
+    }
+
+    pub(crate) fn finish(self) -> Child {
+        let _ = std::fs::remove_dir_all(&self.dir);
+        notify_main_pid(self.child.id());
+        self.child
+    }
+}
+
+// For systemd to follow the new server rather than stop the service.
+fn notify_main_pid(pid: u32) {
+    let socket = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+    let message = format!("MAINPID={}\n", pid);
+    let res =
+        UnixDatagram::unbound().and_then(|datagram| match socket.as_bytes().strip_prefix(b"@") {
+            Some(name) => send_abstract(&datagram, name, message.as_bytes()),
+            None => datagram.send_to(message.as_bytes(), Path::new(&socket)),
+        });
+    if let Err(e) = res {
+        warn!("failed to tell systemd about new server {}: {}", pid, e);
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn send_abstract(datagram: &UnixDatagram, name: &[u8], message: &[u8]) -> Result<usize, IoError> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    datagram.send_to_addr(message, &addr)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn send_abstract(_: &UnixDatagram, _: &[u8], _: &[u8]) -> Result<usize, IoError> {
+    Err(IoError::new(
+        ErrorKind::Unsupported,
+        "abstract sockets are only supported on Linux",
+    ))
+}
@@ -0,0 +1,277 @@
+// Session output as styled HTML, for support tools to embed terminal
+// snapshots in web reports: a `<pre>` with a span per run of cells sharing
+// colors and attributes, the history that scrolled off the top first.
+// `Session::to_html` renders what `ServerConfig::scrollback` kept, or the
+// screen without one, and `recording_html` replays a recording, streams or
+// asciicast, unless it is encrypted. Kept output can start mid-sequence,
+// garbling the first characters. Default colors are those of xterm, the
+// embedding page can override them through the `wspty` class.
+
+use crate::colors::rgb;
+use anyhow::{anyhow, bail, Context};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::path::Path;
+
+// Lines of history kept when replaying output.
+const HISTORY: usize = 10_000;
+const DEFAULT_SIZE: (u16, u16) = (24, 80);
+const FOREGROUND: &str = "#e5e5e5";
+const BACKGROUND: &str = "#000000";
+
+// `output` as it shows on a terminal of that many rows and columns.
+pub(crate) fn replay(rows: u16, cols: u16, output: &[u8]) -> String {
+    let mut parser = vt100::Parser::new(rows, cols, HISTORY);
+    parser.process(output);
+    render(parser.screen_mut())
+}
+
+// What the recording of session `id` in `dir` shows once replayed.
+pub fn recording_html(dir: &Path, id: u64) -> Result<String, anyhow::Error> {
+    let cast = dir.join(format!("{}.cast", id));
+    let output = dir.join(format!("{}.output.jsonl", id));
+    let events = if cast.exists() {
+        cast_events(&std::fs::read_to_string(&cast)?)
+            .with_context(|| format!("failed to read {}", cast.display()))?
+    } else if output.exists() {
+        let resize = dir.join(format!("{}.resize.jsonl", id));
+        let resizes = if resize.exists() {
+            std::fs::read_to_string(&resize)?
+        } else {
+            String::new()
+        };
+        stream_events(&std::fs::read_to_string(&output)?, &resizes)
+            .with_context(|| format!("failed to read {}", output.display()))?
+    } else if dir.join(format!("{}.json.age", id)).exists() {
+        bail!("recording {} is encrypted", id);
+    } else {
+        bail!("no recording {} in {}", id, dir.display());
+    };
+    let (rows, cols) = events.size;
+    let mut parser = vt100::Parser::new(rows, cols, HISTORY);
+    for event in events.events {
+        match event {
+            Event::Output(data) => parser.process(data.as_bytes()),
+            Event::Resize(cols, rows) => parser.screen_mut().set_size(rows, cols),
+        }
+    }
+    Ok(render(parser.screen_mut()))
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Output(String),
+    // Columns and rows.
+    Resize(u16, u16),
+}
+
+struct Events {
+    // Rows and columns to start with.
+    size: (u16, u16),
+    events: Vec<Event>,
+}
+
+// `COLSxROWS`, as recordings have them.
+fn parse_size(size: &str) -> Result<(u16, u16), anyhow::Error> {
+    let (cols, rows) = size
+        .split_once('x')
+        .ok_or_else(|| anyhow!("bad size {:?}", size))?;
+    Ok((cols.parse()?, rows.parse()?))
+}
+
+fn cast_events(cast: &str) -> Result<Events, anyhow::Error> {
+    let mut lines = cast.lines();
+    let header: serde_json::Value = serde_json::from_str(lines.next().unwrap_or_default())?;
+    let dimension = |name| {
+        header[name]
+            .as_u64()
+            .and_then(|n| u16::try_from(n).ok())
+            .ok_or_else(|| anyhow!("no {} in the header", name))
+    };
+    let size = (dimension("height")?, dimension("width")?);
+    let mut events = vec![];
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (_, code, data): (f64, String, String) = serde_json::from_str(line)?;
+        match code.as_str() {
+            "o" => events.push(Event::Output(data)),
+            "r" => {
+                let (cols, rows) = parse_size(&data)?;
+                events.push(Event::Resize(cols, rows));
+            }
+            _ => (),
+        }
+    }
+    Ok(Events { size, events })
+}
+
+// The streams don't tell the size the session started with, only the
+// resizes after that.
+fn stream_events(output: &str, resizes: &str) -> Result<Events, anyhow::Error> {
+    let mut timed = vec![];
+    for line in output.lines().filter(|line| !line.is_empty()) {
+        let (time, data): (f64, String) = serde_json::from_str(line)?;
+        timed.push((time, Event::Output(data)));
+    }
+    for line in resizes.lines().filter(|line| !line.is_empty()) {
+        let (time, size): (f64, String) = serde_json::from_str(line)?;
+        let (cols, rows) = parse_size(&size)?;
+        timed.push((time, Event::Resize(cols, rows)));
+    }
+    timed.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    Ok(Events {
+        size: DEFAULT_SIZE,
+        events: timed.into_iter().map(|(_, event)| event).collect(),
+    })
+}
+
+// The history and the rows of `screen`, scrolled back to the bottom after.
+pub(crate) fn render(screen: &mut vt100::Screen) -> String {
+    let (rows, cols) = screen.size();
+    screen.set_scrollback(usize::MAX);
+    let history = screen.scrollback();
+    let mut lines = vec![];
+    for line in 0..history + rows as usize {
+        let offset = history.saturating_sub(line);
+        screen.set_scrollback(offset);
+        lines.push(render_row(screen, (line + offset - history) as u16, cols));
+    }
+    screen.set_scrollback(0);
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    let mut html = format!(
+        "<pre class=\"wspty\" style=\"color:{};background:{}\">",
+        FOREGROUND, BACKGROUND
+    );
+    for line in lines {
+        html.push_str(&line);
+        html.push('\n');
+    }
+    html.push_str("</pre>\n");
+    html
+}
+
+fn render_row(screen: &vt100::Screen, row: u16, cols: u16) -> String {
+    // Runs of cells, by style.
+    let mut runs: Vec<(String, String)> = vec![];
+    for col in 0..cols {
+        let cell = match screen.cell(row, col) {
+            Some(cell) if !cell.is_wide_continuation() => cell,
+            _ => continue,
+        };
+        let style = style(cell);
+        let text = match cell.contents() {
+            "" => " ",
+            contents => contents,
+        };
+        match runs.last_mut() {
+            Some((last, run)) if *last == style => run.push_str(text),
+            _ => runs.push((style, text.to_owned())),
+        }
+    }
+    if let Some((style, run)) = runs.last_mut() {
+        if style.is_empty() {
+            run.truncate(run.trim_end_matches(' ').len());
+        }
+    }
+    let mut html = String::new();
+    for (style, run) in runs.iter().filter(|(_, run)| !run.is_empty()) {
+        if style.is_empty() {
+            html.push_str(&escape(run));
+        } else {
+            let _ = write!(html, "<span style=\"{}\">{}</span>", style, escape(run));
+        }
+    }
+    html
+}
+
+fn style(cell: &vt100::Cell) -> String {
+    let (mut foreground, mut background) = (color(cell.fgcolor()), color(cell.bgcolor()));
+    if cell.inverse() {
+        (foreground, background) = (
+            Some(background.unwrap_or_else(|| BACKGROUND.to_owned())),
+            Some(foreground.unwrap_or_else(|| FOREGROUND.to_owned())),
+        );
+    }
+    let mut style = String::new();
+    if let Some(foreground) = foreground {
+        let _ = write!(style, "color:{};", foreground);
+    }
+    if let Some(background) = background {
+        let _ = write!(style, "background:{};", background);
+    }
+    if cell.bold() {
+        style.push_str("font-weight:bold;");
+    }
+    if cell.dim() {
+        style.push_str("opacity:0.5;");
+    }
+    if cell.italic() {
+        style.push_str("font-style:italic;");
+    }
+    if cell.underline() {
+        style.push_str("text-decoration:underline;");
+    }
+    style
+}
+
+// `None` for the default one.
+fn color(color: vt100::Color) -> Option<String> {
+    let (r, g, b) = match color {
+        vt100::Color::Default => return None,
+        vt100::Color::Idx(index) => rgb(index),
+        vt100::Color::Rgb(r, g, b) => (r, g, b),
+    };
+    Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styles_become_spans() {
+        let html = replay(3, 20, b"a<b \x1b[1;31mred\x1b[0m \x1b[7minv\x1b[0m\r\n\r\n");
+        assert_eq!(
+            html,
+            "<pre class=\"wspty\" style=\"color:#e5e5e5;background:#000000\">\
+             a&lt;b <span style=\"color:#cd0000;font-weight:bold;\">red</span> \
+             <span style=\"color:#000000;background:#e5e5e5;\">inv</span>\n</pre>\n"
+        );
+    }
+
+    #[test]
+    fn history_comes_first() {
+        let html = replay(2, 10, b"1\r\n2\r\n3\r\n4");
+        assert!(html.contains(">1\n2\n3\n4\n</pre>"), "{}", html);
+    }
+
+    #[test]
+    fn streams_are_interleaved() {
+        let output = "[0.1,\"abc\"]\n[0.3,\"def\"]\n";
+        let resizes = "[0.2,\"100x30\"]\n";
+        let events = stream_events(output, resizes).unwrap();
+        assert_eq!(
+            events.events,
+            [
+                Event::Output("abc".into()),
+                Event::Resize(100, 30),
+                Event::Output("def".into())
+            ]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+// Per session counters for measuring the protocol overhead, enabled with
+// `ServerConfig::instrumentation`: messages and bytes each way, wakeups of
+// the pty reader and the time spent decoding client messages. The rates
+// are logged every second and `SessionHandle::throughput` has the totals.
+// Clients can compare JSON resizes with `proto::encode_raw_resize` ones.
+
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use wspty_proto::{ClientMessage, DecodeError};
+
+#[derive(Default)]
+pub(crate) struct Counters {
+    frames_in: AtomicU64,
+    bytes_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_out: AtomicU64,
+    wakeups: AtomicU64,
+    decode_nanos: AtomicU64,
+}
+
+// Totals of an instrumented session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Throughput {
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    // Reads from the pty.
+    pub wakeups: u64,
+    pub decode_time: Duration,
+}
+
+impl Throughput {
+    fn since(&self, earlier: &Throughput) -> Throughput {
+        Throughput {
+            frames_in: self.frames_in - earlier.frames_in,
+            bytes_in: self.bytes_in - earlier.bytes_in,
+            frames_out: self.frames_out - earlier.frames_out,
+            bytes_out: self.bytes_out - earlier.bytes_out,
+            wakeups: self.wakeups - earlier.wakeups,
+            decode_time: self.decode_time - earlier.decode_time,
+        }
+    }
+}
+
+impl Counters {
+    // Decodes a binary message from the client, timing it.
+    pub(crate) fn decode<'a>(&self, data: &'a [u8]) -> Result<ClientMessage<'a>, DecodeError> {
+        let started = Instant::now();
+        let res = ClientMessage::decode(data);
+        let elapsed = started.elapsed().as_nanos() as u64;
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.decode_nanos.fetch_add(elapsed, Ordering::Relaxed);
+        res
+    }
+
+    pub(crate) fn sent(&self, len: usize) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn woke(&self) {
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Throughput {
+        Throughput {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            wakeups: self.wakeups.load(Ordering::Relaxed),
+            decode_time: Duration::from_nanos(self.decode_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+// Logs the rates of `session` every second it is busy, until `done`.
+pub(crate) async fn report(
+    session: String,
+    counters: Arc<Counters>,
+    mut done: watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut last = Throughput::default();
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = done.wait_for(|done| *done) => return,
+        }
+        let now = counters.snapshot();
+        let rates = now.since(&last);
+        last = now;
+        if rates == Throughput::default() {
+            continue;
+        }
+        info!(
+            "session {}: {} msg/s {} B/s in, {} msg/s {} B/s out, {} wakeups/s, {:?} decoding",
+            session,
+            rates.frames_in,
+            rates.bytes_in,
+            rates.frames_out,
+            rates.bytes_out,
+            rates.wakeups,
+            rates.decode_time
+        );
+    }
+}
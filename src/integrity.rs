@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use wspty_proto::{self as proto, ServerMessage};
+
+// Bytes of sealed frames kept around for retransmission.
+const HISTORY_LEN: usize = 1 << 20;
+
+// Seals output into checked frames. Chaining the crc of each frame into the
+// next one detects a dropped frame as well as a corrupted one.
+#[derive(Default)]
+pub(crate) struct Integrity {
+    enabled: bool,
+    next_seq: u64,
+    crc: u32,
+    history: VecDeque<(u64, Vec<u8>)>,
+    history_len: usize,
+}
+
+impl Integrity {
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.history.clear();
+            self.history_len = 0;
+        }
+    }
+
+    pub(crate) fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        self.crc = proto::checksum(self.crc, data);
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let frame = ServerMessage::CheckedOutput {
+            seq,
+            crc: self.crc,
+            data,
+        }
+        .encode();
+
+        self.history_len += frame.len();
+        self.history.push_back((seq, frame.clone()));
+        while self.history_len > HISTORY_LEN {
+            match self.history.pop_front() {
+                Some((_, old)) => self.history_len -= old.len(),
+                None => break,
+            }
+        }
+        frame
+    }
+
+    // Frames `from..=to` as originally sent, or None if some of them are
+    // not in the history anymore.
+    pub(crate) fn retransmit(&self, from: u64, to: u64) -> Option<Vec<Vec<u8>>> {
+        let first = self.history.front()?.0;
+        if from < first || to >= self.next_seq || from > to {
+            return None;
+        }
+        let start = (from - first) as usize;
+        let end = (to - first) as usize;
+        Some(
+            self.history
+                .range(start..=end)
+                .map(|(_, frame)| frame.clone())
+                .collect(),
+        )
+    }
+}
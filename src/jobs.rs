@@ -0,0 +1,102 @@
+// Job control as over SSH. With `tostop` background jobs writing to the
+// terminal get stopped by SIGTTOU, as after `stty tostop`, those reading
+// from it always are by SIGTTIN. The process groups of the child's session
+// are scanned from `/proc` and clients get a `proto::JobEvent` whenever
+// one gets stopped, then when it is continued or done. Processes that left
+// the session are not watched.
+
+use crate::outbox::Outbox;
+use crate::procfs::session_processes;
+use crate::SessionHandle;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use tungstenite::Message;
+use wspty_proto::{JobEvent, JobState, ServerMessage};
+
+#[derive(Clone, Debug)]
+pub struct JobControl {
+    pub tostop: bool,
+    // Time between two scans of the session's processes.
+    pub interval: Duration,
+}
+
+impl Default for JobControl {
+    fn default() -> Self {
+        JobControl {
+            tostop: false,
+            interval: Duration::from_millis(500),
+        }
+    }
+}
+
+// The stopped process groups of session `sid`, with the command of their
+// leader, and all the groups.
+type Scan = (HashMap<libc::pid_t, String>, HashSet<libc::pid_t>);
+
+fn scan(sid: libc::pid_t) -> Option<Scan> {
+    let mut stopped = HashMap::new();
+    let mut groups = HashSet::new();
+    for process in session_processes(sid)? {
+        groups.insert(process.pgrp);
+        if process.state != 'T' {
+            continue;
+        }
+        if process.pid == process.pgrp {
+            stopped.insert(process.pgrp, process.comm);
+        } else {
+            stopped.entry(process.pgrp).or_insert(process.comm);
+        }
+    }
+    Some((stopped, groups))
+}
+
+// Runs until the session is over.
+pub(crate) async fn watch(control: JobControl, handle: SessionHandle, websocket_sender: Outbox) {
+    let sid = unsafe { libc::tcgetsid(handle.master.as_raw_fd()) };
+    if sid <= 0 {
+        warn!("no session to watch the jobs of in {}", handle);
+        return;
+    }
+    let mut ticker = tokio::time::interval(control.interval);
+    let mut stopped: HashMap<libc::pid_t, String> = HashMap::new();
+    loop {
+        ticker.tick().await;
+        let (now, groups) = match tokio::task::spawn_blocking(move || scan(sid)).await {
+            Ok(Some(scan)) => scan,
+            _ => {
+                warn!("failed to read the jobs of session {}", handle);
+                return;
+            }
+        };
+        let mut events = vec![];
+        for (&pgid, command) in now.iter() {
+            if !stopped.contains_key(&pgid) {
+                events.push((pgid, command.clone(), JobState::Stopped));
+            }
+        }
+        for (pgid, command) in stopped.drain() {
+            if !now.contains_key(&pgid) {
+                let state = if groups.contains(&pgid) {
+                    JobState::Continued
+                } else {
+                    JobState::Done
+                };
+                events.push((pgid, command, state));
+            }
+        }
+        for (pgid, command, state) in events {
+            let event = JobEvent {
+                pgid,
+                command,
+                state,
+            };
+            let frame = ServerMessage::Job(event).encode();
+            if websocket_sender.send(Message::Binary(frame)).is_err() {
+                return;
+            }
+        }
+        stopped = now;
+    }
+}
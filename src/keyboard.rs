@@ -0,0 +1,113 @@
+// Tracks the kitty keyboard protocol flags requested by the application
+// (https://sw.kovidgoyal.net/kitty/keyboard-protocol/). The sequences go
+// through untouched in raw output, but screen diffs lose them, so they are
+// kept aside for frame mode, and clients that encode keys themselves can
+// follow the flags through control frames.
+
+const MAX_STACK: usize = 16;
+const MAX_PARAMS: usize = 16;
+
+enum State {
+    Ground,
+    Escape,
+    // Prefix byte ('>', '<', '=' or '?') and parameters so far.
+    Csi(u8, Vec<u8>),
+    // Some other CSI sequence.
+    Ignore,
+}
+
+pub(crate) struct KeyboardModes {
+    state: State,
+    stack: Vec<u32>,
+    // Raw sequences seen since the last `take_sequences()`.
+    sequences: Vec<u8>,
+}
+
+impl Default for KeyboardModes {
+    fn default() -> Self {
+        KeyboardModes {
+            state: State::Ground,
+            stack: vec![],
+            sequences: vec![],
+        }
+    }
+}
+
+impl KeyboardModes {
+    pub(crate) fn flags(&self) -> u32 {
+        self.stack.last().copied().unwrap_or(0)
+    }
+
+    pub(crate) fn take_sequences(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.sequences)
+    }
+
+    pub(crate) fn process(&mut self, data: &[u8]) {
+        for &b in data {
+            self.state = match std::mem::replace(&mut self.state, State::Ground) {
+                State::Ground if b == 0x1b => State::Escape,
+                State::Ground => State::Ground,
+                State::Escape if b == b'[' => State::Csi(0, vec![]),
+                State::Escape if b == 0x1b => State::Escape,
+                State::Escape => State::Ground,
+                State::Csi(0, params) if params.is_empty() && b"><=?".contains(&b) => {
+                    State::Csi(b, params)
+                }
+                State::Csi(prefix, mut params) if b.is_ascii_digit() || b == b';' => {
+                    if params.len() < MAX_PARAMS {
+                        params.push(b);
+                        State::Csi(prefix, params)
+                    } else {
+                        State::Ignore
+                    }
+                }
+                State::Csi(prefix, params) if b == b'u' && prefix != 0 => {
+                    self.apply(prefix, &params);
+                    State::Ground
+                }
+                State::Csi(..) | State::Ignore if (0x40..0x7f).contains(&b) => State::Ground,
+                State::Csi(..) | State::Ignore => State::Ignore,
+            };
+        }
+    }
+
+    fn apply(&mut self, prefix: u8, params: &[u8]) {
+        let mut values = params.split(|&b| b == b';').map(|v| {
+            std::str::from_utf8(v)
+                .ok()
+                .and_then(|v| v.parse::<u32>().ok())
+        });
+        let first = values.next().flatten();
+        let second = values.next().flatten();
+        match prefix {
+            b'>' => {
+                if self.stack.len() == MAX_STACK {
+                    self.stack.remove(0);
+                }
+                self.stack.push(first.unwrap_or(0));
+            }
+            b'<' => {
+                let n = first.unwrap_or(1) as usize;
+                self.stack.truncate(self.stack.len().saturating_sub(n));
+            }
+            b'=' => {
+                let flags = first.unwrap_or(0);
+                if self.stack.is_empty() {
+                    self.stack.push(0);
+                }
+                let top = self.stack.last_mut().unwrap();
+                match second.unwrap_or(1) {
+                    2 => *top |= flags,
+                    3 => *top &= !flags,
+                    _ => *top = flags,
+                }
+            }
+            // Queries are for the client terminal to answer.
+            _ => (),
+        }
+        self.sequences.extend_from_slice(b"\x1b[");
+        self.sequences.push(prefix);
+        self.sequences.extend_from_slice(params);
+        self.sequences.push(b'u');
+    }
+}
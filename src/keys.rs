@@ -0,0 +1,269 @@
+// Ed25519 keys pinned between agents and their broker, so that someone on
+// the network path, or holding a leaked bearer token, can impersonate
+// neither. Each side sends a random nonce, the agent with its upgrade
+// requests and the broker in the response, then signs both along with the
+// device id and its role: the broker in the response headers, the agent in
+// a first text message once upgraded. A side only checks the other when it
+// has its key pinned, `AgentConfig::broker_key` and
+// `BrokerConfig::device_keys`. The sessions themselves are only as private
+// as the transport, use TLS between the broker and its clients.
+
+use crate::{AgentConfig, BrokerConfig};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tungstenite::handshake::client::{Request, Response};
+use tungstenite::handshake::server::Response as ServerResponse;
+use tungstenite::http::HeaderMap;
+use tungstenite::{Error as WsError, Message};
+
+const NONCE: &str = "x-wspty-nonce";
+const SIGNATURE: &str = "x-wspty-signature";
+const NONCE_LEN: usize = 32;
+// For the agent's proof.
+const PROOF_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The key pair of an agent or a broker.
+#[derive(Clone)]
+pub struct DeviceKey {
+    pair: Arc<Ed25519KeyPair>,
+}
+
+impl fmt::Debug for DeviceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeviceKey({})", self.public_key())
+    }
+}
+
+impl DeviceKey {
+    // A new key pair, along with its PKCS#8 document to store.
+    pub fn generate() -> Result<(Self, Vec<u8>), anyhow::Error> {
+        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow::anyhow!("failed to generate a key pair"))?;
+        let key = Self::from_pkcs8(document.as_ref())?;
+        Ok((key, document.as_ref().to_vec()))
+    }
+
+    pub fn from_pkcs8(document: &[u8]) -> Result<Self, anyhow::Error> {
+        let pair = Ed25519KeyPair::from_pkcs8(document)
+            .map_err(|e| anyhow::anyhow!("invalid key pair: {}", e))?;
+        Ok(DeviceKey {
+            pair: Arc::new(pair),
+        })
+    }
+
+    // Reads a PKCS#8 document as written from `generate`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        Self::from_pkcs8(&std::fs::read(path)?)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut key = [0u8; 32];
+        key.copy_from_slice(self.pair.public_key().as_ref());
+        PublicKey(key)
+    }
+
+    fn sign(&self, statement: &[u8]) -> String {
+        encode(self.pair.sign(statement).as_ref())
+    }
+}
+
+// Shown and parsed as 64 hex digits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn from_hex(hex: &str) -> Result<Self, anyhow::Error> {
+        let bytes = decode(hex).filter(|bytes| bytes.len() == 32);
+        let bytes = bytes.ok_or_else(|| anyhow::anyhow!("not an ed25519 public key: {}", hex))?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&bytes);
+        Ok(PublicKey(key))
+    }
+
+    fn verify(&self, statement: &[u8], signature: &str) -> bool {
+        decode(signature).is_some_and(|signature| {
+            UnparsedPublicKey::new(&ED25519, self.0)
+                .verify(statement, &signature)
+                .is_ok()
+        })
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", encode(&self.0))
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn nonce() -> Result<String, anyhow::Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("no randomness"))?;
+    Ok(encode(&nonce))
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// What a side signs, nonces in the order the agent and broker sent them.
+fn statement(role: &str, device: &str, agent_nonce: &str, broker_nonce: &str) -> Vec<u8> {
+    format!(
+        "wspty {}\0{}\0{}\0{}",
+        role, device, agent_nonce, broker_nonce
+    )
+    .into_bytes()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Proof {
+    signature: String,
+}
+
+// Adds the agent's nonce to an upgrade request, when there are keys.
+pub(crate) fn challenge_broker(
+    agent: &AgentConfig,
+    mut request: Request,
+) -> Result<(Request, Option<String>), anyhow::Error> {
+    if agent.key.is_none() && agent.broker_key.is_none() {
+        return Ok((request, None));
+    }
+    let nonce = nonce()?;
+    request.headers_mut().insert(NONCE, nonce.parse()?);
+    Ok((request, Some(nonce)))
+}
+
+// Checks the broker's signature if pinned, then proves the agent's key if
+// asked to. Runs before anything else goes over `ws`.
+pub(crate) async fn authenticate_broker<O>(
+    agent: &AgentConfig,
+    nonce: Option<String>,
+    response: &Response,
+    ws_outgoing: &mut O,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+{
+    let agent_nonce = match nonce {
+        Some(nonce) => nonce,
+        None => return Ok(()),
+    };
+    let broker_nonce = header(response.headers(), NONCE).unwrap_or_default();
+    if let Some(ref pinned) = agent.broker_key {
+        let signature = header(response.headers(), SIGNATURE).unwrap_or_default();
+        let statement = statement("broker", &agent.device_id, &agent_nonce, broker_nonce);
+        if broker_nonce.is_empty() || !pinned.verify(&statement, signature) {
+            anyhow::bail!("broker {} failed to prove its key", agent.broker);
+        }
+    }
+    if let (Some(key), false) = (agent.key.as_ref(), broker_nonce.is_empty()) {
+        let statement = statement("agent", &agent.device_id, &agent_nonce, broker_nonce);
+        let proof = serde_json::to_string(&Proof {
+            signature: key.sign(&statement),
+        })?;
+        ws_outgoing.send(Message::Text(proof)).await?;
+    }
+    Ok(())
+}
+
+// The nonces of an agent connection, for the broker to check the proof.
+pub(crate) struct Challenge {
+    device: String,
+    agent_nonce: String,
+    broker_nonce: String,
+}
+
+// Answers the agent's nonce in the upgrade response, signing it with the
+// broker key. Returns the challenge to check the agent's proof against when
+// the device has a pinned key, refusing agents that sent no nonce then.
+pub(crate) fn answer_agent(
+    config: &BrokerConfig,
+    device: &str,
+    request: &Request,
+    response: &mut ServerResponse,
+) -> Result<Option<Challenge>, String> {
+    let pinned = config
+        .device_keys
+        .as_ref()
+        .is_some_and(|keys| keys.contains_key(device));
+    if config.device_keys.is_some() && !pinned {
+        return Err(format!("no key pinned for {}", device));
+    }
+    let agent_nonce = match header(request.headers(), NONCE) {
+        Some(nonce) if decode(nonce).is_some_and(|nonce| nonce.len() == NONCE_LEN) => nonce,
+        Some(_) => return Err("invalid nonce".to_owned()),
+        None if pinned => return Err("device key required".to_owned()),
+        None => return Ok(None),
+    };
+    let broker_nonce = nonce().map_err(|e| e.to_string())?;
+    let headers = response.headers_mut();
+    headers.insert(NONCE, broker_nonce.parse().unwrap());
+    if let Some(ref key) = config.key {
+        let statement = statement("broker", device, agent_nonce, &broker_nonce);
+        headers.insert(SIGNATURE, key.sign(&statement).parse().unwrap());
+    }
+    Ok(pinned.then(|| Challenge {
+        device: device.to_owned(),
+        agent_nonce: agent_nonce.to_owned(),
+        broker_nonce,
+    }))
+}
+
+// Waits for the agent's proof of its pinned key.
+pub(crate) async fn authenticate_agent<I>(
+    config: &BrokerConfig,
+    challenge: Challenge,
+    ws_incoming: &mut I,
+) -> Result<(), anyhow::Error>
+where
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let device = challenge.device.as_str();
+    let pinned = match config
+        .device_keys
+        .as_ref()
+        .and_then(|keys| keys.get(device))
+    {
+        Some(pinned) => pinned,
+        None => anyhow::bail!("no key pinned for {}", device),
+    };
+    let proof = loop {
+        match tokio::time::timeout(PROOF_TIMEOUT, ws_incoming.next()).await {
+            Ok(Some(Ok(Message::Text(proof)))) => break proof,
+            Ok(Some(Ok(Message::Ping(_) | Message::Pong(_)))) => continue,
+            _ => anyhow::bail!("{} sent no proof of its key", device),
+        }
+    };
+    let proof: Proof = serde_json::from_str(&proof)?;
+    let statement = statement(
+        "agent",
+        device,
+        &challenge.agent_nonce,
+        &challenge.broker_nonce,
+    );
+    if !pinned.verify(&statement, &proof.signature) {
+        anyhow::bail!("{} failed to prove its key", device);
+    }
+    Ok(())
+}
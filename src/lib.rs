@@ -6,21 +6,216 @@ use std::fs::File;
 use std::io::{Error as IoError, ErrorKind, Read, Write};
 use std::os::unix::io::RawFd;
 use std::os::unix::prelude::*;
+use std::process::ExitStatus;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::io::unix::AsyncFd;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, Error, ReadBuf};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, Error, Interest, ReadBuf};
 use tokio::process::Command;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 
+mod accounting;
+mod agent;
+mod alerts;
+mod arbitration;
+mod auth;
+mod ban;
+mod bind;
+mod broker;
+#[cfg(feature = "challenge-auth")]
+mod challenge;
+mod client;
+mod clock;
+mod colors;
+mod commands;
+mod config;
+mod container;
+mod control;
+mod cpu;
+mod deflate;
+mod devices;
+mod dial;
+mod dump;
+mod env;
+mod events;
+mod expect;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod fragment;
+mod framing;
+mod gc;
+mod guard;
+mod handoff;
+mod handover;
+mod html;
+mod instrument;
+mod integrity;
+mod introspect;
+mod jobs;
+mod keyboard;
+#[cfg(feature = "device-keys")]
+mod keys;
+mod limit;
+mod lines;
+mod malformed;
+#[cfg(feature = "mdns")]
+mod mdns;
+mod memory;
+mod metrics;
+mod migrate;
+mod mirror;
+mod mux;
+mod notifications;
+mod outbox;
+mod panics;
+mod persist;
+mod pipe;
+mod plain;
+mod policy;
+mod pool;
+mod power;
+mod presence;
+mod preset;
+mod probes;
+mod procfs;
+mod profiles;
+mod prompt;
+mod proxy;
+#[cfg(feature = "quic")]
+mod quic;
+mod quota;
+mod ratelimit;
+mod recording;
+mod render;
+mod repeats;
+mod retention;
+mod rlimit;
+#[cfg(feature = "resume-routing")]
+mod routing;
+mod sanitize;
+mod scrollback;
+mod selftest;
+mod sentinel;
+mod sequencer;
 mod server;
+mod session;
+mod sharing;
+mod sizing;
+mod spawn;
+mod ssh;
+mod teardown;
+mod theme;
+mod timeline;
+mod timeout;
+#[cfg(feature = "tls")]
+mod tls;
+mod toggles;
+mod tokens;
+mod trace;
+mod transfer;
+mod tunnel;
+mod ui;
+#[cfg(feature = "io-uring")]
+mod uring;
+mod usage;
+mod user;
+mod utf8;
+mod validate;
+mod vt;
+mod workspace;
 
-pub use server::start_server;
+pub use accounting::{BandwidthCaps, BandwidthLedger, TenantUsage};
+pub use agent::AgentConfig;
+pub use alerts::{Alert, AlertKind, Alerts};
+pub use auth::{Authenticator, Listener, QueryOverrides, TokenValidator, UpgradeRequest};
+pub use ban::BanList;
+pub use bind::BindOptions;
+pub use broker::{start_broker, AgentValidator, BrokerConfig, DeviceAccess};
+#[cfg(feature = "challenge-auth")]
+pub use challenge::ChallengeAuth;
+pub use client::WsPtyClient;
+pub use clock::{Clock, Sleep, TokioClock};
+pub use colors::ColorLevel;
+pub use commands::{CommandMetrics, CommandRecord, CommandStats};
+pub use config::{Profile, ServerConfig, UNIX_PEER};
+pub use container::{ContainerAccess, ContainerExec, Runtime};
+pub use control::ControlChannels;
+pub use cpu::CpuBudget;
+pub use deflate::OutputCompression;
+pub use dial::Dialer;
+pub use dump::{DumpFilter, FrameDumps};
+pub use events::{EventHandler, SessionError};
+pub use expect::OutputPattern;
+#[cfg(feature = "fault-injection")]
+pub use faults::Faults;
+pub use fragment::MessageLimits;
+pub use gc::{GarbageCollection, Orphan, OrphanKind, OrphanPolicy, ReapHook};
+pub use guard::DropPolicy;
+pub use handoff::HandoffPolicy;
+pub use html::recording_html;
+pub use instrument::Throughput;
+pub use jobs::JobControl;
+#[cfg(feature = "device-keys")]
+pub use keys::{DeviceKey, PublicKey};
+pub use limit::{PeerLimit, SessionLimit};
+pub use malformed::{MalformedAction, MalformedPolicy};
+#[cfg(feature = "mdns")]
+pub use mdns::MdnsConfig;
+pub use memory::{MemoryBudget, MemoryUsage};
+pub use metrics::{metrics, Metrics};
+pub use mirror::MirrorConfig;
+pub use persist::Persistence;
+pub use policy::{
+    AllowedCommand, Allowlist, CommandPolicy, EnvironmentPolicy, FallbackShell, SpawnPolicy,
+};
+pub use pool::SessionPool;
+pub use power::LowPower;
+pub use preset::SecurityPreset;
+pub use probes::Probe;
+pub use proxy::ProxyRoute;
+#[cfg(feature = "quic")]
+pub use quic::QuicConfig;
+pub use quota::Quota;
+pub use ratelimit::{MemoryRateLimiter, RateLimit, RateLimiter, RedisRateLimiter};
+pub use recording::{RecordingConfig, RecordingFormat};
+pub use render::RenderHints;
+pub use repeats::RepeatLimit;
+pub use retention::{Purge, PurgeHook, PurgeReason, Retention};
+pub use rlimit::ResourceLimits;
+#[cfg(feature = "resume-routing")]
+pub use routing::ResumeRouting;
+pub use selftest::self_test;
+pub use server::{serve_pty, start_server, start_server_with_config, Server, ServerHandle};
+pub use session::{Attachment, Session, SessionHandle, SessionIdGenerator, SpawnInfo};
+pub use sharing::{Sharing, SharingPolicy};
+pub use sizing::SizePolicy;
+pub use ssh::SshRoute;
+pub use teardown::Teardown;
+pub use theme::Theme;
+pub use timeline::{TimelineEntry, TimelineEvent};
+pub use timeout::Timeouts;
+#[cfg(feature = "tls")]
+pub use tls::{AlpnAcceptor, TlsConfig, TlsStream, HTTP_1_1};
+pub use toggles::{Feature, TogglePolicy};
+pub use tokens::{Capabilities, TokenClaims, TokenPolicy};
+pub use transfer::{FileTransfer, TransferPolicy};
+pub use tunnel::{Credentials, Tunnel};
+pub use ui::{PageHook, UiConfig, UiRequest};
+#[cfg(feature = "io-uring")]
+pub use uring::UringReader;
+pub use usage::{AccountingExport, SessionRecord};
+pub use user::UserMapping;
+pub use validate::ConfigProblem;
+pub use workspace::{Mount, Workspace};
+pub use wspty_proto as proto;
 
 pub struct PtyMaster {
     inner: Arc<AsyncFd<File>>,
     closed: Arc<AtomicBool>,
     slave: Option<File>,
+    exit: watch::Receiver<Option<ExitStatus>>,
+    // For ptys of a `PtyCommand`, see the `guard` module.
+    guard: Option<Arc<guard::Guard>>,
 }
 
 impl Clone for PtyMaster {
@@ -29,102 +224,329 @@ impl Clone for PtyMaster {
             inner: self.inner.clone(),
             closed: self.closed.clone(),
             slave: self.slave.as_ref().map(|s| s.try_clone().unwrap()),
+            exit: self.exit.clone(),
+            guard: self.guard.clone(),
         }
     }
 }
 
 impl PtyMaster {
     pub fn new() -> Result<Self, IoError> {
-        let inner = unsafe {
-            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        Self::from_file(open_master()?)
+    }
 
-            if fd < 0 {
-                return Err(IoError::last_os_error());
-            }
+    // Same as `new()`, with the blocking syscalls (grantpt notably) run on
+    // the blocking thread pool instead of a reactor thread.
+    pub async fn open() -> Result<Self, IoError> {
+        Self::from_file(blocking(open_master).await?)
+    }
 
-            if libc::grantpt(fd) != 0 {
-                return Err(IoError::last_os_error());
-            }
+    fn from_file(file: File) -> Result<Self, IoError> {
+        Ok(PtyMaster {
+            inner: Arc::new(AsyncFd::new(file)?),
+            closed: Arc::new(AtomicBool::new(false)),
+            slave: None,
+            exit: watch::channel(None).1,
+            guard: None,
+        })
+    }
 
-            if libc::unlockpt(fd) != 0 {
-                return Err(IoError::last_os_error());
-            }
+    // Lets the child spawned by `PtyCommand::run` outlive every handle and
+    // the stopper, whatever the `DropPolicy`. Sending on the stopper still
+    // stops it.
+    pub fn detach(&self) {
+        if let Some(ref guard) = self.guard {
+            guard.detach();
+        }
+    }
+
+    // Stops the child spawned by `PtyCommand::run` as its stopper would,
+    // detached or not.
+    pub fn kill(&self) {
+        if let Some(ref guard) = self.guard {
+            guard.kill();
+        }
+    }
+
+    // Waits for the child spawned by `PtyCommand::run` to exit. `None` for
+    // ptys without a child of ours, e.g. adopted from another server.
+    pub async fn exit_status(&self) -> Option<ExitStatus> {
+        let mut exit = self.exit.clone();
+        let status = exit.wait_for(Option::is_some).await.ok()?;
+        *status
+    }
 
-            let flags = libc::fcntl(fd, libc::F_GETFL, 0);
-            if flags < 0 {
+    // The same, without holding on to the pty.
+    pub(crate) fn on_exit(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut exit = self.exit.clone();
+        async move {
+            let _ = exit.wait_for(Option::is_some).await;
+        }
+    }
+
+    // Sends `signal` to the foreground process group of the terminal, the
+    // child's own when nothing else runs in the foreground.
+    pub fn send_signal(&self, signal: libc::c_int) -> Result<(), IoError> {
+        unsafe {
+            let pgrp = libc::tcgetpgrp(self.as_raw_fd());
+            if pgrp <= 0 || libc::killpg(pgrp, signal) != 0 {
                 return Err(IoError::last_os_error());
             }
+        }
+        Ok(())
+    }
 
-            if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
-                log::warn!("fnctl F_SETFL O_NONBLOCK failed");
-            }
+    // Whether Ctrl-S and Ctrl-Q stop and restart the output (IXON).
+    pub(crate) fn flow_control(&self) -> Result<bool, IoError> {
+        let mut flow_control = false;
+        update_termios(self.as_raw_fd(), |termios| {
+            flow_control = termios.c_iflag & libc::IXON != 0;
+            false
+        })?;
+        Ok(flow_control)
+    }
 
-            AsyncFd::new(std::fs::File::from_raw_fd(fd))?
-        };
-        Ok(PtyMaster {
-            inner: Arc::new(inner),
-            closed: Arc::new(AtomicBool::new(false)),
-            slave: None,
+    pub(crate) fn set_flow_control(&self, enabled: bool) -> Result<(), IoError> {
+        update_termios(self.as_raw_fd(), |termios| {
+            if enabled {
+                termios.c_iflag |= libc::IXON;
+            } else {
+                termios.c_iflag &= !libc::IXON;
+            }
+            true
         })
     }
 
-    #[cfg(target_os = "macos")]
     pub fn open_sync_pty_slave(&mut self) -> Result<File, IoError> {
-        let fd = self.as_raw_fd();
-        let buf = unsafe { libc::ptsname(fd) };
-        if buf.is_null() {
-            return Err(IoError::last_os_error());
+        let slave = open_slave(self.as_raw_fd())?;
+        self.slave.replace(slave.try_clone()?);
+        Ok(slave)
+    }
+
+    pub async fn open_pty_slave(&mut self) -> Result<File, IoError> {
+        // The closure keeps the master fd alive even if we get cancelled.
+        let inner = self.inner.clone();
+        let slave = blocking(move || open_slave(inner.get_ref().as_raw_fd())).await?;
+        self.slave.replace(slave.try_clone()?);
+        Ok(slave)
+    }
+
+    pub fn resize(&mut self, cols: libc::c_ushort, lines: libc::c_ushort) -> Result<(), IoError> {
+        self.resize_with_pixels(cols, lines, 0, 0)
+    }
+
+    // The kernel sends SIGWINCH to the foreground process group for us.
+    pub fn resize_with_pixels(
+        &self,
+        cols: libc::c_ushort,
+        lines: libc::c_ushort,
+        xpixel: libc::c_ushort,
+        ypixel: libc::c_ushort,
+    ) -> Result<(), IoError> {
+        set_window_size(self.as_raw_fd(), cols, lines, xpixel, ypixel)
+    }
+
+    // Reads what the child wrote without waiting, `WouldBlock` if there is
+    // nothing yet. With `poll_read_ready` or `readable` to wait, for event
+    // loops driving the pty themselves rather than through `AsyncRead`, both
+    // directions from the same task. Once shut down, what is left is read
+    // without waiting and then 0, as for `AsyncRead`.
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if self.closed.load(core::sync::atomic::Ordering::SeqCst) {
+            return self.drain(buf);
         }
-        let ptsname = OsStr::from_bytes(unsafe { CStr::from_ptr(buf as _) }.to_bytes());
-        match std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(ptsname)
-        {
-            Ok(slave) => {
-                self.slave.replace(slave.try_clone().unwrap());
-                Ok(slave)
+        let n = self
+            .inner
+            .try_io(Interest::READABLE, |mut file| file.read(buf))?;
+        // Shut down meanwhile, the read may have got the wake up byte.
+        if self.closed.load(core::sync::atomic::Ordering::SeqCst) {
+            return Ok(unwake(&buf[..n]));
+        }
+        Ok(n)
+    }
+
+    // What the child wrote before it exited, which shutting down must not
+    // lose, without the byte `poll_shutdown` wrote to wake the reader up.
+    pub(crate) fn drain(&self, buf: &mut [u8]) -> Result<usize, IoError> {
+        match self.inner.get_ref().read(buf) {
+            Ok(n) => Ok(unwake(&buf[..n])),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.raw_os_error() == Some(libc::EIO) => {
+                Ok(0)
             }
             Err(e) => Err(e),
         }
     }
 
-    #[cfg(not(target_os = "macos"))]
-    pub fn open_sync_pty_slave(&mut self) -> Result<File, IoError> {
-        let mut buf: [libc::c_char; 512] = [0; 512];
-        let fd = self.as_raw_fd();
+    // Same thing for writing, `WouldBlock` when the pty buffer is full.
+    pub fn try_write(&self, buf: &[u8]) -> Result<usize, IoError> {
+        self.inner
+            .try_io(Interest::WRITABLE, |mut file| file.write(buf))
+    }
+
+    // Ready once `try_read` may find something, it can still get
+    // `WouldBlock` though.
+    pub fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        self.inner.poll_read_ready(cx).map_ok(|_| ())
+    }
+
+    pub fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        self.inner.poll_write_ready(cx).map_ok(|_| ())
+    }
+
+    pub async fn readable(&self) -> Result<(), IoError> {
+        self.inner.readable().await.map(|_| ())
+    }
+
+    pub async fn writable(&self) -> Result<(), IoError> {
+        self.inner.writable().await.map(|_| ())
+    }
+
+    pub async fn resize_async(
+        &self,
+        cols: libc::c_ushort,
+        lines: libc::c_ushort,
+        xpixel: libc::c_ushort,
+        ypixel: libc::c_ushort,
+    ) -> Result<(), IoError> {
+        let inner = self.inner.clone();
+        blocking(move || set_window_size(inner.get_ref().as_raw_fd(), cols, lines, xpixel, ypixel))
+            .await
+    }
+}
+
+// The length of `data` without the trailing byte that `poll_shutdown`
+// writes.
+fn unwake(data: &[u8]) -> usize {
+    match data.last() {
+        Some(0) => data.len() - 1,
+        _ => data.len(),
+    }
+}
 
-        if unsafe { libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) } != 0 {
+async fn blocking<F, T>(f: F) -> Result<T, IoError>
+where
+    F: FnOnce() -> Result<T, IoError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(IoError::other)?
+}
+
+fn open_master() -> Result<File, IoError> {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+
+        if fd < 0 {
+            let e = IoError::last_os_error();
+            if is_pty_exhausted(&e) {
+                metrics::pty_exhausted();
+            }
+            return Err(e);
+        }
+        let file = File::from_raw_fd(fd);
+
+        // Keep the master out of other sessions' children.
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
             return Err(IoError::last_os_error());
         }
 
-        let ptsname = OsStr::from_bytes(unsafe { CStr::from_ptr(&buf as _) }.to_bytes());
-        match std::fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(ptsname)
-        {
-            Ok(slave) => {
-                self.slave.replace(slave.try_clone().unwrap());
-                Ok(slave)
-            }
-            Err(e) => Err(e),
+        if libc::grantpt(fd) != 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        if libc::unlockpt(fd) != 0 {
+            return Err(IoError::last_os_error());
         }
+
+        let flags = libc::fcntl(fd, libc::F_GETFL, 0);
+        if flags < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+            log::warn!("fnctl F_SETFL O_NONBLOCK failed");
+        }
+
+        Ok(file)
     }
+}
 
-    pub fn resize(&mut self, cols: libc::c_ushort, lines: libc::c_ushort) -> Result<(), IoError> {
-        let fd = self.as_raw_fd();
-        let winsz = libc::winsize {
-            ws_row: lines,
-            ws_col: cols,
-            ws_xpixel: 0,
-            ws_ypixel: 0,
-        };
-        if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsz) } != 0 {
+// What posix_openpt fails with once all the pty devices are in use
+// (kernel.pty.max on Linux).
+pub(crate) fn is_pty_exhausted(e: &IoError) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::ENOSPC))
+}
+
+#[cfg(target_os = "macos")]
+fn open_slave(fd: RawFd) -> Result<File, IoError> {
+    let buf = unsafe { libc::ptsname(fd) };
+    if buf.is_null() {
+        return Err(IoError::last_os_error());
+    }
+    let ptsname = OsStr::from_bytes(unsafe { CStr::from_ptr(buf as _) }.to_bytes());
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(ptsname)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn open_slave(fd: RawFd) -> Result<File, IoError> {
+    let mut buf: [libc::c_char; 512] = [0; 512];
+
+    if unsafe { libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) } != 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    let ptsname = OsStr::from_bytes(unsafe { CStr::from_ptr(&buf as _) }.to_bytes());
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(ptsname)
+}
+
+fn set_window_size(
+    fd: RawFd,
+    cols: libc::c_ushort,
+    lines: libc::c_ushort,
+    xpixel: libc::c_ushort,
+    ypixel: libc::c_ushort,
+) -> Result<(), IoError> {
+    let winsz = libc::winsize {
+        ws_row: lines,
+        ws_col: cols,
+        ws_xpixel: xpixel,
+        ws_ypixel: ypixel,
+    };
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &winsz) } != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+fn set_tostop(fd: RawFd) -> Result<(), IoError> {
+    update_termios(fd, |termios| {
+        termios.c_lflag |= libc::TOSTOP;
+        true
+    })
+}
+
+// Sets the terminal attributes `update` returns true for.
+fn update_termios<F>(fd: RawFd, update: F) -> Result<(), IoError>
+where
+    F: FnOnce(&mut libc::termios) -> bool,
+{
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(IoError::last_os_error());
+        }
+        if update(&mut termios) && libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
             return Err(IoError::last_os_error());
         }
-        Ok(())
     }
+    Ok(())
 }
 
 impl AsRawFd for PtyMaster {
@@ -143,16 +565,14 @@ impl AsyncRead for PtyMaster {
             unsafe { &mut *(buf.unfilled_mut() as *mut [std::mem::MaybeUninit<u8>] as *mut [u8]) };
         loop {
             let closed = self.closed.load(core::sync::atomic::Ordering::SeqCst);
-            if closed {
-                return Poll::Ready(Ok(()));
+            if !closed {
+                match self.poll_read_ready(cx) {
+                    Poll::Ready(Ok(())) => (),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
             }
-            let mut g = match self.inner.poll_read_ready(cx) {
-                Poll::Ready(Ok(g)) => g,
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Pending => return Poll::Pending,
-            };
-            let f = self.inner.clone();
-            match f.get_ref().read(b) {
+            match self.try_read(b) {
                 Ok(s) => {
                     if s.gt(&0) {
                         unsafe {
@@ -162,14 +582,8 @@ impl AsyncRead for PtyMaster {
                     }
                     return Poll::Ready(Ok(()));
                 }
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {
-                        g.clear_ready();
-                    }
-                    _ => {
-                        return Poll::Ready(Err(e));
-                    }
-                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Poll::Ready(Err(e)),
             }
         }
     }
@@ -182,20 +596,14 @@ impl AsyncWrite for PtyMaster {
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
         loop {
-            let mut g = match self.inner.poll_write_ready(cx) {
-                Poll::Ready(Ok(g)) => g,
+            match self.poll_write_ready(cx) {
+                Poll::Ready(Ok(())) => (),
                 Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                 Poll::Pending => return Poll::Pending,
-            };
-            let f = self.inner.clone();
-            match f.get_ref().write(buf) {
-                Ok(s) => return Poll::Ready(Ok(s)),
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {
-                        g.clear_ready();
-                    }
-                    _ => return Poll::Ready(Err(e)),
-                },
+            }
+            match self.try_write(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                res => return Poll::Ready(res),
             }
         }
     }
@@ -223,9 +631,11 @@ impl AsyncWrite for PtyMaster {
     fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
         use core::sync::atomic::Ordering;
 
-        if let Ok(true) =
-            self.closed
-                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        // Already shut down.
+        if self
+            .closed
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
         {
             return Poll::Ready(Ok(()));
         }
@@ -237,28 +647,199 @@ impl AsyncWrite for PtyMaster {
     }
 }
 
+// Run between fork and exec, see `PtyCommand::pre_exec`.
+type Hook = Box<dyn FnMut() -> Result<(), IoError> + Send + Sync>;
+
+// A command to run in a new pty, built from a `Command` or a program. The
+// child gets the pty as its stdio and, by default, as the controlling
+// terminal of a new session.
 pub struct PtyCommand {
     inner: Command,
+    close_fds: bool,
+    keep_fds: Vec<RawFd>,
+    tostop: bool,
+    account: Option<user::Account>,
+    size: Option<(u16, u16)>,
+    controlling_tty: bool,
+    hooks: Vec<Hook>,
+    stop_signal: Option<libc::c_int>,
+    on_drop: DropPolicy,
 }
 
 impl From<Command> for PtyCommand {
     fn from(c: Command) -> Self {
-        PtyCommand { inner: c }
+        PtyCommand {
+            inner: c,
+            close_fds: true,
+            keep_fds: vec![],
+            tostop: false,
+            account: None,
+            size: None,
+            controlling_tty: true,
+            hooks: vec![],
+            stop_signal: Some(libc::SIGKILL),
+            on_drop: DropPolicy::default(),
+        }
     }
 }
 
+// How long children get to exit after a `PtyCommand::stop_signal` other
+// than SIGKILL, before getting SIGKILL.
+const STOP_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
 impl PtyCommand {
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Command::new(program).into()
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.inner.arg(arg);
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.inner.args(args);
+        self
+    }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, value: V) -> &mut Self {
+        self.inner.env(key, value);
+        self
+    }
+
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.inner.envs(vars);
+        self
+    }
+
+    pub fn current_dir<P: AsRef<std::path::Path>>(&mut self, dir: P) -> &mut Self {
+        self.inner.current_dir(dir);
+        self
+    }
+
+    // For what the other methods don't cover. Its stdio is replaced by the
+    // pty.
+    pub fn command_mut(&mut self) -> &mut Command {
+        &mut self.inner
+    }
+
+    // By default every fd besides stdio is closed on exec, whether or not it
+    // was opened with O_CLOEXEC, so children can't reach the listener or
+    // other sessions' ptys.
+    pub fn close_fds(&mut self, close: bool) -> &mut Self {
+        self.close_fds = close;
+        self
+    }
+
+    // Lets `fd` through to the child even when closing fds.
+    pub fn keep_fd(&mut self, fd: RawFd) -> &mut Self {
+        self.keep_fds.push(fd);
+        self
+    }
+
+    // Background jobs writing to the terminal get SIGTTOU, as after `stty
+    // tostop`.
+    pub fn tostop(&mut self, tostop: bool) -> &mut Self {
+        self.tostop = tostop;
+        self
+    }
+
+    // Runs the child as the `user` account, with its `HOME`, `USER`,
+    // `LOGNAME` and `SHELL`, see the `user` module. The server needs to be
+    // root for that.
+    pub fn user(&mut self, user: &str) -> Result<&mut Self, IoError> {
+        let account = user::Account::lookup(user)?;
+        account.env(&mut self.inner);
+        Ok(self.run_as(account))
+    }
+
+    // The child switches to `account` before exec, and gets the pty.
+    pub(crate) fn run_as(&mut self, account: user::Account) -> &mut Self {
+        self.account = Some(account);
+        self
+    }
+
+    // Size of the terminal as the child starts, 0x0 otherwise.
+    pub fn size(&mut self, cols: u16, rows: u16) -> &mut Self {
+        self.size = Some((cols, rows));
+        self
+    }
+
+    // Whether the pty becomes the controlling terminal of the child's
+    // session. Without it the child gets no SIGINT from ^C, no SIGHUP when
+    // the pty closes and `PtyMaster::send_signal` has no foreground process
+    // group to signal, but it still gets a session of its own.
+    pub fn controlling_tty(&mut self, controlling_tty: bool) -> &mut Self {
+        self.controlling_tty = controlling_tty;
+        self
+    }
+
+    /// Runs `hook` in the child once the pty is set up, before switching
+    /// users and exec. Hooks run in the order they were added.
+    ///
+    /// # Safety
+    ///
+    /// As for `Command::pre_exec`: `hook` runs in a forked copy of a
+    /// multithreaded process, so it must not allocate or take locks.
+    pub unsafe fn pre_exec<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: FnMut() -> Result<(), IoError> + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    // Sent to the child when the stopper given to `run` fires or is
+    // dropped, followed by SIGKILL if it is still running after 5 seconds.
+    // SIGKILL by default, `None` leaves the child running.
+    pub fn stop_signal(&mut self, signal: Option<libc::c_int>) -> &mut Self {
+        self.stop_signal = signal;
+        self
+    }
+
+    // What becomes of the child once the last handle on its pty is dropped,
+    // killed by default.
+    pub fn on_drop(&mut self, policy: DropPolicy) -> &mut Self {
+        self.on_drop = policy;
+        self
+    }
+
     pub async fn run(
         &mut self,
         mut stopper: mpsc::UnboundedReceiver<()>,
     ) -> Result<PtyMaster, IoError> {
-        let mut pty_master = PtyMaster::new()?;
-        let slave = pty_master.open_sync_pty_slave()?;
+        let mut pty_master = PtyMaster::open().await?;
+        let slave = pty_master.open_pty_slave().await?;
+        if self.tostop {
+            set_tostop(slave.as_raw_fd())?;
+        }
+        if let Some(ref account) = self.account {
+            nix::unistd::fchown(slave.as_raw_fd(), Some(account.uid), None)?;
+        }
+        if let Some((cols, rows)) = self.size {
+            pty_master.resize(cols, rows)?;
+        }
         self.inner
             .stdin(slave.try_clone().unwrap())
             .stdout(slave.try_clone().unwrap())
             .stderr(slave.try_clone().unwrap());
         let master_fd = pty_master.as_raw_fd();
+        let close_fds = self.close_fds;
+        let mut keep_fds = self.keep_fds.clone();
+        keep_fds.sort_unstable();
+        let max_fd = max_fd();
+        let account = self.account.clone();
+        let controlling_tty = self.controlling_tty;
+        let mut hooks = std::mem::take(&mut self.hooks);
         unsafe {
             self.inner.pre_exec(move || {
                 if libc::close(master_fd) != 0 {
@@ -269,27 +850,52 @@ impl PtyCommand {
                     return Err(IoError::last_os_error());
                 }
 
-                if libc::ioctl(0, libc::TIOCSCTTY as _, 1) != 0 {
+                if controlling_tty && libc::ioctl(0, libc::TIOCSCTTY as _, 1) != 0 {
                     return Err(IoError::last_os_error());
                 }
+
+                if close_fds {
+                    cloexec_fds(&keep_fds, max_fd)?;
+                }
+
+                for hook in hooks.iter_mut() {
+                    hook()?;
+                }
+
+                // Last, nothing above would be allowed anymore.
+                if let Some(ref account) = account {
+                    account.switch()?;
+                }
                 Ok(())
             });
         }
 
         let mut child = self.inner.spawn()?;
+        let stop_signal = self.stop_signal;
+        let (exit_sender, exit) = watch::channel(None);
+        let (guard, orphan) = guard::Guard::new(self.on_drop, child.id(), exit.clone());
+        pty_master.exit = exit;
+        // The watcher's handle doesn't count.
         let mut master_cl = pty_master.clone();
+        pty_master.guard = Some(Arc::new(guard));
         let fut = async move {
-            tokio::select! {
-                _exit_st = child.wait() => (),
-                _ = stopper.recv() => {
-                    let _ = child.start_kill().map_err(|e| {
-                        error!("failed to kill pty child: {:?}", e);
-                    });
-                    let _ = child.wait().await.map_err(|e| {
-                        error!("kill wait pty child error: {:?}", e);
-                    });
+            let status = tokio::select! {
+                status = child.wait() => status,
+                request = stopper.recv() => {
+                    if request.is_none() && orphan.detached() {
+                        child.wait().await
+                    } else {
+                        stop(&mut child, stop_signal).await
+                    }
                 },
-            };
+                _ = orphan.stop.notified() => stop(&mut child, stop_signal).await,
+            }
+            .inspect_err(|e| {
+                error!("kill wait pty child error: {:?}", e);
+            });
+            if let Ok(status) = status {
+                exit_sender.send_replace(Some(status));
+            }
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             master_cl.shutdown().await?;
             Ok::<(), anyhow::Error>(())
@@ -298,3 +904,83 @@ impl PtyCommand {
         Ok(pty_master)
     }
 }
+
+async fn stop(
+    child: &mut tokio::process::Child,
+    signal: Option<libc::c_int>,
+) -> Result<ExitStatus, IoError> {
+    match signal {
+        None => return child.wait().await,
+        Some(libc::SIGKILL) => (),
+        Some(signal) => {
+            if let Some(pid) = child.id() {
+                unsafe {
+                    libc::kill(pid as libc::pid_t, signal);
+                }
+            }
+            if let Ok(status) = tokio::time::timeout(STOP_GRACE, child.wait()).await {
+                return status;
+            }
+        }
+    }
+    let _ = child.start_kill().map_err(|e| {
+        error!("failed to kill pty child: {:?}", e);
+    });
+    child.wait().await
+}
+
+fn max_fd() -> RawFd {
+    match unsafe { libc::sysconf(libc::_SC_OPEN_MAX) } {
+        n if n > 0 => n.min(RawFd::MAX as _) as RawFd,
+        _ => 1024,
+    }
+}
+
+// Marks every fd from 3 up as close-on-exec, except the sorted `keep` ones
+// which get the flag cleared. Marking rather than closing leaves the pipe
+// std uses to report exec errors alone. Runs between fork and exec, so it
+// must not allocate.
+fn cloexec_fds(keep: &[RawFd], max_fd: RawFd) -> Result<(), IoError> {
+    let mut first = 3;
+    for &fd in keep.iter().filter(|&&fd| fd >= 3) {
+        if fd >= first {
+            cloexec_range(first, fd - 1, max_fd);
+        }
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) != 0 {
+                return Err(IoError::last_os_error());
+            }
+        }
+        first = fd + 1;
+    }
+    cloexec_range(first, RawFd::MAX, max_fd);
+    Ok(())
+}
+
+fn cloexec_range(first: RawFd, last: RawFd, max_fd: RawFd) {
+    if first > last {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    unsafe {
+        if libc::syscall(
+            libc::SYS_close_range,
+            first as libc::c_uint,
+            last as libc::c_uint,
+            libc::CLOSE_RANGE_CLOEXEC,
+        ) == 0
+        {
+            return;
+        }
+    }
+    // Older kernels and other unixes.
+    for fd in first..=last.min(max_fd) {
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags >= 0 {
+                libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC);
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, watch};
+
+struct Waiter {
+    ticket: u64,
+    position: watch::Sender<usize>,
+    admit: oneshot::Sender<Slot>,
+}
+
+struct Limit {
+    max: usize,
+    queue: bool,
+    active: usize,
+    waiting: VecDeque<Waiter>,
+    next_ticket: u64,
+}
+
+// Caps the number of concurrent sessions. Once full, new clients are
+// turned away or, with `queue`, kept waiting in arrival order and told
+// their position with `proto::QueuePosition` updates.
+#[derive(Clone)]
+pub struct SessionLimit {
+    inner: Arc<Mutex<Limit>>,
+}
+
+pub(crate) enum Admission {
+    Admitted(Slot),
+    Queued(Ticket),
+    Full,
+}
+
+// Held for the lifetime of an admitted session.
+pub(crate) struct Slot {
+    limit: SessionLimit,
+}
+
+pub(crate) struct Ticket {
+    limit: SessionLimit,
+    ticket: u64,
+    // 1 for the next client to be admitted.
+    pub(crate) position: watch::Receiver<usize>,
+    pub(crate) admit: oneshot::Receiver<Slot>,
+}
+
+impl SessionLimit {
+    pub fn new(max: usize, queue: bool) -> Self {
+        SessionLimit {
+            inner: Arc::new(Mutex::new(Limit {
+                max,
+                queue,
+                active: 0,
+                waiting: VecDeque::new(),
+                next_ticket: 0,
+            })),
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.inner.lock().unwrap().active
+    }
+
+    // The maximum, and whether clients over it are queued.
+    pub(crate) fn max(&self) -> (usize, bool) {
+        let limit = self.inner.lock().unwrap();
+        (limit.max, limit.queue)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.inner.lock().unwrap().waiting.len()
+    }
+
+    // Whether a new client would be turned away right now.
+    pub(crate) fn rejects(&self) -> bool {
+        let limit = self.inner.lock().unwrap();
+        !limit.queue && limit.active >= limit.max
+    }
+
+    pub(crate) fn acquire(&self) -> Admission {
+        let mut limit = self.inner.lock().unwrap();
+        if limit.active < limit.max && limit.waiting.is_empty() {
+            limit.active += 1;
+            return Admission::Admitted(Slot {
+                limit: self.clone(),
+            });
+        }
+        if !limit.queue {
+            return Admission::Full;
+        }
+        let ticket = limit.next_ticket;
+        limit.next_ticket += 1;
+        let (position, position_receiver) = watch::channel(limit.waiting.len() + 1);
+        let (admit, admit_receiver) = oneshot::channel();
+        limit.waiting.push_back(Waiter {
+            ticket,
+            position,
+            admit,
+        });
+        Admission::Queued(Ticket {
+            limit: self.clone(),
+            ticket,
+            position: position_receiver,
+            admit: admit_receiver,
+        })
+    }
+
+    // Admits waiting clients while there is room. Returns the slots of
+    // clients that went away meanwhile, to be dropped without the lock.
+    fn promote(&self, limit: &mut Limit) -> Vec<Slot> {
+        let mut unclaimed = vec![];
+        while limit.active < limit.max {
+            let waiter = match limit.waiting.pop_front() {
+                Some(waiter) => waiter,
+                None => break,
+            };
+            limit.active += 1;
+            let slot = Slot {
+                limit: self.clone(),
+            };
+            if let Err(slot) = waiter.admit.send(slot) {
+                unclaimed.push(slot);
+            }
+        }
+        for (index, waiter) in limit.waiting.iter().enumerate() {
+            waiter.position.send_if_modified(|position| {
+                let changed = *position != index + 1;
+                *position = index + 1;
+                changed
+            });
+        }
+        unclaimed
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let unclaimed = {
+            let mut limit = self.limit.inner.lock().unwrap();
+            limit.active -= 1;
+            self.limit.promote(&mut limit)
+        };
+        drop(unclaimed);
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let unclaimed = {
+            let mut limit = self.limit.inner.lock().unwrap();
+            limit.waiting.retain(|waiter| waiter.ticket != self.ticket);
+            self.limit.promote(&mut limit)
+        };
+        drop(unclaimed);
+    }
+}
+
+// Caps the number of concurrent sessions per client IP address, clients
+// over it are turned away. Unix socket clients all count as `UNIX_PEER`.
+#[derive(Clone)]
+pub struct PeerLimit {
+    max: usize,
+    active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+pub(crate) struct PeerSlot {
+    limit: PeerLimit,
+    ip: IpAddr,
+}
+
+impl PeerLimit {
+    pub fn new(max: usize) -> Self {
+        PeerLimit {
+            max,
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn active(&self, ip: IpAddr) -> usize {
+        self.active.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+
+    pub(crate) fn rejects(&self, ip: IpAddr) -> bool {
+        self.active(ip) >= self.max
+    }
+
+    pub(crate) fn acquire(&self, ip: IpAddr) -> Option<PeerSlot> {
+        let mut active = self.active.lock().unwrap();
+        if active.get(&ip).copied().unwrap_or(0) >= self.max {
+            return None;
+        }
+        *active.entry(ip).or_insert(0) += 1;
+        Some(PeerSlot {
+            limit: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for PeerSlot {
+    fn drop(&mut self) {
+        let mut active = self.limit.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// What a session holds against the limits until it ends.
+#[derive(Default)]
+pub(crate) struct Slots {
+    pub(crate) session: Option<Slot>,
+    pub(crate) peer: Option<PeerSlot>,
+    // See `ServerConfig::profile_limits`.
+    pub(crate) profile: Option<Slot>,
+}
@@ -0,0 +1,46 @@
+// Input held back until lines are complete, for clients asking for it with
+// a `proto::LineInput`. It works on the bytes the client sends, before the
+// pty's own line discipline, which still applies to what gets written.
+
+const BACKSPACE: u8 = 0x08;
+const KILL_LINE: u8 = 0x15;
+const DELETE: u8 = 0x7f;
+
+#[derive(Default)]
+pub(crate) struct LineEditor {
+    line: Vec<u8>,
+}
+
+impl LineEditor {
+    // What to write to the pty for `input`, empty while the line isn't
+    // complete.
+    pub(crate) fn feed(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut written = vec![];
+        for &byte in input {
+            match byte {
+                BACKSPACE | DELETE => self.erase(),
+                KILL_LINE => self.line.clear(),
+                byte if byte < 0x20 => {
+                    written.append(&mut self.line);
+                    written.push(byte);
+                }
+                byte => self.line.push(byte),
+            }
+        }
+        written
+    }
+
+    // The held line, when leaving line input.
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.line)
+    }
+
+    // Drops the last character, all of its UTF-8 bytes.
+    fn erase(&mut self) {
+        while let Some(byte) = self.line.pop() {
+            if byte & 0xc0 != 0x80 {
+                break;
+            }
+        }
+    }
+}
@@ -1,10 +1,30 @@
 use log::debug;
-use wspty::start_server;
+use wspty::{self_test, start_server_with_config, ServerConfig, UiConfig};
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let _ = start_server()
+    let args: Vec<String> = std::env::args().collect();
+    let config = ServerConfig {
+        ui: args
+            .iter()
+            .any(|arg| arg == "--serve-ui")
+            .then(UiConfig::default),
+        ..Default::default()
+    };
+    // `--self-test [profile]`, see the `selftest` module.
+    if let Some(pos) = args.iter().position(|arg| arg == "--self-test") {
+        let profile = args.get(pos + 1).filter(|arg| !arg.starts_with("--"));
+        match self_test(&config, profile.map(String::as_str)).await {
+            Ok(()) => println!("self-test passed"),
+            Err(e) => {
+                eprintln!("self-test failed: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    let _ = start_server_with_config(config)
         .await
         .map_err(|e| debug!("wspty server exit with error: {:?}", e));
 }
@@ -0,0 +1,122 @@
+// What becomes of client messages the server can't make sense of: those
+// that don't decode, and control messages over their size limit, e.g. a
+// resize with megabytes of JSON. `ServerConfig::malformed` picks an action
+// per opcode, `default` for the others: dropping the message, dropping it
+// and telling the client with a `proto::Malformed`, or closing the
+// connection with a protocol error and `malformed` as reason. Input, upload
+// chunks and channel messages (`RAW`) only have the limits set for their
+// own opcode, the WebSocket ones (see `MessageLimits`) bounding them
+// otherwise. Unknown opcodes aren't malformed, they are ignored.
+
+use std::collections::HashMap;
+use wspty_proto::{self as proto, DecodeError, Malformed};
+
+const RAW: &[u8] = &[
+    proto::INPUT,
+    proto::COMPOSITION,
+    proto::CLIENT_CHUNK,
+    proto::CLIENT_CHANNEL,
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MalformedAction {
+    Ignore,
+    #[default]
+    Reply,
+    Disconnect,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MalformedPolicy {
+    pub default: MalformedAction,
+    // By opcode.
+    pub actions: HashMap<u8, MalformedAction>,
+    // Largest payload of control messages, unlimited if unset.
+    pub max_payload: Option<usize>,
+    // By opcode, raw ones included.
+    pub max_payloads: HashMap<u8, usize>,
+}
+
+impl MalformedPolicy {
+    pub(crate) fn action(&self, malformed: &Malformed) -> MalformedAction {
+        malformed
+            .opcode
+            .and_then(|opcode| self.actions.get(&opcode).copied())
+            .unwrap_or(self.default)
+    }
+
+    // What is wrong with a message before decoding it.
+    pub(crate) fn check(&self, data: &[u8]) -> Result<(), Malformed> {
+        let (&opcode, payload) = match data.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let max = match self.max_payloads.get(&opcode) {
+            Some(&max) => Some(max),
+            None if RAW.contains(&opcode) => None,
+            None => self.max_payload,
+        };
+        match max {
+            Some(max) if payload.len() > max => Err(Malformed {
+                opcode: Some(opcode),
+                error: format!("payload of {} bytes, over {}", payload.len(), max),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn malformed(data: &[u8], error: DecodeError) -> Malformed {
+    Malformed {
+        opcode: data.first().copied(),
+        error: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_limits() {
+        let mut policy = MalformedPolicy {
+            max_payload: Some(8),
+            ..Default::default()
+        };
+        policy.max_payloads.insert(proto::COMPOSITION, 4);
+        policy.max_payloads.insert(proto::ENVIRONMENT, 64);
+        let mut resize = vec![proto::RESIZE];
+        resize.extend_from_slice(&[b' '; 9]);
+        assert_eq!(
+            policy.check(&resize).unwrap_err().opcode,
+            Some(proto::RESIZE)
+        );
+        let mut environment = vec![proto::ENVIRONMENT];
+        environment.extend_from_slice(&[b' '; 9]);
+        assert!(policy.check(&environment).is_ok());
+        assert!(policy.check(&[proto::INPUT; 100]).is_ok());
+        assert!(policy.check(&[proto::COMPOSITION; 6]).is_err());
+        assert!(policy.check(&[]).is_ok());
+    }
+
+    #[test]
+    fn actions() {
+        let mut policy = MalformedPolicy::default();
+        policy
+            .actions
+            .insert(proto::RESIZE, MalformedAction::Disconnect);
+        let error = |opcode| Malformed {
+            opcode,
+            error: String::new(),
+        };
+        assert_eq!(
+            policy.action(&error(Some(proto::RESIZE))),
+            MalformedAction::Disconnect
+        );
+        assert_eq!(
+            policy.action(&error(Some(proto::SIGNAL))),
+            MalformedAction::Reply
+        );
+        assert_eq!(policy.action(&error(None)), MalformedAction::Reply);
+    }
+}
@@ -0,0 +1,79 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+pub const SERVICE_TYPE: &str = "_wspty._tcp.local.";
+
+// DNS-SD advertisement settings. The TXT record always carries the crate
+// version, `txt` entries are added on top of it.
+#[derive(Clone, Debug)]
+pub struct MdnsConfig {
+    pub instance_name: String,
+    pub txt: HashMap<String, String>,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            instance_name: hostname(),
+            txt: HashMap::new(),
+        }
+    }
+}
+
+fn hostname() -> String {
+    nix::unistd::gethostname()
+        .ok()
+        .and_then(|h| h.into_string().ok())
+        .unwrap_or_else(|| "wspty".to_owned())
+}
+
+// Keeps the service registered until dropped.
+pub(crate) struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+pub(crate) fn advertise(
+    config: &MdnsConfig,
+    addr: SocketAddr,
+) -> Result<Advertisement, anyhow::Error> {
+    let daemon = ServiceDaemon::new()?;
+
+    let mut txt = config.txt.clone();
+    txt.insert("version".to_owned(), env!("CARGO_PKG_VERSION").to_owned());
+
+    let host_name = format!("{}.local.", hostname());
+    let ip = addr.ip();
+    let info = if ip.is_unspecified() || ip.is_loopback() {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &config.instance_name,
+            &host_name,
+            (),
+            addr.port(),
+            txt,
+        )?
+        .enable_addr_auto()
+    } else {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &config.instance_name,
+            &host_name,
+            ip,
+            addr.port(),
+            txt,
+        )?
+    };
+
+    let fullname = info.get_fullname().to_owned();
+    daemon.register(info)?;
+    Ok(Advertisement { daemon, fullname })
+}
@@ -0,0 +1,219 @@
+// Approximate memory held for each connection, checked against
+// `ServerConfig::memory_budget` a few times a second: the messages queued
+// for the client (see the `outbox` module), the compression state and the
+// session's scrollback. Parked connections count until they expire. When a
+// connection goes over `per_connection`, or all of them over `total`, load
+// is shed in a set order: scrollbacks are dropped first, the largest first,
+// then the clients with the most queued, which are the slowest to read,
+// get closed with `CloseCode::Again` until usage is back under. New
+// connections are turned away while over `total`. Followers attached with
+// the `sharing` module are bounded by their lag instead and not counted.
+
+use crate::deflate::Deflater;
+use crate::outbox::Outbox;
+use crate::SessionHandle;
+use log::{debug, warn};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tungstenite::protocol::frame::coding::CloseCode;
+use wspty_proto::protocol::CloseReason;
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemoryUsage {
+    pub queued: usize,
+    pub compression: usize,
+    pub scrollback: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.queued + self.compression + self.scrollback
+    }
+}
+
+struct Connection {
+    handle: SessionHandle,
+    outbox: Outbox,
+    deflater: Arc<Mutex<Option<Deflater>>>,
+    // Closed already, what it holds is about to be freed.
+    shed: bool,
+}
+
+impl Connection {
+    fn usage(&self) -> MemoryUsage {
+        let compression = self
+            .deflater
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, Deflater::footprint);
+        let scrollback = self
+            .handle
+            .scrollback
+            .as_ref()
+            .map_or(0, |scrollback| scrollback.lock().unwrap().len());
+        MemoryUsage {
+            queued: self.outbox.queued(),
+            compression,
+            scrollback,
+        }
+    }
+
+    fn drop_scrollback(&self) {
+        if let Some(ref scrollback) = self.handle.scrollback {
+            debug!(
+                "scrollback of session {} dropped to save memory",
+                self.handle
+            );
+            scrollback.lock().unwrap().clear();
+        }
+    }
+
+    fn shed(&mut self, usage: &MemoryUsage) {
+        warn!(
+            "session {} closed to save memory, it held {} bytes",
+            self.handle,
+            usage.total()
+        );
+        self.handle
+            .close(CloseCode::Again, CloseReason::MemoryBudget);
+        self.shed = true;
+    }
+}
+
+struct Budget {
+    per_connection: usize,
+    total: usize,
+    connections: Mutex<HashMap<u64, Connection>>,
+    // As of the last check.
+    used: AtomicUsize,
+    watching: AtomicBool,
+}
+
+// Shared between the server and whoever wants to read the usage.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<Budget>,
+}
+
+impl MemoryBudget {
+    pub fn new(per_connection: usize, total: usize) -> Self {
+        MemoryBudget {
+            inner: Arc::new(Budget {
+                per_connection,
+                total,
+                connections: Mutex::new(HashMap::new()),
+                used: AtomicUsize::new(0),
+                watching: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    // Bytes per connection and in all.
+    pub(crate) fn limits(&self) -> (usize, usize) {
+        (self.inner.per_connection, self.inner.total)
+    }
+
+    // Per session id, as of now.
+    pub fn stats(&self) -> HashMap<u64, MemoryUsage> {
+        self.inner
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, connection)| (id, connection.usage()))
+            .collect()
+    }
+
+    pub(crate) fn register(
+        &self,
+        handle: &SessionHandle,
+        outbox: &Outbox,
+        deflater: &Arc<Mutex<Option<Deflater>>>,
+    ) {
+        let connection = Connection {
+            handle: handle.clone(),
+            outbox: outbox.clone(),
+            deflater: deflater.clone(),
+            shed: false,
+        };
+        self.inner
+            .connections
+            .lock()
+            .unwrap()
+            .insert(handle.id(), connection);
+        if !self.inner.watching.swap(true, Ordering::Relaxed) {
+            tokio::spawn(watch(Arc::downgrade(&self.inner)));
+        }
+    }
+
+    pub(crate) fn unregister(&self, id: u64) {
+        self.inner.connections.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn rejects(&self) -> bool {
+        self.inner.used.load(Ordering::Relaxed) >= self.inner.total
+    }
+}
+
+async fn watch(budget: Weak<Budget>) {
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        match budget.upgrade() {
+            Some(budget) => budget.enforce(),
+            None => return,
+        }
+    }
+}
+
+impl Budget {
+    fn enforce(&self) {
+        let mut connections = self.connections.lock().unwrap();
+        let mut usage: Vec<(u64, MemoryUsage)> = connections
+            .iter()
+            .filter(|(_, connection)| !connection.shed)
+            .map(|(&id, connection)| (id, connection.usage()))
+            .collect();
+        for (id, usage) in usage.iter_mut() {
+            if usage.total() <= self.per_connection {
+                continue;
+            }
+            let connection = connections.get_mut(id).unwrap();
+            if usage.scrollback > 0 {
+                connection.drop_scrollback();
+                usage.scrollback = 0;
+            }
+            if usage.total() > self.per_connection {
+                connection.shed(usage);
+            }
+        }
+        usage.retain(|(id, _)| !connections[id].shed);
+        let mut used: usize = usage.iter().map(|(_, usage)| usage.total()).sum();
+        if used > self.total {
+            usage.sort_by_key(|(_, usage)| Reverse(usage.scrollback));
+            for (id, usage) in usage.iter_mut() {
+                if used <= self.total || usage.scrollback == 0 {
+                    break;
+                }
+                connections[id].drop_scrollback();
+                used -= usage.scrollback;
+                usage.scrollback = 0;
+            }
+            usage.sort_by_key(|(_, usage)| Reverse(usage.queued));
+            for (id, usage) in usage.iter() {
+                if used <= self.total {
+                    break;
+                }
+                connections.get_mut(id).unwrap().shed(usage);
+                used -= usage.total();
+            }
+        }
+        self.used.store(used, Ordering::Relaxed);
+    }
+}
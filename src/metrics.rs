@@ -0,0 +1,246 @@
+// Process wide counters, read with `metrics()` or scraped by Prometheus
+// from `UiConfig::metrics_path`. See `EventHandler` for following sessions
+// one by one instead.
+
+use crate::gc::KINDS;
+use crate::OrphanKind;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static PTY_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_SESSIONS: AtomicU64 = AtomicU64::new(0);
+static SESSIONS: AtomicU64 = AtomicU64::new(0);
+static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+static SPAWN_FAILURES: AtomicU64 = AtomicU64::new(0);
+static SESSION_PANICS: AtomicU64 = AtomicU64::new(0);
+
+// Upper bounds of the session duration buckets, in seconds.
+const DURATION_BUCKETS: [u64; 8] = [1, 10, 60, 300, 1800, 3600, 14400, 86400];
+// Sessions that lasted at most each bound and more than the previous one,
+// the last for those that lasted longer.
+static DURATIONS: [AtomicU64; DURATION_BUCKETS.len() + 1] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static DURATION_TOTAL_MS: AtomicU64 = AtomicU64::new(0);
+// By `OrphanKind`, see the `gc` module.
+static ORPHANS: [AtomicU64; KINDS.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+static REAPED: [AtomicU64; KINDS.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    // Sessions refused because no pty device was left.
+    pub pty_exhausted: u64,
+    // Sessions running now, parked ones included.
+    pub active_sessions: u64,
+    // Sessions started, adopted ones included.
+    pub sessions: u64,
+    // Typed by clients and attachments.
+    pub bytes_in: u64,
+    // Read from the ptys.
+    pub bytes_out: u64,
+    // Commands that couldn't be spawned, for lack of ptys notably.
+    pub spawn_failures: u64,
+    // Sessions and connections lost to a bug, see the `panics` module.
+    pub session_panics: u64,
+    // How many of the sessions that ended lasted at most each number of
+    // seconds, as Prometheus histogram buckets.
+    pub session_durations: Vec<(u64, u64)>,
+    pub ended_sessions: u64,
+    pub session_duration_total: Duration,
+    // Orphans found and reaped, by kind, see the `gc` module.
+    pub orphans: Vec<(OrphanKind, u64)>,
+    pub reaped: Vec<(OrphanKind, u64)>,
+}
+
+pub fn metrics() -> Metrics {
+    let mut below = 0;
+    let session_durations = DURATION_BUCKETS
+        .iter()
+        .zip(DURATIONS.iter())
+        .map(|(&bound, count)| {
+            below += count.load(Ordering::Relaxed);
+            (bound, below)
+        })
+        .collect();
+    let ended_sessions = below + DURATIONS[DURATION_BUCKETS.len()].load(Ordering::Relaxed);
+    let by_kind = |counts: &[AtomicU64]| {
+        KINDS
+            .iter()
+            .zip(counts.iter())
+            .map(|(&kind, count)| (kind, count.load(Ordering::Relaxed)))
+            .collect()
+    };
+    Metrics {
+        pty_exhausted: PTY_EXHAUSTED.load(Ordering::Relaxed),
+        active_sessions: ACTIVE_SESSIONS.load(Ordering::Relaxed),
+        sessions: SESSIONS.load(Ordering::Relaxed),
+        bytes_in: BYTES_IN.load(Ordering::Relaxed),
+        bytes_out: BYTES_OUT.load(Ordering::Relaxed),
+        spawn_failures: SPAWN_FAILURES.load(Ordering::Relaxed),
+        session_panics: SESSION_PANICS.load(Ordering::Relaxed),
+        session_durations,
+        ended_sessions,
+        session_duration_total: Duration::from_millis(DURATION_TOTAL_MS.load(Ordering::Relaxed)),
+        orphans: by_kind(&ORPHANS),
+        reaped: by_kind(&REAPED),
+    }
+}
+
+impl Metrics {
+    // In the Prometheus text format.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP wspty_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE wspty_{} {}", name, kind);
+            let _ = writeln!(out, "wspty_{} {}", name, value);
+        };
+        metric(
+            "pty_exhausted_total",
+            "counter",
+            "Sessions refused for lack of pty devices.",
+            self.pty_exhausted,
+        );
+        metric(
+            "active_sessions",
+            "gauge",
+            "Sessions running, parked ones included.",
+            self.active_sessions,
+        );
+        metric(
+            "sessions_total",
+            "counter",
+            "Sessions started.",
+            self.sessions,
+        );
+        metric(
+            "input_bytes_total",
+            "counter",
+            "Bytes typed into ptys.",
+            self.bytes_in,
+        );
+        metric(
+            "output_bytes_total",
+            "counter",
+            "Bytes read from ptys.",
+            self.bytes_out,
+        );
+        metric(
+            "spawn_failures_total",
+            "counter",
+            "Commands that couldn't be spawned.",
+            self.spawn_failures,
+        );
+        metric(
+            "session_panics_total",
+            "counter",
+            "Sessions and connections lost to a server bug.",
+            self.session_panics,
+        );
+
+        let name = "wspty_session_duration_seconds";
+        let _ = writeln!(out, "# HELP {} How long sessions lasted.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for &(bound, count) in self.session_durations.iter() {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{le=\"+Inf\"}} {}",
+            name, self.ended_sessions
+        );
+        let total = self.session_duration_total.as_secs_f64();
+        let _ = writeln!(out, "{}_sum {}", name, total);
+        let _ = writeln!(out, "{}_count {}", name, self.ended_sessions);
+
+        let by_kind = [
+            ("orphans_total", "Orphaned resources found.", &self.orphans),
+            (
+                "reaped_total",
+                "Orphaned resources cleaned up.",
+                &self.reaped,
+            ),
+        ];
+        for (name, help, counts) in by_kind {
+            let _ = writeln!(out, "# HELP wspty_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE wspty_{} counter", name);
+            for &(kind, count) in counts.iter() {
+                let _ = writeln!(out, "wspty_{}{{kind=\"{}\"}} {}", name, kind.name(), count);
+            }
+        }
+        out
+    }
+}
+
+pub(crate) fn pty_exhausted() {
+    PTY_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn input(len: usize) {
+    BYTES_IN.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn output(len: usize) {
+    BYTES_OUT.fetch_add(len as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn spawn_failed() {
+    SPAWN_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn session_panicked() {
+    SESSION_PANICS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn orphan(kind: OrphanKind, reaped: bool) {
+    let counts = if reaped { &REAPED } else { &ORPHANS };
+    counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+// Counts a session as active for as long as it lives.
+pub(crate) struct Running {
+    started: Instant,
+}
+
+impl Running {
+    pub(crate) fn start() -> Self {
+        SESSIONS.fetch_add(1, Ordering::Relaxed);
+        ACTIVE_SESSIONS.fetch_add(1, Ordering::Relaxed);
+        Running {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        ACTIVE_SESSIONS.fetch_sub(1, Ordering::Relaxed);
+        let lasted = self.started.elapsed();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| lasted <= Duration::from_secs(bound))
+            .unwrap_or(DURATION_BUCKETS.len());
+        DURATIONS[bucket].fetch_add(1, Ordering::Relaxed);
+        DURATION_TOTAL_MS.fetch_add(lasted.as_millis() as u64, Ordering::Relaxed);
+    }
+}
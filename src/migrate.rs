@@ -0,0 +1,352 @@
+// Live session migration between two servers on the same host, e.g. for
+// rolling upgrades.
+//
+// The old server stops pumping data, then sends the pty master and the
+// client socket over a Unix socket as SCM_RIGHTS, along with a length
+// prefixed JSON header followed by a redraw of the screen to seed the new
+// server's terminal state. The WebSocket connection carries on from the new
+// server, so clients should be idle while migrating: a partially received
+// frame would be lost. Frame and integrity modes start over disabled.
+//
+// The old server keeps the child as its own, and is expected to exit once
+// its sessions are migrated. The new server hangs up the child's session
+// when the client goes away.
+//
+// Handing a session over to some other local program uses the same format
+// with only the pty master attached, and a header made of `id`, `rows`, and
+// `cols`, followed by the screen redraw. The client connection is closed.
+//
+// Headers are trusted, the session's owner among them, so the socket is
+// only open to the user the server runs as: it is created with mode 0600 in
+// a private directory, then moved into place, and peers running as someone
+// else are hung up on before anything is read.
+
+use crate::server::{run_session, NEXT_SESSION_ID};
+use crate::sizing::Sizes;
+use crate::timeline::TimelineEvent;
+use crate::vt::VtState;
+use crate::{PtyMaster, ServerConfig, SessionHandle, SpawnInfo};
+use futures::StreamExt;
+use log::{debug, error, warn};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use serde::{Deserialize, Serialize};
+use std::fs::{DirBuilder, File, Permissions};
+use std::io::{Error as IoError, ErrorKind, IoSlice, IoSliceMut, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, Role};
+use wspty_proto::protocol::CloseReason;
+use wspty_proto::{self as proto, WindowSize};
+
+// Headers carry the child environment.
+const MAX_HEADER_LEN: usize = 1 << 20;
+const MAX_SCROLLBACK_LEN: usize = 16 << 20;
+
+#[derive(Serialize)]
+struct HandOver {
+    id: u64,
+    rows: u16,
+    cols: u16,
+    scrollback: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    id: u64,
+    peer: SocketAddr,
+    rows: u16,
+    cols: u16,
+    // Length of the screen redraw following the header.
+    scrollback: usize,
+    #[serde(default)]
+    spawn: Option<SpawnInfo>,
+    #[serde(default)]
+    identity: Option<String>,
+    #[serde(default)]
+    session_id: Option<String>,
+    #[serde(default)]
+    correlation_id: Option<String>,
+    #[serde(default)]
+    low_power: bool,
+    #[serde(default)]
+    render_rate: Option<u32>,
+    // Unless it is `identity`, see the `handoff` module.
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+pub(crate) async fn send_session(
+    handle: &SessionHandle,
+    socket: &Path,
+) -> Result<(), anyhow::Error> {
+    let transport = match handle.transport {
+        Some(ref transport) => transport.clone(),
+        None => anyhow::bail!("session {} can't be migrated", handle),
+    };
+    let mut stream = UnixStream::connect(socket)?;
+
+    handle.detach.notify_one();
+    handle.detached.notified().await;
+    // Keeps the reaper from waking up the new server's reader with a NUL
+    // once the child exits.
+    handle.master.closed.store(true, Ordering::SeqCst);
+
+    let (scrollback, (rows, cols)) = {
+        let mut vt = handle.vt.lock().unwrap();
+        (vt.snapshot(), vt.size())
+    };
+    let header = serde_json::to_vec(&Header {
+        id: handle.id(),
+        peer: handle.peer(),
+        rows,
+        cols,
+        scrollback: scrollback.len(),
+        spawn: handle.spawn_info().cloned(),
+        identity: handle.identity.clone(),
+        session_id: Some(handle.session_id().to_owned()),
+        correlation_id: handle.correlation_id.clone(),
+        low_power: handle.low_power,
+        render_rate: handle.render_rate,
+        owner: handle
+            .owner()
+            .filter(|owner| handle.identity() != Some(owner)),
+    })?;
+    let master = handle.master.clone();
+    tokio::task::spawn_blocking(move || {
+        let fds = [master.as_raw_fd(), transport.as_raw_fd()];
+        send_fds(&stream, &fds, &proto::length_prefixed(&header))?;
+        stream.write_all(&scrollback)
+    })
+    .await??;
+    handle.record(TimelineEvent::Migrated);
+    debug!("session {} migrated to {:?}", handle, socket);
+    Ok(())
+}
+
+pub(crate) async fn hand_over(handle: &SessionHandle, socket: &Path) -> Result<(), anyhow::Error> {
+    let mut stream = UnixStream::connect(socket)?;
+
+    handle.farewell.lock().unwrap().replace(CloseFrame {
+        code: CloseCode::Normal,
+        reason: CloseReason::HandedOver.to_string().into(),
+    });
+    handle.detach.notify_one();
+    handle.detached.notified().await;
+    handle.master.closed.store(true, Ordering::SeqCst);
+
+    let (scrollback, (rows, cols)) = {
+        let mut vt = handle.vt.lock().unwrap();
+        (vt.snapshot(), vt.size())
+    };
+    let header = serde_json::to_vec(&HandOver {
+        id: handle.id(),
+        rows,
+        cols,
+        scrollback: scrollback.len(),
+    })?;
+    let master = handle.master.clone();
+    tokio::task::spawn_blocking(move || {
+        send_fds(
+            &stream,
+            &[master.as_raw_fd()],
+            &proto::length_prefixed(&header),
+        )?;
+        stream.write_all(&scrollback)
+    })
+    .await??;
+    debug!("session {} handed over to {:?}", handle, socket);
+    Ok(())
+}
+
+pub(crate) async fn accept(path: PathBuf, config: Arc<ServerConfig>) -> Result<(), anyhow::Error> {
+    serve(bind(&path)?, config).await
+}
+
+// Never reachable by others, even before its permissions are set.
+fn bind(path: &Path) -> Result<UnixListener, IoError> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if !parent.exists() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    let dir = parent.join(format!(".wspty-migration-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        // Left over by a previous instance.
+        let _ = std::fs::remove_file(path);
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&dir);
+    bound
+}
+
+pub(crate) async fn serve(
+    listener: UnixListener,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let uid = stream.peer_cred().map(|cred| cred.uid());
+        if uid.as_ref().ok() != Some(&nix::unistd::geteuid().as_raw()) {
+            warn!("refusing a migration from uid {:?}", uid);
+            continue;
+        }
+        let config = config.clone();
+        tokio::spawn(async move {
+            let _ = adopt(stream, config)
+                .await
+                .map_err(|e| error!("failed to adopt migrated session: {:?}", e));
+        });
+    }
+}
+
+async fn adopt(
+    stream: tokio::net::UnixStream,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error> {
+    let mut stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    let (mut fds, header, scrollback) = tokio::task::spawn_blocking(move || {
+        let mut len = [0u8; 4];
+        let fds = recv_fds(&stream, &mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_HEADER_LEN {
+            return Err(IoError::new(ErrorKind::InvalidData, "header too large"));
+        }
+        let mut header = vec![0u8; len];
+        stream.read_exact(&mut header)?;
+        let header: Header = serde_json::from_slice(&header)?;
+        if header.scrollback > MAX_SCROLLBACK_LEN {
+            return Err(IoError::new(ErrorKind::InvalidData, "scrollback too large"));
+        }
+        let mut scrollback = vec![0u8; header.scrollback];
+        stream.read_exact(&mut scrollback)?;
+        Ok((fds, header, scrollback))
+    })
+    .await??;
+    if fds.len() != 2 {
+        anyhow::bail!("expected 2 fds, got {}", fds.len());
+    }
+    let transport = fds.pop().unwrap();
+    let master = PtyMaster::from_file(File::from(fds.pop().unwrap()))?;
+
+    let tcp = std::net::TcpStream::from(transport.try_clone()?);
+    tcp.set_nonblocking(true)?;
+    let limits = config.message_limits;
+    let ws_stream = WebSocketStream::from_raw_socket(
+        tokio::net::TcpStream::from_std(tcp)?,
+        Role::Server,
+        Some(limits.websocket_config()),
+    )
+    .await;
+    let (ws_outgoing, ws_incoming) = ws_stream.split();
+    let ws_outgoing = limits.fragmenting(ws_outgoing);
+
+    let mut vt = VtState::new(header.rows, header.cols);
+    vt.process(&scrollback);
+    if config.command_sentinels {
+        vt.track_commands();
+    }
+    NEXT_SESSION_ID.fetch_max(header.id + 1, Ordering::Relaxed);
+    let mut handle = SessionHandle::new(
+        header.id,
+        header.peer,
+        master.clone(),
+        Arc::new(Mutex::new(vt)),
+        Some(transport),
+    );
+    handle.spawn = header.spawn.map(Arc::new);
+    *handle.owner.lock().unwrap() = header.owner.or(header.identity.clone());
+    handle.identity = header.identity;
+    if let Some(session_id) = header.session_id {
+        handle.session_id = session_id.into();
+    }
+    handle.correlation_id = header.correlation_id;
+    handle.low_power = header.low_power;
+    handle.render_rate = header.render_rate;
+    let size = WindowSize {
+        cols: header.cols,
+        rows: header.rows,
+        xpixel: 0,
+        ypixel: 0,
+    };
+    handle.sizes = Arc::new(Mutex::new(Sizes::new(config.size_policy, Some(size))));
+    debug!("adopted session {} from {:?}", handle, header.peer);
+
+    let (stop_sender, mut stop_receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        let _ = stop_receiver.recv().await;
+        hang_up(&master);
+    });
+    run_session(
+        ws_outgoing,
+        ws_incoming,
+        handle,
+        stop_sender,
+        Default::default(),
+        config,
+    )
+    .await
+}
+
+// Not our child, so signal its session rather than killing it.
+fn hang_up(master: &PtyMaster) {
+    unsafe {
+        let sid = libc::tcgetsid(master.as_raw_fd());
+        if sid > 0 {
+            libc::killpg(sid, libc::SIGHUP);
+        }
+    }
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd], data: &[u8]) -> Result<(), IoError> {
+    let iov = [IoSlice::new(data)];
+    let cmsg = [ControlMessage::ScmRights(fds)];
+    let sent = sendmsg::<()>(stream.as_raw_fd(), &iov, &cmsg, MsgFlags::empty(), None)?;
+    (&*stream).write_all(&data[sent..])
+}
+
+// Fills `buf` entirely, returning the fds received along the way.
+fn recv_fds(stream: &UnixStream, buf: &mut [u8]) -> Result<Vec<OwnedFd>, IoError> {
+    let mut cmsg = nix::cmsg_space!([RawFd; 2]);
+    let mut iov = [IoSliceMut::new(buf)];
+    let msg = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+    let received = msg.bytes;
+    let mut fds = vec![];
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(raw) = cmsg {
+            fds.extend(
+                raw.into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+    if received == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    (&*stream).read_exact(&mut buf[received..])?;
+    Ok(fds)
+}
@@ -0,0 +1,79 @@
+// Live copy of sessions' output to a supervision endpoint. The server
+// connects to the WebSocket `url` for each session, sends a JSON text
+// message describing it, then the same output frames the client gets. A
+// slow or failing mirror never holds the session back: frames are queued,
+// and mirroring stops on the first error.
+
+use crate::SessionHandle;
+use futures::SinkExt;
+use log::{debug, error};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio_tungstenite::connect_async;
+use tungstenite::Message;
+use wspty_proto as proto;
+
+#[derive(Clone, Debug)]
+pub struct MirrorConfig {
+    pub url: String,
+}
+
+#[derive(Serialize)]
+struct Header<'a> {
+    id: u64,
+    session_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<&'a str>,
+    peer: SocketAddr,
+    command: &'a str,
+    timestamp: u64,
+}
+
+#[derive(Clone)]
+pub(crate) struct Mirror {
+    sender: UnboundedSender<Vec<u8>>,
+}
+
+impl Mirror {
+    pub(crate) fn start(config: &MirrorConfig, handle: &SessionHandle, command: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let header = serde_json::to_string(&Header {
+            id: handle.id(),
+            session_id: handle.session_id(),
+            correlation_id: handle.correlation_id(),
+            peer: handle.peer(),
+            command,
+            timestamp,
+        })
+        .unwrap_or_default();
+        let url = config.url.clone();
+        let session = handle.to_string();
+        let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+        tokio::spawn(async move {
+            let res = async {
+                let (mut ws, _) = connect_async(url.as_str()).await?;
+                ws.send(Message::Text(header)).await?;
+                while let Some(data) = receiver.recv().await {
+                    ws.send(Message::Binary(proto::frame(proto::OUTPUT, &data)))
+                        .await?;
+                }
+                ws.close(None).await?;
+                Ok::<(), anyhow::Error>(())
+            };
+            match res.await {
+                Ok(()) => debug!("session {} mirror done", session),
+                Err(e) => error!("session {} mirror to {} failed: {:?}", session, url, e),
+            }
+        });
+        Mirror { sender }
+    }
+
+    pub(crate) fn output(&self, data: &[u8]) {
+        let _ = self.sender.send(data.to_vec());
+    }
+}
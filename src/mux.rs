@@ -0,0 +1,211 @@
+// Several sessions over one connection, e.g. for tabbed frontends, see
+// `proto::Open` for the framing. Each channel is served like a connection
+// of its own (admission checks, session limit and all) through in-memory
+// streams: messages from the client are unwrapped and routed to the
+// channel's session, whose messages are wrapped with the channel id on the
+// way out, and its close frame turned into a `proto::ChannelClosed`.
+// Closing the connection closes every channel, losing it leaves channels
+// for `ServerConfig::persistence` like lost connections.
+
+use crate::auth::Handshake;
+use crate::server::serve_command;
+use crate::ServerConfig;
+use futures::channel::mpsc::unbounded;
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::{
+    self as proto, ChannelClosed, ChannelInfo, Channels, ClientMessage, Open, ServerMessage,
+};
+
+// Channels open at once on a connection, more are refused.
+const MAX_CHANNELS: usize = 64;
+
+struct Channel {
+    command: String,
+    // Tells this channel apart from an earlier one with the same id.
+    serial: u64,
+    incoming: futures::channel::mpsc::UnboundedSender<Result<Message, WsError>>,
+}
+
+impl Channel {
+    // Ends the session's incoming stream with a close frame, as a closed
+    // connection would.
+    fn close(&self) {
+        let _ = self.incoming.unbounded_send(Ok(Message::Close(None)));
+        self.incoming.close_channel();
+    }
+}
+
+enum Event {
+    // The session sent its close frame.
+    Closed(u32, u64),
+    // The session is over.
+    Done(u32, u64),
+}
+
+fn closed_message(channel: u32, reason: Option<String>) -> Message {
+    let closed = ChannelClosed { channel, reason };
+    Message::Binary(ServerMessage::ChannelClosed(closed).encode())
+}
+
+fn channel_sink(
+    outgoing: UnboundedSender<Message>,
+    events: UnboundedSender<Event>,
+    channel: u32,
+    serial: u64,
+) -> impl Sink<Message, Error = WsError> + Send + Unpin {
+    Box::pin(futures::sink::unfold(
+        outgoing,
+        move |outgoing, msg: Message| {
+            let sent = match msg {
+                Message::Binary(data) => outgoing.send(Message::Binary(
+                    ServerMessage::Channel(channel, &data).encode(),
+                )),
+                Message::Close(frame) => {
+                    let reason = frame
+                        .map(|frame| frame.reason.into_owned())
+                        .filter(|reason| !reason.is_empty());
+                    let _ = events.send(Event::Closed(channel, serial));
+                    outgoing.send(closed_message(channel, reason))
+                }
+                _ => Ok(()),
+            };
+            future::ready(
+                sent.map(|()| outgoing)
+                    .map_err(|_| WsError::ConnectionClosed),
+            )
+        },
+    ))
+}
+
+// Serves a multiplexed connection, starting with its first `open`.
+pub(crate) async fn serve_mux<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    peer: SocketAddr,
+    handshake: Handshake,
+    first: Open,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let (outgoing, mut queue) = unbounded_channel::<Message>();
+    let (events, mut event_queue) = unbounded_channel();
+    let mut channels: HashMap<u32, Channel> = HashMap::new();
+    let mut serial = 0;
+    let mut open = |open: Open, channels: &mut HashMap<u32, Channel>| {
+        if channels.contains_key(&open.channel) {
+            warn!("channel {} already open for {:?}", open.channel, peer);
+            return;
+        }
+        if channels.len() >= MAX_CHANNELS {
+            let reason = Some("too many channels".into());
+            let _ = outgoing.send(closed_message(open.channel, reason));
+            return;
+        }
+        serial += 1;
+        let command = open.command.clone();
+        let (incoming, channel_incoming) = unbounded();
+        channels.insert(
+            open.channel,
+            Channel {
+                command: open.command,
+                serial,
+                incoming,
+            },
+        );
+        let (channel, serial) = (open.channel, serial);
+        let channel_outgoing = channel_sink(outgoing.clone(), events.clone(), channel, serial);
+        let session = serve_command(
+            channel_outgoing,
+            channel_incoming,
+            Some(Ok(Message::Text(command))),
+            peer,
+            handshake.clone(),
+            config.clone(),
+            None,
+        );
+        let done = events.clone();
+        tokio::spawn(async move {
+            let res = session.await;
+            debug!("channel {} for {:?} done: {:?}", channel, peer, res);
+            let _ = done.send(Event::Done(channel, serial));
+        });
+    };
+    open(first, &mut channels);
+
+    let mut closed = false;
+    loop {
+        tokio::select! {
+            Some(msg) = queue.recv() => ws_outgoing.send(msg).await?,
+            Some(event) = event_queue.recv() => match event {
+                Event::Closed(channel, serial) => {
+                    if channels.get(&channel).is_some_and(|c| c.serial == serial) {
+                        channels.remove(&channel);
+                    }
+                }
+                Event::Done(channel, serial) => {
+                    if channels.get(&channel).is_some_and(|c| c.serial == serial) {
+                        channels.remove(&channel);
+                        // Queued after the channel's last messages.
+                        let _ = outgoing.send(closed_message(channel, None));
+                    }
+                }
+            },
+            msg = ws_incoming.next() => match msg {
+                Some(Ok(Message::Binary(data))) => match ClientMessage::decode(&data) {
+                    Ok(ClientMessage::Channel(channel, message)) => match channels.get(&channel) {
+                        Some(channel) => {
+                            let _ = channel.incoming.unbounded_send(Ok(Message::Binary(message.to_vec())));
+                        }
+                        None => debug!("message for unknown channel {} from {:?}", channel, peer),
+                    },
+                    Ok(ClientMessage::Open(request)) => open(request, &mut channels),
+                    Ok(ClientMessage::CloseChannel(close)) => {
+                        // Confirmed with a `ChannelClosed` once the session is over.
+                        if let Some(channel) = channels.get(&close.channel) {
+                            channel.close();
+                        }
+                    }
+                    Ok(ClientMessage::ListChannels) => {
+                        let mut list: Vec<ChannelInfo> = channels
+                            .iter()
+                            .map(|(&channel, c)| ChannelInfo {
+                                channel,
+                                command: c.command.clone(),
+                            })
+                            .collect();
+                        list.sort_by_key(|info| info.channel);
+                        let msg = ServerMessage::Channels(Channels { channels: list });
+                        ws_outgoing.send(Message::Binary(msg.encode())).await?;
+                    }
+                    Ok(ClientMessage::Ping) => {
+                        ws_outgoing.send(Message::Binary(vec![proto::PONG])).await?;
+                    }
+                    _ => debug!("unexpected message on multiplexed connection from {:?}", peer),
+                },
+                Some(Ok(Message::Ping(data))) => ws_outgoing.send(Message::Pong(data)).await?,
+                Some(Ok(Message::Close(_))) => {
+                    closed = true;
+                    break;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+    // Otherwise the connection was lost, so are the channels.
+    if closed {
+        for channel in channels.values() {
+            channel.close();
+        }
+    }
+    Ok(())
+}
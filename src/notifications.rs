@@ -0,0 +1,39 @@
+// Messages for the users of a session, sent with `SessionHandle::notify` by
+// the server or its hooks, `recording enabled` for instance. They go to the
+// client and the viewers attached to the session as `Notification` frames,
+// which frontends show outside the terminal, and are left out of its output
+// and recordings. Users only get those sent while they follow the session,
+// parked sessions get them on resuming along with the output they missed.
+
+use crate::outbox::Outbox;
+use crate::SessionHandle;
+use log::debug;
+use tokio::sync::broadcast::error::RecvError;
+use tungstenite::Message;
+use wspty_proto::{Notification, ServerMessage};
+
+// Notifications buffered per user before the oldest get dropped.
+pub(crate) const BACKLOG: usize = 16;
+
+pub(crate) fn message(notification: &Notification) -> Message {
+    Message::Binary(ServerMessage::Notification(notification.clone()).encode())
+}
+
+// Tells the client, until the session is over.
+pub(crate) async fn watch(handle: SessionHandle, sender: Outbox) {
+    let mut notifications = handle.notifications.subscribe();
+    loop {
+        match notifications.recv().await {
+            Ok(notification) => {
+                if sender.send(message(&notification)).is_err() {
+                    break;
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                debug!("session {} dropped {} notifications", handle, missed);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+    debug!("session {} notifications done", handle);
+}
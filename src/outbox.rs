@@ -0,0 +1,96 @@
+// Queue of the messages for a session's client, counting the bytes not yet
+// taken for its socket. The pty is only read from while that is under the
+// limit, so on a slow link the command ends up blocked writing to its
+// terminal, as with a slow local terminal, instead of the queue growing
+// without bound. Other messages are never held back, which keeps them in
+// order with the output.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::error::{SendError, TryRecvError};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tungstenite::Message;
+
+struct Backlog {
+    bytes: AtomicUsize,
+    limit: usize,
+    drained: Notify,
+}
+
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    sender: UnboundedSender<Message>,
+    backlog: Arc<Backlog>,
+}
+
+pub(crate) struct OutboxReceiver {
+    receiver: UnboundedReceiver<Message>,
+    backlog: Arc<Backlog>,
+}
+
+pub(crate) fn outbox(limit: usize) -> (Outbox, OutboxReceiver) {
+    let (sender, receiver) = unbounded_channel();
+    let backlog = Arc::new(Backlog {
+        bytes: AtomicUsize::new(0),
+        limit,
+        drained: Notify::new(),
+    });
+    let outbox = Outbox {
+        sender,
+        backlog: backlog.clone(),
+    };
+    (outbox, OutboxReceiver { receiver, backlog })
+}
+
+impl Outbox {
+    pub(crate) fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
+        let len = msg.len();
+        // Counted first, the receiver could take it right away.
+        self.backlog.bytes.fetch_add(len, Ordering::SeqCst);
+        self.sender.send(msg).inspect_err(|_| {
+            self.backlog.bytes.fetch_sub(len, Ordering::SeqCst);
+        })
+    }
+
+    // Bytes not taken yet.
+    pub(crate) fn queued(&self) -> usize {
+        self.backlog.bytes.load(Ordering::SeqCst)
+    }
+
+    // Waits for the client to take enough of the queue to be under the
+    // limit.
+    pub(crate) async fn drained(&self) {
+        loop {
+            let drained = self.backlog.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+            if self.backlog.bytes.load(Ordering::SeqCst) < self.backlog.limit {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+impl OutboxReceiver {
+    pub(crate) async fn recv(&mut self) -> Option<Message> {
+        let msg = self.receiver.recv().await?;
+        self.taken(&msg);
+        Some(msg)
+    }
+
+    pub(crate) fn try_recv(&mut self) -> Result<Message, TryRecvError> {
+        let msg = self.receiver.try_recv()?;
+        self.taken(&msg);
+        Ok(msg)
+    }
+
+    fn taken(&self, msg: &Message) {
+        let len = msg.len();
+        let before = self.backlog.bytes.fetch_sub(len, Ordering::SeqCst);
+        if before - len < self.backlog.limit {
+            self.backlog.drained.notify_waiters();
+        }
+    }
+}
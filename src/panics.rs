@@ -0,0 +1,76 @@
+// Panics in the tasks of a session, bugs of the server, end that session
+// rather than have it vanish: the client gets a last line of output saying
+// so and a close with `CloseCode::Error`, `EventHandler::on_session_error`
+// is called and the session is cleaned up as if the client went away. The
+// `session_panics` metric counts them, with those happening before there
+// is a session, which only get logged.
+
+use crate::timeline::TimelineEvent;
+use crate::{EventHandler, SessionError, SessionHandle};
+use futures::FutureExt;
+use log::error;
+use std::any::Any;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use wspty_proto::protocol::CloseReason;
+
+pub(crate) const APOLOGY: &[u8] =
+    b"\r\n[the server ran into an internal error and lost this session, sorry]\r\n";
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => (*message).to_owned(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".to_owned()),
+    }
+}
+
+// Accounts for a panic in the `task` of the session, returns what to close
+// the connection with.
+pub(crate) fn caught(
+    handle: &SessionHandle,
+    task: &'static str,
+    payload: Box<dyn Any + Send>,
+    events: Option<&dyn EventHandler>,
+) -> CloseFrame<'static> {
+    let error = SessionError {
+        task,
+        message: message(&*payload),
+    };
+    error!(
+        "session {} lost to a panic in its {} task: {}",
+        handle, task, error.message
+    );
+    crate::metrics::session_panicked();
+    handle.record(TimelineEvent::Failed {
+        task: task.to_owned(),
+        message: error.message.clone(),
+    });
+    if let Some(events) = events {
+        events.on_session_error(handle, &error);
+    }
+    CloseFrame {
+        code: CloseCode::Error,
+        reason: CloseReason::InternalError.to_string().into(),
+    }
+}
+
+// For connection tasks, before their session runs.
+pub(crate) async fn guard<F>(fut: F, peer: SocketAddr)
+where
+    F: Future<Output = ()>,
+{
+    if let Err(payload) = AssertUnwindSafe(fut).catch_unwind().await {
+        error!(
+            "connection from {:?} lost to a panic: {}",
+            peer,
+            message(&*payload)
+        );
+        crate::metrics::session_panicked();
+    }
+}
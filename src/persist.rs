@@ -0,0 +1,222 @@
+// Sessions outliving their client's connection, for flaky networks. Each
+// session gets a token, sent in a `proto::Session` message when it starts.
+// When the connection is lost, or closed with `Away` as browsers do when
+// reloading a page, the child keeps running and its output is buffered up
+// to `buffer_limit` bytes. A client sending `proto::Resume` with the token
+// in place of the command within `ttl` gets the session back: the buffered
+// output is replayed, or the screen redrawn if it didn't fit. Otherwise the
+// child is killed.
+
+use crate::server::{resume_session, Live};
+use crate::SessionHandle;
+use futures::{Sink, Stream};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Error as IoError, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::protocol::CloseReason;
+
+// A session waiting for its client, as handed to the connection resuming
+// it.
+pub(crate) struct Parked {
+    pub(crate) live: Live,
+    pub(crate) buffer: Vec<Message>,
+    pub(crate) overflowed: bool,
+}
+
+struct Entry {
+    identity: Option<String>,
+    handle: SessionHandle,
+    claim: oneshot::Sender<oneshot::Sender<Parked>>,
+}
+
+#[derive(Clone)]
+pub struct Persistence {
+    buffer_limit: usize,
+    ttl: Duration,
+    parked: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl Default for Persistence {
+    fn default() -> Self {
+        Self::new(1024 * 1024, Duration::from_secs(300))
+    }
+}
+
+impl std::fmt::Debug for Persistence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Persistence")
+            .field("buffer_limit", &self.buffer_limit)
+            .field("ttl", &self.ttl)
+            .field("parked", &self.parked())
+            .finish()
+    }
+}
+
+impl Persistence {
+    pub fn new(buffer_limit: usize, ttl: Duration) -> Self {
+        Persistence {
+            buffer_limit,
+            ttl,
+            parked: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn buffer_limit(&self) -> usize {
+        self.buffer_limit
+    }
+
+    pub(crate) fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // Sessions currently waiting for their client.
+    pub fn parked(&self) -> usize {
+        self.parked.lock().unwrap().len()
+    }
+
+    // For the `gc` module.
+    pub(crate) fn parked_sessions(&self) -> Vec<SessionHandle> {
+        let parked = self.parked.lock().unwrap();
+        parked.values().map(|entry| entry.handle.clone()).collect()
+    }
+
+    // Keeps `live` running without a client until claimed with `token` or
+    // the ttl expires.
+    pub(crate) fn park(&self, token: String, mut live: Live) {
+        let (claim, mut claimed) = oneshot::channel::<oneshot::Sender<Parked>>();
+        let identity = live.handle().owner();
+        let entry = Entry {
+            identity,
+            handle: live.handle().clone(),
+            claim,
+        };
+        self.parked.lock().unwrap().insert(token.clone(), entry);
+        debug!("session {} parked", live.handle());
+
+        let parked = self.parked.clone();
+        let (limit, ttl) = (self.buffer_limit, self.ttl);
+        tokio::spawn(async move {
+            let handle = live.handle().clone();
+            let mut buffer = vec![];
+            let mut buffered = 0;
+            let mut overflowed = false;
+            let mut expired = false;
+            let clock = handle.clock.clone();
+            let mut expiry = clock.sleep_until(clock.now() + ttl);
+            loop {
+                tokio::select! {
+                    reply = &mut claimed => {
+                        if let Ok(reply) = reply {
+                            let _ = reply.send(Parked { live, buffer, overflowed });
+                        }
+                        return;
+                    }
+                    Some(msg) = live.receiver.recv() => {
+                        buffered += msg.len();
+                        if buffered > limit {
+                            // Redrawn from the terminal state instead.
+                            overflowed = true;
+                            buffer.clear();
+                        } else if !overflowed {
+                            buffer.push(msg);
+                        }
+                    }
+                    _ = &mut expiry, if !expired => {
+                        expired = true;
+                        // Otherwise being claimed, the request is on its way.
+                        if parked.lock().unwrap().remove(&token).is_some() {
+                            info!("session {} expired", handle);
+                            live.stop();
+                            live.end().await;
+                            return;
+                        }
+                    }
+                    _ = handle.terminate.notified(), if !expired => {
+                        expired = true;
+                        if parked.lock().unwrap().remove(&token).is_some() {
+                            info!("session {} terminated while parked", handle);
+                            live.stop();
+                            live.end().await;
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    // Takes the session parked with `token`, if it belongs to `identity`,
+    // see `SessionHandle::owner`.
+    async fn claim(&self, token: &str, identity: Option<&str>) -> Option<Parked> {
+        let claim = {
+            let mut parked = self.parked.lock().unwrap();
+            match parked.get(token) {
+                Some(entry) if entry.identity.as_deref() == identity => {
+                    parked.remove(token).unwrap().claim
+                }
+                _ => return None,
+            }
+        };
+        let (reply, parked) = oneshot::channel();
+        claim.send(reply).ok()?;
+        parked.await.ok()
+    }
+}
+
+// Random token for a new session.
+pub(crate) fn token() -> Result<String, IoError> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Serves a `proto::Resume` request from `peer`.
+pub(crate) async fn resume<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    peer: SocketAddr,
+    identity: Option<String>,
+    token: &str,
+    config: Arc<crate::ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let banned = config
+        .bans
+        .as_ref()
+        .is_some_and(|bans| bans.is_banned(peer.ip(), identity.as_deref()));
+    let parked = match config.persistence {
+        Some(ref persistence) if !banned => persistence.claim(token, identity.as_deref()).await,
+        _ => None,
+    };
+    let parked = match parked {
+        Some(parked) => parked,
+        None => {
+            warn!("refusing to resume an unknown session for {:?}", peer);
+            let farewell = CloseFrame {
+                code: CloseCode::Policy,
+                reason: CloseReason::UnknownSession.to_string().into(),
+            };
+            crate::teardown::refuse(
+                &mut ws_outgoing,
+                &mut ws_incoming,
+                farewell,
+                config.teardown,
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    info!("session {} resumed from {:?}", parked.live.handle(), peer);
+    resume_session(ws_outgoing, ws_incoming, parked, config).await
+}
@@ -0,0 +1,142 @@
+// Output only sessions, for commands like `journalctl -f` that never read
+// input. The child gets plain pipes instead of a pty, which saves a pty
+// device and its line discipline. Input and resizes are ignored.
+//
+// Both streams are sent as output, unless `ServerConfig::separate_stderr`
+// is set, then each chunk goes in a `STREAM_OUTPUT` message naming the
+// stream, so that UIs can show stderr differently, which nothing behind a
+// pty can. Each stream is sanitized on its own then.
+
+use crate::accounting::Meter;
+use crate::sanitize::Sanitizer;
+use crate::server::exit_message;
+use crate::ServerConfig;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::{self as proto, ClientMessage, ServerMessage};
+
+pub(crate) async fn serve_pipe<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    command: &str,
+    peer: SocketAddr,
+    identity: Option<&str>,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let mut cmd = Command::new(command);
+    let account = config.account(identity)?;
+    config.prepare(&mut cmd, account.as_ref());
+    if let Some(account) = account {
+        unsafe {
+            cmd.pre_exec(move || account.switch());
+        }
+    }
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+
+    let meter = config
+        .bandwidth
+        .clone()
+        .map(|ledger| Meter::new(ledger, identity, peer));
+
+    let separate = config.separate_stderr;
+    // Indexed by stream, the stdout one being shared when not separate.
+    let mut sanitizers = [
+        config.sanitize_output.then(Sanitizer::default),
+        (config.sanitize_output && separate).then(Sanitizer::default),
+    ];
+    let mut out_buf = vec![0u8; 4096];
+    let mut err_buf = vec![0u8; 4096];
+    while stdout.is_some() || stderr.is_some() {
+        let (stream, data) = tokio::select! {
+            res = async { stdout.as_mut().unwrap().read(&mut out_buf).await }, if stdout.is_some() => {
+                match res? {
+                    0 => {
+                        stdout = None;
+                        continue;
+                    }
+                    n => (proto::STDOUT, &out_buf[..n]),
+                }
+            }
+            res = async { stderr.as_mut().unwrap().read(&mut err_buf).await }, if stderr.is_some() => {
+                match res? {
+                    0 => {
+                        stderr = None;
+                        continue;
+                    }
+                    n => (proto::STDERR, &err_buf[..n]),
+                }
+            }
+            msg = ws_incoming.next() => {
+                match msg {
+                    Some(Ok(Message::Binary(data))) => {
+                        if let Ok(ClientMessage::Ping) = ClientMessage::decode(&data) {
+                            ws_outgoing.send(Message::Binary(vec![proto::PONG])).await?;
+                        }
+                    }
+                    Some(Ok(Message::Ping(data))) => ws_outgoing.send(Message::Pong(data)).await?,
+                    Some(Ok(_)) => (),
+                    // The client is gone, kill_on_drop takes care of the child.
+                    _ => return Ok(()),
+                }
+                continue;
+            }
+        };
+        let sanitizer = match (stream, separate) {
+            (proto::STDERR, true) => &mut sanitizers[1],
+            _ => &mut sanitizers[0],
+        };
+        let data = match sanitizer {
+            Some(ref mut sanitizer) => onlcr(&sanitizer.filter(data)),
+            None => onlcr(data),
+        };
+        if data.is_empty() {
+            continue;
+        }
+        if let Some(ref meter) = meter {
+            meter.account(data.len()).await?;
+        }
+        let msg = match separate {
+            true => ServerMessage::StreamOutput {
+                stream,
+                data: &data,
+            }
+            .encode(),
+            false => proto::frame(proto::OUTPUT, &data),
+        };
+        ws_outgoing.send(Message::Binary(msg)).await?;
+    }
+
+    if let Ok(status) = child.wait().await {
+        ws_outgoing.send(exit_message(status)).await?;
+    }
+    ws_outgoing.send(Message::Close(None)).await?;
+    Ok(())
+}
+
+// What the pty line discipline would do for a terminal on the other side.
+fn onlcr(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / 16);
+    for &b in data {
+        if b == b'\n' {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    out
+}
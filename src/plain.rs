@@ -0,0 +1,122 @@
+// Output as plain text, for attachments pasting it in chat messages,
+// tickets or CI logs: escape sequences are dropped whole (CSI, OSC and the
+// other string sequences, charset selections), as are the control
+// characters but tabs and newlines. Carriage returns end lines, so that
+// progress bars redrawing one give a line per update rather than one long
+// line. Sequences and codepoints split across reads are completed with the
+// next ones.
+
+use crate::utf8::Utf8Framer;
+
+enum State {
+    Ground,
+    Escape,
+    // Waiting for the final byte of an escape with intermediates.
+    Intermediate,
+    Csi,
+    // Of an OSC, DCS, SOS, PM or APC, up to BEL or ST.
+    String,
+}
+
+pub(crate) struct PlainText {
+    state: State,
+    // A carriage return not yet known to be followed by a newline.
+    return_held: bool,
+    utf8: Utf8Framer,
+}
+
+impl Default for PlainText {
+    fn default() -> Self {
+        PlainText {
+            state: State::Ground,
+            return_held: false,
+            utf8: Utf8Framer::default(),
+        }
+    }
+}
+
+impl PlainText {
+    pub(crate) fn strip(&mut self, data: &[u8]) -> String {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            self.state = match std::mem::replace(&mut self.state, State::Ground) {
+                State::Ground => self.ground(b, &mut out),
+                State::Escape => match b {
+                    b'[' => State::Csi,
+                    b']' | b'P' | b'X' | b'^' | b'_' => State::String,
+                    0x1b => State::Escape,
+                    0x20..=0x2f => State::Intermediate,
+                    _ => State::Ground,
+                },
+                State::Intermediate => match b {
+                    0x20..=0x2f => State::Intermediate,
+                    _ => State::Ground,
+                },
+                State::Csi => match b {
+                    0x20..=0x3f => State::Csi,
+                    0x40..=0x7e => State::Ground,
+                    // Not a sequence after all.
+                    _ => self.ground(b, &mut out),
+                },
+                // ST is ESC \, the backslash then ends the escape.
+                State::String => match b {
+                    0x07 => State::Ground,
+                    0x1b => State::Escape,
+                    _ => State::String,
+                },
+            };
+        }
+        String::from_utf8_lossy(&self.utf8.frame(&out)).into_owned()
+    }
+
+    fn ground(&mut self, b: u8, out: &mut Vec<u8>) -> State {
+        match b {
+            0x1b => return State::Escape,
+            b'\r' => self.return_held = true,
+            b'\n' => {
+                self.return_held = false;
+                out.push(b);
+            }
+            b'\t' | 0x20..=0x7e | 0x80.. => {
+                if std::mem::take(&mut self.return_held) {
+                    out.push(b'\n');
+                }
+                out.push(b);
+            }
+            _ => (),
+        }
+        State::Ground
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_are_stripped() {
+        let mut plain = PlainText::default();
+        let out = plain.strip(
+            b"\x1b]0;title\x07\x1b[1;31mred\x1b[0m\t\x1b(Bok\x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\\r\n",
+        );
+        assert_eq!(out, "red\toklink\n");
+    }
+
+    #[test]
+    fn carriage_returns_end_lines() {
+        let mut plain = PlainText::default();
+        let out = plain.strip(b"10%\r\x1b[K50%\r\x1b[K100%\r\n$ \x07");
+        assert_eq!(out, "10%\n50%\n100%\n$ ");
+    }
+
+    #[test]
+    fn split_input_is_completed() {
+        let mut plain = PlainText::default();
+        let mut out = plain.strip(b"\x1b[3");
+        out += &plain.strip(b"8;5;1m\xc3");
+        assert_eq!(out, "");
+        out += &plain.strip(b"\xa9t\xc3\xa9\r");
+        out += &plain.strip(b"\n");
+        assert_eq!(out, "été\n");
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use wspty_proto::SpawnRequest;
+
+// Decides which commands a client may run, from the requested command
+// (empty for the default shell) and the client identity if authenticated.
+// Returns why the command was refused.
+pub type CommandPolicy = Arc<dyn Fn(&str, Option<&str>) -> Result<(), String> + Send + Sync>;
+
+// Decides what runs for a client's spawn request, from the request, the
+// peer and the client identity if authenticated. Can rewrite the request,
+// e.g. to pin a program's path or arguments, or refuse it with a reason.
+// Runs before the `CommandPolicy`, which sees the rewritten request.
+pub trait SpawnPolicy: Send + Sync {
+    fn check(
+        &self,
+        request: &mut SpawnRequest,
+        peer: SocketAddr,
+        identity: Option<&str>,
+    ) -> Result<(), String>;
+}
+
+// Only lets clients run the listed commands. The empty command is the
+// default shell.
+#[derive(Clone, Debug, Default)]
+pub struct Allowlist {
+    pub commands: HashMap<String, AllowedCommand>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AllowedCommand {
+    // What runs instead, e.g. `["/usr/bin/python3", "-I"]` for `python`,
+    // the command as requested if empty.
+    pub argv: Vec<String>,
+    // Whether clients may add arguments, after `argv`.
+    pub arguments: bool,
+}
+
+impl SpawnPolicy for Allowlist {
+    fn check(
+        &self,
+        request: &mut SpawnRequest,
+        _peer: SocketAddr,
+        _identity: Option<&str>,
+    ) -> Result<(), String> {
+        let allowed = self
+            .commands
+            .get(request.program())
+            .ok_or_else(|| format!("{} is not allowed", request.program()))?;
+        if request.cmd.len() > 1 && !allowed.arguments {
+            return Err(format!("{} takes no arguments", request.program()));
+        }
+        if !allowed.argv.is_empty() {
+            let arguments = request.cmd.iter().skip(1).cloned();
+            request.cmd = allowed.argv.iter().cloned().chain(arguments).collect();
+        }
+        Ok(())
+    }
+}
+
+// Decides which environment variables a client may set in its session,
+// from the variable name and the client identity if authenticated. See
+// the `env` module.
+pub type EnvironmentPolicy = Arc<dyn Fn(&str, Option<&str>) -> bool + Send + Sync>;
+
+// Run instead of commands refused by the `CommandPolicy`, rather than
+// closing the connection.
+#[derive(Clone, Debug)]
+pub struct FallbackShell {
+    // E.g. `/bin/rbash`, not checked against the policy.
+    pub command: String,
+    // Shown instead of the default explanation, before the shell output.
+    pub banner: Option<String>,
+}
+
+impl FallbackShell {
+    pub(crate) fn banner(&self, command: &str, reason: &str) -> String {
+        match self.banner {
+            Some(ref banner) => banner.clone(),
+            None => {
+                let command = if command.is_empty() {
+                    "the default shell"
+                } else {
+                    command
+                };
+                format!(
+                    "{} is not allowed: {}\r\nStarting {} instead.\r\n",
+                    command, reason, self.command
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::server::spawn_shell;
+use crate::workspace::Provisioned;
+use crate::{PtyMaster, ServerConfig, SpawnInfo};
+use log::error;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+
+// Dropping the stop sender kills the child.
+pub(crate) struct Warm {
+    pub(crate) master: PtyMaster,
+    pub(crate) stopper: UnboundedSender<()>,
+    pub(crate) spawn: SpawnInfo,
+    pub(crate) workspace: Option<Arc<Provisioned>>,
+}
+
+struct Pool {
+    command: String,
+    size: usize,
+    ready: VecDeque<Warm>,
+    spawning: usize,
+}
+
+// Shells spawned ahead of time for one command, handed to connections
+// asking for it so they don't wait for the shell startup. Whatever the shell
+// prints meanwhile stays in the pty until the session reads it.
+#[derive(Clone)]
+pub struct SessionPool {
+    inner: Arc<Mutex<Pool>>,
+}
+
+impl SessionPool {
+    // An empty `command` stands for the default one.
+    pub fn new(command: &str, size: usize) -> Self {
+        SessionPool {
+            inner: Arc::new(Mutex::new(Pool {
+                command: command.to_owned(),
+                size,
+                ready: VecDeque::new(),
+                spawning: 0,
+            })),
+        }
+    }
+
+    pub(crate) fn command(&self) -> String {
+        self.inner.lock().unwrap().command.clone()
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+
+    pub fn ready(&self) -> usize {
+        self.inner.lock().unwrap().ready.len()
+    }
+
+    // Spawns shells until the pool is full again.
+    pub(crate) fn fill(&self, config: &Arc<ServerConfig>) {
+        let command = {
+            let mut pool = self.inner.lock().unwrap();
+            let missing = pool.size.saturating_sub(pool.ready.len() + pool.spawning);
+            if missing == 0 {
+                return;
+            }
+            pool.spawning += missing;
+            (0..missing)
+                .map(|_| pool.command.clone())
+                .collect::<Vec<_>>()
+        };
+        for command in command {
+            let pool = self.clone();
+            let config = config.clone();
+            tokio::spawn(async move {
+                let warm = spawn_shell(
+                    &crate::spawn::plain(&command),
+                    &config,
+                    None,
+                    config.default_size,
+                )
+                .await
+                .map_err(|e| error!("failed to pre-spawn {:?}: {:?}", command, e));
+                let mut inner = pool.inner.lock().unwrap();
+                inner.spawning -= 1;
+                if let (Ok(warm), true) = (warm, inner.ready.len() < inner.size) {
+                    inner.ready.push_back(warm);
+                }
+            });
+        }
+    }
+
+    // Kills the ready shells and stops spawning more, once the server is
+    // shutting down.
+    pub(crate) fn drain(&self) {
+        let mut pool = self.inner.lock().unwrap();
+        pool.size = 0;
+        pool.ready.clear();
+    }
+
+    // Takes a ready shell if `command` is the pooled one, and starts
+    // spawning its replacement.
+    pub(crate) fn claim(&self, command: &str, config: &Arc<ServerConfig>) -> Option<Warm> {
+        let warm = {
+            let mut pool = self.inner.lock().unwrap();
+            if pool.command != command {
+                return None;
+            }
+            // Skip shells that died while waiting.
+            pool.ready
+                .retain(|warm| !warm.master.closed.load(Ordering::SeqCst));
+            pool.ready.pop_front()
+        };
+        self.fill(config);
+        warm
+    }
+}
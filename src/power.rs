@@ -0,0 +1,43 @@
+// Low-power mode, for battery powered or metered clients attached to mostly
+// idle sessions, phones for instance. Clients ask for it with the
+// `X-Power-Mode: low` header or the `power=low` query parameter of the
+// upgrade request, servers with `ServerConfig::low_power` set grant it by
+// sending the header back. Their sessions then get pings no more often than
+// `ping_interval`, no latency traces, and pty output held up to `batching`
+// to go out in fewer, larger messages, which typing feels. The mode stays
+// with the session when it is resumed or migrated.
+
+use crate::{ServerConfig, SessionHandle, Timeouts};
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct LowPower {
+    pub ping_interval: Duration,
+    pub batching: Duration,
+}
+
+impl Default for LowPower {
+    fn default() -> Self {
+        LowPower {
+            ping_interval: Duration::from_secs(5 * 60),
+            batching: Duration::from_millis(100),
+        }
+    }
+}
+
+impl LowPower {
+    pub(crate) fn timeouts(&self, mut timeouts: Timeouts) -> Timeouts {
+        timeouts.ping_interval = timeouts
+            .ping_interval
+            .map(|interval| interval.max(self.ping_interval));
+        timeouts
+    }
+}
+
+// The mode of the session, if its client got it.
+pub(crate) fn granted<'a>(
+    config: &'a ServerConfig,
+    handle: &SessionHandle,
+) -> Option<&'a LowPower> {
+    config.low_power.as_ref().filter(|_| handle.low_power)
+}
@@ -0,0 +1,136 @@
+// Who follows a shared session and what they are doing, with
+// `ServerConfig::presence`: its client and the embedder's attachments,
+// numbered as in the `arbitration` module, then whether they are typing,
+// just watching or away. Everyone gets the changes, the client in
+// `proto::Presence` messages and attachments from `Attachment::presence`,
+// so that frontends can show who else is there.
+
+use crate::arbitration::CLIENT;
+use crate::outbox::Outbox;
+use crate::SessionHandle;
+use log::debug;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+use wspty_proto::{Activity, Member, Presence, ServerMessage};
+
+// Since the last input.
+const TYPING: Duration = Duration::from_secs(2);
+const IDLE: Duration = Duration::from_secs(60);
+// How often activities are brought up to date.
+const TICK: Duration = Duration::from_secs(1);
+
+struct Participant {
+    name: Option<String>,
+    // When it joined until it types.
+    last_input: Instant,
+    typed: bool,
+    activity: Activity,
+}
+
+impl Participant {
+    fn new(name: Option<String>) -> Self {
+        Participant {
+            name,
+            last_input: Instant::now(),
+            typed: false,
+            activity: Activity::Active,
+        }
+    }
+}
+
+pub(crate) struct Roster {
+    participants: BTreeMap<u64, Participant>,
+}
+
+impl Default for Roster {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Roster {
+    // With the client, named `name`.
+    pub(crate) fn new(name: Option<String>) -> Self {
+        Roster {
+            participants: std::iter::once((CLIENT, Participant::new(name))).collect(),
+        }
+    }
+
+    pub(crate) fn join(&mut self, participant: u64, name: Option<String>) {
+        self.participants
+            .insert(participant, Participant::new(name));
+    }
+
+    pub(crate) fn leave(&mut self, participant: u64) {
+        self.participants.remove(&participant);
+    }
+
+    // Returns whether that changed the activity of `participant`.
+    pub(crate) fn typed(&mut self, participant: u64) -> bool {
+        match self.participants.get_mut(&participant) {
+            Some(participant) => {
+                participant.last_input = Instant::now();
+                participant.typed = true;
+                let changed = participant.activity != Activity::Typing;
+                participant.activity = Activity::Typing;
+                changed
+            }
+            None => false,
+        }
+    }
+
+    // Returns whether any activity changed with time.
+    fn update(&mut self, now: Instant) -> bool {
+        let mut changed = false;
+        for participant in self.participants.values_mut() {
+            let activity = match now.duration_since(participant.last_input) {
+                quiet if quiet < TYPING && participant.typed => Activity::Typing,
+                quiet if quiet < IDLE => Activity::Active,
+                _ => Activity::Idle,
+            };
+            changed |= activity != participant.activity;
+            participant.activity = activity;
+        }
+        changed
+    }
+
+    pub(crate) fn members(&self) -> Vec<Member> {
+        self.participants
+            .iter()
+            .map(|(&id, participant)| Member {
+                id,
+                name: participant.name.clone(),
+                activity: participant.activity,
+            })
+            .collect()
+    }
+}
+
+// Keeps the activities up to date and tells the client about each change,
+// until the session is over.
+pub(crate) async fn watch(handle: SessionHandle, sender: Outbox) {
+    let mut roster = handle.roster.subscribe();
+    let mut ticker = tokio::time::interval(TICK);
+    loop {
+        let participants = roster.borrow_and_update().members();
+        let msg = ServerMessage::Presence(Presence { participants }).encode();
+        if sender.send(Message::Binary(msg)).is_err() {
+            break;
+        }
+        let changed = loop {
+            tokio::select! {
+                res = roster.changed() => break res.is_ok(),
+                now = ticker.tick() => {
+                    handle
+                        .roster
+                        .send_if_modified(|roster| roster.update(now.into_std()));
+                }
+            }
+        };
+        if !changed {
+            break;
+        }
+    }
+    debug!("session {} presence done", handle);
+}
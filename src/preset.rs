@@ -0,0 +1,67 @@
+// Bundles of settings for new deployments, applied with
+// `ServerConfig::with_preset` before the deployment's own: opting out of one
+// of them is resetting its field afterwards. `Hardened` only lets clients
+// run the default command, without arguments or environment variables of
+// their own, starts it with a scrubbed environment (see
+// `ServerConfig::clear_env`) and resource limits, closes sessions idle for
+// `IDLE_TIMEOUT`, refuses browsers until `allowed_origins` lists their
+// origins and refuses clients of listeners without authentication.
+
+use crate::{ResourceLimits, ServerConfig};
+use std::sync::Arc;
+use std::time::Duration;
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const LIMITS: ResourceLimits = ResourceLimits {
+    processes: Some(512),
+    open_files: Some(1024),
+    memory: Some(4 << 30),
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityPreset {
+    Hardened,
+}
+
+impl SecurityPreset {
+    pub(crate) fn apply(self, config: &mut ServerConfig) {
+        match self {
+            SecurityPreset::Hardened => {
+                config.command_policy = Some(Arc::new(|command, _| {
+                    if command.is_empty() {
+                        Ok(())
+                    } else {
+                        Err(format!("{} is not allowed", command))
+                    }
+                }));
+                config.spawn_policy = None;
+                config.environment_policy = None;
+                config.clear_env = true;
+                config.resource_limits = Some(LIMITS);
+                config.timeouts.idle = Some(IDLE_TIMEOUT);
+                config.allowed_origins = Some(vec![]);
+                config.require_auth = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn hardened() {
+        let config = ServerConfig::default().with_preset(SecurityPreset::Hardened);
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        assert!(config.admit(peer, Some("alice"), "").allowed);
+        assert!(!config.admit(peer, Some("alice"), "/bin/sh").allowed);
+        let request = r#"{"cmd":[],"env":{"LD_PRELOAD":"/tmp/x.so"}}"#;
+        assert!(!config.admit(peer, Some("alice"), request).allowed);
+        assert!(config.clear_env && config.require_auth);
+        assert_eq!(config.allowed_origins, Some(vec![]));
+        assert_eq!(config.timeouts.idle, Some(IDLE_TIMEOUT));
+        assert!(config.resource_limits.is_some());
+    }
+}
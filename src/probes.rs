@@ -0,0 +1,147 @@
+// Startup probes, for sessions whose environment is broken (a missing
+// toolchain, a dead NFS home) to fail with a `proto::ProbeFailure` saying
+// what is wrong instead of a shell erroring in confusing ways. The probes of
+// `ServerConfig::probes`, or of the profile picked, run one after the other
+// before the command is started, through `sh -c` with its account,
+// environment and working directory, clients being told with `probing`
+// `proto::Progress` messages. A probe fails when it exits with another code
+// than 0, takes longer than its `timeout` or doesn't print what `expect`
+// matches. Resumed or migrated sessions aren't probed again.
+
+use crate::user::Account;
+use crate::ServerConfig;
+use futures::{Sink, SinkExt, Stream};
+use log::warn;
+use regex::Regex;
+use std::net::SocketAddr;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::protocol::CloseReason;
+use wspty_proto::{ProbeFailure, Progress, ServerMessage, Stage};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// Of the output sent back in `ProbeFailure`.
+const MAX_OUTPUT: usize = 2048;
+
+#[derive(Clone, Debug, Default)]
+pub struct Probe {
+    pub name: String,
+    // Run with `sh -c`, e.g. `test -w ~ && cargo --version`.
+    pub command: String,
+    // Looked for in its stdout, then stderr.
+    pub expect: Option<Regex>,
+    // 5 seconds if unset.
+    pub timeout: Option<Duration>,
+}
+
+impl Probe {
+    async fn check(
+        &self,
+        config: &ServerConfig,
+        account: Option<&Account>,
+    ) -> Result<(), ProbeFailure> {
+        let mut failure = ProbeFailure {
+            name: self.name.clone(),
+            command: self.command.clone(),
+            exit_code: None,
+            output: String::new(),
+            expected: None,
+            error: String::new(),
+        };
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(&self.command);
+        config.prepare(&mut cmd, account);
+        if let Some(account) = account.cloned() {
+            unsafe {
+                cmd.pre_exec(move || account.switch());
+            }
+        }
+        cmd.stdin(Stdio::null()).kill_on_drop(true);
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let output = match tokio::time::timeout(timeout, cmd.output()).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                failure.error = e.to_string();
+                return Err(failure);
+            }
+            Err(_) => {
+                failure.error = format!("timed out after {:?}", timeout);
+                return Err(failure);
+            }
+        };
+        let mut printed = output.stdout;
+        printed.extend_from_slice(&output.stderr);
+        let printed = String::from_utf8_lossy(&printed);
+        failure.exit_code = output.status.code();
+        failure.error = match (output.status.code(), output.status.signal()) {
+            (Some(0), _) => match self.expect {
+                Some(ref expect) if !expect.is_match(&printed) => {
+                    failure.expected = Some(expect.as_str().to_owned());
+                    "unexpected output".to_owned()
+                }
+                _ => return Ok(()),
+            },
+            (Some(code), _) => format!("exited with {}", code),
+            (None, signal) => format!("killed by signal {}", signal.unwrap_or_default()),
+        };
+        let mut end = printed.len().min(MAX_OUTPUT);
+        while !printed.is_char_boundary(end) {
+            end -= 1;
+        }
+        failure.output = printed[..end].to_owned();
+        Err(failure)
+    }
+}
+
+// Runs the probes of `config` for a client, telling it how it goes. Returns
+// whether the session can start, the connection being closed otherwise.
+pub(crate) async fn run<O, I>(
+    config: &ServerConfig,
+    account: Option<&Account>,
+    peer: SocketAddr,
+    ws_outgoing: &mut O,
+    ws_incoming: &mut I,
+) -> Result<bool, anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    for probe in config.probes.iter() {
+        let progress = Progress {
+            stage: Stage::Probing,
+            detail: Some(probe.name.clone()),
+        };
+        ws_outgoing
+            .send(Message::Binary(ServerMessage::Progress(progress).encode()))
+            .await?;
+        let failure = match probe.check(config, account).await {
+            Ok(()) => continue,
+            Err(failure) => failure,
+        };
+        warn!(
+            "probe {} failed for {:?}: {}",
+            failure.name, peer, failure.error
+        );
+        if let Some(ref events) = config.events {
+            events.on_probe_failure(peer, &failure);
+        }
+        let reason = CloseReason::ProbeFailed(failure.name.clone());
+        ws_outgoing
+            .send(Message::Binary(
+                ServerMessage::ProbeFailed(failure).encode(),
+            ))
+            .await?;
+        let farewell = CloseFrame {
+            code: CloseCode::Error,
+            reason: reason.to_string().into(),
+        };
+        crate::teardown::refuse(ws_outgoing, ws_incoming, farewell, config.teardown).await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
@@ -0,0 +1,64 @@
+// The processes of a session, from `/proc`. Only implemented on Linux,
+// elsewhere nothing is found.
+
+pub(crate) struct Process {
+    pub(crate) pid: libc::pid_t,
+    pub(crate) comm: String,
+    // As in proc(5), `T` for stopped.
+    pub(crate) state: char,
+    pub(crate) pgrp: libc::pid_t,
+    // User and system time, and the time of the children already waited
+    // for, in clock ticks.
+    pub(crate) ticks: u64,
+}
+
+// The processes of session `sid`, `None` if `/proc` can't be read.
+pub(crate) fn session_processes(sid: libc::pid_t) -> Option<Vec<Process>> {
+    let mut processes = vec![];
+    for entry in std::fs::read_dir("/proc").ok()? {
+        let path = match entry {
+            Ok(entry) => entry.path().join("stat"),
+            Err(_) => continue,
+        };
+        // Processes come and go while iterating.
+        let stat = match std::fs::read_to_string(path) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // The command name is in parentheses and can contain anything.
+        let (head, rest) = match stat.rsplit_once(')') {
+            Some(split) => split,
+            None => continue,
+        };
+        let (pid, comm) = match head.split_once(" (") {
+            Some((pid, comm)) => (pid.parse().ok(), comm),
+            None => continue,
+        };
+        // Fields from the state on, see proc(5).
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        if fields.get(3).and_then(|s| s.parse().ok()) != Some(sid) {
+            continue;
+        }
+        let (pid, state, pgrp) = match (pid, fields.first(), fields.get(2)) {
+            (Some(pid), Some(state), Some(pgrp)) => match pgrp.parse() {
+                Ok(pgrp) => (pid, state.chars().next().unwrap_or('?'), pgrp),
+                Err(_) => continue,
+            },
+            _ => continue,
+        };
+        let ticks = fields
+            .iter()
+            .skip(11)
+            .take(4)
+            .filter_map(|s| s.parse::<u64>().ok())
+            .sum();
+        processes.push(Process {
+            pid,
+            comm: comm.to_owned(),
+            state,
+            pgrp,
+            ticks,
+        });
+    }
+    Some(processes)
+}
@@ -0,0 +1,140 @@
+// Profile inheritance, for deployments with dozens of profiles differing in
+// a few settings. A profile with a `base` starts from the profile of that
+// name in `ServerConfig::profiles`, resolved the same way: the fields it
+// leaves unset are the base's, its `env` is added to the base's, its own
+// variables winning, and its `probes` run after the base's. Chains are at
+// most `MAX_DEPTH` long. Unknown bases and cycles are reported by
+// `ServerConfig::validate`, clients picking such a profile are refused.
+
+use crate::Profile;
+use std::collections::HashMap;
+
+const MAX_DEPTH: usize = 16;
+
+impl Profile {
+    // Takes what it leaves unset from `base`.
+    fn inherit(&mut self, base: &Profile) {
+        if self.default_command.is_none() {
+            self.default_command = base.default_command.clone();
+        }
+        if self.working_dir.is_none() {
+            self.working_dir = base.working_dir.clone();
+        }
+        for (name, value) in base.env.iter() {
+            if !self.env.contains_key(name) {
+                self.env.insert(name.clone(), value.clone());
+            }
+        }
+        if self.startup.is_none() {
+            self.startup = base.startup.clone();
+        }
+        if self.default_size.is_none() {
+            self.default_size = base.default_size;
+        }
+        if self.devices.is_none() {
+            self.devices = base.devices.clone();
+        }
+        let probes = std::mem::take(&mut self.probes);
+        self.probes = base.probes.iter().cloned().chain(probes).collect();
+    }
+}
+
+// The profile `name` with what it inherits, without a `base`.
+pub(crate) fn resolve(profiles: &HashMap<String, Profile>, name: &str) -> Result<Profile, String> {
+    let mut profile = profiles
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("no such profile: {}", name))?;
+    let mut chain = vec![name.to_owned()];
+    while let Some(base) = profile.base.take() {
+        if chain.contains(&base) {
+            return Err(format!("profile {} inherits from itself", name));
+        }
+        if chain.len() == MAX_DEPTH {
+            return Err(format!("profile {} has over {} bases", name, MAX_DEPTH));
+        }
+        let parent = profiles
+            .get(&base)
+            .ok_or_else(|| format!("no such base profile: {}", base))?;
+        profile.inherit(parent);
+        profile.base = parent.base.clone();
+        chain.push(base);
+    }
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Probe;
+
+    fn probe(name: &str) -> Probe {
+        Probe {
+            name: name.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn inheritance() {
+        let mut profiles = HashMap::new();
+        let mut base = Profile {
+            default_command: Some("/bin/bash".to_owned()),
+            startup: Some("cd /src".to_owned()),
+            probes: vec![probe("home")],
+            ..Default::default()
+        };
+        base.env.insert("LANG".to_owned(), "C.UTF-8".to_owned());
+        base.env
+            .insert("RUSTUP_TOOLCHAIN".to_owned(), "stable".to_owned());
+        profiles.insert("base".to_owned(), base);
+        let mut nightly = Profile {
+            base: Some("base".to_owned()),
+            startup: Some("cd /nightly".to_owned()),
+            probes: vec![probe("cargo")],
+            ..Default::default()
+        };
+        nightly
+            .env
+            .insert("RUSTUP_TOOLCHAIN".to_owned(), "nightly".to_owned());
+        profiles.insert("nightly".to_owned(), nightly);
+        profiles.insert(
+            "gpu".to_owned(),
+            Profile {
+                base: Some("nightly".to_owned()),
+                devices: Some(vec!["/dev/dri".to_owned()]),
+                ..Default::default()
+            },
+        );
+        let gpu = resolve(&profiles, "gpu").unwrap();
+        assert_eq!(gpu.base, None);
+        assert_eq!(gpu.default_command.as_deref(), Some("/bin/bash"));
+        assert_eq!(gpu.startup.as_deref(), Some("cd /nightly"));
+        assert_eq!(gpu.env["RUSTUP_TOOLCHAIN"], "nightly");
+        assert_eq!(gpu.env["LANG"], "C.UTF-8");
+        assert_eq!(gpu.devices, Some(vec!["/dev/dri".to_owned()]));
+        let probes: Vec<_> = gpu.probes.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(probes, ["home", "cargo"]);
+    }
+
+    #[test]
+    fn broken_chains() {
+        let mut profiles = HashMap::new();
+        let based = |base: &str| Profile {
+            base: Some(base.to_owned()),
+            ..Default::default()
+        };
+        profiles.insert("a".to_owned(), based("b"));
+        profiles.insert("b".to_owned(), based("a"));
+        profiles.insert("c".to_owned(), based("missing"));
+        assert!(resolve(&profiles, "a").is_err());
+        assert!(resolve(&profiles, "c").is_err());
+        assert!(resolve(&profiles, "d").is_err());
+        for i in 0..MAX_DEPTH {
+            profiles.insert(i.to_string(), based(&(i + 1).to_string()));
+        }
+        profiles.insert(MAX_DEPTH.to_string(), Profile::default());
+        assert!(resolve(&profiles, "1").is_ok());
+        assert!(resolve(&profiles, "0").is_err());
+    }
+}
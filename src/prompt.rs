@@ -0,0 +1,77 @@
+// Input held back until the shell prompt shows, for attachments driven by
+// bots (see `Attachment::expect_prompt`): typing a command while something
+// else runs would hand it to that program instead, a password prompt for
+// instance. The prompt is matched against the line the cursor is on, up to
+// the cursor, so it should be anchored with `$`. Inputs go in turn, each
+// waiting for the prompt to come back in the output following the one
+// before, and fail with `ErrorKind::TimedOut` when it doesn't in time.
+
+use crate::SessionHandle;
+use regex::Regex;
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::{Mutex, MutexGuard};
+
+pub(crate) struct PromptGuard {
+    prompt: Regex,
+    timeout: Duration,
+    turn: Mutex<Turn>,
+}
+
+pub(crate) struct Turn {
+    // The output since the last input.
+    output: Receiver<Vec<u8>>,
+    typed: bool,
+}
+
+impl PromptGuard {
+    pub(crate) fn new(handle: &SessionHandle, prompt: Regex, timeout: Duration) -> Self {
+        let turn = Turn {
+            output: handle.output.subscribe(),
+            typed: false,
+        };
+        PromptGuard {
+            prompt,
+            timeout,
+            turn: Mutex::new(turn),
+        }
+    }
+
+    // Waits for the turn of the input and the prompt, the input must be
+    // written before the turn is dropped.
+    pub(crate) async fn wait(
+        &self,
+        handle: &SessionHandle,
+    ) -> Result<MutexGuard<'_, Turn>, IoError> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        let mut turn = tokio::time::timeout_at(deadline, self.turn.lock())
+            .await
+            .map_err(|_| IoError::new(ErrorKind::TimedOut, "earlier input still queued"))?;
+        // What is on the screen before the first input, the shell may have
+        // printed its prompt already.
+        let mut fresh = !turn.typed;
+        loop {
+            if fresh && self.at_prompt(handle) {
+                break;
+            }
+            match tokio::time::timeout_at(deadline, turn.output.recv()).await {
+                Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => fresh = true,
+                Ok(Err(RecvError::Closed)) => {
+                    return Err(IoError::new(ErrorKind::BrokenPipe, "session over"))
+                }
+                Err(_) => return Err(IoError::new(ErrorKind::TimedOut, "no prompt")),
+            }
+        }
+        // From now on, for the next input to wait for what this one prints.
+        turn.output = turn.output.resubscribe();
+        turn.typed = true;
+        Ok(turn)
+    }
+
+    fn at_prompt(&self, handle: &SessionHandle) -> bool {
+        let line = handle.vt.lock().unwrap().cursor_line();
+        self.prompt.is_match(&line)
+    }
+}
@@ -0,0 +1,102 @@
+// Gateway mode: sessions matching a `ProxyRoute` are forwarded to another
+// wspty server instead of spawning anything locally. Messages are relayed
+// as they are both ways, so the client speaks to the downstream server's
+// session (frame mode, integrity, themes...) as if it was connected there.
+// Admission checks and the session limit still apply on the gateway.
+// Resumes go the same way to the server holding the session, see the
+// `routing` module.
+
+use crate::tunnel::Tunnel;
+use crate::Dialer;
+use futures::{future, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use tokio_tungstenite::connect_async;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::protocol::CloseReason;
+
+#[derive(Clone, Debug)]
+pub struct ProxyRoute {
+    // Matches the commands starting with it, stripped from the command
+    // sent downstream. "team-a/" sends "team-a/htop" as "htop", and
+    // "team-a/" alone as the default shell.
+    pub prefix: String,
+    // Only matches this client identity, if set.
+    pub identity: Option<String>,
+    // WebSocket URL of the downstream server, e.g. `ws://10.0.0.2:7703/`.
+    pub upstream: String,
+    // Proxy to reach the downstream server through, see the `tunnel`
+    // module.
+    pub tunnel: Option<Tunnel>,
+}
+
+// The first route matching, with the command to send downstream.
+pub(crate) fn route<'a>(
+    routes: &'a [ProxyRoute],
+    command: &'a str,
+    identity: Option<&str>,
+) -> Option<(&'a ProxyRoute, &'a str)> {
+    routes.iter().find_map(|route| {
+        if route.identity.is_some() && route.identity.as_deref() != identity {
+            return None;
+        }
+        command
+            .strip_prefix(route.prefix.as_str())
+            .map(|command| (route, command))
+    })
+}
+
+pub(crate) async fn serve_proxy<O, I>(
+    mut ws_outgoing: O,
+    ws_incoming: I,
+    route: &ProxyRoute,
+    // The command, or whatever else opens the session downstream, and its
+    // description for the logs.
+    first: Message,
+    what: &str,
+    peer: SocketAddr,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let connected = match route.tunnel {
+        Some(ref tunnel) => {
+            let dialer = Dialer {
+                tunnel: Some(tunnel.clone()),
+                ..Default::default()
+            };
+            dialer.connect_websocket(&route.upstream).await
+        }
+        None => connect_async(route.upstream.as_str()).await,
+    };
+    let downstream = match connected {
+        Ok((downstream, _)) => downstream,
+        Err(e) => {
+            warn!("failed to reach {} for {:?}: {:?}", route.upstream, peer, e);
+            ws_outgoing
+                .send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Again,
+                    reason: CloseReason::UpstreamUnavailable.to_string().into(),
+                })))
+                .await?;
+            return Ok(());
+        }
+    };
+    info!("proxying {} to {} for {:?}", what, route.upstream, peer);
+    let (mut downstream_outgoing, downstream_incoming) = downstream.split();
+    downstream_outgoing.send(first).await?;
+
+    // Pings are answered on each hop.
+    let relayed = |msg: &Message| future::ready(!msg.is_ping() && !msg.is_pong());
+    let upstream = ws_incoming.try_filter(relayed).forward(downstream_outgoing);
+    let downstream = downstream_incoming.try_filter(relayed).forward(ws_outgoing);
+    let res = tokio::select! {
+        res = upstream => res,
+        res = downstream => res,
+    };
+    debug!("proxy for {:?} done: {:?}", peer, res);
+    Ok(())
+}
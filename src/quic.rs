@@ -0,0 +1,203 @@
+// Experimental QUIC transport.
+//
+// The client opens one bidirectional stream carrying the same frames as the
+// WebSocket binary messages, each prefixed by its length as a big endian
+// u32. The first frame is the command to run, and may be empty to get the
+// default one.
+//
+// Keystrokes can also be sent as datagrams made of a big endian u32
+// sequence number followed by the input bytes. The server applies them in
+// order, drops duplicates and answers each datagram with one carrying the
+// last applied sequence number, so clients know what to retransmit.
+
+use crate::auth::Handshake;
+use crate::server::serve_session;
+use crate::ServerConfig;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
+use log::{debug, error};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::rustls::pki_types::pem::PemObject;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use quinn::{Connection, Endpoint, Incoming, RecvStream, SendStream};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tungstenite::{Error as WsError, Message};
+use wspty_proto as proto;
+
+pub const ALPN: &[u8] = b"wspty";
+
+const MAX_FRAME_LEN: usize = 1 << 20;
+const MAX_PENDING_DATAGRAMS: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct QuicConfig {
+    pub addr: SocketAddr,
+    // PEM encoded certificate chain and private key.
+    pub cert_chain: PathBuf,
+    pub private_key: PathBuf,
+}
+
+impl QuicConfig {
+    pub(crate) fn load(&self) -> Result<quinn::ServerConfig, anyhow::Error> {
+        let certs =
+            CertificateDer::pem_file_iter(&self.cert_chain)?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(&self.private_key)?;
+        let mut tls = quinn::rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        Ok(quinn::ServerConfig::with_crypto(Arc::new(
+            QuicServerConfig::try_from(tls)?,
+        )))
+    }
+}
+
+pub(crate) async fn serve(
+    config: Arc<ServerConfig>,
+    quic: QuicConfig,
+) -> Result<(), anyhow::Error> {
+    let endpoint = Endpoint::server(quic.load()?, quic.addr)?;
+    while let Some(incoming) = endpoint.accept().await {
+        let config = config.clone();
+        let fut = async move {
+            let _ = handle_connection(incoming, config)
+                .await
+                .map_err(|e| error!("handle quic connection error: {:?}", e));
+        };
+        tokio::spawn(fut);
+    }
+    Ok(())
+}
+
+async fn handle_connection(
+    incoming: Incoming,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error> {
+    let connection = incoming.await?;
+    let peer = connection.remote_address();
+    debug!("handling quic connection from {:?}", peer);
+    let (send, mut recv) = connection.accept_bi().await?;
+
+    let command = read_frame(&mut recv).await?.unwrap_or_default();
+    let command = Message::Text(String::from_utf8(command)?);
+
+    let frames = stream::unfold(recv, |mut recv| async move {
+        match read_frame(&mut recv).await {
+            Ok(Some(frame)) => Some((Ok(Message::Binary(frame)), recv)),
+            Ok(None) => None,
+            Err(e) => Some((Err(WsError::Io(e)), recv)),
+        }
+    });
+    let keystrokes = stream::unfold(
+        (connection.clone(), Reorder::default()),
+        |(connection, mut reorder)| async move {
+            let input = next_keystrokes(&connection, &mut reorder).await?;
+            Some((
+                stream::iter(input.into_iter().map(Ok)),
+                (connection, reorder),
+            ))
+        },
+    )
+    .flatten();
+    let incoming = stream::once(async { Ok(command) }).chain(stream::select(frames, keystrokes));
+
+    let outgoing = Box::pin(futures::sink::unfold(
+        send,
+        |mut send: SendStream, msg: Message| async move {
+            let data = match msg {
+                Message::Binary(data) => data,
+                Message::Text(text) => text.into_bytes(),
+                _ => return Ok(send),
+            };
+            write_frame(&mut send, &data).await.map_err(WsError::Io)?;
+            Ok::<_, WsError>(send)
+        },
+    ));
+
+    serve_session(
+        outgoing,
+        Box::pin(incoming),
+        peer,
+        Handshake::default(),
+        config,
+        None,
+    )
+    .await
+}
+
+async fn read_frame(recv: &mut RecvStream) -> Result<Option<Vec<u8>>, IoError> {
+    let mut len = [0u8; 4];
+    match AsyncReadExt::read_exact(recv, &mut len).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(IoError::new(ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut frame = vec![0u8; len];
+    AsyncReadExt::read_exact(recv, &mut frame).await?;
+    Ok(Some(frame))
+}
+
+async fn write_frame(send: &mut SendStream, data: &[u8]) -> Result<(), IoError> {
+    AsyncWriteExt::write_all(send, &proto::length_prefixed(data)).await
+}
+
+// Waits for the next datagrams that can be applied in order, acking them.
+async fn next_keystrokes(connection: &Connection, reorder: &mut Reorder) -> Option<Vec<Message>> {
+    loop {
+        let datagram = connection.read_datagram().await.ok()?;
+        let seq = match proto::decode_keystrokes(&datagram) {
+            Ok((seq, _)) => seq,
+            Err(_) => continue,
+        };
+        let ready = reorder.push(seq, datagram.slice(4..));
+        let _ = connection.send_datagram(Bytes::copy_from_slice(
+            &reorder.next.wrapping_sub(1).to_be_bytes(),
+        ));
+        if !ready.is_empty() {
+            let input = ready
+                .into_iter()
+                .map(|data| Message::Binary(proto::frame(proto::INPUT, &data)))
+                .collect();
+            return Some(input);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Reorder {
+    next: u32,
+    pending: BTreeMap<u32, Bytes>,
+}
+
+impl Reorder {
+    fn push(&mut self, seq: u32, data: Bytes) -> Vec<Bytes> {
+        let behind = self.next.wrapping_sub(seq);
+        if behind != 0 && behind <= u32::MAX / 2 {
+            // Already applied.
+            return vec![];
+        }
+        self.pending.insert(seq, data);
+        if self.pending.len() > MAX_PENDING_DATAGRAMS {
+            // Give up on the missing ones rather than buffering forever.
+            if let Some(first) = self.pending.keys().next() {
+                self.next = *first;
+            }
+        }
+        let mut ready = vec![];
+        while let Some(data) = self.pending.remove(&self.next) {
+            ready.push(data);
+            self.next = self.next.wrapping_add(1);
+        }
+        ready
+    }
+}
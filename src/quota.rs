@@ -0,0 +1,129 @@
+// Limits on what sessions write to their workspace, for those not on a
+// tmpfs, its size limiting them already. Each workspace gets a project id
+// of its own, inherited by everything created in it, and the filesystem
+// refuses writes once the project is over its limit. It must be mounted
+// with project quotas on (`prjquota` for XFS, the `project` and `quota`
+// features for ext4), and the server run as root. Quotas are set through
+// `quotactl_fd`, Linux 5.14 or later.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Error as IoError;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+
+// Project ids given to live workspaces, by any `Quota`.
+static IN_USE: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+const PRJQUOTA: libc::c_int = 2;
+const FS_XFLAG_PROJINHERIT: u32 = 0x200;
+// The kernel counts limits in blocks of that many bytes.
+const QUOTA_BLOCK: u64 = 1024;
+
+#[derive(Clone, Debug)]
+pub struct Quota {
+    // What each session can write.
+    pub bytes: u64,
+    // Given to workspaces, one each while they exist. They must not be used
+    // for anything else on the filesystem.
+    pub projects: Range<u32>,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct FsXattr {
+    xflags: u32,
+    extsize: u32,
+    nextents: u32,
+    projid: u32,
+    cowextsize: u32,
+    pad: [u8; 8],
+}
+
+impl Quota {
+    // Limits what gets written in `dir`, returns the project id it was
+    // given, to `release` it.
+    pub(crate) fn apply(&self, dir: &Path) -> Result<u32, IoError> {
+        let project = {
+            let mut in_use = IN_USE.lock().unwrap();
+            let project = self
+                .projects
+                .clone()
+                .find(|id| !in_use.contains(id))
+                .ok_or_else(|| IoError::other("no project id left for the quota"))?;
+            in_use.insert(project);
+            project
+        };
+        let res = set_project(dir, project).and_then(|()| set_limit(dir, project, self.bytes));
+        if let Err(e) = res {
+            IN_USE.lock().unwrap().remove(&project);
+            return Err(e);
+        }
+        Ok(project)
+    }
+}
+
+// Lifts the limit of `project` once its workspace is gone, `dir` being any
+// path of the same filesystem.
+pub(crate) fn release(dir: &Path, project: u32) -> Result<(), IoError> {
+    let res = set_limit(dir, project, 0);
+    IN_USE.lock().unwrap().remove(&project);
+    res
+}
+
+// What the project uses, in bytes.
+pub(crate) fn usage(dir: &Path, project: u32) -> Result<u64, IoError> {
+    let mut quota: libc::dqblk = unsafe { std::mem::zeroed() };
+    quotactl(dir, libc::Q_GETQUOTA, project, &mut quota)?;
+    Ok(quota.dqb_curspace)
+}
+
+fn set_project(dir: &Path, project: u32) -> Result<(), IoError> {
+    let file = File::open(dir)?;
+    let mut attr = FsXattr::default();
+    let get = nix::request_code_read!(b'X', 31, std::mem::size_of::<FsXattr>());
+    if unsafe { libc::ioctl(file.as_raw_fd(), get as _, &mut attr) } != 0 {
+        return Err(IoError::last_os_error());
+    }
+    attr.projid = project;
+    attr.xflags |= FS_XFLAG_PROJINHERIT;
+    let set = nix::request_code_write!(b'X', 32, std::mem::size_of::<FsXattr>());
+    if unsafe { libc::ioctl(file.as_raw_fd(), set as _, &attr) } != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+// No limit with 0 bytes.
+fn set_limit(dir: &Path, project: u32, bytes: u64) -> Result<(), IoError> {
+    let mut quota: libc::dqblk = unsafe { std::mem::zeroed() };
+    quota.dqb_bhardlimit = bytes.div_ceil(QUOTA_BLOCK);
+    quota.dqb_bsoftlimit = quota.dqb_bhardlimit;
+    quota.dqb_valid = libc::QIF_BLIMITS;
+    quotactl(dir, libc::Q_SETQUOTA, project, &mut quota)
+}
+
+fn quotactl(
+    dir: &Path,
+    command: libc::c_int,
+    project: u32,
+    quota: &mut libc::dqblk,
+) -> Result<(), IoError> {
+    let file = File::open(dir)?;
+    let command = (command << 8) | PRJQUOTA;
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_quotactl_fd,
+            file.as_raw_fd(),
+            command,
+            project,
+            quota as *mut libc::dqblk,
+        )
+    };
+    if res != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
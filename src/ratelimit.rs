@@ -0,0 +1,207 @@
+// Rate limiting of tenant traffic, see `BandwidthCaps::rate`. The ledger
+// defaults to in-memory token buckets, so limits only hold per server
+// instance. `RedisRateLimiter` keeps the buckets in Redis instead, for a
+// fleet behind a load balancer to share them.
+
+use futures::future::BoxFuture;
+use log::warn;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    // Tokens per second.
+    pub rate: u64,
+    // Tokens that can be spent at once after a pause.
+    pub burst: u64,
+}
+
+pub trait RateLimiter: Send + Sync {
+    // Takes `cost` tokens from the bucket of `key`, and returns how long
+    // the caller should wait to stay under `limit`.
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit, cost: u64) -> BoxFuture<'a, Duration>;
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+#[derive(Default)]
+pub struct MemoryRateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryRateLimiter {
+    fn take(&self, key: &str, limit: RateLimit, cost: u64) -> Duration {
+        if limit.rate == 0 {
+            return Duration::ZERO;
+        }
+        let (rate, burst) = (limit.rate as f64, limit.burst as f64);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.refilled = now;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst) - cost as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+impl RateLimiter for MemoryRateLimiter {
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit, cost: u64) -> BoxFuture<'a, Duration> {
+        let delay = self.take(key, limit, cost);
+        Box::pin(async move { delay })
+    }
+}
+
+// Same bucket as `MemoryRateLimiter`, timed with the Redis clock so that
+// servers don't need theirs in sync. Returns the wait in milliseconds.
+const TOKEN_BUCKET_SCRIPT: &str = "\
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local cost = tonumber(ARGV[3])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(state[1]) or burst
+local ts = tonumber(state[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - ts) * rate / 1000) - cost
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil((burst - tokens) * 1000 / rate) + 1000)
+if tokens >= 0 then return 0 end
+return math.ceil(-tokens * 1000 / rate)";
+
+// Local view of a Redis bucket between two round trips.
+struct Pending {
+    cost: u64,
+    synced: Option<Instant>,
+    wait_until: Instant,
+}
+
+// Token buckets stored in Redis under `wspty:rate:<key>`. Costs are
+// accumulated locally and sent at most every `sync_interval` per key, so
+// that output isn't held by a round trip per chunk. When Redis can't be
+// reached traffic isn't limited. Needs Redis 5 or later.
+pub struct RedisRateLimiter {
+    addr: String,
+    password: Option<String>,
+    pub sync_interval: Duration,
+    connection: tokio::sync::Mutex<Option<BufReader<TcpStream>>>,
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+impl RedisRateLimiter {
+    // `addr` is the `host:port` of the Redis server.
+    pub fn new(addr: &str, password: Option<&str>) -> Self {
+        RedisRateLimiter {
+            addr: addr.to_owned(),
+            password: password.map(str::to_owned),
+            sync_interval: Duration::from_millis(100),
+            connection: tokio::sync::Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>, IoError> {
+        let mut connection = BufReader::new(TcpStream::connect(&self.addr).await?);
+        if let Some(ref password) = self.password {
+            command(&mut connection, &["AUTH", password]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn take(&self, key: &str, limit: RateLimit, cost: u64) -> Result<u64, IoError> {
+        let mut connection = self.connection.lock().await;
+        let stream = match *connection {
+            Some(ref mut stream) => stream,
+            None => connection.insert(self.connect().await?),
+        };
+        let key = format!("wspty:rate:{}", key);
+        let (rate, burst, cost) = (
+            limit.rate.to_string(),
+            limit.burst.to_string(),
+            cost.to_string(),
+        );
+        let args = ["EVAL", TOKEN_BUCKET_SCRIPT, "1", &key, &rate, &burst, &cost];
+        let res = command(stream, &args).await;
+        if res.is_err() {
+            *connection = None;
+        }
+        res
+    }
+}
+
+impl RateLimiter for RedisRateLimiter {
+    fn acquire<'a>(&'a self, key: &'a str, limit: RateLimit, cost: u64) -> BoxFuture<'a, Duration> {
+        Box::pin(async move {
+            if limit.rate == 0 {
+                return Duration::ZERO;
+            }
+            let now = Instant::now();
+            let cost = {
+                let mut pending = self.pending.lock().unwrap();
+                let entry = pending.entry(key.to_owned()).or_insert(Pending {
+                    cost: 0,
+                    synced: None,
+                    wait_until: now,
+                });
+                entry.cost += cost;
+                if entry
+                    .synced
+                    .is_some_and(|synced| now.duration_since(synced) < self.sync_interval)
+                {
+                    return entry.wait_until.saturating_duration_since(now);
+                }
+                entry.synced = Some(now);
+                std::mem::take(&mut entry.cost)
+            };
+            let wait = match self.take(key, limit, cost).await {
+                Ok(wait) => Duration::from_millis(wait),
+                Err(e) => {
+                    warn!("failed to apply rate limit with redis: {:?}", e);
+                    Duration::ZERO
+                }
+            };
+            if let Some(entry) = self.pending.lock().unwrap().get_mut(key) {
+                entry.wait_until = now + wait;
+            }
+            wait
+        })
+    }
+}
+
+// Sends `args` as a RESP array and reads an integer or status reply. The
+// connection must be dropped after an error, other replies aren't skipped.
+async fn command(stream: &mut BufReader<TcpStream>, args: &[&str]) -> Result<u64, IoError> {
+    let mut request = format!("*{}\r\n", args.len());
+    for arg in args {
+        request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.get_mut().write_all(request.as_bytes()).await?;
+
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end();
+    match line.split_at(line.len().min(1)) {
+        (":", value) => value
+            .parse()
+            .map_err(|_| IoError::new(ErrorKind::InvalidData, "invalid integer reply")),
+        ("+", _) => Ok(0),
+        ("-", error) => Err(IoError::other(error.to_owned())),
+        _ => Err(IoError::new(ErrorKind::InvalidData, "unexpected reply")),
+    }
+}
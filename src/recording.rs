@@ -0,0 +1,321 @@
+// Session recording. Each session gets a `<id>.json` header and one file
+// per stream: `<id>.output.jsonl`, `<id>.input.jsonl` and
+// `<id>.resize.jsonl`. Lines are `[time, data]` with the time in seconds
+// since the session started, the same clock for all streams, so replay
+// tools can interleave them. With `RecordingFormat::Asciicast` sessions get
+// an asciinema v2 `<id>.cast` file instead, for `asciinema play`. Writes
+// happen on the blocking thread pool so the pty pump never waits on the
+// disk. See the `retention` module to bound the directory size.
+
+use crate::{Retention, SessionHandle, SpawnInfo};
+use log::error;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Error as IoError, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+// Recordings still being written, as `<dir>/<id>`.
+static ACTIVE: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+pub(crate) fn is_active(dir: &Path, id: u64) -> bool {
+    ACTIVE.lock().unwrap().contains(&dir.join(id.to_string()))
+}
+
+#[derive(Clone)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub retention: Option<Retention>,
+    // Encrypt the files for this age recipient, they get an `.age`
+    // extension. Encrypted streams are written in 64KiB chunks, so the end
+    // of a recording can be lost if the server dies.
+    #[cfg(feature = "age")]
+    pub recipient: Option<age::x25519::Recipient>,
+    pub format: RecordingFormat,
+    // Also record what clients type in casts, as `i` events. Passwords
+    // typed at prompts included, the streams format always has them.
+    pub cast_input: bool,
+    // Only record sessions once their client turns it on with a
+    // `proto::Toggle`, see the `toggles` module.
+    pub on_demand: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecordingFormat {
+    #[default]
+    Streams,
+    Asciicast,
+}
+
+#[derive(Clone, Copy)]
+enum Stream {
+    Output,
+    Input,
+    Resize,
+}
+
+impl Stream {
+    // The asciicast event type.
+    fn code(self) -> &'static str {
+        match self {
+            Stream::Output => "o",
+            Stream::Input => "i",
+            Stream::Resize => "r",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Header<'a> {
+    id: u64,
+    session_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation_id: Option<&'a str>,
+    peer: SocketAddr,
+    command: &'a str,
+    // Unix time in seconds.
+    timestamp: u64,
+    spawn: &'a SpawnInfo,
+}
+
+// See https://docs.asciinema.org/manual/asciicast/v2/.
+#[derive(Serialize)]
+struct CastHeader<'a> {
+    version: u8,
+    width: u16,
+    height: u16,
+    timestamp: u64,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    title: &'a str,
+    env: BTreeMap<&'a str, &'a str>,
+}
+
+enum Sink {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "age")]
+    Age(age::stream::StreamWriter<BufWriter<File>>),
+}
+
+impl Sink {
+    fn create(config: &RecordingConfig, path: &Path) -> Result<Self, anyhow::Error> {
+        #[cfg(feature = "age")]
+        if let Some(ref recipient) = config.recipient {
+            let mut path = path.as_os_str().to_owned();
+            path.push(".age");
+            let file = BufWriter::new(File::create(path)?);
+            let encryptor = age::Encryptor::with_recipients(std::iter::once(recipient as _))?;
+            return Ok(Sink::Age(encryptor.wrap_output(file)?));
+        }
+        let _ = config;
+        Ok(Sink::Plain(BufWriter::new(File::create(path)?)))
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Sink::Plain(file) => file,
+            #[cfg(feature = "age")]
+            Sink::Age(writer) => writer,
+        }
+    }
+
+    fn finish(self) -> Result<(), IoError> {
+        match self {
+            Sink::Plain(mut file) => file.flush(),
+            #[cfg(feature = "age")]
+            Sink::Age(writer) => writer.finish()?.flush(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    start: Instant,
+    input: bool,
+    // Unset while the client has recording off.
+    enabled: Arc<AtomicBool>,
+    sender: UnboundedSender<(Stream, f64, Vec<u8>)>,
+}
+
+impl Recorder {
+    pub(crate) async fn start(
+        config: &RecordingConfig,
+        handle: &SessionHandle,
+        spawn: &SpawnInfo,
+    ) -> Result<Self, anyhow::Error> {
+        let id = handle.id();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let format = config.format;
+        let input = format == RecordingFormat::Streams || config.cast_input;
+        let header = match format {
+            RecordingFormat::Streams => serde_json::to_vec(&Header {
+                id,
+                session_id: handle.session_id(),
+                correlation_id: handle.correlation_id(),
+                peer: handle.peer(),
+                command: &spawn.command,
+                timestamp,
+                spawn,
+            })?,
+            RecordingFormat::Asciicast => {
+                let (height, width) = handle.vt.lock().unwrap().size();
+                let env = ["TERM", "SHELL"]
+                    .iter()
+                    .filter_map(|&name| Some((name, spawn.env.get(name)?.as_str())))
+                    .collect();
+                let mut header = serde_json::to_vec(&CastHeader {
+                    version: 2,
+                    width,
+                    height,
+                    timestamp,
+                    title: &spawn.command,
+                    env,
+                })?;
+                header.push(b'\n');
+                header
+            }
+        };
+
+        let active = config.dir.join(id.to_string());
+        ACTIVE.lock().unwrap().insert(active.clone());
+        let config = config.clone();
+        let sinks = tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(&config.dir)?;
+            let open =
+                |name: &str| Sink::create(&config, &config.dir.join(format!("{}.{}", id, name)));
+            if format == RecordingFormat::Asciicast {
+                let mut sink = open("cast")?;
+                sink.writer().write_all(&header)?;
+                return Ok(vec![sink]);
+            }
+            let mut sink = open("json")?;
+            sink.writer().write_all(&header)?;
+            sink.finish()?;
+            Ok::<_, anyhow::Error>(vec![
+                open("output.jsonl")?,
+                open("input.jsonl")?,
+                open("resize.jsonl")?,
+            ])
+        })
+        .await?;
+        let mut sinks = match sinks {
+            Ok(sinks) => sinks,
+            Err(e) => {
+                ACTIVE.lock().unwrap().remove(&active);
+                return Err(e);
+            }
+        };
+
+        let (sender, mut receiver) = unbounded_channel::<(Stream, f64, Vec<u8>)>();
+        tokio::task::spawn_blocking(move || {
+            let mut pending: [Vec<u8>; 3] = Default::default();
+            while let Some((stream, time, data)) = receiver.blocking_recv() {
+                let pending = &mut pending[stream as usize];
+                pending.extend_from_slice(&data);
+                let data = take_utf8(pending);
+                if data.is_empty() {
+                    continue;
+                }
+                let (line, sink) = match format {
+                    RecordingFormat::Streams => {
+                        (serde_json::json!([time, data]), &mut sinks[stream as usize])
+                    }
+                    RecordingFormat::Asciicast => (
+                        serde_json::json!([time, stream.code(), data]),
+                        &mut sinks[0],
+                    ),
+                };
+                let line = line.to_string() + "\n";
+                let writer = sink.writer();
+                if let Err(e) = writer.write_all(line.as_bytes()) {
+                    error!("failed to write recording: {:?}", e);
+                    break;
+                }
+                // Flush once caught up.
+                if receiver.is_empty() {
+                    for sink in sinks.iter_mut() {
+                        let _ = sink.writer().flush();
+                    }
+                }
+            }
+            for sink in sinks {
+                if let Err(e) = sink.finish() {
+                    error!("failed to finish recording: {:?}", e);
+                }
+            }
+            ACTIVE.lock().unwrap().remove(&active);
+        });
+        Ok(Recorder {
+            start: Instant::now(),
+            input,
+            enabled: Arc::new(AtomicBool::new(true)),
+            sender,
+        })
+    }
+
+    // Turned off, the recording goes on with what happens once it is back
+    // on.
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, stream: Stream, data: Vec<u8>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        let _ = self.sender.send((stream, time, data));
+    }
+
+    pub(crate) fn output(&self, data: &[u8]) {
+        self.record(Stream::Output, data.to_vec());
+    }
+
+    pub(crate) fn input(&self, data: &[u8]) {
+        if self.input {
+            self.record(Stream::Input, data.to_vec());
+        }
+    }
+
+    pub(crate) fn resize(&self, cols: u16, rows: u16) {
+        self.record(Stream::Resize, format!("{}x{}", cols, rows).into_bytes());
+    }
+}
+
+// Decodes what can be of `pending`, leaving a character split across reads
+// for next time. Invalid bytes are replaced.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let mut out = String::new();
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(pending) {
+            Ok(text) => {
+                out.push_str(text);
+                pending.clear();
+                return out;
+            }
+            Err(e) => (e.valid_up_to(), e.error_len()),
+        };
+        out.push_str(std::str::from_utf8(&pending[..valid]).unwrap());
+        match invalid {
+            Some(len) => {
+                out.push(char::REPLACEMENT_CHARACTER);
+                pending.drain(..valid + len);
+            }
+            None => {
+                pending.drain(..valid);
+                return out;
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// Output paced to what clients can draw, for low-power devices following a
+// shared session alongside fast ones. Clients declare the frames per second
+// they can render with the `X-Render-Rate` header or the `fps` query
+// parameter of the upgrade request. Servers with `ServerConfig::render_hints`
+// set send the header back, then coalesce the pty output over a frame,
+// `max_window` at most, before it goes out to the client. Those declaring
+// nothing, or `max_rate` and more, get the output as it comes. Each
+// connection is paced on its own: the session's client, whose rate stays
+// with the session when it is resumed or migrated as low-power mode does
+// (see the `power` module), and every attachment following it (see the
+// `sharing` module and `Attachment::coalesce`).
+
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct RenderHints {
+    pub max_rate: u32,
+    // For the slowest clients.
+    pub max_window: Duration,
+}
+
+impl Default for RenderHints {
+    fn default() -> Self {
+        RenderHints {
+            max_rate: 120,
+            max_window: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RenderHints {
+    // How long output is coalesced for clients rendering `fps` frames a
+    // second.
+    pub(crate) fn window(&self, fps: Option<u32>) -> Option<Duration> {
+        let fps = fps.filter(|&fps| fps > 0 && fps < self.max_rate)?;
+        Some((Duration::from_secs(1) / fps).min(self.max_window))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let hints = RenderHints::default();
+        assert_eq!(hints.window(None), None);
+        assert_eq!(hints.window(Some(0)), None);
+        assert_eq!(hints.window(Some(120)), None);
+        assert_eq!(hints.window(Some(10)), Some(Duration::from_millis(100)));
+        assert_eq!(hints.window(Some(1)), Some(Duration::from_millis(500)));
+    }
+}
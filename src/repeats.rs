@@ -0,0 +1,213 @@
+// Output filter for tight loops printing the same line over and over, an
+// error retried without pause for instance. Once the same line came `after`
+// times in a row, each less than `within` after the one before, the next
+// ones are dropped until another line comes or the loop pauses, then a
+// "[last line repeated N times]" line tells how many were. Applied to what
+// clients and followers get and to the screen, the recording and mirror
+// keep everything. The start of a line that may be another repeat is held
+// back until the line is complete or the run is over.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Clone, Debug)]
+pub struct RepeatLimit {
+    // Identical lines in a row let through.
+    pub after: usize,
+    // Longest time between two of them for them to be a run.
+    pub within: Duration,
+}
+
+impl Default for RepeatLimit {
+    fn default() -> Self {
+        RepeatLimit {
+            after: 3,
+            within: Duration::from_millis(250),
+        }
+    }
+}
+
+pub(crate) struct RepeatFilter {
+    limit: RepeatLimit,
+    // The line of the run, with its line ending.
+    last: Vec<u8>,
+    run: usize,
+    seen: Instant,
+    dropped: usize,
+    // The start of the next line while it could be another repeat.
+    held: Vec<u8>,
+    // Whether the start of the current line went out already.
+    started: bool,
+}
+
+impl RepeatFilter {
+    pub(crate) fn new(limit: RepeatLimit) -> Self {
+        RepeatFilter {
+            limit,
+            last: Vec::new(),
+            run: 0,
+            seen: Instant::now(),
+            dropped: 0,
+            held: Vec::new(),
+            started: false,
+        }
+    }
+
+    pub(crate) fn filter(&mut self, data: &[u8], now: Instant) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut rest = data;
+        while !rest.is_empty() {
+            match rest.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    let (line, tail) = rest.split_at(end + 1);
+                    self.line(line, now, &mut out);
+                    rest = tail;
+                }
+                None => {
+                    self.partial(rest, now, &mut out);
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    // When the run is over if nothing comes, for `flush` to be called then.
+    pub(crate) fn expiry(&self) -> Option<Instant> {
+        if self.dropped > 0 || !self.held.is_empty() {
+            Some(self.seen + self.limit.within)
+        } else {
+            None
+        }
+    }
+
+    // Ends the run, giving the count of dropped lines and what was held.
+    pub(crate) fn flush(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.summary(&mut out);
+        if !self.held.is_empty() {
+            out.append(&mut self.held);
+            self.started = true;
+        }
+        self.run = 0;
+        out
+    }
+
+    fn line(&mut self, line: &[u8], now: Instant, out: &mut Vec<u8>) {
+        if std::mem::take(&mut self.started) {
+            // Too late to drop it.
+            out.extend_from_slice(line);
+            self.last.clear();
+            self.run = 0;
+            return;
+        }
+        let mut line = {
+            let mut whole = std::mem::take(&mut self.held);
+            whole.extend_from_slice(line);
+            whole
+        };
+        let repeat = self.run > 0 && line == self.last && self.in_run(now);
+        self.seen = now;
+        if repeat {
+            self.run += 1;
+            if self.run > self.limit.after {
+                self.dropped += 1;
+                return;
+            }
+            out.append(&mut line);
+        } else {
+            self.summary(out);
+            out.extend_from_slice(&line);
+            self.last = line;
+            self.run = 1;
+        }
+    }
+
+    fn partial(&mut self, data: &[u8], now: Instant, out: &mut Vec<u8>) {
+        if self.started {
+            out.extend_from_slice(data);
+            return;
+        }
+        self.held.extend_from_slice(data);
+        let droppable = self.run >= self.limit.after && self.in_run(now);
+        if droppable && self.last.starts_with(&self.held) {
+            return;
+        }
+        self.summary(out);
+        out.append(&mut self.held);
+        self.started = true;
+    }
+
+    fn in_run(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.seen) <= self.limit.within
+    }
+
+    fn summary(&mut self, out: &mut Vec<u8>) {
+        if self.dropped > 0 {
+            let times = if self.dropped == 1 { "time" } else { "times" };
+            let line = format!("[last line repeated {} {}]\r\n", self.dropped, times);
+            out.extend_from_slice(line.as_bytes());
+            self.dropped = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter() -> RepeatFilter {
+        RepeatFilter::new(RepeatLimit {
+            after: 2,
+            within: Duration::from_millis(100),
+        })
+    }
+
+    #[test]
+    fn runs_are_cut() {
+        let mut filter = filter();
+        let now = Instant::now();
+        let out = filter.filter(b"err\r\nerr\r\nerr\r\nerr\r\n$ ", now);
+        assert_eq!(out, b"err\r\nerr\r\n[last line repeated 2 times]\r\n$ ");
+        assert_eq!(filter.expiry(), None);
+    }
+
+    #[test]
+    fn slow_repeats_go_through() {
+        let mut filter = filter();
+        let mut now = Instant::now();
+        let mut out = Vec::new();
+        for _ in 0..4 {
+            out.extend(filter.filter(b"tick\n", now));
+            now += Duration::from_millis(200);
+        }
+        assert_eq!(out, b"tick\n".repeat(4));
+    }
+
+    #[test]
+    fn lines_split_over_reads() {
+        let mut filter = filter();
+        let now = Instant::now();
+        assert_eq!(filter.filter(b"err\nerr\ne", now), b"err\nerr\n");
+        assert_eq!(filter.filter(b"rr\ne", now), b"");
+        // Not a repeat after all.
+        assert_eq!(
+            filter.filter(b"nd", now),
+            b"[last line repeated 1 time]\r\nend"
+        );
+        assert_eq!(filter.filter(b"\nerr\n", now), b"\nerr\n");
+    }
+
+    #[test]
+    fn run_ends_when_output_pauses() {
+        let mut filter = filter();
+        let now = Instant::now();
+        assert_eq!(
+            filter.filter(b"err\n".repeat(5).as_slice(), now),
+            b"err\nerr\n"
+        );
+        assert_eq!(filter.expiry(), Some(now + Duration::from_millis(100)));
+        assert_eq!(filter.flush(), b"[last line repeated 3 times]\r\n");
+        assert_eq!(filter.expiry(), None);
+    }
+}
@@ -0,0 +1,146 @@
+// Retention policy for the recording directory. A background task
+// periodically deletes whole recordings, all the `<id>.*` files together,
+// once they haven't been modified for `max_age` or, oldest first, while the
+// directory holds more than `max_size` bytes. Recordings of live sessions
+// are kept.
+
+use crate::recording::is_active;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub type PurgeHook = Arc<dyn Fn(&Purge) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub max_size: Option<u64>,
+    // Time between two checks of the directory.
+    pub interval: Duration,
+    // Called for each deleted recording, to keep an audit trail.
+    pub on_purge: Option<PurgeHook>,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            max_age: None,
+            max_size: None,
+            interval: Duration::from_secs(3600),
+            on_purge: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PurgeReason {
+    Expired,
+    OverSize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Purge {
+    pub id: u64,
+    pub files: Vec<PathBuf>,
+    pub bytes: u64,
+    pub modified: SystemTime,
+    pub reason: PurgeReason,
+}
+
+struct Recording {
+    files: Vec<PathBuf>,
+    bytes: u64,
+    modified: SystemTime,
+}
+
+pub(crate) async fn run(dir: PathBuf, retention: Retention) {
+    loop {
+        let (dir, retention) = (dir.clone(), retention.clone());
+        let interval = retention.interval;
+        match tokio::task::spawn_blocking(move || purge(&dir, &retention)).await {
+            Ok(Err(e)) => error!("failed to apply recording retention: {:?}", e),
+            Err(e) => error!("recording retention task failed: {:?}", e),
+            Ok(Ok(())) => (),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+fn purge(dir: &Path, retention: &Retention) -> Result<(), IoError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut recordings: BTreeMap<u64, Recording> = BTreeMap::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let id = match name
+            .to_str()
+            .and_then(|name| name.split('.').next())
+            .and_then(|id| id.parse().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let modified = metadata.modified()?;
+        let recording = recordings.entry(id).or_insert(Recording {
+            files: vec![],
+            bytes: 0,
+            modified,
+        });
+        recording.files.push(entry.path());
+        recording.bytes += metadata.len();
+        recording.modified = recording.modified.max(modified);
+    }
+
+    let mut total: u64 = recordings.values().map(|r| r.bytes).sum();
+    let mut recordings: Vec<_> = recordings.into_iter().collect();
+    recordings.sort_by_key(|(_, r)| r.modified);
+
+    let now = SystemTime::now();
+    for (id, recording) in recordings {
+        if is_active(dir, id) {
+            continue;
+        }
+        let age = now.duration_since(recording.modified).unwrap_or_default();
+        let reason = if retention.max_age.is_some_and(|max| age > max) {
+            PurgeReason::Expired
+        } else if retention.max_size.is_some_and(|max| total > max) {
+            PurgeReason::OverSize
+        } else {
+            continue;
+        };
+
+        for file in recording.files.iter() {
+            if let Err(e) = std::fs::remove_file(file) {
+                error!("failed to delete {}: {:?}", file.display(), e);
+            }
+        }
+        total -= recording.bytes;
+        let purge = Purge {
+            id,
+            files: recording.files,
+            bytes: recording.bytes,
+            modified: recording.modified,
+            reason,
+        };
+        info!(
+            "purged recording {} ({:?}, {} bytes)",
+            id, reason, purge.bytes
+        );
+        if let Some(ref on_purge) = retention.on_purge {
+            on_purge(&purge);
+        }
+    }
+    Ok(())
+}
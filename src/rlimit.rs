@@ -0,0 +1,46 @@
+// Resource limits for the commands, set between fork and exec so that a
+// client can't exhaust the host, e.g. with a fork bomb. Soft and hard limits
+// both get the value, commands can only lower them further.
+
+use std::io::Error as IoError;
+use tokio::process::Command;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResourceLimits {
+    // `RLIMIT_NPROC`, which counts every process of the user the command
+    // runs as, not only its descendants.
+    pub processes: Option<u64>,
+    // `RLIMIT_NOFILE`.
+    pub open_files: Option<u64>,
+    // `RLIMIT_AS`, in bytes of address space.
+    pub memory: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub(crate) fn apply(self, cmd: &mut Command) {
+        unsafe {
+            cmd.pre_exec(move || self.set());
+        }
+    }
+
+    // Runs in the child, so it must not allocate.
+    fn set(&self) -> Result<(), IoError> {
+        let limits = [
+            (libc::RLIMIT_NPROC, self.processes),
+            (libc::RLIMIT_NOFILE, self.open_files),
+            (libc::RLIMIT_AS, self.memory),
+        ];
+        for (resource, value) in limits {
+            if let Some(value) = value {
+                let limit = libc::rlimit {
+                    rlim_cur: value as libc::rlim_t,
+                    rlim_max: value as libc::rlim_t,
+                };
+                if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+// Resume tokens naming the server holding the session, for clusters behind
+// gateways. With `ServerConfig::resume_routing`, servers of the cluster
+// hand out resume tokens (see the `persist` module) made of their
+// `instance` name, the random token and the HMAC-SHA256 of both under the
+// key all of them share. Gateways check it and forward `proto::Resume`
+// requests to the upstream of that instance as the `proxy` module does,
+// without looking the session up anywhere. Tokens not checking out, and
+// those naming the server itself, are resumed locally, which refuses the
+// sessions it doesn't hold.
+
+use ring::hmac;
+use std::collections::HashMap;
+
+#[derive(Clone, Default)]
+pub struct ResumeRouting {
+    // Shared by all the servers of the cluster.
+    pub key: Vec<u8>,
+    // Name of this server in its tokens, gateways have none. Without dots.
+    pub instance: Option<String>,
+    // WebSocket URLs of the instances, for gateways.
+    pub upstreams: HashMap<String, String>,
+}
+
+impl ResumeRouting {
+    fn key(&self) -> hmac::Key {
+        hmac::Key::new(hmac::HMAC_SHA256, &self.key)
+    }
+
+    // The resume token for a session, from a random one.
+    pub(crate) fn sign(&self, token: String) -> String {
+        let instance = match self.instance {
+            Some(ref instance) => instance,
+            None => return token,
+        };
+        let routed = format!("{}.{}", instance, token);
+        let mac = hmac::sign(&self.key(), routed.as_bytes());
+        format!("{}.{}", routed, encode(mac.as_ref()))
+    }
+
+    // Where the session of `token` is to be resumed, if on another server.
+    pub(crate) fn upstream(&self, token: &str) -> Option<&str> {
+        let (routed, mac) = token.rsplit_once('.')?;
+        let (instance, _) = routed.split_once('.')?;
+        if self.instance.as_deref() == Some(instance) {
+            return None;
+        }
+        hmac::verify(&self.key(), routed.as_bytes(), &decode(mac)?).ok()?;
+        self.upstreams.get(instance).map(String::as_str)
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        let instance = |name: &str| ResumeRouting {
+            key: b"cluster".to_vec(),
+            instance: Some(name.to_owned()),
+            ..ResumeRouting::default()
+        };
+        let gateway = ResumeRouting {
+            key: b"cluster".to_vec(),
+            upstreams: [("b".to_owned(), "ws://10.0.0.2:7703/".to_owned())].into(),
+            ..ResumeRouting::default()
+        };
+        let token = instance("b").sign("0123".to_owned());
+        assert!(token.starts_with("b.0123."));
+        assert_eq!(gateway.upstream(&token), Some("ws://10.0.0.2:7703/"));
+        assert_eq!(instance("b").upstream(&token), None);
+        assert_eq!(
+            gateway.upstream(&instance("c").sign("0123".to_owned())),
+            None
+        );
+        assert_eq!(gateway.upstream(&token.replace("0123", "0124")), None);
+        assert_eq!(gateway.upstream("0123"), None);
+        assert_eq!(gateway.sign("0123".to_owned()), "0123");
+    }
+}
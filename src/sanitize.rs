@@ -0,0 +1,155 @@
+// Output filter dropping escape sequences an untrusted program could use
+// against the client terminal: queries whose answers get typed back into
+// the shell (window title reports, DECRQSS, clipboard reads) and font or
+// key remapping (OSC 50, DECUDK). Everything else goes through unchanged.
+// Sequences split across reads are held back until complete.
+
+// Longest sequence held back before giving up and letting it through.
+const MAX_HELD: usize = 64;
+
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    Osc,
+    Dcs,
+    // Passing the rest of a string sequence through, or dropping it.
+    String { keep: bool, escape: bool },
+}
+
+pub(crate) struct Sanitizer {
+    state: State,
+    held: Vec<u8>,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Sanitizer {
+            state: State::Ground,
+            held: vec![],
+        }
+    }
+}
+
+impl Sanitizer {
+    pub(crate) fn filter(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            self.state = match std::mem::replace(&mut self.state, State::Ground) {
+                State::Ground if b == 0x1b => {
+                    self.held.push(b);
+                    State::Escape
+                }
+                State::Ground => {
+                    out.push(b);
+                    State::Ground
+                }
+                State::Escape if b == 0x1b => {
+                    out.push(b);
+                    State::Escape
+                }
+                State::Escape => {
+                    self.held.push(b);
+                    match b {
+                        b'[' => State::Csi,
+                        b']' => State::Osc,
+                        b'P' => State::Dcs,
+                        _ => self.release(&mut out),
+                    }
+                }
+                State::Csi => {
+                    self.held.push(b);
+                    if (0x40..0x7f).contains(&b) {
+                        if &self.held[2..] == b"20t" || &self.held[2..] == b"21t" {
+                            self.held.clear();
+                            State::Ground
+                        } else {
+                            self.release(&mut out)
+                        }
+                    } else if self.held.len() > MAX_HELD {
+                        self.release(&mut out)
+                    } else {
+                        State::Csi
+                    }
+                }
+                State::Osc => {
+                    self.held.push(b);
+                    let body = &self.held[2..];
+                    if b == 0x07 || body.ends_with(b"\x1b\\") {
+                        let keep = !(body.starts_with(b"50;") || is_clipboard_query(body));
+                        if keep {
+                            out.extend_from_slice(&self.held);
+                        }
+                        self.held.clear();
+                        State::Ground
+                    } else if body.starts_with(b"50;") {
+                        self.held.clear();
+                        State::String {
+                            keep: false,
+                            escape: false,
+                        }
+                    } else if self.held.len() > MAX_HELD
+                        || (body.len() > 2 && !body.starts_with(b"52;"))
+                    {
+                        // Not one we're after, stop buffering it.
+                        out.extend_from_slice(&self.held);
+                        let escape = b == 0x1b;
+                        self.held.clear();
+                        State::String { keep: true, escape }
+                    } else {
+                        State::Osc
+                    }
+                }
+                State::Dcs => {
+                    self.held.push(b);
+                    let body = &self.held[2..];
+                    if body == b"$q" || b == b'|' {
+                        // DECRQSS or DECUDK.
+                        self.held.clear();
+                        State::String {
+                            keep: false,
+                            escape: false,
+                        }
+                    } else if (0x40..0x7f).contains(&b) || self.held.len() > MAX_HELD {
+                        out.extend_from_slice(&self.held);
+                        self.held.clear();
+                        State::String {
+                            keep: true,
+                            escape: false,
+                        }
+                    } else {
+                        State::Dcs
+                    }
+                }
+                State::String { keep, escape } => {
+                    if keep {
+                        out.push(b);
+                    }
+                    if (escape && b == b'\\') || b == 0x07 {
+                        State::Ground
+                    } else {
+                        State::String {
+                            keep,
+                            escape: b == 0x1b,
+                        }
+                    }
+                }
+            };
+        }
+        out
+    }
+
+    fn release(&mut self, out: &mut Vec<u8>) -> State {
+        out.append(&mut self.held);
+        State::Ground
+    }
+}
+
+// OSC 52 with `?` as data asks the terminal for the clipboard contents.
+fn is_clipboard_query(body: &[u8]) -> bool {
+    let body = body
+        .strip_suffix(b"\x07")
+        .or_else(|| body.strip_suffix(b"\x1b\\"))
+        .unwrap_or(body);
+    body.starts_with(b"52;") && body.ends_with(b";?")
+}
@@ -0,0 +1,88 @@
+// The last output of each session, kept with `ServerConfig::scrollback`
+// for clients to replay with a `proto::Replay`, e.g. after reloading the
+// page of a persisted session. It is what the pty wrote, escape sequences
+// included, so replays starting mid-sequence can garble a few characters;
+// they are cut at line or codepoint boundaries to make that rare.
+
+use std::collections::VecDeque;
+use wspty_proto::Replay;
+
+pub(crate) struct Scrollback {
+    kept: VecDeque<u8>,
+    capacity: usize,
+    // Whether older output was dropped, so that `kept` may start anywhere.
+    trimmed: bool,
+}
+
+impl Scrollback {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Scrollback {
+            kept: VecDeque::with_capacity(capacity),
+            capacity,
+            trimmed: false,
+        }
+    }
+
+    pub(crate) fn push(&mut self, output: &[u8]) {
+        let skipped = output.len().saturating_sub(self.capacity);
+        let output = &output[skipped..];
+        let overflow = (self.kept.len() + output.len()).saturating_sub(self.capacity);
+        self.trimmed |= skipped > 0 || overflow > 0;
+        self.kept.drain(..overflow);
+        self.kept.extend(output);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.kept.len()
+    }
+
+    // Drops all the output kept, what comes next is kept again.
+    pub(crate) fn clear(&mut self) {
+        self.trimmed |= !self.kept.is_empty();
+        self.kept = VecDeque::new();
+    }
+
+    pub(crate) fn replay(&self, replay: Replay) -> Vec<u8> {
+        let kept = self.kept.len();
+        let mut start = match replay.bytes {
+            Some(bytes) => kept.saturating_sub(bytes as usize),
+            None => 0,
+        };
+        if let Some(lines) = replay.lines {
+            start = start.max(self.lines_start(lines));
+        }
+        if start > 0 || self.trimmed {
+            while start < kept && is_continuation(self.kept[start]) {
+                start += 1;
+            }
+        }
+        self.kept.range(start..).copied().collect()
+    }
+
+    // Where the last `lines` lines start, a trailing newline ending the last
+    // one.
+    fn lines_start(&self, lines: u64) -> usize {
+        let kept = self.kept.len();
+        if lines == 0 {
+            return kept;
+        }
+        let end = match self.kept.back() {
+            Some(b'\n') => kept - 1,
+            _ => kept,
+        };
+        let mut seen = 0;
+        for i in (0..end).rev() {
+            if self.kept[i] == b'\n' {
+                seen += 1;
+                if seen == lines {
+                    return i + 1;
+                }
+            }
+        }
+        0
+    }
+}
+
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
@@ -0,0 +1,103 @@
+// A smoke test for deployments: `self_test` serves one session of the
+// configuration on a loopback port, with one of its profiles or its own,
+// connects to it with a `WsPtyClient` and types `echo ok` into the default
+// command, expecting `ok` back through the whole stack (upgrade, policies,
+// probes, pty and framing), then `exit`. Authentication is left out, even
+// with `require_auth`, the client being the server itself, and so are the
+// other listeners. The
+// binary runs it with `--self-test [profile]`, exiting with 1 on failure.
+
+use crate::server::serve_connection;
+use crate::{Listener, ServerConfig, WsPtyClient};
+use anyhow::anyhow;
+use futures::StreamExt;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use wspty_proto::SpawnRequest;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Whether `ok` was printed on a line of its own, not only echoed.
+fn printed_ok(output: &[u8]) -> bool {
+    (0..output.len()).any(|i| {
+        output[i..].starts_with(b"ok\r\n") && (i == 0 || matches!(output[i - 1], b'\r' | b'\n'))
+    })
+}
+
+pub async fn self_test(config: &ServerConfig, profile: Option<&str>) -> Result<(), anyhow::Error> {
+    let mut config = config.clone();
+    if let Some(name) = profile {
+        config.resolve_profile(name).map_err(|e| anyhow!(e))?;
+        config.query_overrides.profile = true;
+    }
+    config.token_auth = None;
+    config.require_auth = false;
+    #[cfg(feature = "challenge-auth")]
+    {
+        config.challenge_auth = None;
+    }
+    let config = Arc::new(config);
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let mut server = tokio::spawn(async move {
+        let (stream, peer) = listener.accept().await?;
+        let listener = Listener {
+            addr,
+            bind: Default::default(),
+            path: None,
+            authenticator: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        };
+        serve_connection(stream, peer, config, &listener, None).await
+    });
+    let url = match profile {
+        Some(name) => format!("ws://{}/?profile={}", addr, name),
+        None => format!("ws://{}/", addr),
+    };
+    let deadline = Instant::now() + TIMEOUT;
+    let res = tokio::time::timeout_at(deadline, run(&url)).await;
+    let res = match res {
+        Ok(res) => res,
+        Err(_) => Err(anyhow!("no answer after {:?}", TIMEOUT)),
+    };
+    let ended = tokio::time::timeout_at(deadline, &mut server).await;
+    server.abort();
+    match ended {
+        Ok(Ok(Err(e))) if res.is_ok() => Err(e),
+        Err(_) if res.is_ok() => Err(anyhow!("the session didn't end after {:?}", TIMEOUT)),
+        _ => res,
+    }
+}
+
+async fn run(url: &str) -> Result<(), anyhow::Error> {
+    let mut client = WsPtyClient::connect(url).await?;
+    client.spawn(&SpawnRequest::default()).await?;
+    client.write(b"echo ok\r").await?;
+    let mut printed = vec![];
+    let mut output = client.on_output();
+    while let Some(data) = output.next().await {
+        printed.extend_from_slice(&data);
+        if printed_ok(&printed) {
+            break;
+        }
+    }
+    drop(output);
+    if !printed_ok(&printed) {
+        let reason = match client.wait_exit().await {
+            Ok(exit) => format!("the command exited first: {:?}", exit),
+            Err(e) => e.to_string(),
+        };
+        return Err(anyhow!(
+            "no ok in {:?}: {}",
+            String::from_utf8_lossy(&printed),
+            reason
+        ));
+    }
+    client.write(b"exit\r").await?;
+    client.wait_exit().await?;
+    Ok(())
+}
@@ -0,0 +1,154 @@
+// Semantic prompt marks (OSC 133, https://gitlab.freedesktop.org/Per_Bothner/specifications/blob/master/proposals/semantic-prompts.md)
+// turned into `CommandEvent`s: `A` when the prompt starts, `B` where the
+// command line starts, `C` when a command starts producing output and
+// `D;<exit code>` once it is done. Bash is set up to emit them when spawned
+// with sentinels enabled, other shells need their own integration. The
+// sequences go through to the client untouched. What was typed between `B`
+// and `C` is read off the screen for the `commands` module, only the last
+// line of commands spanning several.
+
+use std::time::Instant;
+use wspty_proto::CommandEvent;
+
+// Longest OSC payload looked at, enough for the mark and an exit code.
+const MAX_PAYLOAD: usize = 32;
+
+enum State {
+    Ground,
+    Escape,
+    Osc(Vec<u8>),
+    // Escape inside an OSC, for the ST terminator.
+    OscEscape(Vec<u8>),
+}
+
+// A command that ran, for the `commands` module.
+pub(crate) struct Finished {
+    pub(crate) command: Option<String>,
+    pub(crate) exit_code: Option<i32>,
+    // In milliseconds.
+    pub(crate) duration: u64,
+}
+
+pub(crate) struct CommandSentinels {
+    state: State,
+    // Where the command line starts on the cursor's row.
+    input: Option<u16>,
+    // The running command and its start.
+    command: Option<String>,
+    started: Option<Instant>,
+    events: Vec<CommandEvent>,
+    finished: Vec<Finished>,
+}
+
+impl Default for CommandSentinels {
+    fn default() -> Self {
+        CommandSentinels {
+            state: State::Ground,
+            input: None,
+            command: None,
+            started: None,
+            events: vec![],
+            finished: vec![],
+        }
+    }
+}
+
+impl CommandSentinels {
+    pub(crate) fn take_events(&mut self) -> Vec<CommandEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub(crate) fn take_finished(&mut self) -> Vec<Finished> {
+        std::mem::take(&mut self.finished)
+    }
+
+    // Also feeds `data` to `parser`, up to each mark before applying it, so
+    // that marks see the screen where they are.
+    pub(crate) fn process(&mut self, data: &[u8], parser: &mut vt100::Parser) {
+        let mut fed = 0;
+        for (i, &b) in data.iter().enumerate() {
+            let mut mark = None;
+            self.state = match std::mem::replace(&mut self.state, State::Ground) {
+                State::Ground if b == 0x1b => State::Escape,
+                State::Ground => State::Ground,
+                State::Escape if b == b']' => State::Osc(vec![]),
+                State::Escape if b == 0x1b => State::Escape,
+                State::Escape => State::Ground,
+                State::Osc(payload) if b == 0x07 => {
+                    mark = Some(payload);
+                    State::Ground
+                }
+                State::Osc(payload) if b == 0x1b => State::OscEscape(payload),
+                State::Osc(mut payload) => {
+                    if payload.len() < MAX_PAYLOAD {
+                        payload.push(b);
+                    }
+                    State::Osc(payload)
+                }
+                State::OscEscape(payload) if b == b'\\' => {
+                    mark = Some(payload);
+                    State::Ground
+                }
+                // Unterminated, the escape starts a new sequence.
+                State::OscEscape(_) if b == b']' => State::Osc(vec![]),
+                State::OscEscape(_) => State::Ground,
+            };
+            if let Some(payload) = mark {
+                parser.process(&data[fed..=i]);
+                fed = i + 1;
+                self.apply(&payload, parser.screen());
+            }
+        }
+        parser.process(&data[fed..]);
+    }
+
+    fn apply(&mut self, payload: &[u8], screen: &vt100::Screen) {
+        let mut fields = payload.split(|&b| b == b';');
+        if fields.next() != Some(b"133") {
+            return;
+        }
+        match fields.next() {
+            Some(b"A") => self.events.push(CommandEvent::Prompt),
+            Some(b"B") => {
+                self.input = Some(screen.cursor_position().1);
+                self.events.push(CommandEvent::Input);
+            }
+            Some(b"C") => {
+                self.command = self.input.take().and_then(|col| command_line(screen, col));
+                self.started = Some(Instant::now());
+                self.events.push(CommandEvent::Started);
+            }
+            // Also sent before the first prompt, when nothing ran.
+            Some(b"D") if self.started.is_some() => {
+                let duration = self.started.take().map(|t| t.elapsed().as_millis() as u64);
+                let exit_code = fields
+                    .next()
+                    .and_then(|code| std::str::from_utf8(code).ok())
+                    .and_then(|code| code.parse().ok());
+                self.events.push(CommandEvent::Finished {
+                    exit_code,
+                    duration,
+                });
+                self.finished.push(Finished {
+                    command: self.command.take(),
+                    exit_code,
+                    duration: duration.unwrap_or_default(),
+                });
+            }
+            _ => (),
+        }
+    }
+}
+
+// What was typed from `col` of the row the cursor left when the command
+// was entered, and the rows it wraps from.
+fn command_line(screen: &vt100::Screen, col: u16) -> Option<String> {
+    let last = screen.cursor_position().0.checked_sub(1)?;
+    let mut first = last;
+    while first > 0 && screen.row_wrapped(first - 1) {
+        first -= 1;
+    }
+    let line = screen.contents_between(first, col, last, screen.size().1);
+    let line = line.trim();
+    (!line.is_empty()).then(|| line.to_owned())
+}
@@ -0,0 +1,188 @@
+// Keeps output the server adds to the pty's, theme sequences or a repaint
+// for instance, from landing in the middle of an escape sequence or of a
+// UTF-8 character the pty output sent so far ends with, which frontends
+// would then render as garbage. The pty output is followed as it goes out,
+// under the vt lock as the injected output is: injected output goes out
+// right away when the pty output is between sequences, or else right after
+// the pty output that completes the sequence. Held output goes out anyway
+// after `HOLD_TIMEOUT`, for programs leaving a sequence unfinished. The
+// bells of the pty output are told apart from the BELs ending OSC strings
+// the same way, see the `alerts` module.
+
+use std::time::Duration;
+
+pub(crate) const HOLD_TIMEOUT: Duration = Duration::from_millis(250);
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+// Abort sequences.
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Scan {
+    Ground,
+    Escape,
+    Csi,
+    // OSC, DCS, SOS, PM and APC, up to their terminator.
+    String,
+    // An ESC in a string, the start of ST.
+    StringEscape,
+    // Continuation bytes of a UTF-8 character still to come.
+    Utf8(u8),
+}
+
+pub(crate) struct Sequencer {
+    scan: Scan,
+    held: Vec<u8>,
+}
+
+impl Default for Sequencer {
+    fn default() -> Self {
+        Sequencer {
+            scan: Scan::Ground,
+            held: Vec::new(),
+        }
+    }
+}
+
+impl Sequencer {
+    // Follows pty output going out, returns the injected output that can
+    // go after it.
+    pub(crate) fn output(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        for &b in data {
+            self.scan = step(self.scan, b);
+        }
+        if self.scan == Scan::Ground {
+            self.release()
+        } else {
+            None
+        }
+    }
+
+    // Returns `data` when it can go out now, or holds it back.
+    pub(crate) fn inject(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+        if self.scan == Scan::Ground && self.held.is_empty() {
+            return Some(data.to_vec());
+        }
+        self.held.extend_from_slice(data);
+        None
+    }
+
+    // The client caught up with a diff, in frame mode.
+    pub(crate) fn resync(&mut self) {
+        self.scan = Scan::Ground;
+    }
+
+    // What is held back, once done waiting.
+    pub(crate) fn release(&mut self) -> Option<Vec<u8>> {
+        if self.held.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.held))
+        }
+    }
+}
+
+// Counts the bells pty output rings.
+pub(crate) struct Bells {
+    scan: Scan,
+}
+
+impl Default for Bells {
+    fn default() -> Self {
+        Bells { scan: Scan::Ground }
+    }
+}
+
+impl Bells {
+    pub(crate) fn count(&mut self, data: &[u8]) -> usize {
+        let mut bells = 0;
+        for &b in data {
+            if b == BEL && !matches!(self.scan, Scan::String) {
+                bells += 1;
+            }
+            self.scan = step(self.scan, b);
+        }
+        bells
+    }
+}
+
+fn step(scan: Scan, b: u8) -> Scan {
+    match (scan, b) {
+        (_, CAN) | (_, SUB) => Scan::Ground,
+        (Scan::StringEscape, b'\\') => Scan::Ground,
+        (Scan::String, BEL) => Scan::Ground,
+        (Scan::String, ESC) => Scan::StringEscape,
+        (Scan::String, _) => Scan::String,
+        (_, ESC) => Scan::Escape,
+        // Not ST, so the start of another sequence.
+        (Scan::StringEscape, b) => step(Scan::Escape, b),
+        (Scan::Escape, b'[') => Scan::Csi,
+        (Scan::Escape, b']') | (Scan::Escape, b'P') | (Scan::Escape, b'X') => Scan::String,
+        (Scan::Escape, b'^') | (Scan::Escape, b'_') => Scan::String,
+        // Intermediate bytes and C0 controls, executed as they come.
+        (Scan::Escape, 0x00..=0x2f) => Scan::Escape,
+        (Scan::Escape, _) => Scan::Ground,
+        (Scan::Csi, 0x40..=0x7e) => Scan::Ground,
+        (Scan::Csi, _) => Scan::Csi,
+        (Scan::Utf8(left), 0x80..=0xbf) if left > 1 => Scan::Utf8(left - 1),
+        (Scan::Utf8(_), 0x80..=0xbf) => Scan::Ground,
+        // Cut short, the byte stands on its own.
+        (Scan::Utf8(_), b) => step(Scan::Ground, b),
+        (Scan::Ground, 0xc2..=0xdf) => Scan::Utf8(1),
+        (Scan::Ground, 0xe0..=0xef) => Scan::Utf8(2),
+        (Scan::Ground, 0xf0..=0xf4) => Scan::Utf8(3),
+        (Scan::Ground, _) => Scan::Ground,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injects_between_sequences() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.output(b"ls\r\n\x1b[31mred\x1b[0m"), None);
+        assert_eq!(sequencer.inject(b"!"), Some(b"!".to_vec()));
+    }
+
+    #[test]
+    fn holds_until_sequence_ends() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.output(b"\x1b[3"), None);
+        assert_eq!(sequencer.inject(b"!"), None);
+        assert_eq!(sequencer.inject(b"?"), None);
+        assert_eq!(sequencer.output(b"1mred"), Some(b"!?".to_vec()));
+        assert_eq!(sequencer.output(b"\x1b]0;title"), None);
+        assert_eq!(sequencer.inject(b"!"), None);
+        assert_eq!(sequencer.output(b"\x1b"), None);
+        assert_eq!(sequencer.output(b"\\"), Some(b"!".to_vec()));
+        assert_eq!(sequencer.output("é".as_bytes()[..1].as_ref()), None);
+        assert_eq!(sequencer.inject(b"!"), None);
+        assert_eq!(sequencer.output(&"é".as_bytes()[1..]), Some(b"!".to_vec()));
+    }
+
+    #[test]
+    fn aborted_sequences() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.output(b"\x1b]0;title"), None);
+        assert_eq!(sequencer.inject(b"!"), None);
+        assert_eq!(sequencer.output(b"\x18"), Some(b"!".to_vec()));
+        // ESC in a string that doesn't end it starts another sequence.
+        assert_eq!(sequencer.output(b"\x1bP1\x1b[1"), None);
+        assert_eq!(sequencer.inject(b"!"), None);
+        assert_eq!(sequencer.release(), Some(b"!".to_vec()));
+        assert_eq!(sequencer.release(), None);
+    }
+
+    #[test]
+    fn bells() {
+        let mut bells = Bells::default();
+        assert_eq!(bells.count(b"done\x07\x1b]0;title\x07"), 1);
+        assert_eq!(bells.count(b"\x1b]2;split"), 0);
+        assert_eq!(bells.count(b" title\x07\x07"), 1);
+        assert_eq!(bells.count(b"\x1b[1m\x07"), 1);
+    }
+}
@@ -1,149 +1,2705 @@
-use crate::{PtyCommand, PtyMaster};
+use crate::accounting::Meter;
+use crate::alerts::Monitor;
+use crate::arbitration::{Floor, CLIENT};
+use crate::auth::{Handshake, Listener, Refusal, Upgrade};
+use crate::clock::{Clock, Ticker};
+use crate::colors::ColorFilter;
+use crate::commands::Commands;
+use crate::control::Control;
+use crate::deflate::Deflater;
+use crate::devices::PrivateDev;
+use crate::dump::{Direction, FrameDump};
+#[cfg(feature = "fault-injection")]
+use crate::faults::{Cut, Injector};
+use crate::framing;
+use crate::instrument::Counters;
+use crate::integrity::Integrity;
+use crate::limit::{Admission, Slot, Slots};
+use crate::lines::LineEditor;
+use crate::malformed::{malformed, MalformedAction};
+use crate::mirror::Mirror;
+use crate::outbox::{outbox, Outbox, OutboxReceiver};
+use crate::persist::Parked;
+use crate::pool::Warm;
+use crate::presence::Roster;
+use crate::recording::Recorder;
+use crate::repeats::RepeatFilter;
+use crate::sanitize::Sanitizer;
+use crate::scrollback::Scrollback;
+use crate::sizing::{Client, Sizes};
+use crate::teardown;
+use crate::timeline::TimelineEvent;
+use crate::tokens::Capabilities;
+use crate::trace::Tracer;
+use crate::transfer::Transfers;
+use crate::ui;
+#[cfg(feature = "io-uring")]
+use crate::uring::UringReader;
+use crate::utf8::Utf8Framer;
+use crate::vt::VtState;
+use crate::{
+    is_pty_exhausted, PtyCommand, PtyMaster, ServerConfig, Session, SessionHandle, SessionLimit,
+    SessionRecord, SpawnInfo, UNIX_PEER,
+};
 use bytes::BytesMut;
-use futures::SinkExt;
-use futures::StreamExt;
-use futures_util::stream::{SplitSink, SplitStream};
-use log::{debug, error};
-use serde::Deserialize;
-use std::collections::HashMap;
+use futures::{future, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error as IoError, ErrorKind};
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsFd, OwnedFd};
+use std::os::unix::process::ExitStatusExt;
+use std::panic::AssertUnwindSafe;
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixListener};
 use tokio::process::Command;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio_tungstenite::{accept_async, WebSocketStream};
-use tungstenite::Message;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::{
+    self as proto, protocol::CloseReason, ClientMessage, Decision, DecodeError, Exit, Malformed,
+    ResizeSource, Resized, ServerMessage, SpawnRequest, WindowSize,
+};
 
-#[derive(Deserialize, Debug)]
-struct WindowSize {
-    cols: u16,
-    rows: u16,
+pub(crate) static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Kept from clients waiting for a session slot, the rest is dropped.
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+pub(crate) const DEFAULT_OUTPUT_BACKLOG: usize = 256 * 1024;
+
+fn output_message(data: &[u8]) -> Message {
+    Message::Binary(proto::frame(proto::OUTPUT, data))
+}
+
+// Per connection state shared by the incoming, pty and outgoing tasks.
+#[derive(Clone)]
+struct SessionState {
+    handle: SessionHandle,
+    meter: Option<Meter>,
+    vt: Arc<Mutex<VtState>>,
+    integrity: Arc<Mutex<Integrity>>,
+    // Set while the client wants compressed output.
+    deflater: Arc<Mutex<Option<Deflater>>>,
+    // Set for clients showing less than 24 bit color.
+    colors: Arc<Mutex<Option<ColorFilter>>>,
+    // Last size pushed to the client, for its `Resize` echoing it.
+    pushed_size: Arc<Mutex<Option<WindowSize>>>,
+    // Set while the client's input is dropped, see the `toggles` module.
+    read_only: Arc<AtomicBool>,
+    tracer: Option<Arc<Mutex<Tracer>>>,
+    config: Arc<ServerConfig>,
+    // Whether the client was told about the progress of the setup, and
+    // gets the `ready` stage with the first output.
+    progress: bool,
+    // Set when control traffic has a connection of its own, see the
+    // `control` module.
+    control: Option<Arc<Control>>,
+    // Set when its frames are written to a file, see the `dump` module.
+    dump: Option<FrameDump>,
 }
 
-async fn handle_websocket_incoming(
-    mut incoming: SplitStream<WebSocketStream<TcpStream>>,
+// Returns whether the connection was lost rather than closed by the client,
+// leaving for another page counts as lost.
+async fn handle_websocket_incoming<I>(
+    mut incoming: I,
     mut pty_shell_writer: PtyMaster,
-    websocket_sender: UnboundedSender<Message>,
-    stop_sender: UnboundedSender<()>,
-) -> Result<(), anyhow::Error> {
+    websocket_sender: Outbox,
+    state: SessionState,
+) -> Result<bool, anyhow::Error>
+where
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let mut closed = false;
+    // Set while the client wants line input.
+    let mut line_editor: Option<LineEditor> = None;
+    let mut transfers = state.config.file_transfer.clone().map(|config| {
+        // Uploads are given to the account commands run as.
+        let uid = state
+            .handle
+            .spawn
+            .as_ref()
+            .map(|spawn| spawn.uid)
+            .filter(|&uid| uid != nix::unistd::geteuid().as_raw());
+        Transfers::new(
+            config,
+            websocket_sender.clone(),
+            state.handle.identity.clone(),
+            uid,
+        )
+    });
     while let Some(Ok(msg)) = incoming.next().await {
+        if let Some(ref dump) = state.dump {
+            dump.record(Direction::In, &msg);
+        }
+        if msg.is_binary() || msg.is_text() {
+            state.handle.touch();
+        }
         match msg {
-            Message::Binary(data) => match data[0] {
-                0 => {
-                    if data.len().gt(&0) {
-                        pty_shell_writer.write_all(&data[1..]).await?;
+            Message::Binary(data) => {
+                let policy = &state.config.malformed;
+                let decoded = policy
+                    .check(&data)
+                    .and_then(|()| decode(&data, &state).map_err(|e| malformed(&data, e)));
+                let message = match decoded {
+                    Ok(message) => message,
+                    Err(malformed) => {
+                        reject(malformed, &state, &websocket_sender)?;
+                        continue;
                     }
+                };
+                match message {
+                    message if state.handle.capabilities.missing(&message).is_some() => {
+                        let scope = state
+                            .handle
+                            .capabilities
+                            .missing(&message)
+                            .unwrap_or_default();
+                        debug!(
+                            "session {} client token doesn't allow {}",
+                            state.handle, scope
+                        );
+                        state.handle.record(TimelineEvent::Denied {
+                            scope: scope.to_owned(),
+                        });
+                        let denied = Capabilities::denial(&message, scope);
+                        websocket_sender
+                            .send(Message::Binary(ServerMessage::Denied(denied).encode()))?;
+                    }
+                    ClientMessage::Input(_)
+                    | ClientMessage::Composition(_)
+                    | ClientMessage::Signal(_)
+                    | ClientMessage::Environment(_)
+                    | ClientMessage::LineInput(_)
+                    | ClientMessage::Transfer(_)
+                    | ClientMessage::TransferChunk(..)
+                        if !state.handle.can_write(CLIENT)
+                            || state.read_only.load(Ordering::Relaxed) =>
+                    {
+                        debug!("session {} client has no write access", state.handle);
+                    }
+                    ClientMessage::Input(input) => {
+                        state.handle.typed(CLIENT);
+                        let received = Instant::now();
+                        if let Some(ref meter) = state.meter {
+                            meter.account(input.len()).await?;
+                        }
+                        crate::metrics::input(input.len());
+                        let raw = state.vt.lock().unwrap().raw();
+                        let edited;
+                        let input = match line_editor {
+                            Some(ref mut editor) if !raw => {
+                                edited = editor.feed(input);
+                                &edited[..]
+                            }
+                            _ => input,
+                        };
+                        if input.is_empty() {
+                            continue;
+                        }
+                        if let Some(recorder) = state.handle.recorder() {
+                            recorder.input(input);
+                        }
+                        pty_shell_writer.write_all(input).await?;
+                        if let Some(ref tracer) = state.tracer {
+                            tracer.lock().unwrap().written(received);
+                        }
+                    }
+                    ClientMessage::Composition(text) => {
+                        state.handle.typed(CLIENT);
+                        let received = Instant::now();
+                        if let Some(ref meter) = state.meter {
+                            meter.account(text.len()).await?;
+                        }
+                        crate::metrics::input(text.len());
+                        let raw = state.vt.lock().unwrap().raw();
+                        let edited;
+                        let text = match line_editor {
+                            Some(ref mut editor) if !raw => {
+                                edited = editor.feed(text.as_bytes());
+                                &edited[..]
+                            }
+                            _ => text.as_bytes(),
+                        };
+                        if text.is_empty() {
+                            continue;
+                        }
+                        if let Some(recorder) = state.handle.recorder() {
+                            recorder.input(text);
+                        }
+                        // One write, so the shell reads the whole text at once
+                        // when it fits in the pty buffer.
+                        pty_shell_writer.write_all(text).await?;
+                        if let Some(ref tracer) = state.tracer {
+                            tracer.lock().unwrap().written(received);
+                        }
+                    }
+                    ClientMessage::Resize(size) => {
+                        let size = state.config.clamp_size(size);
+                        let pushed = state.pushed_size.lock().unwrap().take();
+                        let (pty_size, _) = state.handle.pty_size();
+                        let echo = pushed.is_some_and(|pushed| same_size(pushed, size))
+                            && same_size(pty_size, size);
+                        if echo {
+                            debug!("session {} client echoed its resize", state.handle);
+                            let resized = ServerMessage::Resized(Resized {
+                                size: pty_size,
+                                error: None,
+                                source: ResizeSource::Client,
+                            });
+                            websocket_sender.send(Message::Binary(resized.encode()))?;
+                            continue;
+                        }
+                        let (size, error) = match state.handle.negotiate(Client::Owner, size).await
+                        {
+                            Ok(size) => {
+                                if let Some(ref events) = state.config.events {
+                                    events.on_resize(&state.handle, size);
+                                }
+                                (size, None)
+                            }
+                            Err(e) => {
+                                warn!("failed to resize session {}: {}", state.handle, e);
+                                (size, Some(e.to_string()))
+                            }
+                        };
+                        let resized = ServerMessage::Resized(Resized {
+                            size,
+                            error,
+                            source: ResizeSource::Client,
+                        });
+                        websocket_sender.send(Message::Binary(resized.encode()))?;
+                    }
+                    ClientMessage::Ping => {
+                        websocket_sender.send(Message::Binary(vec![proto::PONG]))?;
+                    }
+                    ClientMessage::FrameMode(mode) => {
+                        let mut vt = state.vt.lock().unwrap();
+                        let interval = mode.interval.map(Duration::from_millis);
+                        if let Some(frame) = vt.set_frame_mode(mode.enabled, interval) {
+                            websocket_sender.send(output_message(&frame))?;
+                        }
+                    }
+                    ClientMessage::KeyboardProtocol(protocol) => {
+                        let frames = {
+                            let mut vt = state.vt.lock().unwrap();
+                            vt.set_keyboard_protocol(protocol.kitty);
+                            vt.take_notifications()
+                        };
+                        for frame in frames {
+                            websocket_sender.send(Message::Binary(frame))?;
+                        }
+                    }
+                    ClientMessage::Signal(request) => {
+                        debug!("{:?} for session {}", request.signal, state.handle);
+                        if let Err(e) = state.handle.signal(request.signal) {
+                            warn!("failed to signal session {}: {}", state.handle, e);
+                        }
+                    }
+                    ClientMessage::Grant(grant) => {
+                        state.handle.grant(grant.participant, grant.write)
+                    }
+                    ClientMessage::LineInput(mode) if mode.enabled => {
+                        line_editor.get_or_insert_with(LineEditor::default);
+                    }
+                    ClientMessage::LineInput(_) => {
+                        let line = match line_editor.take() {
+                            Some(mut editor) => editor.take(),
+                            None => continue,
+                        };
+                        if let Some(recorder) = state.handle.recorder() {
+                            recorder.input(&line);
+                        }
+                        pty_shell_writer.write_all(&line).await?;
+                    }
+                    ClientMessage::Transfer(transfer) => match transfers {
+                        Some(ref mut transfers) => transfers.start(transfer).await,
+                        None => {
+                            let status = proto::TransferStatus {
+                                id: transfer.id,
+                                state: proto::TransferState::Failed,
+                                transferred: 0,
+                                size: None,
+                                error: Some("file transfer is disabled".into()),
+                            };
+                            let status = ServerMessage::TransferStatus(status);
+                            websocket_sender.send(Message::Binary(status.encode()))?;
+                        }
+                    },
+                    ClientMessage::TransferChunk(id, data) => {
+                        if let Some(ref mut transfers) = transfers {
+                            if let Some(ref meter) = state.meter {
+                                meter.account(data.len()).await?;
+                            }
+                            transfers.chunk(id, data).await;
+                        }
+                    }
+                    ClientMessage::CancelTransfer(cancel) => {
+                        if let Some(ref mut transfers) = transfers {
+                            transfers.cancel(cancel.id);
+                        }
+                    }
+                    ClientMessage::Pause(pause) => {
+                        if pause.paused {
+                            state.handle.pause();
+                        } else {
+                            state.handle.resume();
+                        }
+                    }
+                    ClientMessage::Theme(request) => match state.config.themes.get(&request.name) {
+                        Some(theme) => inject(
+                            &theme.sequences(),
+                            &state.vt,
+                            &websocket_sender,
+                            &state.handle.clock,
+                        )?,
+                        None => debug!("unknown theme {:?}", request.name),
+                    },
+                    ClientMessage::Compression(compression) => {
+                        let config = match state.config.compression {
+                            Some(ref config) => config,
+                            None => {
+                                debug!("compression is disabled");
+                                continue;
+                            }
+                        };
+                        *state.deflater.lock().unwrap() =
+                            compression.enabled.then(|| Deflater::new(config));
+                    }
+                    ClientMessage::Replay(replay) => {
+                        let scrollback = match state.handle.scrollback {
+                            Some(ref scrollback) => scrollback,
+                            None => {
+                                debug!("scrollback is disabled");
+                                continue;
+                            }
+                        };
+                        // Under the vt lock, so that the output it has doesn't
+                        // also come after it.
+                        let _vt = state.vt.lock().unwrap();
+                        let data = scrollback.lock().unwrap().replay(replay);
+                        let scrollback = ServerMessage::Scrollback(&data);
+                        websocket_sender.send(Message::Binary(scrollback.encode()))?;
+                    }
+                    ClientMessage::IntegrityMode(mode) => {
+                        state.integrity.lock().unwrap().set_enabled(mode.enabled);
+                    }
+                    ClientMessage::Retransmit(range) => {
+                        let frames = state
+                            .integrity
+                            .lock()
+                            .unwrap()
+                            .retransmit(range.from, range.to);
+                        match frames {
+                            Some(frames) => {
+                                for frame in frames {
+                                    websocket_sender.send(Message::Binary(frame))?;
+                                }
+                            }
+                            None => {
+                                // Too old, repaint the screen instead.
+                                let snapshot = state.vt.lock().unwrap().snapshot();
+                                inject(
+                                    &snapshot,
+                                    &state.vt,
+                                    &websocket_sender,
+                                    &state.handle.clock,
+                                )?;
+                            }
+                        }
+                    }
+                    ClientMessage::Trace(trace) => {
+                        if let Some(ref tracer) = state.tracer {
+                            tracer.lock().unwrap().start(trace);
+                        }
+                    }
+                    ClientMessage::Environment(environment) => {
+                        let policy = match state.config.environment_policy {
+                            Some(ref policy) => policy,
+                            None => {
+                                debug!("environment updates are disabled");
+                                continue;
+                            }
+                        };
+                        match crate::env::export_line(&environment.vars, policy, &state.handle) {
+                            Ok(Some(line)) => pty_shell_writer.write_all(line.as_bytes()).await?,
+                            Ok(None) => (),
+                            Err(e) => warn!(
+                                "failed to update the environment of session {}: {}",
+                                state.handle, e
+                            ),
+                        }
+                    }
+                    ClientMessage::Handoff(handoff) => {
+                        match crate::handoff::hand_off(&state.handle, &state.config, &handoff.to) {
+                            // Parked for the new owner.
+                            Ok(()) => return Ok(true),
+                            Err(reason) => {
+                                warn!("refused a handoff of session {}: {}", state.handle, reason);
+                                let decision = Decision {
+                                    allowed: false,
+                                    reason: Some(reason),
+                                };
+                                let decision = ServerMessage::Decision(decision);
+                                websocket_sender.send(Message::Binary(decision.encode()))?;
+                            }
+                        }
+                    }
+                    ClientMessage::Toggle(toggle) => {
+                        let features = crate::toggles::toggle(
+                            &state.handle,
+                            &state.config,
+                            &state.read_only,
+                            &websocket_sender,
+                            toggle,
+                        )
+                        .await;
+                        let features = ServerMessage::Features(features);
+                        websocket_sender.send(Message::Binary(features.encode()))?;
+                    }
+                    ClientMessage::DryRun(_)
+                    | ClientMessage::Auth(_)
+                    | ClientMessage::ChallengeResponse(_)
+                    | ClientMessage::Resume(_)
+                    | ClientMessage::Attach(_)
+                    | ClientMessage::Control(_)
+                    | ClientMessage::Open(_)
+                    | ClientMessage::CloseChannel(_)
+                    | ClientMessage::ListChannels
+                    | ClientMessage::Channel(..)
+                    | ClientMessage::Unknown(..) => {}
                 }
-                1 => {
-                    let resize_msg: WindowSize = serde_json::from_slice(&data[1..])?;
-                    pty_shell_writer.resize(resize_msg.cols, resize_msg.rows)?;
-                }
-                2 => {
-                    websocket_sender.send(Message::Binary(vec![1u8]))?;
-                }
-                _ => (),
-            },
+            }
             Message::Ping(data) => websocket_sender.send(Message::Pong(data))?,
+            Message::Close(frame) => {
+                closed = !matches!(
+                    frame,
+                    Some(CloseFrame {
+                        code: CloseCode::Away,
+                        ..
+                    })
+                );
+            }
             _ => (),
         };
     }
-    let _ = stop_sender
-        .send(())
-        .map_err(|e| debug!("failed to send stop signal: {:?}", e));
+    Ok(!closed)
+}
+
+// Drops a message of the client as `ServerConfig::malformed` says.
+fn reject(
+    malformed: Malformed,
+    state: &SessionState,
+    websocket_sender: &Outbox,
+) -> Result<(), anyhow::Error> {
+    debug!(
+        "session {} client sent a malformed message: {}",
+        state.handle, malformed.error
+    );
+    match state.config.malformed.action(&malformed) {
+        MalformedAction::Ignore => (),
+        MalformedAction::Reply => {
+            let reply = ServerMessage::Malformed(malformed).encode();
+            websocket_sender.send(Message::Binary(reply))?;
+        }
+        MalformedAction::Disconnect => {
+            let opcode = malformed.opcode.map(|opcode| opcode.to_string());
+            let reason = CloseReason::Malformed(opcode.unwrap_or_default());
+            state.handle.close(CloseCode::Protocol, reason);
+        }
+    }
     Ok(())
 }
 
-async fn handle_pty_incoming(
-    mut pty_shell_reader: PtyMaster,
-    websocket_sender: UnboundedSender<Message>,
+fn decode<'a>(data: &'a [u8], state: &SessionState) -> Result<ClientMessage<'a>, DecodeError> {
+    match state.handle.counters {
+        Some(ref counters) => counters.decode(data),
+        None => ClientMessage::decode(data),
+    }
+}
+
+async fn handle_pty_incoming<R: AsyncRead + Unpin>(
+    mut pty_shell_reader: R,
+    websocket_sender: Outbox,
+    state: SessionState,
 ) -> Result<(), anyhow::Error> {
+    let SessionState {
+        handle,
+        meter,
+        vt,
+        tracer,
+        config,
+        mut progress,
+        ..
+    } = state;
+    let mut paused = handle.paused.subscribe();
+    #[cfg(feature = "fault-injection")]
+    let mut cut = config.faults.as_ref().map(Cut::new);
+    let mut sanitizer = config.sanitize_output.then(Sanitizer::default);
+    let mut framer = config.utf8_frames.then(Utf8Framer::default);
+    let mut repeats = config.repeated_lines.clone().map(RepeatFilter::new);
+    let batching = crate::power::granted(&config, &handle).map(|low_power| low_power.batching);
+    // Low-power clients drawing fast enough still get batches.
+    let batching = config
+        .render_hints
+        .as_ref()
+        .and_then(|hints| hints.window(handle.render_rate))
+        .max(batching);
     let fut = async move {
-        let mut buffer = BytesMut::with_capacity(1024);
-        buffer.resize(1024, 0u8);
+        let len = config.read_buffer_size.unwrap_or(1024).max(1);
+        let mut buffer = BytesMut::with_capacity(len + 1);
+        buffer.resize(len + 1, 0u8);
+        let clock = handle.clock.clone();
+        let mut frame_interval = vt.lock().unwrap().frame_interval();
+        let mut ticker = Ticker::new(clock.clone(), clock.now(), frame_interval);
         loop {
-            buffer[0] = 0u8;
+            while *paused.borrow_and_update() {
+                paused.changed().await?;
+            }
+            websocket_sender.drained().await;
+
+            let (frame_mode, raw) = {
+                let vt = vt.lock().unwrap();
+                if vt.frame_interval() != frame_interval {
+                    frame_interval = vt.frame_interval();
+                    ticker = Ticker::new(clock.clone(), clock.now(), frame_interval);
+                }
+                (vt.frame_mode(), vt.raw())
+            };
+
+            buffer[0] = proto::OUTPUT;
             let mut tail = &mut buffer[1..];
-            let n = pty_shell_reader.read_buf(&mut tail).await?;
+            let expiry = repeats.as_ref().and_then(RepeatFilter::expiry);
+            let n = tokio::select! {
+                res = pty_shell_reader.read_buf(&mut tail) => res?,
+                res = paused.changed() => {
+                    res?;
+                    continue;
+                }
+                _ = ticker.tick(), if frame_mode => {
+                    let frame = vt.lock().unwrap().take_frame();
+                    if let Some(frame) = frame {
+                        if let Err(e) = websocket_sender.send(output_message(&frame)) {
+                            anyhow::bail!("failed to send msg to client: {:?}", e);
+                        }
+                        if let Some(ref meter) = meter {
+                            meter.account(frame.len()).await?;
+                        }
+                    }
+                    continue;
+                }
+                // The count of lines dropped once the loop printing them
+                // pauses.
+                _ = clock.sleep_until(expiry.unwrap_or_else(|| clock.now())), if expiry.is_some() => {
+                    let output = repeats.as_mut().map(RepeatFilter::flush).unwrap_or_default();
+                    if forward(&output, &vt, &handle, &websocket_sender)? {
+                        if let Some(ref meter) = meter {
+                            meter.account(output.len()).await?;
+                        }
+                    }
+                    continue;
+                }
+            };
+            // What follows shortly goes in the same message, see the
+            // `power` module.
+            let mut n = n;
+            if let (Some(batching), true, false) = (batching, n > 0, raw) {
+                let deadline = clock.now() + batching;
+                while !tail.is_empty() {
+                    let res = tokio::select! {
+                        res = pty_shell_reader.read_buf(&mut tail) => Some(res),
+                        _ = clock.sleep_until(deadline) => None,
+                    };
+                    match res {
+                        Some(Ok(0)) | None => break,
+                        Some(res) => n += res?,
+                    }
+                }
+            }
+            if let Some(ref counters) = handle.counters {
+                counters.woke();
+            }
+            if std::mem::take(&mut progress) {
+                let ready = proto::Progress {
+                    stage: proto::Stage::Ready,
+                    detail: None,
+                };
+                websocket_sender.send(Message::Binary(ServerMessage::Progress(ready).encode()))?;
+            }
             if n == 0 {
+                // The last changes of the screen in frame mode go out too.
+                let frame = vt.lock().unwrap().take_frame();
+                if let Some(frame) = frame {
+                    if let Err(e) = websocket_sender.send(output_message(&frame)) {
+                        anyhow::bail!("failed to send msg to client: {:?}", e);
+                    }
+                }
+                let held = repeats.as_mut().map(RepeatFilter::flush);
+                if let Some(held) = held.filter(|held| !held.is_empty()) {
+                    if let Err(e) = websocket_sender.send(output_message(&held)) {
+                        anyhow::bail!("failed to send msg to client: {:?}", e);
+                    }
+                }
+                // A sequence cut short for good goes out as it is.
+                let held = framer.as_mut().map(Utf8Framer::flush);
+                if let Some(held) = held.filter(|held| !held.is_empty()) {
+                    if let Err(e) = websocket_sender.send(output_message(&held)) {
+                        anyhow::bail!("failed to send msg to client: {:?}", e);
+                    }
+                }
+                // As does what waits for it to end.
+                let held = vt.lock().unwrap().sequencer().release();
+                if let Some(held) = held {
+                    if let Err(e) = websocket_sender.send(output_message(&held)) {
+                        anyhow::bail!("failed to send msg to client: {:?}", e);
+                    }
+                }
                 break;
             }
-            match websocket_sender.send(Message::Binary(buffer[..n + 1].to_vec())) {
-                Ok(_) => (),
-                Err(e) => anyhow::bail!("failed to send msg to client: {:?}", e),
+            if let Some(ref tracer) = tracer {
+                tracer.lock().unwrap().read();
+            }
+            crate::metrics::output(n);
+            #[cfg(feature = "fault-injection")]
+            let n = match cut {
+                Some(ref mut cut) => {
+                    let (n, kill) = cut.cut(n);
+                    if kill {
+                        handle.master.kill();
+                    }
+                    n
+                }
+                None => n,
+            };
+
+            if raw {
+                // What the filters held goes out first.
+                let mut held = framer.as_mut().map(Utf8Framer::flush).unwrap_or_default();
+                held.extend(
+                    repeats
+                        .as_mut()
+                        .map(RepeatFilter::flush)
+                        .unwrap_or_default(),
+                );
+                if forward(&held, &vt, &handle, &websocket_sender)? {
+                    if let Some(ref meter) = meter {
+                        meter.account(held.len()).await?;
+                    }
+                }
+            }
+            let filtered;
+            let output = match sanitizer {
+                Some(ref mut sanitizer) if !raw => {
+                    filtered = sanitizer.filter(&buffer[1..n + 1]);
+                    &filtered[..]
+                }
+                _ => &buffer[1..n + 1],
+            };
+            let framed;
+            let output = match framer {
+                Some(ref mut framer) if !raw => {
+                    framed = framer.frame(output);
+                    &framed[..]
+                }
+                _ => output,
+            };
+
+            if let Some(recorder) = handle.recorder() {
+                recorder.output(output);
+            }
+            if let Some(ref mirror) = handle.mirror {
+                mirror.output(output);
+            }
+
+            let deduped;
+            let output = match repeats {
+                Some(ref mut repeats) if !raw => {
+                    deduped = repeats.filter(output, clock.now());
+                    &deduped[..]
+                }
+                _ => output,
+            };
+
+            let sent = forward(output, &vt, &handle, &websocket_sender)?;
+            if sent {
+                if let Some(ref meter) = meter {
+                    meter.account(output.len()).await?;
+                }
             }
         }
         Ok::<(), anyhow::Error>(())
     };
-    fut.await.map_err(|e| {
-        error!("handle pty incoming error: {:?}", &e);
-        e
+    fut.await.inspect_err(|e| {
+        error!("handle pty incoming error: {:?}", e);
     })
 }
 
-async fn write_to_websocket(
-    mut outgoing: SplitSink<WebSocketStream<TcpStream>, Message>,
-    mut receiver: UnboundedReceiver<Message>,
+// Puts output on the screen and sends it unless in frame mode, returning
+// whether it was sent. Raw output is sent with the lock held so that it
+// can't overtake the last diff when frame mode gets disabled. During raw
+// passthrough it is only sent, see the `toggles` module.
+fn forward(
+    output: &[u8],
+    vt: &Mutex<VtState>,
+    handle: &SessionHandle,
+    websocket_sender: &Outbox,
+) -> Result<bool, anyhow::Error> {
+    let mut vt = vt.lock().unwrap();
+    if vt.raw() {
+        handle.tap(output);
+        if !output.is_empty() {
+            if let Err(e) = websocket_sender.send(output_message(output)) {
+                anyhow::bail!("failed to send msg to client: {:?}", e);
+            }
+        }
+        return Ok(!output.is_empty());
+    }
+    vt.process(output);
+    handle.tap(output);
+    let sent = !vt.frame_mode() && !output.is_empty();
+    if sent {
+        if let Err(e) = websocket_sender.send(output_message(output)) {
+            anyhow::bail!("failed to send msg to client: {:?}", e);
+        }
+        if let Some(held) = vt.sequencer().output(output) {
+            if let Err(e) = websocket_sender.send(output_message(&held)) {
+                anyhow::bail!("failed to send msg to client: {:?}", e);
+            }
+        }
+    }
+    for frame in vt.take_notifications() {
+        if let Err(e) = websocket_sender.send(Message::Binary(frame)) {
+            anyhow::bail!("failed to send msg to client: {:?}", e);
+        }
+    }
+    let finished = vt.take_finished();
+    drop(vt);
+    if let Some(ref alerts) = handle.alerts {
+        alerts.output(handle, output);
+    }
+    if let Some(ref commands) = handle.commands {
+        for finished in finished {
+            commands.record(handle, finished);
+        }
+    }
+    Ok(sent)
+}
+
+// Sends output of the server's own along with the pty's, unless that would
+// put it in the middle of an escape sequence, see the `sequencer` module.
+fn inject(
+    data: &[u8],
+    vt: &Arc<Mutex<VtState>>,
+    websocket_sender: &Outbox,
+    clock: &Arc<dyn Clock>,
 ) -> Result<(), anyhow::Error> {
-    while let Some(msg) = receiver.recv().await {
-        outgoing.send(msg).await?;
+    let mut locked = vt.lock().unwrap();
+    let now = if locked.frame_mode() || locked.raw() {
+        Some(data.to_vec())
+    } else {
+        locked.sequencer().inject(data)
+    };
+    match now {
+        Some(data) => websocket_sender.send(output_message(&data))?,
+        None => {
+            let vt = vt.clone();
+            let websocket_sender = websocket_sender.clone();
+            let clock = clock.clone();
+            tokio::spawn(async move {
+                let deadline = clock.now() + crate::sequencer::HOLD_TIMEOUT;
+                clock.sleep_until(deadline).await;
+                let mut vt = vt.lock().unwrap();
+                if let Some(held) = vt.sequencer().release() {
+                    let _ = websocket_sender.send(output_message(&held));
+                }
+            });
+        }
     }
     Ok(())
 }
 
-async fn handle_connection(stream: TcpStream) -> Result<(), anyhow::Error> {
-    let ws_stream = accept_async(stream).await?;
-    let (ws_outgoing, mut ws_incoming) = ws_stream.split();
-    let (sender, receiver) = unbounded_channel();
-    let ws_sender = sender.clone();
+// Brings the colors of output messages down to what the client can show,
+// then seals them when integrity mode is on, or compresses them. Also
+// returns whether the message is output.
+fn seal(mut msg: Message, state: &SessionState) -> (Message, bool) {
+    if let Message::Binary(ref mut data) = msg {
+        let output = data.first() == Some(&proto::OUTPUT);
+        let filter = output && !state.vt.lock().unwrap().raw();
+        if let (Some(colors), true) = (state.colors.lock().unwrap().as_mut(), filter) {
+            *data = proto::frame(proto::OUTPUT, &colors.filter(&data[1..]));
+        }
+        // The output of split sessions goes as it is.
+        if state.control.is_some() {
+            return (msg, output);
+        }
+        let mut integrity = state.integrity.lock().unwrap();
+        if integrity.enabled() && output {
+            return (Message::Binary(integrity.seal(&data[1..])), true);
+        }
+        if let (Some(deflater), true) = (state.deflater.lock().unwrap().as_mut(), output) {
+            if let Some(compressed) = deflater.compress(&data[1..]) {
+                let msg = proto::frame(proto::COMPRESSED_OUTPUT, &compressed);
+                return (Message::Binary(msg), true);
+            }
+        }
+        return (msg, output);
+    }
+    (msg, false)
+}
 
-    // Default command.
-    let mut cmd = Command::new("/usr/bin/bash");
+fn dumped(msg: &Message, state: &SessionState) {
+    if let Some(ref dump) = state.dump {
+        dump.record(Direction::Out, msg);
+    }
+}
 
-    if let Some(Ok(Message::Text(cmd2))) = ws_incoming.next().await {
-        cmd = Command::new(cmd2);
+// What goes out on the client's connection, see the `control` module.
+fn split(msg: Message, state: &SessionState) -> Option<Message> {
+    match state.control {
+        Some(ref control) => control.route(msg),
+        None => Some(msg),
     }
+}
 
-    if let Ok(home) = std::env::var("HOME") {
-        cmd.current_dir(home);
+// Messages are only taken from the queue once the sink can accept them, so
+// that none is lost when this gets cancelled for the teardown.
+async fn write_to_websocket<O>(
+    outgoing: &mut O,
+    receiver: &mut OutboxReceiver,
+    state: &SessionState,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+{
+    #[cfg(feature = "fault-injection")]
+    let mut injector = state.config.faults.as_ref().map(Injector::new);
+    loop {
+        #[cfg(feature = "fault-injection")]
+        if let Some(pause) = injector.as_mut().and_then(Injector::stall) {
+            tokio::time::sleep(pause).await;
+        }
+        future::poll_fn(|cx| outgoing.poll_ready_unpin(cx)).await?;
+        let (msg, output) = match receiver.recv().await {
+            Some(msg) => seal(msg, state),
+            None => break,
+        };
+        let msg = match split(msg, state) {
+            Some(msg) => msg,
+            None => continue,
+        };
+        #[cfg(feature = "fault-injection")]
+        if let (Some(injector), true) = (injector.as_mut(), output) {
+            if injector.drop_output() {
+                continue;
+            }
+            tokio::time::sleep(injector.output_delay()).await;
+            injector.sent();
+        }
+        let close = msg.is_close();
+        if let Some(ref counters) = state.handle.counters {
+            counters.sent(msg.len());
+        }
+        dumped(&msg, state);
+        outgoing.start_send_unpin(msg)?;
+        outgoing.flush().await?;
+        if close {
+            break;
+        }
+        let report = match state.tracer {
+            Some(ref tracer) if output => tracer.lock().unwrap().sent(),
+            _ => None,
+        };
+        if let Some(report) = report {
+            let report = ServerMessage::TraceReport(report).encode();
+            if let Some(report) = split(Message::Binary(report), state) {
+                dumped(&report, state);
+                outgoing.send(report).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// A listener once its TLS configuration is loaded.
+struct Realm {
+    listener: Listener,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Realm {
+    fn new(listener: Listener) -> Result<Self, anyhow::Error> {
+        Ok(Realm {
+            #[cfg(feature = "tls")]
+            tls: match listener.tls {
+                Some(ref tls) => Some(tls.load()?),
+                None => None,
+            },
+            listener,
+        })
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    config: Arc<ServerConfig>,
+    realm: Arc<Realm>,
+) -> Result<(), anyhow::Error> {
+    #[cfg(feature = "tls")]
+    if let Some(ref tls) = realm.tls {
+        let stream = crate::tls::accept(stream, tls.clone()).await?;
+        if let Some(protocol) = stream.alpn_protocol() {
+            if protocol != crate::tls::HTTP_1_1 {
+                let acceptors = &realm.listener.tls.as_ref().unwrap().acceptors;
+                match acceptors.get(protocol) {
+                    Some(acceptor) => acceptor(stream, peer),
+                    None => warn!("no acceptor for {:?}", String::from_utf8_lossy(protocol)),
+                }
+                return Ok(());
+            }
+        }
+        return upgrade(stream, peer, config, &realm.listener, None).await;
     }
 
+    let transport = stream.as_fd().try_clone_to_owned()?;
+    serve_connection(stream, peer, config, &realm.listener, Some(transport)).await
+}
+
+// A plain connection to any of the listeners.
+pub(crate) async fn serve_connection<S>(
+    stream: S,
+    peer: SocketAddr,
+    config: Arc<ServerConfig>,
+    listener: &Listener,
+    transport: Option<OwnedFd>,
+) -> Result<(), anyhow::Error>
+where
+    S: ui::Peek + AsyncRead + AsyncWrite + Unpin,
+{
+    if config.ui.is_some() && !ui::is_websocket_upgrade(&stream).await? {
+        ui::serve_asset(stream, &config).await?;
+        return Ok(());
+    }
+    upgrade(stream, peer, config, listener, transport).await
+}
+
+// Runs the WebSocket handshake and the session, `transport` is the client
+// socket for sessions that can be migrated.
+async fn upgrade<S>(
+    stream: S,
+    peer: SocketAddr,
+    config: Arc<ServerConfig>,
+    listener: &Listener,
+    transport: Option<OwnedFd>,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut handshake = Handshake::default();
+    let upgrade = Upgrade {
+        config: &config,
+        listener,
+        peer,
+        handshake: &mut handshake,
+    };
+    let limits = config.message_limits;
+    let ws_stream =
+        accept_hdr_async_with_config(stream, upgrade, Some(limits.websocket_config())).await?;
+    let (ws_outgoing, ws_incoming) = ws_stream.split();
+    let ws_outgoing = limits.fragmenting(ws_outgoing);
+    if handshake.envelope {
+        serve_session(
+            framing::outgoing(ws_outgoing),
+            framing::incoming(ws_incoming),
+            peer,
+            handshake,
+            config,
+            transport,
+        )
+        .await
+    } else {
+        serve_session(ws_outgoing, ws_incoming, peer, handshake, config, transport).await
+    }
+}
+
+// Runs a session on a WebSocket that another HTTP server already upgraded,
+// to serve the terminal on one of its routes instead of a listener of our
+// own. `peer` and `identity`, as the embedder authenticated the client, are
+// what the policies get. Clients speak the binary protocol and
+// `MessageLimits` only apply to outgoing messages, the incoming ones being
+// limited by the `WebSocketConfig` the stream was accepted with.
+pub async fn serve_pty<S>(
+    ws: WebSocketStream<S>,
+    peer: SocketAddr,
+    identity: Option<String>,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = Handshake {
+        identity,
+        ..Default::default()
+    };
+    let (ws_outgoing, ws_incoming) = ws.split();
+    let ws_outgoing = config.message_limits.fragmenting(ws_outgoing);
+    serve_session(ws_outgoing, ws_incoming, peer, handshake, config, None).await
+}
+
+// Semantic prompt marks for the `sentinel` module. `$?` is still the exit
+// code of the last command when the prompt command runs, which also appends
+// the input mark to whatever prompt the rc files set.
+const BASH_PS0: &str = "\x1b]133;C\x07";
+const BASH_PROMPT_COMMAND: &str = concat!(
+    "printf '\\033]133;D;%s\\007\\033]133;A\\007' \"$?\"; ",
+    "case $PS1 in *133\\;B*) ;; *) PS1=\"$PS1\"'\\[\\033]133;B\\007\\]';; esac"
+);
+
+// Spawns `request` in a new pty, the default shell if no program is given,
+// for a client with `identity`, with a terminal of `size` as (cols, rows).
+pub(crate) async fn spawn_shell(
+    request: &SpawnRequest,
+    config: &ServerConfig,
+    identity: Option<&str>,
+    size: Option<(u16, u16)>,
+) -> Result<Warm, IoError> {
+    let res = spawn(request, config, identity, size).await;
+    if res.is_err() {
+        crate::metrics::spawn_failed();
+    }
+    res
+}
+
+async fn spawn(
+    request: &SpawnRequest,
+    config: &ServerConfig,
+    identity: Option<&str>,
+    size: Option<(u16, u16)>,
+) -> Result<Warm, IoError> {
+    let account = config.account(identity)?;
+    let exec = match (&request.container, &config.container_exec) {
+        (Some(container), Some(exec)) => Some((exec, container.as_str())),
+        (Some(_), None) => {
+            return Err(IoError::new(
+                ErrorKind::PermissionDenied,
+                "containers are not enabled",
+            ))
+        }
+        (None, _) => None,
+    };
+    // Containers go first, their commands are never routed.
+    let remote = match exec {
+        Some(_) => None,
+        None => crate::ssh::route(&config.ssh_routes, request, identity),
+    };
+    let (program, mut cmd) = match (&account, exec, &remote) {
+        (_, Some((exec, container)), _) => (exec.runtime.name(), exec.command(container, request)),
+        (_, None, Some((route, remote))) => ("ssh", route.command(remote)),
+        (Some(account), None, None)
+            if request.program().is_empty() && config.default_command.is_none() =>
+        {
+            let mut cmd = Command::new(&account.shell);
+            cmd.arg0(account.login_name());
+            (account.shell.as_str(), cmd)
+        }
+        _ => {
+            let program = config.resolve_command(request.program());
+            (program, Command::new(program))
+        }
+    };
     let mut envs = HashMap::new();
     envs.insert("COLORTERM", "truecolor");
     envs.insert("TERM", "xterm-256color");
+    if config.command_sentinels && program.ends_with("bash") {
+        envs.insert("PS0", BASH_PS0);
+        envs.insert("PROMPT_COMMAND", BASH_PROMPT_COMMAND);
+    }
 
     cmd.envs(&envs);
+    config.prepare(&mut cmd, account.as_ref());
+    // Sessions opened over SSH or in containers get them on the other side.
+    if exec.is_none() && remote.is_none() {
+        cmd.args(request.cmd.iter().skip(1));
+        if let Some(ref cwd) = request.cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.envs(&request.env);
+    }
+    let dev = match (&config.devices, exec, &remote) {
+        (Some(devices), None, None) => Some(PrivateDev::new(devices)?),
+        _ => None,
+    };
+    let workspace = match (&config.workspace, exec, &remote) {
+        (Some(workspace), None, None) => Some(Arc::new(workspace.provision(account.as_ref())?)),
+        _ => None,
+    };
+    if let Some(ref workspace) = workspace {
+        if request.cwd.is_none() {
+            cmd.current_dir(&workspace.path);
+        }
+        cmd.env("HOME", &workspace.path);
+    }
 
+    let mut spawn = SpawnInfo::capture(
+        &crate::spawn::command_line(request),
+        cmd.as_std(),
+        !config.clear_env,
+    );
+    spawn.host = remote.map(|(route, _)| route.host.clone());
+    spawn.container = exec.map(|(_, container)| container.to_owned());
     let mut pty_cmd = PtyCommand::from(cmd);
-    let (stop_sender, stop_receiver) = unbounded_channel();
-    let pty_master = pty_cmd.run(stop_receiver).await?;
+    if let Some(account) = account {
+        spawn.uid = account.uid.as_raw();
+        pty_cmd.run_as(account);
+    }
+    if let Some(ref control) = config.job_control {
+        pty_cmd.tostop(control.tostop);
+    }
+    if let Some((cols, rows)) = size {
+        pty_cmd.size(cols, rows);
+    }
+    if let Some(mut dev) = dev {
+        unsafe {
+            pty_cmd.pre_exec(move || dev.mount());
+        }
+    }
+    let (stopper, stop_receiver) = unbounded_channel();
+    let master = match pty_cmd.run(stop_receiver).await {
+        Ok(master) => master,
+        Err(e) => {
+            if let Some(workspace) = workspace {
+                workspace.remove();
+            }
+            return Err(e);
+        }
+    };
+    if let Some(workspace) = workspace.clone() {
+        let exited = master.on_exit();
+        tokio::spawn(async move {
+            exited.await;
+            let _ = tokio::task::spawn_blocking(move || workspace.remove()).await;
+        });
+    }
+    Ok(Warm {
+        master,
+        stopper,
+        spawn,
+        workspace,
+    })
+}
+
+// Closes the connection of a client turned away with a policy violation.
+async fn refuse_session<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    peer: SocketAddr,
+    reason: CloseReason,
+    config: &ServerConfig,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    warn!("rejecting session from {:?}: {}", peer, reason);
+    let farewell = CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.to_string().into(),
+    };
+    teardown::refuse(
+        &mut ws_outgoing,
+        &mut ws_incoming,
+        farewell,
+        config.teardown,
+    )
+    .await?;
+    Ok(())
+}
+
+// Runs a shell for a client speaking the binary protocol, whatever the
+// transport is.
+pub(crate) async fn serve_session<O, I>(
+    ws_outgoing: O,
+    mut ws_incoming: I,
+    peer: SocketAddr,
+    mut handshake: Handshake,
+    config: Arc<ServerConfig>,
+    transport: Option<OwnedFd>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    #[cfg(feature = "challenge-auth")]
+    let mut ws_outgoing = ws_outgoing;
+    #[cfg(feature = "challenge-auth")]
+    if let (Some(auth), false) = (config.challenge_auth.as_ref(), handshake.authenticated) {
+        match auth.authenticate(&mut ws_outgoing, &mut ws_incoming).await {
+            Ok(key_identity) => {
+                handshake.identity.get_or_insert(key_identity);
+                handshake.authenticated = true;
+            }
+            Err(reason) => {
+                let reason = CloseReason::Unauthorized(reason);
+                return refuse_session(ws_outgoing, ws_incoming, peer, reason, &config).await;
+            }
+        }
+    }
+    let mut first = ws_incoming.next().await;
+    // Nothing is spawned before the token is checked.
+    if let (Some(validate), false) = (config.token_auth.as_ref(), handshake.authenticated) {
+        let token = match first {
+            Some(Ok(Message::Binary(ref data))) => match ClientMessage::decode(data) {
+                Ok(ClientMessage::Auth(auth)) => Some(auth.token),
+                _ => None,
+            },
+            _ => None,
+        };
+        let res = token
+            .ok_or_else(|| "authentication required".to_owned())
+            .and_then(|token| validate(&token, peer));
+        match res {
+            Ok(claims) => {
+                handshake.identity.get_or_insert(claims.identity);
+                handshake.capabilities = claims.capabilities;
+                first = ws_incoming.next().await;
+            }
+            Err(reason) => {
+                let reason = CloseReason::Unauthorized(reason);
+                return refuse_session(ws_outgoing, ws_incoming, peer, reason, &config).await;
+            }
+        }
+    }
+
+    if let Some(ref events) = config.events {
+        events.on_connect(peer, handshake.identity.as_deref());
+    }
+    if let Some(Ok(Message::Binary(ref data))) = first {
+        match ClientMessage::decode(data) {
+            Ok(ClientMessage::Resume(resume)) => {
+                #[cfg(feature = "resume-routing")]
+                if let Some(upstream) = config
+                    .resume_routing
+                    .as_ref()
+                    .and_then(|routing| routing.upstream(&resume.token))
+                {
+                    let route = crate::ProxyRoute {
+                        prefix: String::new(),
+                        identity: None,
+                        upstream: upstream.to_owned(),
+                        tunnel: None,
+                    };
+                    let first = Message::Binary(data.clone());
+                    return crate::proxy::serve_proxy(
+                        ws_outgoing,
+                        ws_incoming,
+                        &route,
+                        first,
+                        "a resume",
+                        peer,
+                    )
+                    .await;
+                }
+                return crate::persist::resume(
+                    ws_outgoing,
+                    ws_incoming,
+                    peer,
+                    handshake.identity,
+                    &resume.token,
+                    config,
+                )
+                .await;
+            }
+            Ok(ClientMessage::Attach(attach)) => {
+                return crate::sharing::attach(
+                    ws_outgoing,
+                    ws_incoming,
+                    peer,
+                    handshake,
+                    attach,
+                    config,
+                )
+                .await;
+            }
+            Ok(ClientMessage::Control(control)) => {
+                return crate::control::serve(
+                    ws_outgoing,
+                    ws_incoming,
+                    peer,
+                    handshake.identity,
+                    &control.token,
+                    config,
+                )
+                .await;
+            }
+            Ok(ClientMessage::Open(open)) => {
+                return crate::mux::serve_mux(
+                    ws_outgoing,
+                    ws_incoming,
+                    peer,
+                    handshake,
+                    open,
+                    config,
+                )
+                .await;
+            }
+            _ => {}
+        }
+    }
+    serve_command(
+        ws_outgoing,
+        ws_incoming,
+        first,
+        peer,
+        handshake,
+        config,
+        transport,
+    )
+    .await
+}
+
+// The rest of `serve_session` once the client is authenticated, from its
+// `first` message.
+pub(crate) async fn serve_command<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    first: Option<Result<Message, WsError>>,
+    peer: SocketAddr,
+    handshake: Handshake,
+    config: Arc<ServerConfig>,
+    transport: Option<OwnedFd>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let Handshake {
+        identity,
+        correlation_id,
+        profile,
+        size,
+        theme,
+        colors,
+        capabilities,
+        low_power,
+        split,
+        render_rate,
+        ..
+    } = handshake;
+    // The pool's shells were spawned with the server's profile.
+    let pooled = profile.is_none();
+    let config = match profile
+        .as_ref()
+        .and_then(|name| config.resolve_profile(name).ok())
+    {
+        Some(profile) => {
+            let mut custom = (*config).clone();
+            custom.set_profile(profile);
+            Arc::new(custom)
+        }
+        None => config,
+    };
+    let text = match first {
+        Some(Ok(Message::Text(text))) => text,
+        Some(Ok(Message::Binary(data))) => match ClientMessage::decode(&data) {
+            Ok(ClientMessage::DryRun(dry_run)) => {
+                let decision = config.admit(peer, identity.as_deref(), &dry_run.command);
+                debug!("dry run from {:?}: {:?}", peer, decision);
+                ws_outgoing
+                    .send(Message::Binary(ServerMessage::Decision(decision).encode()))
+                    .await?;
+                ws_outgoing.send(Message::Close(None)).await?;
+                return Ok(());
+            }
+            _ => String::new(),
+        },
+        _ => String::new(),
+    };
+    if !capabilities.spawn {
+        let reason = CloseReason::Forbidden("token doesn't allow starting commands".to_owned());
+        return refuse_session(ws_outgoing, ws_incoming, peer, reason, &config).await;
+    }
 
-    let pty_shell_writer = pty_master.clone();
-    let pty_shell_reader = pty_master.clone();
+    let request = SpawnRequest::parse(&text)
+        .map_err(|e| Refusal::Spawn(format!("invalid spawn request: {}", e)));
+    // As requested, for the fallback shell banner.
+    let requested = request
+        .as_ref()
+        .map(crate::spawn::command_line)
+        .unwrap_or_default();
+    // Refused commands get the fallback shell, if any.
+    let admission = config
+        .check_peer(peer, identity.as_deref())
+        .and(request)
+        .and_then(|mut request| {
+            config
+                .check_spawn(&mut request, peer, identity.as_deref())
+                .map(|()| request)
+        });
+    let (request, banner) = match (admission, config.fallback_shell.as_ref()) {
+        (Ok(request), _) => (request, None),
+        (Err(Refusal::Command(reason)), Some(fallback)) => {
+            info!("running {} for {:?}: {}", fallback.command, peer, reason);
+            let banner = fallback.banner(&requested, &reason);
+            (crate::spawn::plain(&fallback.command), Some(banner))
+        }
+        (Err(refusal), _) => {
+            let reason = refusal.into();
+            return refuse_session(ws_outgoing, ws_incoming, peer, reason, &config).await;
+        }
+    };
+    let command = crate::spawn::command_line(&request);
 
-    let res = tokio::select! {
-        res = handle_websocket_incoming(ws_incoming, pty_shell_writer, sender, stop_sender) => res,
-        res = handle_pty_incoming(pty_shell_reader, ws_sender) => res,
-        res = write_to_websocket(ws_outgoing, receiver) => res,
+    // The slots are held until the session ends.
+    let mut slots = Slots::default();
+    if let Some(ref limit) = config.peer_limit {
+        match limit.acquire(peer.ip()) {
+            Some(slot) => slots.peer = Some(slot),
+            None => {
+                let reason = config
+                    .capacity("too many sessions from this address")
+                    .into();
+                return refuse_session(ws_outgoing, ws_incoming, peer, reason, &config).await;
+            }
+        }
+    }
+    // Waiting for the profile's slot first, so as not to hold the server's.
+    let mut queued = vec![];
+    if let Some((name, limit)) = config.profile_limit(profile.as_deref(), request.program()) {
+        let refusal = config.capacity(&format!("too many {} sessions", name));
+        match wait_for_slot(
+            limit,
+            peer,
+            refusal,
+            &mut ws_outgoing,
+            &mut ws_incoming,
+            &config,
+        )
+        .await?
+        {
+            Some((slot, messages)) => {
+                slots.profile = Some(slot);
+                queued.extend(messages);
+            }
+            None => return Ok(()),
+        }
+    }
+    if let Some(ref limit) = config.session_limit {
+        let refusal = config.capacity("server at capacity");
+        match wait_for_slot(
+            limit,
+            peer,
+            refusal,
+            &mut ws_outgoing,
+            &mut ws_incoming,
+            &config,
+        )
+        .await?
+        {
+            Some((slot, messages)) => {
+                slots.session = Some(slot);
+                queued.extend(messages);
+            }
+            None => return Ok(()),
+        }
+    }
+    let mut ws_incoming = futures::stream::iter(queued.into_iter().map(Ok)).chain(ws_incoming);
+
+    if let Some((route, command)) =
+        crate::proxy::route(&config.proxy_routes, &command, identity.as_deref())
+    {
+        // The upstream server gets the request without the route prefix.
+        let command = if request.is_plain() {
+            command.to_owned()
+        } else {
+            let mut request = request.clone();
+            if let Some(program) = request.cmd.first_mut() {
+                if let Some(rest) = program.strip_prefix(route.prefix.as_str()) {
+                    *program = rest.to_owned();
+                }
+            }
+            request.encode()
+        };
+        let what = format!("{:?}", command);
+        let first = Message::Text(command);
+        return crate::proxy::serve_proxy(ws_outgoing, ws_incoming, route, first, &what, peer)
+            .await;
+    }
+
+    if !config.probes.is_empty() {
+        let account = config.account(identity.as_deref())?;
+        let probed = crate::probes::run(
+            &config,
+            account.as_ref(),
+            peer,
+            &mut ws_outgoing,
+            &mut ws_incoming,
+        );
+        if !probed.await? {
+            return Ok(());
+        }
+    }
+
+    if request.is_plain() && config.output_only.contains(&command) {
+        return crate::pipe::serve_pipe(
+            ws_outgoing,
+            ws_incoming,
+            &command,
+            peer,
+            identity.as_deref(),
+            config,
+        )
+        .await;
+    }
+
+    let size = size
+        .map(|(cols, rows)| {
+            let size = config.clamp_size(WindowSize {
+                cols,
+                rows,
+                xpixel: 0,
+                ypixel: 0,
+            });
+            (size.cols, size.rows)
+        })
+        .or(config.default_size);
+    let warm = match config.pool {
+        // Pooled shells run as `run_as`.
+        Some(ref pool) if request.is_plain() && pooled && config.user_mapping.is_none() => {
+            pool.claim(&command, &config)
+        }
+        _ => None,
+    };
+    // Fresh shells start with the right size.
+    let prespawned = warm.is_some();
+    let Warm {
+        master: pty_master,
+        stopper: stop_sender,
+        spawn,
+        workspace,
+    } = match warm {
+        Some(warm) => warm,
+        None => match spawn_shell(&request, &config, identity.as_deref(), size).await {
+            Ok(warm) => warm,
+            Err(e) if is_pty_exhausted(&e) => {
+                warn!("no pty left for {:?}: {:?}", peer, e);
+                if let Some(ref events) = config.events {
+                    events.on_spawn_failure(peer, &e);
+                }
+                ws_outgoing
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Again,
+                        reason: CloseReason::NoPty.to_string().into(),
+                    })))
+                    .await?;
+                return Ok(());
+            }
+            Err(e) => {
+                if let Some(ref events) = config.events {
+                    events.on_spawn_failure(peer, &e);
+                }
+                return Err(e.into());
+            }
+        },
+    };
+
+    if let Some(progress) = progress_message(&spawn) {
+        ws_outgoing.send(progress).await?;
+    }
+
+    let theme = match theme {
+        Some(name) => config.themes.get(&name),
+        None => config.theme.as_ref(),
+    };
+    if let Some(theme) = theme {
+        // Sent before reading anything from the pty.
+        ws_outgoing.send(output_message(&theme.sequences())).await?;
+    }
+    if let Some(banner) = banner {
+        ws_outgoing.send(output_message(banner.as_bytes())).await?;
+    }
+
+    let (cols, rows) = size.unwrap_or((80, 24));
+    if size.is_some() && prespawned {
+        pty_master.resize_async(cols, rows, 0, 0).await?;
+    }
+    if let Some(ref startup) = config.startup {
+        // Typed in as if the user did, the shell reads it once ready.
+        let mut writer = pty_master.clone();
+        writer.write_all(startup.as_bytes()).await?;
+        if !startup.ends_with('\n') {
+            writer.write_all(b"\n").await?;
+        }
+    }
+    let mut vt = VtState::new(rows, cols);
+    if config.command_sentinels {
+        vt.track_commands();
+    }
+    let mut handle = SessionHandle::new(
+        NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+        peer,
+        pty_master,
+        Arc::new(Mutex::new(vt)),
+        transport,
+    );
+    handle.identity = identity;
+    *handle.owner.lock().unwrap() = handle.identity.clone();
+    handle.profile = profile;
+    let size = WindowSize {
+        cols,
+        rows,
+        xpixel: 0,
+        ypixel: 0,
+    };
+    handle.sizes = Arc::new(Mutex::new(Sizes::new(config.size_policy, Some(size))));
+    if let Some(ref generate) = config.session_ids {
+        handle.session_id = generate().into();
+    }
+    handle.correlation_id = correlation_id;
+    handle.colors = colors;
+    handle.capabilities = capabilities;
+    handle.low_power = low_power;
+    handle.render_rate = render_rate;
+    handle.split = split;
+    info!(
+        "session {} for {:?} ({:?}) spawned: {:?}",
+        handle, peer, handle.identity, spawn
+    );
+    if let Some(recording) = config.recording.as_ref().filter(|r| !r.on_demand) {
+        let recorder = Recorder::start(recording, &handle, &spawn)
+            .await
+            .map_err(|e| error!("failed to start recording: {:?}", e))
+            .ok();
+        *handle.recorder.lock().unwrap() = recorder;
+    }
+    if let Some(ref mirror) = config.mirror {
+        handle.mirror = Some(Mirror::start(mirror, &handle, &command));
+    }
+    handle.spawn = Some(Arc::new(spawn));
+    handle.workspace = workspace;
+    if let Some(ref events) = config.events {
+        events.on_spawn(&handle);
+    }
+    run_session(ws_outgoing, ws_incoming, handle, stop_sender, slots, config).await
+}
+
+// Keeps the client waiting for a session slot if the limit allows queueing,
+// with position updates. Returns `None` if the client was turned away or
+// left the queue, otherwise what it sent meanwhile (the initial resize
+// notably) to be handled once the session starts.
+async fn wait_for_slot<O, I>(
+    limit: &SessionLimit,
+    peer: SocketAddr,
+    refusal: Refusal,
+    ws_outgoing: &mut O,
+    ws_incoming: &mut I,
+    config: &ServerConfig,
+) -> Result<Option<(Slot, Vec<Message>)>, anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let mut ticket = match limit.acquire() {
+        Admission::Admitted(slot) => return Ok(Some((slot, vec![]))),
+        Admission::Queued(ticket) => ticket,
+        Admission::Full => {
+            let reason = refusal.into();
+            refuse_session(ws_outgoing, ws_incoming, peer, reason, config).await?;
+            return Ok(None);
+        }
+    };
+
+    let position = *ticket.position.borrow_and_update();
+    debug!("queued session from {:?} at position {}", peer, position);
+    ws_outgoing.send(queue_message(position)).await?;
+    let mut queued = vec![];
+    loop {
+        tokio::select! {
+            slot = &mut ticket.admit => {
+                let slot = slot?;
+                ws_outgoing.send(queue_message(0)).await?;
+                return Ok(Some((slot, queued)));
+            }
+            Ok(()) = ticket.position.changed() => {
+                let position = *ticket.position.borrow_and_update();
+                ws_outgoing.send(queue_message(position)).await?;
+            }
+            msg = ws_incoming.next() => match msg {
+                Some(Ok(Message::Binary(data))) => {
+                    if let Ok(ClientMessage::Ping) = ClientMessage::decode(&data) {
+                        ws_outgoing.send(Message::Binary(vec![proto::PONG])).await?;
+                    } else if queued.len() < MAX_QUEUED_MESSAGES {
+                        queued.push(Message::Binary(data));
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    debug!("{:?} left the queue", peer);
+                    return Ok(None);
+                }
+                _ => (),
+            },
+        }
+    }
+}
+
+// Tells the client about the resizes it didn't ask for.
+async fn push_resizes(state: &SessionState, sender: &Outbox) -> Result<(), anyhow::Error> {
+    let mut pty_size = state.handle.size.subscribe();
+    pty_size.borrow_and_update();
+    while pty_size.changed().await.is_ok() {
+        let (size, source) = *pty_size.borrow_and_update();
+        if source == ResizeSource::Client {
+            continue;
+        }
+        state.pushed_size.lock().unwrap().replace(size);
+        let resized = ServerMessage::Resized(Resized {
+            size,
+            error: None,
+            source,
+        });
+        sender.send(Message::Binary(resized.encode()))?;
+    }
+    std::future::pending().await
+}
+
+// Pixels aside.
+pub(crate) fn same_size(a: WindowSize, b: WindowSize) -> bool {
+    (a.cols, a.rows) == (b.cols, b.rows)
+}
+
+pub(crate) fn exit_message(status: ExitStatus) -> Message {
+    let exit = Exit {
+        code: status.code(),
+        signal: status.signal(),
+    };
+    Message::Binary(ServerMessage::Exit(exit).encode())
+}
+
+// Sent once the child is done and its output flushed.
+fn exited() -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Normal,
+        reason: CloseReason::Exited.to_string().into(),
+    }
+}
+
+fn queue_message(position: usize) -> Message {
+    let queue = proto::QueuePosition {
+        position: position as u32,
+    };
+    Message::Binary(ServerMessage::QueuePosition(queue).encode())
+}
+
+// The first progress of sessions that take a while to get to the command.
+fn progress_message(spawn: &SpawnInfo) -> Option<Message> {
+    let (stage, detail) = match (&spawn.host, &spawn.container) {
+        (_, Some(container)) => (proto::Stage::Attaching, container),
+        (Some(host), None) => (proto::Stage::Connecting, host),
+        (None, None) => return None,
+    };
+    let progress = proto::Progress {
+        stage,
+        detail: Some(detail.clone()),
+    };
+    Some(Message::Binary(ServerMessage::Progress(progress).encode()))
+}
+
+fn session_message(token: &str) -> Message {
+    let session = proto::Session {
+        token: token.to_owned(),
+    };
+    Message::Binary(ServerMessage::Session(session).encode())
+}
+
+// A running session, which may outlive its connection, see the `persist`
+// module.
+pub(crate) struct Live {
+    state: SessionState,
+    sender: Outbox,
+    pub(crate) receiver: OutboxReceiver,
+    // Dropping the last one kills the child.
+    stop_sender: UnboundedSender<()>,
+    pty: JoinHandle<Result<(), anyhow::Error>>,
+    cpu_watch: Option<JoinHandle<()>>,
+    jobs_watch: Option<JoinHandle<()>>,
+    floor_watch: Option<JoinHandle<()>>,
+    presence_watch: Option<JoinHandle<()>>,
+    notification_watch: JoinHandle<()>,
+    timeout_watch: Option<JoinHandle<()>>,
+    // Set when the session can be resumed.
+    token: Option<String>,
+    _slots: Slots,
+    _running: crate::metrics::Running,
+}
+
+impl Live {
+    pub(crate) fn handle(&self) -> &SessionHandle {
+        &self.state.handle
+    }
+
+    pub(crate) fn stop(&self) {
+        let _ = self
+            .stop_sender
+            .send(())
+            .map_err(|e| debug!("failed to send stop signal: {:?}", e));
+    }
+
+    // Stops pumping data, for good.
+    pub(crate) async fn end(mut self) {
+        if !self.pty.is_finished() {
+            self.pty.abort();
+            let _ = (&mut self.pty).await;
+        }
+        if let Some(ref cpu_watch) = self.cpu_watch {
+            cpu_watch.abort();
+        }
+        if let Some(ref jobs_watch) = self.jobs_watch {
+            jobs_watch.abort();
+        }
+        if let Some(ref floor_watch) = self.floor_watch {
+            floor_watch.abort();
+        }
+        if let Some(ref presence_watch) = self.presence_watch {
+            presence_watch.abort();
+        }
+        if let Some(ref timeout_watch) = self.timeout_watch {
+            timeout_watch.abort();
+        }
+        self.notification_watch.abort();
+        if let Some(ref bans) = self.state.config.bans {
+            bans.unregister(self.state.handle.id());
+        }
+        if let Some(ref sharing) = self.state.config.sharing {
+            sharing.unregister(self.state.handle.session_id());
+        }
+        if let Some(ref control) = self.state.control {
+            control.close();
+        }
+        if let Some(ref budget) = self.state.config.memory_budget {
+            budget.unregister(self.state.handle.id());
+        }
+    }
+}
+
+impl Drop for Live {
+    fn drop(&mut self) {
+        self.state.handle.done.send_replace(true);
+    }
+}
+
+// Pumps data between the client and a running pty until either side is
+// done, or the session gets detached.
+pub(crate) async fn run_session<O, I>(
+    ws_outgoing: O,
+    ws_incoming: I,
+    mut handle: SessionHandle,
+    stop_sender: UnboundedSender<()>,
+    slots: Slots,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let (sender, receiver) = outbox(config.output_backlog.unwrap_or(DEFAULT_OUTPUT_BACKLOG));
+    let peer = handle.peer();
+    // Accounting counts the bytes too.
+    if config.instrumentation || config.accounting.is_some() {
+        let counters = Arc::new(Counters::default());
+        if config.instrumentation {
+            let done = handle.done.subscribe();
+            tokio::spawn(crate::instrument::report(
+                handle.to_string(),
+                counters.clone(),
+                done,
+            ));
+        }
+        handle.counters = Some(counters);
+    }
+
+    let pty_shell_reader: Box<dyn AsyncRead + Send + Unpin> = pty_reader(&config, &handle.master)?;
+    let vt = handle.vt.clone();
+    handle.floor = Arc::new(watch::channel(Floor::new(config.input_arbitration)).0);
+    handle.roster = Arc::new(watch::channel(Roster::new(handle.identity.clone())).0);
+    handle.scrollback = config
+        .scrollback
+        .map(|capacity| Arc::new(Mutex::new(Scrollback::new(capacity))));
+    handle.commands = Commands::new(&config);
+    handle.clock = config.clock();
+    handle.alerts = Monitor::new(&config, handle.clock.clone());
+    if let Some(ref alerts) = handle.alerts {
+        tokio::spawn(alerts.clone().run(handle.clone()));
+    }
+    handle.touch();
+    if let Some(ref on_session) = config.on_session {
+        on_session(handle.clone());
+    }
+    handle.record(TimelineEvent::Connected { peer });
+    let events = config.events.clone();
+    let accounting = config.accounting.clone();
+    let exits = handle.clone();
+    tokio::spawn(async move {
+        if let Some(status) = exits.master.exit_status().await {
+            exits.record(TimelineEvent::Exited {
+                code: status.code(),
+                signal: status.signal(),
+            });
+            if let Some(events) = events {
+                events.on_exit(&exits, status);
+            }
+            if let Some(accounting) = accounting {
+                accounting.export(SessionRecord::new(&exits, status, SystemTime::now()));
+            }
+        }
+    });
+    if let Some(ref bans) = config.bans {
+        bans.register(&handle);
+    }
+    if let Some(ref sharing) = config.sharing {
+        sharing.register(&handle);
+    }
+
+    // Probed sessions told the client too.
+    let progress =
+        !config.probes.is_empty() || handle.spawn.as_ref().is_some_and(|spawn| spawn.is_remote());
+    let colors = handle
+        .colors
+        .or(config.color_level)
+        .and_then(ColorFilter::new);
+    // Latency traces keep clients awake.
+    let tracing = config.latency_tracing && !handle.low_power;
+    let control = match config.control_channels {
+        Some(ref channels) if handle.split => Some(channels.register(&handle)?),
+        _ => None,
+    };
+    let dump = config
+        .frame_dumps
+        .as_ref()
+        .and_then(|dumps| FrameDump::start(dumps, &handle));
+    let meter = config
+        .bandwidth
+        .clone()
+        .map(|ledger| Meter::new(ledger, handle.identity(), peer));
+    let state = SessionState {
+        handle,
+        meter,
+        vt,
+        integrity: Arc::new(Mutex::new(Integrity::default())),
+        deflater: Arc::new(Mutex::new(None)),
+        colors: Arc::new(Mutex::new(colors)),
+        pushed_size: Arc::new(Mutex::new(None)),
+        read_only: Arc::new(AtomicBool::new(false)),
+        tracer: tracing.then(|| Arc::new(Mutex::new(Tracer::default()))),
+        config: config.clone(),
+        progress,
+        control,
+        dump,
+    };
+
+    if let Some(ref control) = state.control {
+        sender.send(control.message())?;
+    }
+
+    if let Some(ref budget) = config.memory_budget {
+        budget.register(&state.handle, &sender, &state.deflater);
+    }
+
+    let token = match config.persistence {
+        Some(_) => Some(crate::persist::token()?),
+        None => None,
+    };
+    #[cfg(feature = "resume-routing")]
+    let token = match config.resume_routing {
+        Some(ref routing) => token.map(|token| routing.sign(token)),
+        None => token,
+    };
+    if let Some(ref token) = token {
+        sender.send(session_message(token))?;
+    }
+    if config.sharing.is_some() {
+        let shared = proto::Shared {
+            session_id: state.handle.session_id().to_owned(),
+        };
+        sender.send(Message::Binary(ServerMessage::Shared(shared).encode()))?;
+    }
+    let cpu_watch = config.cpu_budget.clone().map(|budget| {
+        tokio::spawn(crate::cpu::enforce(
+            budget,
+            state.handle.clone(),
+            sender.clone(),
+        ))
+    });
+    let jobs_watch = config.job_control.clone().map(|control| {
+        tokio::spawn(crate::jobs::watch(
+            control,
+            state.handle.clone(),
+            sender.clone(),
+        ))
+    });
+    let floor_watch = config.input_arbitration.then(|| {
+        tokio::spawn(crate::arbitration::watch(
+            state.handle.clone(),
+            sender.clone(),
+        ))
+    });
+    let presence_watch = config
+        .presence
+        .then(|| tokio::spawn(crate::presence::watch(state.handle.clone(), sender.clone())));
+    let notification_watch = tokio::spawn(crate::notifications::watch(
+        state.handle.clone(),
+        sender.clone(),
+    ));
+    let timeouts = match crate::power::granted(&config, &state.handle) {
+        Some(low_power) => low_power.timeouts(config.timeouts.clone()),
+        None => config.timeouts.clone(),
+    };
+    let timeout_watch = timeouts.any().then(|| {
+        tokio::spawn(crate::timeout::watch(
+            timeouts,
+            state.handle.clone(),
+            sender.clone(),
+        ))
+    });
+    let pty = tokio::spawn(handle_pty_incoming(
+        pty_shell_reader,
+        sender.clone(),
+        state.clone(),
+    ));
+    let live = Live {
+        state,
+        sender,
+        receiver,
+        stop_sender,
+        pty,
+        cpu_watch,
+        jobs_watch,
+        floor_watch,
+        presence_watch,
+        notification_watch,
+        timeout_watch,
+        token,
+        _slots: slots,
+        _running: crate::metrics::Running::start(),
+    };
+    drive(ws_outgoing, ws_incoming, live, config).await
+}
+
+// Carries on with a parked session on a new connection, starting with what
+// it missed.
+pub(crate) async fn resume_session<O, I>(
+    mut ws_outgoing: O,
+    ws_incoming: I,
+    parked: Parked,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let Parked {
+        live,
+        buffer,
+        overflowed,
+    } = parked;
+    live.handle().record(TimelineEvent::Reconnected);
+    if let Some(ref alerts) = live.handle().alerts {
+        alerts.detached(false);
+    }
+    // The client starts over with integrity mode and compression off.
+    *live.state.integrity.lock().unwrap() = Integrity::default();
+    *live.state.deflater.lock().unwrap() = None;
+    let mut missed = vec![];
+    if let Some(ref token) = live.token {
+        missed.push(session_message(token));
+    }
+    if overflowed {
+        let snapshot = live.state.vt.lock().unwrap().snapshot();
+        missed.push(output_message(&snapshot));
+    } else {
+        missed.extend(buffer);
+    }
+    for msg in missed {
+        if let Some(msg) = split(msg, &live.state) {
+            dumped(&msg, &live.state);
+            ws_outgoing.feed(msg).await?;
+        }
+    }
+    ws_outgoing.flush().await?;
+    drive(ws_outgoing, ws_incoming, live, config).await
+}
+
+async fn drive<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    mut live: Live,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let state = live.state.clone();
+    let handle = state.handle.clone();
+    let pty_shell_writer = handle.master.clone();
+    let mut detached = false;
+    let mut child_exited = false;
+    let mut lost = false;
+    let mut panicked = false;
+    let events = config.events.as_deref();
+    let mut caught = |task, payload| {
+        panicked = true;
+        (
+            Ok(()),
+            Some(crate::panics::caught(&handle, task, payload, events)),
+        )
+    };
+    let (res, farewell) = {
+        let writer = write_to_websocket(&mut ws_outgoing, &mut live.receiver, &state);
+        let writer = AssertUnwindSafe(writer).catch_unwind();
+        tokio::pin!(writer);
+        let control = state.control.as_ref().map(|control| control.incoming());
+        let incoming = handle_websocket_incoming(
+            crate::control::merge(&mut ws_incoming, control),
+            pty_shell_writer,
+            live.sender.clone(),
+            state.clone(),
+        );
+        let resizes = push_resizes(&state, &live.sender);
+        tokio::select! {
+            res = AssertUnwindSafe(incoming).catch_unwind() => match res {
+                Ok(res) => {
+                    lost = *res.as_ref().unwrap_or(&false);
+                    (res.map(|_| ()), None)
+                }
+                Err(payload) => caught("input", payload),
+            },
+            res = &mut live.pty => match res {
+                Err(e) if e.is_panic() => caught("pty", e.into_panic()),
+                res => {
+                    child_exited = true;
+                    (res.unwrap_or_else(|e| Err(e.into())), Some(exited()))
+                }
+            },
+            res = &mut writer => match res {
+                Ok(res) => (res, None),
+                Err(payload) => caught("output", payload),
+            },
+            res = AssertUnwindSafe(resizes).catch_unwind() => match res {
+                Ok(res) => (res, None),
+                Err(payload) => caught("resize", payload),
+            },
+            _ = handle.detach.notified() => {
+                // Someone else drives the child now, don't kill it when this
+                // side goes away.
+                handle.master.detach();
+                detached = true;
+                (Ok(()), handle.farewell.lock().unwrap().take())
+            }
+            _ = handle.terminate.notified() => (Ok(()), handle.farewell.lock().unwrap().take()),
+        }
     };
     debug!("res = {:?}", res);
+    if let (Some(events), false) = (config.events.as_ref(), detached) {
+        events.on_disconnect(&handle);
+    }
+    if lost {
+        if let (Some(persistence), Some(token)) = (config.persistence.as_ref(), live.token.clone())
+        {
+            // Handed off sessions say why, see the `handoff` module.
+            let farewell = handle.farewell.lock().unwrap().take();
+            if let Some(farewell) = farewell {
+                let _ = ws_outgoing.send(Message::Close(Some(farewell))).await;
+            }
+            handle.record(TimelineEvent::Disconnected);
+            if let Some(ref alerts) = handle.alerts {
+                alerts.detached(true);
+            }
+            persistence.park(token, live);
+            return Ok(());
+        }
+    }
+    if panicked || (!detached && !child_exited && farewell.is_none()) {
+        // The client went away, or the session is lost.
+        live.stop();
+    }
+    if let Some(farewell) = farewell {
+        let mut policy = config.teardown;
+        let deadline = tokio::time::Instant::now() + policy.exit_grace;
+        // The pty is done about when the child gets reaped.
+        let status = if child_exited {
+            let status = handle.master.exit_status();
+            tokio::time::timeout(Duration::from_secs(1), status)
+                .await
+                .ok()
+                .flatten()
+        } else {
+            None
+        };
+        if child_exited {
+            let left = deadline.saturating_duration_since(tokio::time::Instant::now());
+            policy.timeout = policy.timeout.min(left);
+        } else {
+            live.pty.abort();
+        }
+        let prepare = |msg| {
+            let msg = split(seal(msg, &state).0, &state)?;
+            dumped(&msg, &state);
+            Some(msg)
+        };
+        let last = if panicked {
+            prepare(output_message(crate::panics::APOLOGY))
+        } else {
+            status.and_then(|status| split(exit_message(status), &state))
+        };
+        if let Some(ref last) = last {
+            dumped(last, &state);
+        }
+        dumped(&Message::Close(Some(farewell.clone())), &state);
+        teardown::close(
+            &mut ws_outgoing,
+            &mut live.receiver,
+            last,
+            farewell,
+            policy,
+            prepare,
+        )
+        .await;
+        if child_exited {
+            teardown::linger(&mut ws_incoming, deadline).await;
+        }
+    }
+    live.end().await;
+    if detached {
+        handle.detached.notify_one();
+    }
     Ok(())
 }
 
+#[cfg(feature = "io-uring")]
+fn pty_reader(
+    config: &ServerConfig,
+    pty_master: &PtyMaster,
+) -> Result<Box<dyn AsyncRead + Send + Unpin>, IoError> {
+    if config.io_uring {
+        Ok(Box::new(UringReader::new(pty_master)?))
+    } else {
+        Ok(Box::new(pty_master.clone()))
+    }
+}
+
+#[cfg(not(feature = "io-uring"))]
+fn pty_reader(
+    _config: &ServerConfig,
+    pty_master: &PtyMaster,
+) -> Result<Box<dyn AsyncRead + Send + Unpin>, IoError> {
+    Ok(Box::new(pty_master.clone()))
+}
+
+async fn accept_connections(listener: TcpListener, realm: Arc<Realm>, config: Arc<ServerConfig>) {
+    while let Ok((stream, peer)) = listener.accept().await {
+        debug!("handling request from {:?}", peer);
+        let config = config.clone();
+        let realm = realm.clone();
+        let fut = async move {
+            let _ = handle_connection(stream, peer, config, realm)
+                .await
+                .map_err(|e| error!("handle connection error: {:?}", e));
+        };
+        tokio::spawn(crate::panics::guard(fut, peer));
+    }
+}
+
+// Connections over `ServerConfig::unix_socket`.
+async fn accept_unix_connections(
+    listener: UnixListener,
+    realm: Arc<Realm>,
+    config: Arc<ServerConfig>,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        debug!("handling request over {:?}", config.unix_socket);
+        let config = config.clone();
+        let realm = realm.clone();
+        let fut = async move {
+            let _ = serve_connection(stream, UNIX_PEER, config, &realm.listener, None)
+                .await
+                .map_err(|e| error!("handle connection error: {:?}", e));
+        };
+        tokio::spawn(crate::panics::guard(fut, UNIX_PEER));
+    }
+}
+
+// A server and the sessions it runs, for embedders building admin UIs or
+// orchestration on top of the crate. Clones share the sessions.
+#[derive(Clone)]
+pub struct Server {
+    config: ServerConfig,
+    sessions: Arc<Mutex<BTreeMap<u64, Session>>>,
+}
+
+impl Server {
+    // `config.on_session` is still called for each new session.
+    pub fn new(mut config: ServerConfig) -> Self {
+        let sessions = Arc::new(Mutex::new(BTreeMap::new()));
+        let registry = sessions.clone();
+        let on_session = config.on_session.take();
+        config.on_session = Some(Arc::new(move |session: Session| {
+            registry
+                .lock()
+                .unwrap()
+                .insert(session.id(), session.clone());
+            let registry = registry.clone();
+            let closed = session.clone();
+            tokio::spawn(async move {
+                closed.closed().await;
+                registry.lock().unwrap().remove(&closed.id());
+            });
+            if let Some(ref on_session) = on_session {
+                on_session(session);
+            }
+        }));
+        Server { config, sessions }
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    // The sessions running now, parked ones included, by id.
+    pub fn sessions(&self) -> Vec<Session> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn session(&self, id: u64) -> Option<Session> {
+        self.sessions.lock().unwrap().get(&id).cloned()
+    }
+
+    // See `serve_pty`, sessions get registered as with `run()`. Pools,
+    // recording retention and the migration socket are only set up by
+    // `run()`.
+    pub async fn serve_pty<S>(
+        &self,
+        ws: WebSocketStream<S>,
+        peer: SocketAddr,
+        identity: Option<String>,
+    ) -> Result<(), anyhow::Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        serve_pty(ws, peer, identity, Arc::new(self.config.clone())).await
+    }
+
+    // Listens and serves until a listener fails, see
+    // `start_server_with_config`.
+    pub async fn run(&self) -> Result<(), anyhow::Error> {
+        start_server_with_config(self.config.clone()).await
+    }
+
+    // Same thing in the background, until `ServerHandle::shutdown`.
+    // Dropping the handle leaves the server running.
+    pub fn spawn(&self) -> ServerHandle {
+        let stop = Arc::new(Notify::new());
+        let stopped = stop.clone();
+        let config = self.config.clone();
+        let listening = Arc::new(Mutex::new(vec![]));
+        let bound = listening.clone();
+        let task = tokio::spawn(async move { listen(config, stopped.notified(), bound).await });
+        ServerHandle {
+            server: self.clone(),
+            stop,
+            task,
+            listening,
+        }
+    }
+}
+
+// A server running in the background, see `Server::spawn`.
+pub struct ServerHandle {
+    server: Server,
+    stop: Arc<Notify>,
+    task: JoinHandle<Result<(), anyhow::Error>>,
+    listening: Arc<Mutex<Vec<OwnedFd>>>,
+}
+
+impl ServerHandle {
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    // Stops accepting connections and closes every session, parked ones
+    // included, with `reason` as going away. Sessions get up to `grace` to
+    // end, which is when their clients get the rest of their output and the
+    // close frame, then the children still running are killed. Proxied and
+    // output only connections aren't sessions, they end on their own.
+    // Returns the error the server stopped with, if it did before.
+    pub async fn shutdown(self, reason: &str, grace: Duration) -> Result<(), anyhow::Error> {
+        let ServerHandle {
+            server, stop, task, ..
+        } = self;
+        stop.notify_one();
+        let res = task.await.unwrap_or_else(|e| Err(e.into()));
+        if let Some(ref pool) = server.config.pool {
+            pool.drain();
+        }
+        // Sessions still being set up can show up meanwhile.
+        let running = || {
+            server
+                .sessions()
+                .into_iter()
+                .filter(|session| !*session.done.borrow())
+                .collect::<Vec<_>>()
+        };
+        let drained = tokio::time::timeout(grace, async {
+            loop {
+                let sessions = running();
+                if sessions.is_empty() {
+                    break;
+                }
+                for session in sessions.iter() {
+                    session.close(CloseCode::Away, CloseReason::Shutdown(reason.to_owned()));
+                }
+                future::join_all(sessions.iter().map(|session| session.closed())).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            for session in running() {
+                warn!("session {} still running after shutdown, killed", session);
+                session.master.kill();
+            }
+        }
+        res
+    }
+
+    // Hands the listeners and sessions over to a new server started with
+    // `command`, see the `handover` module, and returns it for this process
+    // to exit. Nothing is stopped if it doesn't get to serve. The sessions
+    // left behind end with `shutdown`.
+    pub async fn upgrade(
+        &mut self,
+        command: std::process::Command,
+    ) -> Result<Child, anyhow::Error> {
+        let mut handover = {
+            let listeners = self.listening.lock().unwrap();
+            crate::handover::Handover::start(command, &listeners)?
+        };
+        handover.ready().await?;
+        info!(
+            "new server {} serving, handing sessions over",
+            handover.pid()
+        );
+        self.stop.notify_one();
+        let task = std::mem::replace(&mut self.task, tokio::spawn(future::ready(Ok(()))));
+        let _ = task.await;
+        self.listening.lock().unwrap().clear();
+        let socket = handover.sessions();
+        for session in self.server.sessions() {
+            if *session.done.borrow() {
+                continue;
+            }
+            if let Err(e) = session.migrate(&socket).await {
+                warn!("session {} left behind: {:?}", session, e);
+            }
+        }
+        Ok(handover.finish())
+    }
+}
+
+// Aborted when the server stops.
+#[derive(Default)]
+struct Tasks(Vec<JoinHandle<()>>);
+
+impl Tasks {
+    fn spawn<F>(&mut self, fut: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.0.push(tokio::spawn(fut));
+    }
+}
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for task in self.0.iter() {
+            task.abort();
+        }
+    }
+}
+
 pub async fn start_server() -> Result<(), anyhow::Error> {
-    let addr: SocketAddr = "127.0.0.1:7703".parse().unwrap();
-    match TcpListener::bind(addr).await {
-        Ok(listener) => {
-            while let Ok((stream, peer)) = listener.accept().await {
-                debug!("handling request from {:?}", peer);
-                let fut = async move {
-                    let _ = handle_connection(stream)
-                        .await
-                        .map_err(|e| error!("handle connection error: {:?}", e));
-                };
-                tokio::spawn(fut);
+    start_server_with_config(ServerConfig::default()).await
+}
+
+pub async fn start_server_with_config(config: ServerConfig) -> Result<(), anyhow::Error> {
+    listen(config, future::pending(), Default::default()).await
+}
+
+// Serves until a listener fails or `shutdown` resolves. Copies of the TCP
+// listeners go to `listening`, for `ServerHandle::upgrade`.
+async fn listen<F>(
+    config: ServerConfig,
+    shutdown: F,
+    listening: Arc<Mutex<Vec<OwnedFd>>>,
+) -> Result<(), anyhow::Error>
+where
+    F: std::future::Future<Output = ()>,
+{
+    let config = Arc::new(config);
+    let addr = config.addr();
+    let listener = match config.tcp_disabled {
+        true => None,
+        false => Some(
+            crate::bind::listen(addr, &config.bind)
+                .map_err(|e| anyhow::anyhow!("failed to listen on {}: {:?}", addr, e))?,
+        ),
+    };
+    let unix_listener =
+        match config.unix_socket {
+            Some(ref path) => {
+                // Left over by a previous instance.
+                let _ = std::fs::remove_file(path);
+                Some(UnixListener::bind(path).map_err(|e| {
+                    anyhow::anyhow!("failed to listen on {}: {:?}", path.display(), e)
+                })?)
             }
+            None => None,
+        };
+    if let Some(ref listener) = listener {
+        listening
+            .lock()
+            .unwrap()
+            .push(listener.as_fd().try_clone_to_owned()?);
+    }
+    if listener.is_none() && unix_listener.is_none() && config.agent.is_none() {
+        anyhow::bail!("no listener: TCP disabled without a Unix socket or an agent");
+    }
+
+    let mut tasks = Tasks::default();
+    #[cfg(feature = "quic")]
+    if let Some(ref quic) = config.quic {
+        let fut = crate::quic::serve(config.clone(), quic.clone());
+        tasks.spawn(async move {
+            let _ = fut
+                .await
+                .map_err(|e| error!("quic listener error: {:?}", e));
+        });
+    }
+
+    for realm in config.listeners.iter() {
+        let listener = crate::bind::listen(realm.addr, &realm.bind)
+            .map_err(|e| anyhow::anyhow!("failed to listen on {}: {:?}", realm.addr, e))?;
+        listening
+            .lock()
+            .unwrap()
+            .push(listener.as_fd().try_clone_to_owned()?);
+        let realm = Realm::new(realm.clone())?;
+        tasks.spawn(accept_connections(
+            listener,
+            Arc::new(realm),
+            config.clone(),
+        ));
+    }
+
+    if let Some(ref pool) = config.pool {
+        pool.fill(&config);
+    }
+
+    if let Some(ref recording) = config.recording {
+        if let Some(ref retention) = recording.retention {
+            let fut = crate::retention::run(recording.dir.clone(), retention.clone());
+            tasks.spawn(fut);
         }
-        Err(e) => return Err(anyhow::anyhow!("failed to listen: {:?}", e)),
+    }
+
+    if let Some(ref gc) = config.gc {
+        tasks.spawn(crate::gc::run(config.clone(), gc.clone()));
+    }
+
+    if let Some(ref path) = config.migration_socket {
+        let fut = crate::migrate::accept(path.clone(), config.clone());
+        tasks.spawn(async move {
+            let _ = fut
+                .await
+                .map_err(|e| error!("migration listener error: {:?}", e));
+        });
+    }
+    if let Some(listener) = crate::handover::take_migration() {
+        listener.set_nonblocking(true)?;
+        let fut = crate::migrate::serve(UnixListener::from_std(listener)?, config.clone());
+        tasks.spawn(async move {
+            let _ = fut
+                .await
+                .map_err(|e| error!("upgrade migration error: {:?}", e));
+        });
+    }
+
+    #[cfg(feature = "mdns")]
+    let _advertisement = match (config.mdns.as_ref(), listener.as_ref()) {
+        (Some(mdns), Some(listener)) => crate::mdns::advertise(mdns, listener.local_addr()?)
+            .map_err(|e| error!("failed to advertise over mDNS: {:?}", e))
+            .ok(),
+        _ => None,
+    };
+
+    let realm = Arc::new(Realm::new(Listener {
+        addr,
+        bind: config.bind.clone(),
+        path: None,
+        authenticator: config.authenticator.clone(),
+        #[cfg(feature = "tls")]
+        tls: config.tls.clone(),
+    })?);
+    let unix = unix_listener.map(|unix_listener| {
+        let realm = Arc::new(Realm {
+            listener: Listener {
+                #[cfg(feature = "tls")]
+                tls: None,
+                ..realm.listener.clone()
+            },
+            #[cfg(feature = "tls")]
+            tls: None,
+        });
+        accept_unix_connections(unix_listener, realm, config.clone())
+    });
+    let agent = config
+        .agent
+        .clone()
+        .map(|agent| crate::agent::run(agent, config.clone()));
+    crate::handover::ready();
+    let serve = async {
+        match (listener, unix) {
+            (Some(listener), unix) => {
+                if let Some(unix) = unix {
+                    tasks.spawn(unix);
+                }
+                if let Some(agent) = agent {
+                    tasks.spawn(agent);
+                }
+                accept_connections(listener, realm, config).await;
+            }
+            (None, Some(unix)) => {
+                if let Some(agent) = agent {
+                    tasks.spawn(agent);
+                }
+                unix.await;
+            }
+            (None, None) => {
+                if let Some(agent) = agent {
+                    agent.await;
+                }
+            }
+        }
+    };
+    tokio::select! {
+        _ = serve => (),
+        _ = shutdown => debug!("server shutting down"),
     }
     Ok(())
 }
@@ -0,0 +1,758 @@
+use crate::alerts::Monitor;
+use crate::arbitration::Floor;
+use crate::clock::{Clock, TokioClock};
+use crate::colors::ColorLevel;
+use crate::commands::{CommandStats, Commands};
+use crate::expect::OutputPattern;
+use crate::instrument::{Counters, Throughput};
+use crate::mirror::Mirror;
+use crate::plain::PlainText;
+use crate::presence::Roster;
+use crate::prompt::PromptGuard;
+use crate::recording::Recorder;
+use crate::scrollback::Scrollback;
+use crate::sizing::{Client, Sizes};
+use crate::timeline::{Timeline, TimelineEntry, TimelineEvent};
+use crate::tokens::Capabilities;
+use crate::vt::VtState;
+use crate::workspace::Provisioned;
+use crate::PtyMaster;
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::os::unix::io::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use wspty_proto::protocol::CloseReason;
+use wspty_proto::{Level, Member, Notification, Replay, ResizeSource, Signal, WindowSize};
+
+// What the session's child was started with, for post-incident review.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpawnInfo {
+    // As requested by the client, empty for the default shell.
+    pub command: String,
+    pub argv: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub cwd: Option<PathBuf>,
+    pub uid: u32,
+    // Where it runs, for sessions opened over SSH.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // Or the container it was run in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+}
+
+impl SpawnInfo {
+    // Whether it runs over SSH or in a container.
+    pub(crate) fn is_remote(&self) -> bool {
+        self.host.is_some() || self.container.is_some()
+    }
+
+    // `inherited` is whether `cmd` gets the server's environment.
+    pub(crate) fn capture(command: &str, cmd: &std::process::Command, inherited: bool) -> Self {
+        let argv = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let mut env: BTreeMap<String, String> = std::env::vars_os()
+            .filter(|_| inherited)
+            .map(|(k, v)| {
+                (
+                    k.to_string_lossy().into_owned(),
+                    v.to_string_lossy().into_owned(),
+                )
+            })
+            .collect();
+        for (key, value) in cmd.get_envs() {
+            let key = key.to_string_lossy().into_owned();
+            match value {
+                Some(value) => env.insert(key, value.to_string_lossy().into_owned()),
+                None => env.remove(&key),
+            };
+        }
+        let cwd = cmd
+            .get_current_dir()
+            .map(Path::to_path_buf)
+            .or_else(|| std::env::current_dir().ok());
+        SpawnInfo {
+            command: command.to_owned(),
+            argv,
+            env,
+            cwd,
+            uid: nix::unistd::getuid().as_raw(),
+            host: None,
+            container: None,
+        }
+    }
+}
+
+// Server side control over a running session, handed to
+// `ServerConfig::on_session` when the shell is spawned.
+#[derive(Clone)]
+pub struct SessionHandle {
+    id: u64,
+    // What the session is known as outside, see `ServerConfig::session_ids`.
+    pub(crate) session_id: Arc<str>,
+    // Sent by the client with the upgrade request.
+    pub(crate) correlation_id: Option<String>,
+    // Declared with it, see the `colors` module.
+    pub(crate) colors: Option<ColorLevel>,
+    // Granted by the client's token, see the `tokens` module.
+    pub(crate) capabilities: Capabilities,
+    // Granted to the client, see the `power` module.
+    pub(crate) low_power: bool,
+    // Of the client, see the `render` module.
+    pub(crate) render_rate: Option<u32>,
+    // Granted to the client, see the `control` module.
+    pub(crate) split: bool,
+    peer: SocketAddr,
+    // As returned by the listener's authenticator.
+    pub(crate) identity: Option<String>,
+    // Who the session belongs to, see `owner()`.
+    pub(crate) owner: Arc<Mutex<Option<String>>>,
+    // Picked by the client, see `QueryOverrides`.
+    pub(crate) profile: Option<String>,
+    pub(crate) started: SystemTime,
+    pub(crate) master: PtyMaster,
+    pub(crate) vt: Arc<Mutex<VtState>>,
+    // What each client would have the pty size be, see the `sizing` module.
+    pub(crate) sizes: Arc<Mutex<Sizes>>,
+    // The pty size and who set it, for attached clients to follow.
+    pub(crate) size: Arc<watch::Sender<(WindowSize, ResizeSource)>>,
+    // Who can type, see the `arbitration` module.
+    pub(crate) floor: Arc<watch::Sender<Floor>>,
+    // Who follows the session, see the `presence` module.
+    pub(crate) roster: Arc<watch::Sender<Roster>>,
+    // The client socket, for sessions that can be migrated.
+    pub(crate) transport: Option<Arc<OwnedFd>>,
+    pub(crate) detach: Arc<Notify>,
+    pub(crate) detached: Arc<Notify>,
+    pub(crate) terminate: Arc<Notify>,
+    // Sent to the client when detaching, if set.
+    pub(crate) farewell: Arc<Mutex<Option<CloseFrame<'static>>>>,
+    pub(crate) paused: Arc<watch::Sender<bool>>,
+    pub(crate) recorder: Arc<Mutex<Option<Recorder>>>,
+    pub(crate) mirror: Option<Mirror>,
+    pub(crate) spawn: Option<Arc<SpawnInfo>>,
+    // See `ServerConfig::workspace`.
+    pub(crate) workspace: Option<Arc<Provisioned>>,
+    // Recent output, see the `scrollback` module.
+    pub(crate) scrollback: Option<Arc<Mutex<Scrollback>>>,
+    // Pty output for the attachments, see `attach()`.
+    pub(crate) output: broadcast::Sender<Vec<u8>>,
+    // See the `notifications` module.
+    pub(crate) notifications: broadcast::Sender<Notification>,
+    // Set once the session is over on this server.
+    pub(crate) done: Arc<watch::Sender<bool>>,
+    pub(crate) counters: Option<Arc<Counters>>,
+    // When the client last sent a message, on `clock`.
+    pub(crate) activity: Arc<Mutex<Instant>>,
+    // See `ServerConfig::clock`.
+    pub(crate) clock: Arc<dyn Clock>,
+    // See the `timeline` module.
+    pub(crate) timeline: Arc<Mutex<Timeline>>,
+    // See the `commands` module.
+    pub(crate) commands: Option<Arc<Commands>>,
+    // See the `alerts` module.
+    pub(crate) alerts: Option<Arc<Monitor>>,
+}
+
+// Same thing, under the name the `Server` API uses.
+pub type Session = SessionHandle;
+
+// Names new sessions, with ULIDs or ids minted by a backend for instance.
+pub type SessionIdGenerator = Arc<dyn Fn() -> String + Send + Sync>;
+
+// Output buffered per attachment before it falls behind.
+const ATTACHMENT_BACKLOG: usize = 256;
+
+impl SessionHandle {
+    pub(crate) fn new(
+        id: u64,
+        peer: SocketAddr,
+        master: PtyMaster,
+        vt: Arc<Mutex<VtState>>,
+        transport: Option<OwnedFd>,
+    ) -> Self {
+        let (rows, cols) = vt.lock().unwrap().size();
+        let size = WindowSize {
+            cols,
+            rows,
+            xpixel: 0,
+            ypixel: 0,
+        };
+        SessionHandle {
+            id,
+            session_id: id.to_string().into(),
+            correlation_id: None,
+            colors: None,
+            capabilities: Capabilities::FULL,
+            low_power: false,
+            render_rate: None,
+            split: false,
+            peer,
+            identity: None,
+            owner: Arc::new(Mutex::new(None)),
+            profile: None,
+            started: SystemTime::now(),
+            master,
+            vt,
+            sizes: Arc::new(Mutex::new(Sizes::default())),
+            size: Arc::new(watch::channel((size, ResizeSource::Client)).0),
+            floor: Arc::new(watch::channel(Floor::default()).0),
+            roster: Arc::new(watch::channel(Roster::default()).0),
+            transport: transport.map(Arc::new),
+            detach: Arc::new(Notify::new()),
+            detached: Arc::new(Notify::new()),
+            terminate: Arc::new(Notify::new()),
+            farewell: Arc::new(Mutex::new(None)),
+            paused: Arc::new(watch::channel(false).0),
+            recorder: Arc::new(Mutex::new(None)),
+            mirror: None,
+            spawn: None,
+            workspace: None,
+            scrollback: None,
+            output: broadcast::channel(ATTACHMENT_BACKLOG).0,
+            notifications: broadcast::channel(crate::notifications::BACKLOG).0,
+            done: Arc::new(watch::channel(false).0),
+            counters: None,
+            activity: Arc::new(Mutex::new(Instant::now())),
+            clock: Arc::new(TokioClock),
+            timeline: Arc::new(Mutex::new(Timeline::default())),
+            commands: None,
+            alerts: None,
+        }
+    }
+
+    // Called with the vt lock held, like the attachments' snapshots.
+    pub(crate) fn tap(&self, output: &[u8]) {
+        if let Some(ref scrollback) = self.scrollback {
+            scrollback.lock().unwrap().push(output);
+        }
+        if self.output.receiver_count() > 0 {
+            let _ = self.output.send(output.to_vec());
+        }
+    }
+
+    // Numeric and unique to this server, it names the recordings.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // The generated id, or the numeric one without a generator.
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    // From the `X-Correlation-Id` header or the `correlation_id` query
+    // parameter of the upgrade request.
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+
+    // Who can resume and follow the session: its identity, until handed to
+    // another one, see the `handoff` module.
+    pub fn owner(&self) -> Option<String> {
+        self.owner.lock().unwrap().clone()
+    }
+
+    // Unknown for sessions adopted from an older server.
+    pub fn spawn_info(&self) -> Option<&SpawnInfo> {
+        self.spawn.as_deref()
+    }
+
+    // The client is told with a `proto::Resized`, of the `Api` source.
+    pub async fn resize(
+        &self,
+        cols: u16,
+        rows: u16,
+        xpixel: u16,
+        ypixel: u16,
+    ) -> Result<(), IoError> {
+        let size = WindowSize {
+            cols,
+            rows,
+            xpixel,
+            ypixel,
+        };
+        self.resize_from(size, ResizeSource::Api).await
+    }
+
+    // The size the pty has, and who set it last.
+    pub fn pty_size(&self) -> (WindowSize, ResizeSource) {
+        *self.size.borrow()
+    }
+
+    async fn resize_from(&self, size: WindowSize, source: ResizeSource) -> Result<(), IoError> {
+        self.master
+            .resize_async(size.cols, size.rows, size.xpixel, size.ypixel)
+            .await?;
+        self.resized(size, source);
+        Ok(())
+    }
+
+    fn resized(&self, size: WindowSize, source: ResizeSource) {
+        self.record(TimelineEvent::Resized {
+            cols: size.cols,
+            rows: size.rows,
+            source,
+        });
+        self.vt.lock().unwrap().resize(size.rows, size.cols);
+        self.size.send_replace((size, source));
+        if let Some(recorder) = self.recorder() {
+            recorder.resize(size.cols, size.rows);
+        }
+    }
+
+    // With `ServerConfig::command_metrics`, see the `commands` module.
+    pub fn command_stats(&self) -> Option<CommandStats> {
+        self.commands
+            .as_ref()
+            .map(|commands| commands.stats.lock().unwrap().clone())
+    }
+
+    // What happened to the session so far, oldest first, see the `timeline`
+    // module.
+    pub fn timeline(&self) -> Vec<TimelineEntry> {
+        self.timeline.lock().unwrap().entries()
+    }
+
+    pub(crate) fn record(&self, event: TimelineEvent) {
+        self.timeline.lock().unwrap().record(event);
+    }
+
+    pub(crate) fn recorder(&self) -> Option<Recorder> {
+        self.recorder.lock().unwrap().clone()
+    }
+
+    pub(crate) fn can_write(&self, participant: u64) -> bool {
+        self.floor.borrow().can_write(participant)
+    }
+
+    pub(crate) fn grant(&self, participant: u64, write: bool) {
+        self.floor
+            .send_if_modified(|floor| floor.grant(participant, write));
+    }
+
+    pub(crate) fn typed(&self, participant: u64) {
+        self.roster
+            .send_if_modified(|roster| roster.typed(participant));
+    }
+
+    // Resizes as `client` asks if the size policy lets it, returns the size
+    // the pty has then.
+    pub(crate) async fn negotiate(
+        &self,
+        client: Client,
+        size: WindowSize,
+    ) -> Result<WindowSize, IoError> {
+        let settled = self.sizes.lock().unwrap().resize(client, size);
+        match settled {
+            Some(size) => {
+                self.resize_from(size, client.source()).await?;
+                Ok(size)
+            }
+            None => Ok(self.pty_size().0),
+        }
+    }
+
+    // Stops reading from the pty until `resume()`, the child blocks once
+    // the kernel buffer is full.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    // With `ServerConfig::instrumentation` or `accounting`, see the
+    // `instrument` module.
+    pub fn throughput(&self) -> Option<Throughput> {
+        self.counters.as_ref().map(|counters| counters.snapshot())
+    }
+
+    // Bytes written to the session's workspace, `None` without one, see the
+    // `workspace` module.
+    pub fn disk_usage(&self) -> Option<u64> {
+        let workspace = self.workspace.as_ref()?;
+        workspace
+            .usage()
+            .map_err(|e| warn!("failed to measure the workspace of session {}: {}", self, e))
+            .ok()
+    }
+
+    // The output as styled HTML, see the `html` module.
+    pub fn to_html(&self) -> String {
+        let mut vt = self.vt.lock().unwrap();
+        match self.scrollback {
+            Some(ref scrollback) => {
+                let (rows, cols) = vt.size();
+                let output = scrollback.lock().unwrap().replay(Replay::default());
+                crate::html::replay(rows, cols, &output)
+            }
+            None => vt.html(),
+        }
+    }
+
+    // Since the client last sent a message, see the `timeout` module.
+    pub fn idle_time(&self) -> Duration {
+        let activity = *self.activity.lock().unwrap();
+        self.clock.now().saturating_duration_since(activity)
+    }
+
+    pub(crate) fn touch(&self) {
+        *self.activity.lock().unwrap() = self.clock.now();
+    }
+
+    // Waits for the session to end, or to leave this server (migrated or
+    // handed over).
+    pub async fn closed(&self) {
+        let _ = self.done.subscribe().wait_for(|done| *done).await;
+    }
+
+    // Resolves with what matched once the output does, see the `expect`
+    // module. Output counts from the call on, not from the first poll, so
+    // that the future can be made before typing what prints it.
+    pub fn wait_for<P: Into<OutputPattern>>(
+        &self,
+        pattern: P,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Vec<u8>, IoError>> + Send + 'static {
+        let output = self.output.subscribe();
+        crate::expect::wait(self.clone(), output, pattern.into(), timeout)
+    }
+
+    // Follows the session from the embedding process, see `Attachment`.
+    pub fn attach(&self) -> Attachment {
+        self.attachment(None)
+    }
+
+    // Same thing, as `name` for the others, see `Attachment::presence`.
+    pub fn attach_as(&self, name: &str) -> Attachment {
+        self.attachment(Some(name.to_owned()))
+    }
+
+    fn attachment(&self, name: Option<String>) -> Attachment {
+        let mut id = 0;
+        self.floor.send_modify(|floor| id = floor.join());
+        self.roster.send_modify(|roster| roster.join(id, name));
+        let client = self.sizes.lock().unwrap().attach(id);
+        let mut vt = self.vt.lock().unwrap();
+        Attachment {
+            handle: self.clone(),
+            id,
+            client,
+            roster: self.roster.subscribe(),
+            output: self.output.subscribe(),
+            screen: Some(vt.snapshot()),
+            plain: None,
+            prompt: None,
+            window: None,
+        }
+    }
+
+    // As the client can with a `proto::SignalRequest`. Sessions opened over
+    // SSH or in containers get the character for it typed instead, the other
+    // signals would only reach the ssh or exec client.
+    pub fn signal(&self, signal: Signal) -> Result<(), IoError> {
+        if self.spawn.as_ref().is_some_and(|spawn| spawn.is_remote()) {
+            let typed: &[u8] = match signal {
+                Signal::Int => b"\x03",
+                Signal::Quit => b"\x1c",
+                Signal::Tstp => b"\x1a",
+                _ => {
+                    return Err(IoError::new(
+                        ErrorKind::Unsupported,
+                        format!("{:?} can't be sent to a remote command", signal),
+                    ))
+                }
+            };
+            self.master.try_write(typed)?;
+            self.record(TimelineEvent::Signaled { signal });
+            return Ok(());
+        }
+        let number = match signal {
+            Signal::Int => libc::SIGINT,
+            Signal::Term => libc::SIGTERM,
+            Signal::Hup => libc::SIGHUP,
+            Signal::Quit => libc::SIGQUIT,
+            Signal::Kill => libc::SIGKILL,
+            Signal::Tstp => libc::SIGTSTP,
+            Signal::Cont => libc::SIGCONT,
+            Signal::Usr1 => libc::SIGUSR1,
+            Signal::Usr2 => libc::SIGUSR2,
+        };
+        self.master.send_signal(number)?;
+        self.record(TimelineEvent::Signaled { signal });
+        Ok(())
+    }
+
+    // Shows `text` to the users of the session, outside the terminal, see
+    // the `notifications` module.
+    pub fn notify(&self, level: Level, text: &str) {
+        let notification = Notification {
+            level,
+            text: text.to_owned(),
+        };
+        // Nobody follows the session.
+        let _ = self.notifications.send(notification);
+    }
+
+    // Closes the client connection with a policy violation and `reason`,
+    // and kills the child.
+    pub fn terminate(&self, reason: &str) {
+        self.close(
+            CloseCode::Policy,
+            CloseReason::Terminated(reason.to_owned()),
+        );
+    }
+
+    pub(crate) fn close(&self, code: CloseCode, reason: CloseReason) {
+        let reason = reason.to_string();
+        self.record(TimelineEvent::Ended {
+            reason: reason.clone(),
+        });
+        self.farewell.lock().unwrap().replace(CloseFrame {
+            code,
+            reason: reason.into(),
+        });
+        self.terminate.notify_one();
+    }
+
+    // Hands the live session over to the server listening for migrations
+    // on `socket` (see `ServerConfig::migration_socket`), without the
+    // client noticing. Only WebSocket sessions can be migrated.
+    pub async fn migrate<P: AsRef<Path>>(&self, socket: P) -> Result<(), anyhow::Error> {
+        crate::migrate::send_session(self, socket.as_ref()).await
+    }
+
+    // Passes the pty master to a local process listening on `socket`, for
+    // instance to keep going from tmux or a debugger, and closes the client
+    // connection. See the `migrate` module for the wire format.
+    pub async fn hand_over<P: AsRef<Path>>(&self, socket: P) -> Result<(), anyhow::Error> {
+        crate::migrate::hand_over(self, socket.as_ref()).await
+    }
+}
+
+// How logs refer to the session.
+impl fmt::Display for SessionHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.session_id)?;
+        if let Some(ref correlation_id) = self.correlation_id {
+            write!(f, " [{}]", correlation_id)?;
+        }
+        Ok(())
+    }
+}
+
+// An in-process view of a session, for frontends built on the crate: the
+// pty output as the client gets it, starting with what the terminal shows,
+// and input typed alongside the client's.
+pub struct Attachment {
+    handle: SessionHandle,
+    id: u64,
+    client: Client,
+    roster: watch::Receiver<Roster>,
+    output: broadcast::Receiver<Vec<u8>>,
+    // Sent before the output.
+    screen: Option<Vec<u8>>,
+    // For `plain_text`.
+    plain: Option<PlainText>,
+    // For `expect_prompt`.
+    prompt: Option<PromptGuard>,
+    // See `coalesce`.
+    window: Option<Duration>,
+}
+
+impl Attachment {
+    pub fn session(&self) -> &Session {
+        &self.handle
+    }
+
+    // As a participant of the session, for its client to grant write
+    // access to.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Who follows the session, this attachment included.
+    pub fn participants(&self) -> Vec<Member> {
+        self.roster.borrow().members()
+    }
+
+    // Waits for someone to join, leave, start or stop typing, returns the
+    // participants then, `None` once the session is over. Activities only
+    // change with time with `ServerConfig::presence`.
+    pub async fn presence(&mut self) -> Option<Vec<Member>> {
+        tokio::select! {
+            res = self.roster.changed() => {
+                res.ok()?;
+                Some(self.roster.borrow_and_update().members())
+            }
+            _ = self.handle.closed() => None,
+        }
+    }
+
+    // Always with `ServerConfig::input_arbitration` off.
+    pub fn can_write(&self) -> bool {
+        self.handle.can_write(self.id)
+    }
+
+    // Makes `output` plain UTF-8 text from then on, without escape
+    // sequences (see the `plain` module), and the screen its text, for
+    // frontends pasting it in chat messages or logs.
+    pub fn plain_text(&mut self) {
+        if self.plain.is_some() {
+            return;
+        }
+        if self.screen.is_some() {
+            self.screen = Some(self.handle.vt.lock().unwrap().text().into_bytes());
+        }
+        self.plain = Some(PlainText::default());
+    }
+
+    // Makes `output` return what comes within `window` of the output it
+    // waits for along with it, for frontends that draw slowly, see the
+    // `render` module.
+    pub fn coalesce(&mut self, window: Duration) {
+        self.window = Some(window);
+    }
+
+    // The next output to write to the terminal, `None` once the session is
+    // over. Attachments falling too far behind get the screen redrawn
+    // instead of what they missed.
+    pub async fn output(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let output = self.coalesced_output().await?;
+            let text = match self.plain {
+                Some(ref mut plain) => plain.strip(&output),
+                None => return Some(output),
+            };
+            if !text.is_empty() {
+                return Some(text.into_bytes());
+            }
+        }
+    }
+
+    async fn coalesced_output(&mut self) -> Option<Vec<u8>> {
+        let mut output = self.next_output().await?;
+        let window = match self.window {
+            Some(window) => window,
+            None => return Some(output),
+        };
+        let clock = self.handle.clock.clone();
+        let deadline = clock.now() + window;
+        loop {
+            tokio::select! {
+                more = self.next_output() => match more {
+                    Some(more) => output.extend(more),
+                    None => break,
+                },
+                _ = clock.sleep_until(deadline) => break,
+            }
+        }
+        Some(output)
+    }
+
+    async fn next_output(&mut self) -> Option<Vec<u8>> {
+        if let Some(screen) = self.screen.take() {
+            return Some(screen);
+        }
+        tokio::select! {
+            biased;
+            res = self.output.recv() => match res {
+                Ok(output) => Some(output),
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("attachment to session {} missed {} outputs", self.handle, missed);
+                    let mut vt = self.handle.vt.lock().unwrap();
+                    self.output = self.output.resubscribe();
+                    match self.plain {
+                        Some(ref mut plain) => {
+                            *plain = PlainText::default();
+                            Some(vt.text().into_bytes())
+                        }
+                        None => Some(vt.snapshot()),
+                    }
+                }
+                Err(RecvError::Closed) => None,
+            },
+            _ = self.handle.closed() => None,
+        }
+    }
+
+    // Makes `input` wait for the shell prompt to be on the screen, see the
+    // `prompt` module.
+    pub fn expect_prompt(&mut self, prompt: Regex, timeout: Duration) {
+        self.prompt = Some(PromptGuard::new(&self.handle, prompt, timeout));
+    }
+
+    pub async fn input(&self, data: &[u8]) -> Result<(), IoError> {
+        if !self.can_write() {
+            return Err(IoError::new(ErrorKind::PermissionDenied, "no write access"));
+        }
+        let _turn = match self.prompt {
+            Some(ref prompt) => Some(prompt.wait(&self.handle).await?),
+            None => None,
+        };
+        self.handle.typed(self.id);
+        crate::metrics::input(data.len());
+        if let Some(recorder) = self.handle.recorder() {
+            recorder.input(data);
+        }
+        self.handle.master.clone().write_all(data).await
+    }
+
+    // The size of the attachment's window, which the pty takes or not
+    // depending on `ServerConfig::size_policy`. Returns the pty size then.
+    pub async fn resize(&self, size: WindowSize) -> Result<WindowSize, IoError> {
+        self.handle.negotiate(self.client, size).await
+    }
+}
+
+impl Drop for Attachment {
+    fn drop(&mut self) {
+        self.handle.floor.send_modify(|floor| floor.leave(self.id));
+        self.handle
+            .roster
+            .send_modify(|roster| roster.leave(self.id));
+        let settled = self.handle.sizes.lock().unwrap().detach(self.client);
+        if let Some(size) = settled {
+            let res = self.handle.master.resize_with_pixels(
+                size.cols,
+                size.rows,
+                size.xpixel,
+                size.ypixel,
+            );
+            match res {
+                Ok(()) => self.handle.resized(size, ResizeSource::Participant),
+                Err(e) => warn!("failed to resize session {}: {}", self.handle, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+// Sessions followed by more WebSocket clients than the one that started
+// them, with `ServerConfig::sharing`, for pair debugging or teaching. A
+// client sends a `proto::Attach` with the id of the session in place of the
+// command, then gets what its client does: the screen, the output and the
+// resizes. Viewers' input and resizes are dropped, a writer's are handled
+// alongside the client's, one writer at a time per session. They join as
+// attachments (see `Attachment`), so presence, input arbitration and the
+// size policy treat them like the embedder's. Only the owner of a session (see
+// `SessionHandle::owner`) can follow it, unless the policy says otherwise.
+// Those asking for it get the output as text messages without escape
+// sequences. Output is paced to the rate each client declares, see the
+// `render` module.
+
+use crate::auth::Handshake;
+use crate::server::{exit_message, same_size};
+use crate::timeline::TimelineEvent;
+use crate::tokens::Capabilities;
+use crate::{ServerConfig, Session, SessionHandle};
+use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+use wspty_proto::protocol::CloseReason;
+use wspty_proto::{
+    self as proto, Attach, ClientMessage, ResizeSource, Resized, Role, ServerMessage, WindowSize,
+};
+
+// Whether a client with that identity can follow the session in that role.
+pub type SharingPolicy = Arc<dyn Fn(&Session, Option<&str>, Role) -> bool + Send + Sync>;
+
+struct Shared {
+    handle: SessionHandle,
+    viewers: usize,
+    writer: bool,
+}
+
+#[derive(Clone, Default)]
+pub struct Sharing {
+    // Per session, unlimited if unset.
+    max_viewers: Option<usize>,
+    policy: Option<SharingPolicy>,
+    sessions: Arc<Mutex<HashMap<String, Shared>>>,
+}
+
+impl Sharing {
+    pub fn new(max_viewers: Option<usize>, policy: Option<SharingPolicy>) -> Self {
+        Sharing {
+            max_viewers,
+            policy,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn max_viewers(&self) -> Option<usize> {
+        self.max_viewers
+    }
+
+    // Clients following the session now, writer included.
+    pub fn followers(&self, session_id: &str) -> usize {
+        match self.sessions.lock().unwrap().get(session_id) {
+            Some(shared) => shared.viewers + shared.writer as usize,
+            None => 0,
+        }
+    }
+
+    pub(crate) fn register(&self, handle: &SessionHandle) {
+        let shared = Shared {
+            handle: handle.clone(),
+            viewers: 0,
+            writer: false,
+        };
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(handle.session_id().to_owned(), shared);
+    }
+
+    pub(crate) fn unregister(&self, session_id: &str) {
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+
+    // For the `gc` module.
+    pub(crate) fn shared_sessions(&self) -> Vec<SessionHandle> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .map(|shared| shared.handle.clone())
+            .collect()
+    }
+
+    // Takes a seat in the session, or tells why not.
+    fn join(&self, attach: &Attach, identity: Option<&str>) -> Result<Seat, CloseReason> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let shared = sessions
+            .get_mut(&attach.session_id)
+            .ok_or(CloseReason::UnknownSession)?;
+        let allowed = match self.policy {
+            Some(ref policy) => policy(&shared.handle, identity, attach.role),
+            None => shared.handle.owner().as_deref() == identity,
+        };
+        if !allowed {
+            return Err(CloseReason::UnknownSession);
+        }
+        match attach.role {
+            Role::Writer if shared.writer => {
+                return Err(CloseReason::Capacity(
+                    "the session already has a writer".into(),
+                ))
+            }
+            Role::Writer => shared.writer = true,
+            Role::Viewer if self.max_viewers.is_some_and(|max| shared.viewers >= max) => {
+                return Err(CloseReason::Capacity("too many viewers".into()))
+            }
+            Role::Viewer => shared.viewers += 1,
+        }
+        Ok(Seat {
+            sharing: self.clone(),
+            handle: shared.handle.clone(),
+            role: attach.role,
+        })
+    }
+}
+
+// A client following a session, until dropped.
+struct Seat {
+    sharing: Sharing,
+    handle: SessionHandle,
+    role: Role,
+}
+
+impl Drop for Seat {
+    fn drop(&mut self) {
+        let mut sessions = self.sharing.sessions.lock().unwrap();
+        if let Some(shared) = sessions.get_mut(self.handle.session_id()) {
+            match self.role {
+                Role::Writer => shared.writer = false,
+                Role::Viewer => shared.viewers = shared.viewers.saturating_sub(1),
+            }
+        }
+    }
+}
+
+// The client's resizes are another participant's for followers.
+fn resized_message(size: WindowSize, source: ResizeSource) -> Message {
+    let source = match source {
+        ResizeSource::Client => ResizeSource::Participant,
+        source => source,
+    };
+    let resized = Resized {
+        size,
+        error: None,
+        source,
+    };
+    Message::Binary(ServerMessage::Resized(resized).encode())
+}
+
+pub(crate) async fn attach<O, I>(
+    mut ws_outgoing: O,
+    mut ws_incoming: I,
+    peer: SocketAddr,
+    handshake: Handshake,
+    mut attach: Attach,
+    config: Arc<ServerConfig>,
+) -> Result<(), anyhow::Error>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let Handshake {
+        identity,
+        capabilities,
+        render_rate,
+        ..
+    } = handshake;
+    if !capabilities.input {
+        attach.role = Role::Viewer;
+    }
+    let banned = config
+        .bans
+        .as_ref()
+        .is_some_and(|bans| bans.is_banned(peer.ip(), identity.as_deref()));
+    let seat = match config.sharing {
+        Some(_) if banned => Err(CloseReason::UnknownSession),
+        Some(ref sharing) => sharing.join(&attach, identity.as_deref()),
+        None => Err(CloseReason::UnknownSession),
+    };
+    let seat = match seat {
+        Ok(seat) => seat,
+        Err(reason) => {
+            warn!(
+                "refusing to attach {:?} to session {}: {}",
+                peer, attach.session_id, reason
+            );
+            let farewell = CloseFrame {
+                code: CloseCode::Policy,
+                reason: reason.to_string().into(),
+            };
+            crate::teardown::refuse(
+                &mut ws_outgoing,
+                &mut ws_incoming,
+                farewell,
+                config.teardown,
+            )
+            .await?;
+            return Ok(());
+        }
+    };
+    let handle = seat.handle.clone();
+    info!(
+        "{:?} ({:?}) attached to session {} as a {:?}",
+        peer, identity, handle, seat.role
+    );
+    handle.record(TimelineEvent::Joined {
+        peer,
+        role: seat.role,
+    });
+    let mut attachment = match identity {
+        Some(ref name) => handle.attach_as(name),
+        None => handle.attach(),
+    };
+    if attach.plain {
+        attachment.plain_text();
+    }
+    let window = config
+        .render_hints
+        .as_ref()
+        .and_then(|hints| hints.window(render_rate));
+    if let Some(window) = window {
+        attachment.coalesce(window);
+    }
+    let mut notifications = handle.notifications.subscribe();
+    let mut pty_size = handle.size.subscribe();
+    let (size, source) = *pty_size.borrow_and_update();
+    ws_outgoing.send(resized_message(size, source)).await?;
+    // Last size sent, for resizes echoing it.
+    let mut pushed = Some(size);
+    loop {
+        tokio::select! {
+            output = attachment.output() => match output {
+                Some(output) if attach.plain => {
+                    let text = String::from_utf8_lossy(&output).into_owned();
+                    ws_outgoing.send(Message::Text(text)).await?;
+                }
+                Some(output) => {
+                    let msg = Message::Binary(proto::frame(proto::OUTPUT, &output));
+                    ws_outgoing.send(msg).await?;
+                }
+                None => break,
+            },
+            Ok(()) = pty_size.changed() => {
+                let (size, source) = *pty_size.borrow_and_update();
+                pushed = Some(size);
+                ws_outgoing.send(resized_message(size, source)).await?;
+            }
+            Ok(notification) = notifications.recv() => {
+                ws_outgoing.send(crate::notifications::message(&notification)).await?;
+            }
+            msg = ws_incoming.next() => match msg {
+                Some(Ok(Message::Binary(data))) => {
+                    let input = match ClientMessage::decode(&data) {
+                        Ok(message) if capabilities.missing(&message).is_some() => {
+                            let scope = capabilities.missing(&message).unwrap_or_default();
+                            handle.record(TimelineEvent::Denied {
+                                scope: scope.to_owned(),
+                            });
+                            let denied = Capabilities::denial(&message, scope);
+                            let msg = ServerMessage::Denied(denied).encode();
+                            ws_outgoing.send(Message::Binary(msg)).await?;
+                            continue;
+                        }
+                        Ok(ClientMessage::Input(input)) => input,
+                        Ok(ClientMessage::Composition(text)) => text.as_bytes(),
+                        // Viewers follow the size the writer sets.
+                        Ok(ClientMessage::Resize(_)) if seat.role == Role::Viewer => continue,
+                        Ok(ClientMessage::Resize(size)) => {
+                            let size = config.clamp_size(size);
+                            let echo = pushed.take().is_some_and(|pushed| {
+                                same_size(pushed, size) && same_size(handle.pty_size().0, size)
+                            });
+                            if echo {
+                                continue;
+                            }
+                            if let Err(e) = attachment.resize(size).await {
+                                warn!("failed to resize session {}: {}", handle, e);
+                            }
+                            continue;
+                        }
+                        Ok(ClientMessage::Ping) => {
+                            ws_outgoing.send(Message::Binary(vec![proto::PONG])).await?;
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    if seat.role == Role::Viewer {
+                        continue;
+                    }
+                    if let Err(e) = attachment.input(input).await {
+                        debug!("dropped input of {:?} to session {}: {}", peer, handle, e);
+                    }
+                }
+                Some(Ok(Message::Ping(data))) => ws_outgoing.send(Message::Pong(data)).await?,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    debug!("{:?} left session {}", peer, handle);
+                    handle.record(TimelineEvent::Left { peer });
+                    return Ok(());
+                }
+                _ => (),
+            },
+        }
+    }
+    let reason = match handle.master.exit_status().now_or_never() {
+        Some(Some(status)) => {
+            ws_outgoing.send(exit_message(status)).await?;
+            CloseReason::Exited
+        }
+        _ => CloseReason::SessionEnded,
+    };
+    ws_outgoing
+        .send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Normal,
+            reason: reason.to_string().into(),
+        })))
+        .await?;
+    Ok(())
+}
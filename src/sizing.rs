@@ -0,0 +1,107 @@
+// Settles the pty size of sessions with several clients: the WebSocket
+// client driving it and the embedder's attachments, see
+// `Attachment::resize`. Each knows the size of its own window, but the pty
+// only has one, and letting them all set it leaves curses apps redrawing
+// for whoever resized last, garbled on every other screen.
+
+use std::collections::HashMap;
+use wspty_proto::{ResizeSource, WindowSize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SizePolicy {
+    // The pty takes every resize as it comes.
+    #[default]
+    LastWins,
+    // The largest size fitting in every client's window, the others showing
+    // the screen in a corner of theirs.
+    Smallest,
+    // Only the WebSocket client resizes, attachments follow its size.
+    Owner,
+}
+
+// Who resized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Client {
+    Owner,
+    Attachment(u64),
+}
+
+impl Client {
+    pub(crate) fn source(self) -> ResizeSource {
+        match self {
+            Client::Owner => ResizeSource::Client,
+            Client::Attachment(_) => ResizeSource::Participant,
+        }
+    }
+}
+
+pub(crate) struct Sizes {
+    policy: SizePolicy,
+    owner: Option<WindowSize>,
+    attachments: HashMap<u64, Option<WindowSize>>,
+}
+
+impl Default for Sizes {
+    fn default() -> Self {
+        Self::new(SizePolicy::default(), None)
+    }
+}
+
+impl Sizes {
+    // `owner` as the session was spawned with.
+    pub(crate) fn new(policy: SizePolicy, owner: Option<WindowSize>) -> Self {
+        Sizes {
+            policy,
+            owner,
+            attachments: HashMap::new(),
+        }
+    }
+
+    // An attachment without a size yet, which doesn't count until it
+    // resizes.
+    pub(crate) fn attach(&mut self, id: u64) -> Client {
+        self.attachments.insert(id, None);
+        Client::Attachment(id)
+    }
+
+    // Returns the size for the pty now that `client` is gone, if it
+    // changes.
+    pub(crate) fn detach(&mut self, client: Client) -> Option<WindowSize> {
+        let size = match client {
+            Client::Owner => return None,
+            Client::Attachment(key) => self.attachments.remove(&key).flatten(),
+        };
+        match (self.policy, size) {
+            (SizePolicy::Smallest, Some(_)) => self.smallest(),
+            _ => None,
+        }
+    }
+
+    // Returns the size the pty should take after `client` resized to
+    // `size`, `None` to leave it as it is.
+    pub(crate) fn resize(&mut self, client: Client, size: WindowSize) -> Option<WindowSize> {
+        match client {
+            Client::Owner => self.owner = Some(size),
+            Client::Attachment(key) => {
+                self.attachments.insert(key, Some(size));
+            }
+        }
+        match (self.policy, client) {
+            (SizePolicy::LastWins, _) | (SizePolicy::Owner, Client::Owner) => Some(size),
+            (SizePolicy::Owner, Client::Attachment(_)) => None,
+            (SizePolicy::Smallest, _) => self.smallest(),
+        }
+    }
+
+    // The pixel size only means something for a single window.
+    fn smallest(&self) -> Option<WindowSize> {
+        let mut sizes = self.owner.iter().chain(self.attachments.values().flatten());
+        let first = sizes.next()?;
+        Some(sizes.fold(*first, |smallest, size| WindowSize {
+            cols: smallest.cols.min(size.cols),
+            rows: smallest.rows.min(size.rows),
+            xpixel: 0,
+            ypixel: 0,
+        }))
+    }
+}
@@ -0,0 +1,92 @@
+// Commands requested with arguments, a working directory or environment
+// variables, see `proto::SpawnRequest`. The command policy, proxy routes
+// and the rest of the configuration keyed by command see the program and
+// its arguments as one command line, quoted as for a shell, so that a
+// policy allowing `bash` doesn't allow `bash -c ...`. Variables must be
+// allowed by `ServerConfig::environment_policy`, as for mid-session
+// updates, and the working directory must exist, unless it is on another
+// host or in a container, see the `ssh` and `container` modules.
+
+use crate::auth::Refusal;
+use crate::env::valid_name;
+use crate::ServerConfig;
+use std::path::Path;
+use wspty_proto::SpawnRequest;
+
+// Safe to leave unquoted in a command line.
+fn plain_word(word: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c))
+}
+
+// How `request` is known to the policies, its program alone for requests
+// from older clients.
+pub(crate) fn command_line(request: &SpawnRequest) -> String {
+    if request.cmd.len() <= 1 {
+        return request.program().to_owned();
+    }
+    let words: Vec<String> = request
+        .cmd
+        .iter()
+        .map(|word| {
+            if plain_word(word) {
+                word.clone()
+            } else {
+                format!("'{}'", word.replace('\'', r"'\''"))
+            }
+        })
+        .collect();
+    words.join(" ")
+}
+
+// The request of an older client asking for `command`.
+pub(crate) fn plain(command: &str) -> SpawnRequest {
+    SpawnRequest {
+        cmd: match command {
+            "" => vec![],
+            command => vec![command.to_owned()],
+        },
+        ..Default::default()
+    }
+}
+
+// The checks besides the command policy.
+pub(crate) fn check(
+    request: &SpawnRequest,
+    config: &ServerConfig,
+    identity: Option<&str>,
+) -> Result<(), Refusal> {
+    let refuse = |reason: String| Err(Refusal::Spawn(reason));
+    if request.cmd.iter().any(|word| word.contains('\0')) {
+        return refuse("invalid command".into());
+    }
+    if let Some(ref container) = request.container {
+        let allowed = config
+            .container_exec
+            .as_ref()
+            .is_some_and(|exec| exec.allows(identity, container));
+        if !allowed {
+            return refuse(format!("no access to container {}", container));
+        }
+    }
+    let remote = request.container.is_some()
+        || crate::ssh::route(&config.ssh_routes, request, identity).is_some();
+    if let (Some(ref cwd), false) = (&request.cwd, remote) {
+        let path = Path::new(cwd);
+        if !path.is_absolute() || !path.is_dir() {
+            return refuse(format!("no such directory: {}", cwd));
+        }
+    }
+    for (name, value) in request.env.iter() {
+        let allowed = config
+            .environment_policy
+            .as_ref()
+            .is_some_and(|policy| policy(name, identity));
+        if !valid_name(name) || value.contains('\0') || !allowed {
+            return refuse(format!("{} may not be set", name));
+        }
+    }
+    Ok(())
+}
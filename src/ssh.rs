@@ -0,0 +1,113 @@
+// WebSocket to SSH gateway: commands matching an `SshRoute` run on another
+// host, through the OpenSSH client spawned in the session's pty in place of
+// the command. It asks for a remote pty, so that output, input and resizes
+// go through as for local commands, and everything else about the session
+// (recording, policies, `run_as`, whose keys and `~/.ssh/config` ssh uses)
+// stays as it is. Authentication is left to ssh, password prompts included,
+// they show up in the client's terminal. Signals are sent as the characters
+// the remote terminal turns into them, see `SessionHandle::signal`.
+//
+// The request's working directory and variables are applied on the remote
+// host, so they aren't checked against the local file system.
+
+use std::path::PathBuf;
+use tokio::process::Command;
+use wspty_proto::SpawnRequest;
+
+#[derive(Clone, Debug, Default)]
+pub struct SshRoute {
+    // Matches the programs starting with it, stripped from the program run
+    // on the host. "db/" runs "db/psql" as "psql", and "db/" alone as the
+    // login shell of the remote account.
+    pub prefix: String,
+    // Only matches this client identity, if set.
+    pub identity: Option<String>,
+    // As given to ssh, a host name, an address or an alias of its
+    // configuration.
+    pub host: String,
+    pub port: Option<u16>,
+    // The remote account, ssh's default if unset.
+    pub user: Option<String>,
+    // Private key, ssh's defaults and agent if unset.
+    pub key: Option<PathBuf>,
+    // `-o` options, e.g. `StrictHostKeyChecking=yes`.
+    pub options: Vec<String>,
+    // The client binary, `ssh` from `PATH` if unset.
+    pub program: Option<PathBuf>,
+}
+
+// The first route matching the program of `request`, with what to run on
+// the host.
+pub(crate) fn route<'a>(
+    routes: &'a [SshRoute],
+    request: &SpawnRequest,
+    identity: Option<&str>,
+) -> Option<(&'a SshRoute, SpawnRequest)> {
+    routes.iter().find_map(|route| {
+        if route.identity.is_some() && route.identity.as_deref() != identity {
+            return None;
+        }
+        let program = request.program().strip_prefix(route.prefix.as_str())?;
+        let mut remote = request.clone();
+        match program {
+            "" => remote.cmd.clear(),
+            program => remote.cmd[0] = program.to_owned(),
+        }
+        Some((route, remote))
+    })
+}
+
+impl SshRoute {
+    // The ssh client running `remote`.
+    pub(crate) fn command(&self, remote: &SpawnRequest) -> Command {
+        let mut cmd = Command::new(self.program.as_deref().unwrap_or("ssh".as_ref()));
+        // Twice, ssh has no terminal of its own to tell it otherwise.
+        cmd.arg("-tt");
+        if let Some(port) = self.port {
+            cmd.arg("-p").arg(port.to_string());
+        }
+        if let Some(ref user) = self.user {
+            cmd.arg("-l").arg(user);
+        }
+        if let Some(ref key) = self.key {
+            cmd.arg("-i").arg(key);
+        }
+        for option in self.options.iter() {
+            cmd.arg("-o").arg(option);
+        }
+        cmd.arg("--").arg(&self.host);
+        if let Some(line) = remote_command(remote) {
+            cmd.arg(line);
+        }
+        cmd
+    }
+}
+
+// The command line for the remote shell, `None` for the login shell. Each
+// word is quoted, the remote shell must not see more than the command
+// policy did.
+fn remote_command(remote: &SpawnRequest) -> Option<String> {
+    if remote.cmd.is_empty() && remote.cwd.is_none() && remote.env.is_empty() {
+        return None;
+    }
+    let mut words = vec![];
+    if let Some(ref cwd) = remote.cwd {
+        words.extend(["cd".to_owned(), quote(cwd), "&&".to_owned()]);
+    }
+    words.push("exec".to_owned());
+    if !remote.env.is_empty() {
+        words.push("env".to_owned());
+        for (name, value) in remote.env.iter() {
+            words.push(quote(&format!("{}={}", name, value)));
+        }
+    }
+    match remote.cmd.is_empty() {
+        true => words.extend(["\"$SHELL\"".to_owned(), "-l".to_owned()]),
+        false => words.extend(remote.cmd.iter().map(|word| quote(word))),
+    }
+    Some(words.join(" "))
+}
+
+fn quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
@@ -0,0 +1,127 @@
+// How sessions end on the client side. When the child exits, or the session
+// is terminated or handed over, the messages still queued for the client are
+// sent in order up to `flush_limit` bytes. The rest is dropped from the
+// first message that doesn't fit, so the client never sees a hole in the
+// output. A close frame is sent last, and the whole sequence is given up
+// after `timeout` for clients that don't read anymore. After the child
+// exited, a `proto::Exit` frame comes right before the close frame, and the
+// client gets until `exit_grace` after the exit to read everything and
+// answer the close frame before the connection is dropped: dropping it with
+// messages of the client unread would reset it, losing the last output.
+// Clients turned away before their session started get the close frame
+// alone.
+
+use crate::outbox::OutboxReceiver;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::debug;
+use std::time::Duration;
+use tokio::time::Instant;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Error as WsError, Message};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Teardown {
+    pub flush_limit: usize,
+    pub timeout: Duration,
+    pub exit_grace: Duration,
+}
+
+impl Default for Teardown {
+    fn default() -> Self {
+        Teardown {
+            flush_limit: 1024 * 1024,
+            timeout: Duration::from_secs(5),
+            exit_grace: Duration::from_secs(5),
+        }
+    }
+}
+
+// Flushes `queue` into `outgoing` through `prepare`, which leaves out what
+// goes elsewhere, then sends `last` whatever the flush limit, and
+// `farewell`.
+pub(crate) async fn close<O, F>(
+    outgoing: &mut O,
+    queue: &mut OutboxReceiver,
+    last: Option<Message>,
+    farewell: CloseFrame<'static>,
+    policy: Teardown,
+    mut prepare: F,
+) where
+    O: Sink<Message, Error = WsError> + Unpin,
+    F: FnMut(Message) -> Option<Message>,
+{
+    let mut budget = Some(policy.flush_limit);
+    let mut dropped = 0;
+    let flush = async {
+        while let Ok(msg) = queue.try_recv() {
+            // Only the farewell closes.
+            if msg.is_close() {
+                continue;
+            }
+            budget = budget.and_then(|budget| budget.checked_sub(msg.len()));
+            if budget.is_none() {
+                dropped += 1;
+                continue;
+            }
+            if let Some(msg) = prepare(msg) {
+                outgoing.feed(msg).await?;
+            }
+        }
+        if let Some(last) = last {
+            outgoing.feed(last).await?;
+        }
+        outgoing.send(Message::Close(Some(farewell))).await?;
+        outgoing.close().await
+    };
+    match tokio::time::timeout(policy.timeout, flush).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => debug!("failed to close websocket: {:?}", e),
+        Err(_) => debug!("timed out closing websocket"),
+    }
+    if dropped > 0 {
+        debug!("dropped {} messages queued past the flush limit", dropped);
+    }
+}
+
+// Waits for the client to answer the close frame, until `deadline`.
+pub(crate) async fn linger<I>(incoming: &mut I, deadline: Instant)
+where
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    let answered = async {
+        while let Some(Ok(msg)) = incoming.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    };
+    if tokio::time::timeout_at(deadline, answered).await.is_err() {
+        debug!("timed out waiting for the client to close");
+    }
+}
+
+// Turns a client away before any session started. Dropping the socket right
+// after the close frame could reset the connection while the client's
+// first messages are unread, losing the frame, so the client's answer is
+// awaited.
+pub(crate) async fn refuse<O, I>(
+    outgoing: &mut O,
+    incoming: &mut I,
+    farewell: CloseFrame<'static>,
+    policy: Teardown,
+) -> Result<(), WsError>
+where
+    O: Sink<Message, Error = WsError> + Unpin,
+    I: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    outgoing.send(Message::Close(Some(farewell))).await?;
+    let answered = async {
+        while let Some(Ok(msg)) = incoming.next().await {
+            if msg.is_close() {
+                break;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(policy.timeout, answered).await;
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use std::collections::BTreeMap;
+
+// Colors pushed to the client terminal as OSC sequences, in any format
+// XParseColor understands (`#rrggbb`, `rgb:rr/gg/bb`...).
+#[derive(Clone, Debug, Default)]
+pub struct Theme {
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    pub cursor: Option<String>,
+    // Indexed colors, 0 to 255.
+    pub palette: BTreeMap<u8, String>,
+}
+
+impl Theme {
+    pub(crate) fn sequences(&self) -> Vec<u8> {
+        let mut out = String::new();
+        for (index, color) in &self.palette {
+            out.push_str(&format!("\x1b]4;{};{}\x1b\\", index, color));
+        }
+        let dynamic = [&self.foreground, &self.background, &self.cursor];
+        for (code, color) in (10..).zip(dynamic.iter()) {
+            if let Some(color) = color {
+                out.push_str(&format!("\x1b]{};{}\x1b\\", code, color));
+            }
+        }
+        out.into_bytes()
+    }
+}
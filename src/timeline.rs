@@ -0,0 +1,133 @@
+// What happened to each session, in order, for answering "what happened to
+// my session" from `SessionHandle::timeline` rather than the logs: its
+// client connecting and coming back, viewers joining, resizes, signals,
+// messages its token doesn't allow, the server ending it (timeouts, budgets
+// and bans included) and the exit. The oldest entries go once there are
+// `CAPACITY` of them. Sessions migrated to another server start over.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use wspty_proto::{ResizeSource, Role, Signal};
+
+pub(crate) const CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    // A client took the session, when it started or got migrated here.
+    Connected {
+        peer: SocketAddr,
+    },
+    // Lost its client and parked, see the `persist` module.
+    Disconnected,
+    Reconnected,
+    // Followed from another connection, see the `sharing` module.
+    Joined {
+        peer: SocketAddr,
+        role: Role,
+    },
+    Left {
+        peer: SocketAddr,
+    },
+    Resized {
+        cols: u16,
+        rows: u16,
+        source: ResizeSource,
+    },
+    Signaled {
+        signal: Signal,
+    },
+    // See the `tokens` module.
+    Denied {
+        scope: String,
+    },
+    // By the server, with the reason its client got.
+    Ended {
+        reason: String,
+    },
+    // See the `panics` module.
+    Failed {
+        task: String,
+        message: String,
+    },
+    Migrated,
+    // See the `handoff` module.
+    HandedOff {
+        from: Option<String>,
+        to: String,
+    },
+    Exited {
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimelineEntry {
+    pub at: SystemTime,
+    #[serde(flatten)]
+    pub event: TimelineEvent,
+}
+
+#[derive(Default)]
+pub(crate) struct Timeline {
+    entries: VecDeque<TimelineEntry>,
+}
+
+impl Timeline {
+    pub(crate) fn record(&mut self, event: TimelineEvent) {
+        if self.entries.len() == CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TimelineEntry {
+            at: SystemTime::now(),
+            event,
+        });
+    }
+
+    pub(crate) fn entries(&self) -> Vec<TimelineEntry> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_latest() {
+        let mut timeline = Timeline::default();
+        for cols in 0..CAPACITY as u16 + 2 {
+            timeline.record(TimelineEvent::Resized {
+                cols,
+                rows: 24,
+                source: ResizeSource::Client,
+            });
+        }
+        let entries = timeline.entries();
+        assert_eq!(entries.len(), CAPACITY);
+        assert_eq!(
+            entries[0].event,
+            TimelineEvent::Resized {
+                cols: 2,
+                rows: 24,
+                source: ResizeSource::Client,
+            }
+        );
+    }
+
+    #[test]
+    fn serializes_flat() {
+        let entry = TimelineEntry {
+            at: SystemTime::UNIX_EPOCH,
+            event: TimelineEvent::Signaled {
+                signal: Signal::Int,
+            },
+        };
+        let json = serde_json::to_value(entry).unwrap();
+        assert_eq!(json["event"], "signaled");
+        assert_eq!(json["signal"], "SIGINT");
+    }
+}
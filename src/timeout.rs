@@ -0,0 +1,68 @@
+// Idle and absolute session timeouts. Clients that stopped sending anything,
+// input or resizes, for `idle` get their session ended, and so do all of
+// them `absolute` after the session started. The child is killed, its
+// output flushed as for an exit and the connection closed with
+// `CloseCode::Away` and the reason. WebSocket pings every `ping_interval`
+// keep proxies from dropping quiet connections and find out about dead
+// ones, their pongs don't count as activity. Parked sessions are idle.
+
+use crate::clock::Ticker;
+use crate::outbox::Outbox;
+use crate::SessionHandle;
+use log::info;
+use std::time::Duration;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::Message;
+use wspty_proto::protocol::CloseReason;
+
+#[derive(Clone, Debug, Default)]
+pub struct Timeouts {
+    pub ping_interval: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub absolute: Option<Duration>,
+}
+
+impl Timeouts {
+    pub(crate) fn any(&self) -> bool {
+        self.ping_interval.is_some() || self.idle.is_some() || self.absolute.is_some()
+    }
+}
+
+// How often the timeouts are checked.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+// Runs until the session times out or the client is gone.
+pub(crate) async fn watch(timeouts: Timeouts, handle: SessionHandle, websocket_sender: Outbox) {
+    let clock = handle.clock.clone();
+    let started = clock.now();
+    let mut checks = Ticker::new(clock.clone(), started, CHECK_INTERVAL);
+    let ping_interval = timeouts.ping_interval.unwrap_or(CHECK_INTERVAL);
+    let mut pings = Ticker::new(clock.clone(), started + ping_interval, ping_interval);
+    loop {
+        tokio::select! {
+            _ = checks.tick() => {}
+            _ = pings.tick(), if timeouts.ping_interval.is_some() => {
+                if websocket_sender.send(Message::Ping(vec![])).is_err() {
+                    return;
+                }
+                continue;
+            }
+        }
+        let reason = if timeouts
+            .absolute
+            .is_some_and(|limit| clock.now().saturating_duration_since(started) >= limit)
+        {
+            CloseReason::TimeLimit
+        } else if timeouts
+            .idle
+            .is_some_and(|limit| handle.idle_time() >= limit)
+        {
+            CloseReason::IdleTimeout
+        } else {
+            continue;
+        };
+        info!("ending session {}: {}", handle, reason);
+        handle.close(CloseCode::Away, reason);
+        return;
+    }
+}
@@ -0,0 +1,252 @@
+// TLS for listeners, with protocol selection through ALPN. Connections
+// negotiating `http/1.1`, or no protocol at all, go through the usual
+// WebSocket handshake. The other protocols offered are handed to the
+// acceptor registered for them, so that e.g. an HTTP/2 server can share the
+// port. TLS sessions can't be migrated, and the UI is only served on plain
+// listeners.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConnection;
+use std::collections::HashMap;
+use std::io::{self, Error as IoError, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+pub const HTTP_1_1: &[u8] = b"http/1.1";
+
+pub type AlpnAcceptor = Arc<dyn Fn(TlsStream, SocketAddr) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct TlsConfig {
+    // PEM encoded certificate chain and private key.
+    pub cert_chain: PathBuf,
+    pub private_key: PathBuf,
+    // Protocols offered, in order of preference.
+    pub alpn: Vec<Vec<u8>>,
+    // Takes over connections negotiating other protocols than `http/1.1`.
+    pub acceptors: HashMap<Vec<u8>, AlpnAcceptor>,
+}
+
+impl TlsConfig {
+    // Only offers `http/1.1`, for WebSocket.
+    pub fn new<P: Into<PathBuf>>(cert_chain: P, private_key: P) -> Self {
+        TlsConfig {
+            cert_chain: cert_chain.into(),
+            private_key: private_key.into(),
+            alpn: vec![HTTP_1_1.to_vec()],
+            acceptors: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn load(&self) -> Result<Arc<rustls::ServerConfig>, anyhow::Error> {
+        let certs =
+            CertificateDer::pem_file_iter(&self.cert_chain)?.collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_file(&self.private_key)?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        tls.alpn_protocols = self.alpn.clone();
+        Ok(Arc::new(tls))
+    }
+}
+
+// Server side of a TLS connection, once the handshake is done.
+pub struct TlsStream {
+    io: TcpStream,
+    conn: ServerConnection,
+}
+
+pub(crate) async fn accept(
+    io: TcpStream,
+    config: Arc<rustls::ServerConfig>,
+) -> Result<TlsStream, IoError> {
+    let conn = ServerConnection::new(config).map_err(IoError::other)?;
+    let mut stream = TlsStream { io, conn };
+    std::future::poll_fn(|cx| stream.poll_handshake(cx)).await?;
+    Ok(stream)
+}
+
+// Drives the socket from the synchronous rustls API, `WouldBlock` standing
+// for `Pending`.
+struct SyncAdapter<'a, 'b> {
+    io: &'a mut TcpStream,
+    cx: &'a mut Context<'b>,
+}
+
+impl Read for SyncAdapter<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(&mut *self.io).poll_read(self.cx, &mut buf) {
+            Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+            Poll::Ready(Err(e)) => Err(e),
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Write for SyncAdapter<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+// Best effort writes for when there is nothing to wait with.
+struct TryWriter<'a>(&'a TcpStream);
+
+impl Write for TryWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.try_write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn ready_or_pending<T>(res: io::Result<T>) -> Poll<io::Result<T>> {
+    match res {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Poll::Pending,
+        res => Poll::Ready(res),
+    }
+}
+
+impl TlsStream {
+    // The protocol selected through ALPN, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.conn.alpn_protocol()
+    }
+
+    // Reads and processes TLS records, returns 0 at the end of the stream.
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let mut adapter = SyncAdapter {
+            io: &mut self.io,
+            cx,
+        };
+        let n = ready!(ready_or_pending(self.conn.read_tls(&mut adapter)))?;
+        if let Err(e) = self.conn.process_new_packets() {
+            // Try to let the peer know about the alert.
+            let _ = self.poll_write_tls(cx);
+            return Poll::Ready(Err(IoError::new(ErrorKind::InvalidData, e)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.wants_write() {
+            let mut adapter = SyncAdapter {
+                io: &mut self.io,
+                cx,
+            };
+            if ready!(ready_or_pending(self.conn.write_tls(&mut adapter)))? == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.conn.is_handshaking() {
+            ready!(self.poll_write_tls(cx))?;
+            if self.conn.is_handshaking()
+                && self.conn.wants_read()
+                && ready!(self.poll_read_tls(cx))? == 0
+            {
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        self.poll_write_tls(cx)
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.conn.reader().read(buf.initialize_unfilled()) {
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            // Records like key updates may need an answer.
+            ready!(this.poll_write_tls(cx))?;
+            if ready!(this.poll_read_tls(cx))? == 0 {
+                // Closed without a close_notify alert.
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let n = this.conn.writer().write(buf)?;
+            match this.poll_write_tls(cx) {
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                // Buffered by rustls, sent on the next write or flush.
+                Poll::Pending if n > 0 => return Poll::Ready(Ok(n)),
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(())) if n > 0 || buf.is_empty() => return Poll::Ready(Ok(n)),
+                Poll::Ready(Ok(())) => (),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.writer().flush()?;
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.send_close_notify();
+        ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+}
+
+// Sessions just drop their stream once over, still let the peer know it
+// wasn't truncated.
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.conn.send_close_notify();
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut TryWriter(&self.io)) {
+                Ok(n) if n > 0 => (),
+                _ => break,
+            }
+        }
+    }
+}
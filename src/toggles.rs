@@ -0,0 +1,124 @@
+// Session features the client switches at runtime with a `proto::Toggle`,
+// for UIs to show switches without reconnecting: recording, see
+// `RecordingConfig::on_demand`, dropping the client's own input, software
+// flow control on the pty, and raw passthrough. Each change goes through
+// `ServerConfig::toggle_policy`. Without one the client can make itself
+// read-only and switch flow control, but not touch the recording or go raw.
+// The answer is a `proto::Features` telling what is in effect then, refused
+// and failed changes included.
+//
+// Raw passthrough is for debugging rendering issues the server's filters
+// may cause: while it lasts, output skips sanitizing, UTF-8 framing,
+// repeated line folding, low-power batching, color reduction and frame
+// mode, and input the line editor. The server's screen doesn't see the
+// output either, so snapshots and viewers joining get it as it was before.
+// Recordings, mirrors and viewers keep getting everything.
+
+use crate::outbox::Outbox;
+use crate::recording::Recorder;
+use crate::{ServerConfig, Session, SessionHandle};
+use anyhow::anyhow;
+use log::{debug, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tungstenite::Message;
+use wspty_proto::{self as proto, Features, Toggle};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    Recording,
+    ReadOnly,
+    FlowControl,
+    Raw,
+}
+
+// Whether the client of the session can turn the feature on (true) or off.
+pub type TogglePolicy = Arc<dyn Fn(&Session, Feature, bool) -> bool + Send + Sync>;
+
+// Applies what the policy allows of `toggle`, `read_only` being the
+// client's flag and `websocket_sender` where the last diff goes when raw
+// passthrough ends frame mode.
+pub(crate) async fn toggle(
+    handle: &SessionHandle,
+    config: &ServerConfig,
+    read_only: &AtomicBool,
+    websocket_sender: &Outbox,
+    toggle: Toggle,
+) -> Features {
+    let allowed = |feature, enabled| {
+        let allowed = match config.toggle_policy {
+            Some(ref policy) => policy(handle, feature, enabled),
+            None => !matches!(feature, Feature::Recording | Feature::Raw),
+        };
+        if !allowed {
+            debug!(
+                "session {} can't switch {:?} to {}",
+                handle, feature, enabled
+            );
+        }
+        allowed
+    };
+    if let Some(enabled) = toggle.recording {
+        if allowed(Feature::Recording, enabled) {
+            if let Err(e) = set_recording(handle, config, enabled).await {
+                warn!(
+                    "failed to switch the recording of session {}: {:#}",
+                    handle, e
+                );
+            }
+        }
+    }
+    if let Some(enabled) = toggle.read_only {
+        if allowed(Feature::ReadOnly, enabled) {
+            read_only.store(enabled, Ordering::Relaxed);
+        }
+    }
+    if let Some(enabled) = toggle.flow_control {
+        if allowed(Feature::FlowControl, enabled) {
+            if let Err(e) = handle.master.set_flow_control(enabled) {
+                warn!("failed to switch flow control of session {}: {}", handle, e);
+            }
+        }
+    }
+    if let Some(enabled) = toggle.raw {
+        if allowed(Feature::Raw, enabled) {
+            // Sent with the lock held, before any raw output.
+            let mut vt = handle.vt.lock().unwrap();
+            if let Some(frame) = vt.set_raw(enabled) {
+                let _ = websocket_sender.send(Message::Binary(proto::frame(proto::OUTPUT, &frame)));
+            }
+        }
+    }
+    Features {
+        recording: handle.recorder().is_some_and(|r| r.is_enabled()),
+        read_only: read_only.load(Ordering::Relaxed),
+        flow_control: handle.master.flow_control().unwrap_or(false),
+        raw: handle.vt.lock().unwrap().raw(),
+    }
+}
+
+// The recording is started the first time, only paused after that.
+async fn set_recording(
+    handle: &SessionHandle,
+    config: &ServerConfig,
+    enabled: bool,
+) -> Result<(), anyhow::Error> {
+    if let Some(recorder) = handle.recorder() {
+        recorder.set_enabled(enabled);
+        return Ok(());
+    }
+    if !enabled {
+        return Ok(());
+    }
+    let recording = config
+        .recording
+        .as_ref()
+        .ok_or_else(|| anyhow!("no recording configured"))?;
+    let spawn = handle
+        .spawn
+        .clone()
+        .ok_or_else(|| anyhow!("the command is unknown"))?;
+    let recorder = Recorder::start(recording, handle, &spawn).await?;
+    handle.recorder.lock().unwrap().replace(recorder);
+    Ok(())
+}
@@ -0,0 +1,277 @@
+// Checks on short-lived connection tokens for `ServerConfig::token_auth`,
+// for validators that read claims from them, JWTs for instance. Tokens are
+// refused once expired and before they are valid, up to `skew` either way
+// for clocks between the issuer and the server not agreeing. Tokens with an
+// id (a `jti` or a nonce) can only be used once: ids are remembered until
+// their token expires, or for `replay_ttl` for tokens that don't, so that a
+// leaked token can't open another session. Clients reconnecting, to resume
+// a persisted session for instance, need a new one.
+//
+// Tokens can also be scoped to what their client may do, a monitoring
+// dashboard only reading the output of the sessions it attaches to for
+// instance. Messages outside the token's `Capabilities` are dropped and
+// answered with a `proto::Denied`. Without `spawn` only `Attach` and
+// `Resume` open a session, and followers without `input` attach as
+// viewers. Resumed sessions keep the capabilities they were opened with.
+
+use crate::TokenValidator;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use wspty_proto::{ClientMessage, Denied};
+
+// Output can always be read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    // Starting commands, rather than attaching to sessions.
+    pub spawn: bool,
+    // Typing, transfers and the session settings.
+    pub input: bool,
+    pub resize: bool,
+    // Signals and pausing the output.
+    pub signal: bool,
+}
+
+impl Capabilities {
+    pub const FULL: Capabilities = Capabilities {
+        spawn: true,
+        input: true,
+        resize: true,
+        signal: true,
+    };
+    pub const READ_ONLY: Capabilities = Capabilities {
+        spawn: false,
+        input: false,
+        resize: false,
+        signal: false,
+    };
+    pub const RESIZE_ONLY: Capabilities = Capabilities {
+        resize: true,
+        ..Capabilities::READ_ONLY
+    };
+
+    // From a space separated list of scopes, as in an OAuth `scope` claim:
+    // `full`, or any of `read`, `spawn`, `input`, `resize` and `signal`.
+    // Unknown scopes are ignored.
+    pub fn parse(scope: &str) -> Self {
+        let mut capabilities = Capabilities::READ_ONLY;
+        for scope in scope.split_whitespace() {
+            match scope {
+                "full" => capabilities = Capabilities::FULL,
+                "spawn" => capabilities.spawn = true,
+                "input" => capabilities.input = true,
+                "resize" => capabilities.resize = true,
+                "signal" => capabilities.signal = true,
+                _ => {}
+            }
+        }
+        capabilities
+    }
+
+    // The scope `message` needs that these don't have.
+    pub(crate) fn missing(&self, message: &ClientMessage) -> Option<&'static str> {
+        let (scope, allowed) = match message {
+            ClientMessage::Input(_)
+            | ClientMessage::Composition(_)
+            | ClientMessage::LineInput(_)
+            | ClientMessage::Environment(_)
+            | ClientMessage::Transfer(_)
+            | ClientMessage::TransferChunk(..)
+            | ClientMessage::CancelTransfer(_)
+            | ClientMessage::Grant(_)
+            | ClientMessage::Toggle(_) => ("input", self.input),
+            ClientMessage::Resize(_) => ("resize", self.resize),
+            ClientMessage::Signal(_) | ClientMessage::Pause(_) => ("signal", self.signal),
+            _ => return None,
+        };
+        if allowed {
+            None
+        } else {
+            Some(scope)
+        }
+    }
+
+    // What to answer a message `missing` a scope with.
+    pub(crate) fn denial(message: &ClientMessage, scope: &str) -> Denied {
+        Denied {
+            opcode: message.encode()[0],
+            scope: scope.to_owned(),
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Capabilities::FULL
+    }
+}
+
+// What the validator read from a token it accepted.
+#[derive(Clone, Debug, Default)]
+pub struct TokenClaims {
+    pub identity: String,
+    // `exp` and `nbf` of a JWT.
+    pub expires: Option<SystemTime>,
+    pub not_before: Option<SystemTime>,
+    // `jti` of a JWT, making the token a one-time one.
+    pub id: Option<String>,
+    // See `Capabilities::parse` for a `scope` claim.
+    pub capabilities: Capabilities,
+}
+
+// For opaque tokens, only telling who the client is.
+impl From<String> for TokenClaims {
+    fn from(identity: String) -> Self {
+        TokenClaims {
+            identity,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TokenPolicy {
+    pub skew: Duration,
+    pub replay_ttl: Duration,
+}
+
+impl Default for TokenPolicy {
+    fn default() -> Self {
+        TokenPolicy {
+            skew: Duration::from_secs(30),
+            replay_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+// Ids of the tokens used, with when they can be forgotten.
+#[derive(Default)]
+struct Seen {
+    ids: HashMap<String, SystemTime>,
+}
+
+impl TokenPolicy {
+    // Wraps `validate` for it to be used as `ServerConfig::token_auth`.
+    pub fn validator(self, validate: TokenValidator) -> TokenValidator {
+        let seen = Mutex::new(Seen::default());
+        Arc::new(move |token, peer| {
+            let claims = validate(token, peer)?;
+            self.check(&claims, &mut seen.lock().unwrap(), SystemTime::now())?;
+            Ok(claims)
+        })
+    }
+
+    fn check(&self, claims: &TokenClaims, seen: &mut Seen, now: SystemTime) -> Result<(), String> {
+        if claims
+            .expires
+            .is_some_and(|expires| now > expires + self.skew)
+        {
+            return Err("token expired".to_owned());
+        }
+        if claims
+            .not_before
+            .is_some_and(|not_before| now + self.skew < not_before)
+        {
+            return Err("token not valid yet".to_owned());
+        }
+        if let Some(ref id) = claims.id {
+            seen.ids.retain(|_, forget| *forget > now);
+            if seen.ids.contains_key(id) {
+                return Err("token already used".to_owned());
+            }
+            let forget = match claims.expires {
+                Some(expires) => expires + self.skew,
+                None => now + self.replay_ttl,
+            };
+            seen.ids.insert(id.clone(), forget);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(id: Option<&str>, expires: Option<SystemTime>) -> TokenClaims {
+        TokenClaims {
+            identity: "alice".to_owned(),
+            expires,
+            not_before: None,
+            id: id.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn expiry_tolerates_skew() {
+        let policy = TokenPolicy::default();
+        let mut seen = Seen::default();
+        let now = SystemTime::now();
+        let expired = now - Duration::from_secs(10);
+        assert!(policy
+            .check(&claims(None, Some(expired)), &mut seen, now)
+            .is_ok());
+        let expired = now - Duration::from_secs(60);
+        assert!(policy
+            .check(&claims(None, Some(expired)), &mut seen, now)
+            .is_err());
+        let mut early = claims(None, None);
+        early.not_before = Some(now + Duration::from_secs(10));
+        assert!(policy.check(&early, &mut seen, now).is_ok());
+        early.not_before = Some(now + Duration::from_secs(60));
+        assert!(policy.check(&early, &mut seen, now).is_err());
+    }
+
+    #[test]
+    fn ids_are_used_once() {
+        let policy = TokenPolicy::default();
+        let mut seen = Seen::default();
+        let now = SystemTime::now();
+        let expires = Some(now + Duration::from_secs(60));
+        assert!(policy
+            .check(&claims(Some("a"), expires), &mut seen, now)
+            .is_ok());
+        assert!(policy
+            .check(&claims(Some("b"), expires), &mut seen, now)
+            .is_ok());
+        assert!(policy
+            .check(&claims(Some("a"), expires), &mut seen, now)
+            .is_err());
+        // Forgotten once the token would be refused anyway.
+        let later = now + Duration::from_secs(120);
+        let expires = Some(later + Duration::from_secs(60));
+        assert!(policy
+            .check(&claims(Some("a"), expires), &mut seen, later)
+            .is_ok());
+        assert_eq!(seen.ids.len(), 1);
+    }
+
+    #[test]
+    fn scopes() {
+        assert_eq!(Capabilities::parse("read"), Capabilities::READ_ONLY);
+        assert_eq!(
+            Capabilities::parse("read resize"),
+            Capabilities::RESIZE_ONLY
+        );
+        assert_eq!(Capabilities::parse("spawn full"), Capabilities::FULL);
+        let capabilities = Capabilities::parse("input admin");
+        assert!(capabilities.input && !capabilities.spawn);
+        let size = wspty_proto::WindowSize {
+            cols: 80,
+            rows: 24,
+            xpixel: 0,
+            ypixel: 0,
+        };
+        assert_eq!(
+            capabilities.missing(&ClientMessage::Resize(size)),
+            Some("resize")
+        );
+        assert_eq!(capabilities.missing(&ClientMessage::Input(b"ls\r")), None);
+        assert_eq!(
+            Capabilities::READ_ONLY.missing(&ClientMessage::Input(b"ls\r")),
+            Some("input")
+        );
+        assert_eq!(Capabilities::READ_ONLY.missing(&ClientMessage::Ping), None);
+    }
+}
@@ -0,0 +1,77 @@
+// Latency tracing of single inputs, enabled with
+// `ServerConfig::latency_tracing`. A client sends `proto::Trace` before an
+// input message, the server then notes when that input is written to the
+// pty, when the next output is read and when it is handed to the socket,
+// and answers with a `proto::TraceReport`. Output already on its way can be
+// mistaken for the echo, so trace while the session is otherwise idle.
+
+use std::time::Instant;
+use wspty_proto::{Trace, TraceReport};
+
+struct Pending {
+    trace: Trace,
+    // Both set once the input arrived.
+    received: Option<Instant>,
+    written: Option<Instant>,
+    read: Option<Instant>,
+}
+
+#[derive(Default)]
+pub(crate) struct Tracer {
+    pending: Option<Pending>,
+}
+
+fn micros(from: Instant, to: Instant) -> u64 {
+    to.duration_since(from).as_micros() as u64
+}
+
+impl Tracer {
+    // Replaces a trace still in flight.
+    pub(crate) fn start(&mut self, trace: Trace) {
+        self.pending = Some(Pending {
+            trace,
+            received: None,
+            written: None,
+            read: None,
+        });
+    }
+
+    // `received` is when the input written to the pty arrived.
+    pub(crate) fn written(&mut self, received: Instant) {
+        if let Some(pending) = self.pending.as_mut() {
+            if pending.written.is_none() {
+                pending.received = Some(received);
+                pending.written = Some(Instant::now());
+            }
+        }
+    }
+
+    pub(crate) fn read(&mut self) {
+        if let Some(pending) = self.pending.as_mut() {
+            if pending.written.is_some() {
+                pending.read.get_or_insert_with(Instant::now);
+            }
+        }
+    }
+
+    // Completes the trace once its output went out.
+    pub(crate) fn sent(&mut self) -> Option<TraceReport> {
+        let (trace, received, written, read) = match self.pending {
+            Some(Pending {
+                trace,
+                received: Some(received),
+                written: Some(written),
+                read: Some(read),
+            }) => (trace, received, written, read),
+            _ => return None,
+        };
+        self.pending = None;
+        Some(TraceReport {
+            id: trace.id,
+            origin: trace.origin,
+            write: micros(received, written),
+            echo: micros(written, read),
+            send: micros(read, Instant::now()),
+        })
+    }
+}
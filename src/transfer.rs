@@ -0,0 +1,364 @@
+// Files copied to and from the server beside the terminal, when
+// `ServerConfig::file_transfer` is set, see `proto::Transfer`. Uploads are
+// written next to their path and renamed over it once complete, so that an
+// interrupted one never leaves half a file behind. Downloads are sent from
+// a task of their own, only while the client keeps up with the output, so
+// that the terminal stays responsive during large ones.
+//
+// Files are read and written by the server, as its own user, uploads being
+// given to the account of the session afterwards. What clients can reach is
+// up to `root` and the policy, the shell's own permissions don't apply.
+
+use crate::outbox::Outbox;
+use nix::unistd::Uid;
+use std::collections::HashMap;
+use std::io::{Error as IoError, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinHandle;
+use tungstenite::Message;
+use wspty_proto::{ServerMessage, Transfer, TransferDirection, TransferState, TransferStatus};
+
+const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+// Checks a transfer once its path is resolved, for a client with the
+// identity its authenticator returned. The error is sent to the client.
+pub type TransferPolicy =
+    Arc<dyn Fn(&Path, TransferDirection, Option<&str>) -> Result<(), String> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct FileTransfer {
+    // Relative paths start from it and paths out of it, once their links
+    // are followed, are refused. Only absolute paths are taken if unset.
+    pub root: Option<PathBuf>,
+    // Largest file uploaded or downloaded, unlimited if unset.
+    pub max_size: Option<u64>,
+    // Of downloads, 64 KiB if unset.
+    pub chunk_size: Option<usize>,
+    pub policy: Option<TransferPolicy>,
+}
+
+impl FileTransfer {
+    fn resolve(&self, path: &str) -> Result<PathBuf, String> {
+        let path = Path::new(path);
+        if path.as_os_str().is_empty() || path.to_string_lossy().contains('\0') {
+            return Err("invalid path".into());
+        }
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(format!("{} may not contain ..", path.display()));
+        }
+        let path = match self.root {
+            Some(ref root) if path.is_relative() => root.join(path),
+            None if path.is_relative() => {
+                return Err(format!("{} is not absolute", path.display()))
+            }
+            _ => path.to_owned(),
+        };
+        // Links in `root` can point out of it.
+        let real = real_path(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if let Some(ref root) = self.root {
+            let root = root
+                .canonicalize()
+                .map_err(|e| format!("{}: {}", root.display(), e))?;
+            if !real.starts_with(&root) {
+                return Err(format!("{} is out of {}", path.display(), root.display()));
+            }
+        }
+        Ok(real)
+    }
+
+    fn check_size(&self, size: u64) -> Result<(), String> {
+        match self.max_size {
+            Some(max) if size > max => Err(format!("larger than {} bytes", max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// `path` with the links in it followed, the last one too unless it is
+// dangling or there is nothing there yet.
+fn real_path(path: &Path) -> Result<PathBuf, IoError> {
+    match path.canonicalize() {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let name = path.file_name().ok_or(e)?;
+            let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+            Ok(parent.canonicalize()?.join(name))
+        }
+        res => res,
+    }
+}
+
+struct Upload {
+    file: File,
+    // Where it is written until complete.
+    partial: PathBuf,
+    path: PathBuf,
+    size: u64,
+    written: u64,
+}
+
+// The transfers of one connection, those still in progress being cancelled
+// when it ends.
+pub(crate) struct Transfers {
+    config: FileTransfer,
+    sender: Outbox,
+    identity: Option<String>,
+    // Given the uploaded files.
+    uid: Option<u32>,
+    uploads: HashMap<u32, Upload>,
+    downloads: HashMap<u32, JoinHandle<()>>,
+}
+
+impl Transfers {
+    pub(crate) fn new(
+        config: FileTransfer,
+        sender: Outbox,
+        identity: Option<String>,
+        uid: Option<u32>,
+    ) -> Self {
+        Transfers {
+            config,
+            sender,
+            identity,
+            uid,
+            uploads: HashMap::new(),
+            downloads: HashMap::new(),
+        }
+    }
+
+    pub(crate) async fn start(&mut self, transfer: Transfer) {
+        let id = transfer.id;
+        self.downloads.retain(|_, task| !task.is_finished());
+        if self.uploads.contains_key(&id) || self.downloads.contains_key(&id) {
+            return send_status(&self.sender, failed(id, 0, "transfer id in use"));
+        }
+        let res = match transfer.direction {
+            TransferDirection::Upload => self.upload(transfer).await,
+            TransferDirection::Download => self.download(transfer).await,
+        };
+        if let Err(e) = res {
+            send_status(&self.sender, failed(id, 0, &e));
+        }
+    }
+
+    fn check(&self, transfer: &Transfer) -> Result<PathBuf, String> {
+        let path = self.config.resolve(&transfer.path)?;
+        if let Some(ref policy) = self.config.policy {
+            policy(&path, transfer.direction, self.identity.as_deref())?;
+        }
+        Ok(path)
+    }
+
+    async fn upload(&mut self, transfer: Transfer) -> Result<(), String> {
+        let path = self.check(&transfer)?;
+        let size = transfer.size.ok_or("uploads must have a size")?;
+        self.config.check_size(size)?;
+        let name = path
+            .file_name()
+            .ok_or_else(|| format!("{} is not a file", path.display()))?;
+        let partial = path.with_file_name(format!(".{}.upload", name.to_string_lossy()));
+        // Left over by an interrupted upload, or planted: links are removed,
+        // not followed, and a new one showing up meanwhile fails the upload.
+        let _ = tokio::fs::remove_file(&partial).await;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&partial)
+            .await
+            .map_err(|e| format!("{}: {}", partial.display(), e))?;
+        let upload = Upload {
+            file,
+            partial,
+            path,
+            size,
+            written: 0,
+        };
+        send_status(
+            &self.sender,
+            status(transfer.id, TransferState::Started, 0, size),
+        );
+        self.uploads.insert(transfer.id, upload);
+        if size == 0 {
+            self.chunk(transfer.id, &[]).await;
+        }
+        Ok(())
+    }
+
+    // The next chunk of an upload, ignored for transfers no longer going.
+    pub(crate) async fn chunk(&mut self, id: u32, data: &[u8]) {
+        let upload = match self.uploads.get_mut(&id) {
+            Some(upload) => upload,
+            None => return,
+        };
+        let res = match upload.written + data.len() as u64 {
+            written if written > upload.size => Err("more data than its size".to_owned()),
+            written => {
+                upload.written = written;
+                upload.file.write_all(data).await.map_err(|e| e.to_string())
+            }
+        };
+        let (written, size) = (upload.written, upload.size);
+        let res = match res {
+            Ok(()) if written == size => self.finish(id).await.map(|()| TransferState::Done),
+            Ok(()) => Ok(TransferState::Progress),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(state) => send_status(&self.sender, status(id, state, written, size)),
+            Err(e) => {
+                self.stop(id);
+                send_status(&self.sender, failed(id, written, &e));
+            }
+        }
+    }
+
+    async fn finish(&mut self, id: u32) -> Result<(), String> {
+        let upload = match self.uploads.get_mut(&id) {
+            Some(upload) => upload,
+            None => return Ok(()),
+        };
+        upload.file.sync_all().await.map_err(|e| e.to_string())?;
+        if let Some(uid) = self.uid {
+            nix::unistd::fchown(upload.file.as_raw_fd(), Some(Uid::from_raw(uid)), None)
+                .map_err(|e| e.to_string())?;
+        }
+        tokio::fs::rename(&upload.partial, &upload.path)
+            .await
+            .map_err(|e| format!("{}: {}", upload.path.display(), e))?;
+        self.uploads.remove(&id);
+        Ok(())
+    }
+
+    async fn download(&mut self, transfer: Transfer) -> Result<(), String> {
+        let path = self.check(&transfer)?;
+        let mut file = File::open(&path)
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let meta = file.metadata().await.map_err(|e| e.to_string())?;
+        if !meta.is_file() {
+            return Err(format!("{} is not a file", path.display()));
+        }
+        let size = meta.len();
+        self.config.check_size(size)?;
+        let id = transfer.id;
+        let sender = self.sender.clone();
+        let chunk_size = self.config.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1);
+        send_status(&sender, status(id, TransferState::Started, 0, size));
+        let task = tokio::spawn(async move {
+            let mut buffer = vec![0; chunk_size];
+            let mut sent = 0;
+            loop {
+                sender.drained().await;
+                let n = match file.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => return send_status(&sender, failed(id, sent, &e.to_string())),
+                };
+                let chunk = ServerMessage::TransferChunk(id, &buffer[..n]);
+                if sender.send(Message::Binary(chunk.encode())).is_err() {
+                    return;
+                }
+                sent += n as u64;
+            }
+            send_status(&sender, status(id, TransferState::Done, sent, sent));
+        });
+        self.downloads.insert(id, task);
+        Ok(())
+    }
+
+    // As the client asked, telling it once done.
+    pub(crate) fn cancel(&mut self, id: u32) {
+        if self.stop(id) {
+            send_status(&self.sender, failed(id, 0, "cancelled"));
+        }
+    }
+
+    // Removes what was written of an upload, returns whether the transfer
+    // was still going.
+    fn stop(&mut self, id: u32) -> bool {
+        if let Some(upload) = self.uploads.remove(&id) {
+            let _ = std::fs::remove_file(upload.partial);
+            return true;
+        }
+        match self.downloads.remove(&id) {
+            Some(task) => {
+                task.abort();
+                !task.is_finished()
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Transfers {
+    fn drop(&mut self) {
+        let ids: Vec<u32> = self
+            .uploads
+            .keys()
+            .chain(self.downloads.keys())
+            .copied()
+            .collect();
+        for id in ids {
+            self.stop(id);
+        }
+    }
+}
+
+fn status(id: u32, state: TransferState, transferred: u64, size: u64) -> TransferStatus {
+    TransferStatus {
+        id,
+        state,
+        transferred,
+        size: Some(size),
+        error: None,
+    }
+}
+
+fn failed(id: u32, transferred: u64, error: &str) -> TransferStatus {
+    TransferStatus {
+        id,
+        state: TransferState::Failed,
+        transferred,
+        size: None,
+        error: Some(error.to_owned()),
+    }
+}
+
+fn send_status(sender: &Outbox, status: TransferStatus) {
+    let _ = sender.send(Message::Binary(
+        ServerMessage::TransferStatus(status).encode(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_in_root() {
+        let tmp = std::env::temp_dir().join(format!("wspty-transfer-{}", std::process::id()));
+        let root = tmp.join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::create_dir_all(tmp.join("outside")).unwrap();
+        std::os::unix::fs::symlink(tmp.join("outside"), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("dir"), root.join("inside")).unwrap();
+        let transfer = FileTransfer {
+            root: Some(root.clone()),
+            ..FileTransfer::default()
+        };
+        let resolved = |path: &str| transfer.resolve(path);
+        let root = root.canonicalize().unwrap();
+        assert_eq!(resolved("dir/new"), Ok(root.join("dir/new")));
+        assert_eq!(resolved("inside/new"), Ok(root.join("dir/new")));
+        assert!(resolved("escape/new").is_err());
+        assert!(resolved("escape").is_err());
+        assert!(resolved("../outside/new").is_err());
+        assert!(resolved(&tmp.join("outside/new").to_string_lossy()).is_err());
+        assert!(resolved("missing/new").is_err());
+        std::fs::remove_dir_all(&tmp).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+// SOCKS5 and HTTP CONNECT proxies for outgoing connections, see
+// `Dialer::tunnel`. Host names are resolved by the proxy.
+
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Largest response to a CONNECT request read.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Tunnel {
+    // `proxy` is the `host:port` of the proxy server.
+    Socks5 {
+        proxy: String,
+        credentials: Option<Credentials>,
+    },
+    HttpConnect {
+        proxy: String,
+        credentials: Option<Credentials>,
+    },
+}
+
+impl Tunnel {
+    // `host:port` of the proxy server.
+    pub(crate) fn proxy(&self) -> &str {
+        match self {
+            Tunnel::Socks5 { proxy, .. } | Tunnel::HttpConnect { proxy, .. } => proxy,
+        }
+    }
+
+    // Asks the proxy at the other end of `stream` for a tunnel to
+    // `host:port`.
+    pub(crate) async fn handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), IoError> {
+        match self {
+            Tunnel::Socks5 { credentials, .. } => {
+                socks5_handshake(stream, host, port, credentials.as_ref()).await
+            }
+            Tunnel::HttpConnect { credentials, .. } => {
+                http_connect(stream, host, port, credentials.as_ref()).await
+            }
+        }
+    }
+}
+
+fn proxy_error(message: &str) -> IoError {
+    IoError::new(ErrorKind::ConnectionRefused, message.to_owned())
+}
+
+// See RFC 1928 and RFC 1929 for the username/password authentication.
+async fn socks5_handshake(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<&Credentials>,
+) -> Result<(), IoError> {
+    let method = if credentials.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(proxy_error(
+            "socks5 proxy refused the authentication method",
+        ));
+    }
+    if let Some(credentials) = credentials {
+        let (username, password) = (
+            credentials.username.as_bytes(),
+            credentials.password.as_bytes(),
+        );
+        if username.len() > 255 || password.len() > 255 {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "socks5 credentials too long",
+            ));
+        }
+        let mut request = vec![1, username.len() as u8];
+        request.extend_from_slice(username);
+        request.push(password.len() as u8);
+        request.extend_from_slice(password);
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(proxy_error("socks5 proxy refused the credentials"));
+        }
+    }
+
+    if host.len() > 255 {
+        return Err(IoError::new(ErrorKind::InvalidInput, "host name too long"));
+    }
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 || reply[1] != 0 {
+        return Err(proxy_error(&format!(
+            "socks5 proxy failed to connect (reply {})",
+            reply[1]
+        )));
+    }
+    // Skip the bound address and port.
+    let len = match reply[3] {
+        1 => 4,
+        3 => stream.read_u8().await? as usize,
+        4 => 16,
+        _ => return Err(proxy_error("invalid socks5 reply")),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn http_connect(
+    stream: &mut TcpStream,
+    host: &str,
+    port: u16,
+    credentials: Option<&Credentials>,
+) -> Result<(), IoError> {
+    let target = if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(credentials) = credentials {
+        let basic = format!("{}:{}", credentials.username, credentials.password);
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(basic.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read byte by byte, what follows the response belongs to the tunnel.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_CONNECT_RESPONSE {
+            return Err(proxy_error("response to CONNECT too large"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut parsed = httparse::Response::new(&mut headers);
+    match parsed.parse(&response) {
+        Ok(httparse::Status::Complete(_)) if parsed.code.is_some_and(|code| code / 100 == 2) => {
+            Ok(())
+        }
+        Ok(httparse::Status::Complete(_)) => Err(proxy_error(&format!(
+            "proxy refused to connect: {} {}",
+            parsed.code.unwrap_or_default(),
+            parsed.reason.unwrap_or_default()
+        ))),
+        _ => Err(proxy_error("invalid response to CONNECT")),
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,274 @@
+use crate::ServerConfig;
+use std::io::{Error as IoError, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+
+const INDEX_HTML: &[u8] = include_bytes!("../assets/index.html");
+const WSPTY_JS: &[u8] = include_bytes!("../assets/wspty.js");
+
+const MAX_REQUEST_HEAD: usize = 8192;
+
+// What the page hook gets to look at.
+#[derive(Debug)]
+pub struct UiRequest {
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+pub type PageHook = Arc<dyn Fn(&UiRequest) -> serde_json::Value + Send + Sync>;
+
+#[derive(Clone)]
+pub struct UiConfig {
+    // Path the page is served under, e.g. "/terminal/".
+    pub mount_path: String,
+    pub index_html: Option<Arc<Vec<u8>>>,
+    pub script: Option<Arc<Vec<u8>>>,
+    // Its result is exposed to the page as `window.WSPTY_CONFIG`, which the
+    // built-in script reads a `token` from to pass along when connecting.
+    pub page_hook: Option<PageHook>,
+    // Where `metrics()` can be scraped in the Prometheus format, e.g.
+    // "/metrics", whatever the mount path. Anyone reaching the page can
+    // read them.
+    pub metrics_path: Option<String>,
+    // Where the effective configuration can be read as JSON, secrets
+    // redacted, see the `introspect` module. Anyone reaching the page can
+    // read it.
+    pub config_path: Option<String>,
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        UiConfig {
+            mount_path: "/".to_owned(),
+            index_html: None,
+            script: None,
+            page_hook: None,
+            metrics_path: None,
+            config_path: None,
+        }
+    }
+}
+
+// Sockets the request head can be read from without consuming it.
+pub(crate) trait Peek {
+    fn poll_peek(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<usize, IoError>>;
+}
+
+impl Peek for TcpStream {
+    fn poll_peek(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<usize, IoError>> {
+        TcpStream::poll_peek(self, cx, buf)
+    }
+}
+
+// Tokio has no peek for these.
+impl Peek for UnixStream {
+    fn poll_peek(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<usize, IoError>> {
+        loop {
+            match self.poll_read_ready(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let res = self.try_io(Interest::READABLE, || {
+                let unfilled = buf.initialize_unfilled();
+                let n = unsafe {
+                    libc::recv(
+                        self.as_raw_fd(),
+                        unfilled.as_mut_ptr() as *mut libc::c_void,
+                        unfilled.len(),
+                        libc::MSG_PEEK,
+                    )
+                };
+                if n < 0 {
+                    Err(IoError::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+            match res {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Ok(n) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(n));
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+}
+
+// Looks at the request head without consuming it, so that WebSocket
+// upgrades can still go through the regular handshake.
+pub(crate) async fn is_websocket_upgrade<S: Peek>(stream: &S) -> Result<bool, IoError> {
+    let mut buf = vec![0u8; MAX_REQUEST_HEAD];
+    loop {
+        let n =
+            std::future::poll_fn(|cx| stream.poll_peek(cx, &mut ReadBuf::new(&mut buf))).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf[..n]) {
+            Ok(httparse::Status::Complete(_)) => {
+                return Ok(request.headers.iter().any(|h| {
+                    h.name.eq_ignore_ascii_case("upgrade")
+                        && String::from_utf8_lossy(h.value)
+                            .trim()
+                            .eq_ignore_ascii_case("websocket")
+                }));
+            }
+            Ok(httparse::Status::Partial) if n < buf.len() => {
+                // Wait for more of the head to arrive.
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok(httparse::Status::Partial) => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "request head too large",
+                ))
+            }
+            Err(e) => return Err(IoError::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+pub(crate) async fn serve_asset<S>(mut stream: S, server: &ServerConfig) -> Result<(), IoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; MAX_REQUEST_HEAD];
+    let mut len = 0;
+    let request = loop {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        len += n;
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf[..len]) {
+            Ok(httparse::Status::Complete(_)) => {
+                let target = request.path.unwrap_or("/");
+                let (path, query) = match target.split_once('?') {
+                    Some((path, query)) => (path, Some(query.to_owned())),
+                    None => (target, None),
+                };
+                break UiRequest {
+                    path: path.to_owned(),
+                    query,
+                    headers: request
+                        .headers
+                        .iter()
+                        .map(|h| {
+                            (
+                                h.name.to_owned(),
+                                String::from_utf8_lossy(h.value).into_owned(),
+                            )
+                        })
+                        .collect(),
+                };
+            }
+            Ok(httparse::Status::Partial) if len < buf.len() => (),
+            Ok(httparse::Status::Partial) => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    "request head too large",
+                ))
+            }
+            Err(e) => return Err(IoError::new(ErrorKind::InvalidData, e)),
+        }
+    };
+
+    let config = match server.ui {
+        Some(ref ui) => ui,
+        None => return Ok(()),
+    };
+    let mount = config.mount_path.trim_end_matches('/');
+    let (status, content_type, body, location) = match request.path.strip_prefix(mount) {
+        _ if config.metrics_path.as_deref() == Some(request.path.as_str()) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            crate::metrics().prometheus().into_bytes(),
+            None,
+        ),
+        _ if config.config_path.as_deref() == Some(request.path.as_str()) => (
+            "200 OK",
+            "application/json",
+            server.effective().to_string().into_bytes(),
+            None,
+        ),
+        Some("/") | Some("/index.html") => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            index_page(config, &request),
+            None,
+        ),
+        Some("/wspty.js") => (
+            "200 OK",
+            "text/javascript",
+            config
+                .script
+                .as_ref()
+                .map_or_else(|| WSPTY_JS.to_vec(), |s| s.to_vec()),
+            None,
+        ),
+        // Relative links in the page need the trailing slash.
+        Some("") => (
+            "301 Moved Permanently",
+            "text/plain",
+            vec![],
+            Some(format!("{}/", mount)),
+        ),
+        _ => ("404 Not Found", "text/plain", b"not found".to_vec(), None),
+    };
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    if let Some(location) = location {
+        head.push_str(&format!("Location: {}\r\n", location));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await
+}
+
+fn index_page(config: &UiConfig, request: &UiRequest) -> Vec<u8> {
+    let html = config
+        .index_html
+        .as_ref()
+        .map_or(INDEX_HTML, |html| html.as_slice());
+    let hook = match config.page_hook {
+        Some(ref hook) => hook,
+        None => return html.to_vec(),
+    };
+    // Escape `<` so that the JSON can't close the script element.
+    let json = hook(request).to_string().replace('<', "\\u003c");
+    let script = format!("<script>window.WSPTY_CONFIG = {};</script>", json);
+    let html = String::from_utf8_lossy(html);
+    match html.find("</head>") {
+        Some(pos) => format!("{}{}{}", &html[..pos], script, &html[pos..]).into_bytes(),
+        None => format!("{}{}", script, html).into_bytes(),
+    }
+}
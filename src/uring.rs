@@ -0,0 +1,140 @@
+// io_uring backed reads of the pty master.
+//
+// A dedicated thread keeps a poll+read chain in flight on the master fd and
+// forwards chunks over a bounded channel, so a slow client still applies
+// backpressure to the child. Writes are small keystrokes and stay on the
+// reactor.
+
+use crate::PtyMaster;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::io::Error as IoError;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::Ordering;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::mpsc;
+
+const CHUNK_LEN: usize = 64 * 1024;
+const QUEUED_CHUNKS: usize = 16;
+
+const POLL: u64 = 0;
+const READ: u64 = 1;
+
+pub struct UringReader {
+    chunks: mpsc::Receiver<Result<Vec<u8>, IoError>>,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl UringReader {
+    pub fn new(master: &PtyMaster) -> Result<Self, IoError> {
+        let ring = IoUring::new(8)?;
+        let (sender, chunks) = mpsc::channel(QUEUED_CHUNKS);
+        let master = master.clone();
+        std::thread::Builder::new()
+            .name("wspty-uring".into())
+            .spawn(move || read_loop(ring, master, sender))?;
+        Ok(UringReader {
+            chunks,
+            pending: vec![],
+            offset: 0,
+        })
+    }
+}
+
+fn read_loop(mut ring: IoUring, master: PtyMaster, sender: mpsc::Sender<Result<Vec<u8>, IoError>>) {
+    let fd = types::Fd(master.as_raw_fd());
+    let mut buf = vec![0u8; CHUNK_LEN];
+    loop {
+        if master.closed.load(Ordering::SeqCst) {
+            return drain(&master, &mut buf, &sender);
+        }
+        // The master is non blocking, so wait for it to be readable first.
+        let poll = opcode::PollAdd::new(fd, libc::POLLIN as _)
+            .build()
+            .flags(squeue::Flags::IO_LINK)
+            .user_data(POLL);
+        let read = opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as _)
+            .build()
+            .user_data(READ);
+        unsafe {
+            let mut sq = ring.submission();
+            if sq.push(&poll).is_err() || sq.push(&read).is_err() {
+                let _ = sender.blocking_send(Err(IoError::other("submission queue full")));
+                return;
+            }
+        }
+        if let Err(e) = ring.submit_and_wait(2) {
+            let _ = sender.blocking_send(Err(e));
+            return;
+        }
+
+        let mut res = -libc::EAGAIN;
+        for cqe in ring.completion() {
+            if cqe.user_data() == READ {
+                res = cqe.result();
+            }
+        }
+        // Like the epoll path, drain what is left without the byte
+        // shutdown() wrote to wake us up.
+        if master.closed.load(Ordering::SeqCst) {
+            let n = crate::unwake(&buf[..res.max(0) as usize]);
+            if n > 0 && sender.blocking_send(Ok(buf[..n].to_vec())).is_err() {
+                return;
+            }
+            return drain(&master, &mut buf, &sender);
+        }
+        let chunk = match res {
+            n if n > 0 => Ok(buf[..n as usize].to_vec()),
+            // The slave side is gone.
+            0 => return,
+            n if n == -libc::EIO => return,
+            n if n == -libc::EAGAIN || n == -libc::EINTR || n == -libc::ECANCELED => continue,
+            n => Err(IoError::from_raw_os_error(-n)),
+        };
+        let failed = chunk.is_err();
+        if sender.blocking_send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+fn drain(master: &PtyMaster, buf: &mut [u8], sender: &mpsc::Sender<Result<Vec<u8>, IoError>>) {
+    loop {
+        let chunk = match master.drain(buf) {
+            Ok(0) => return,
+            Ok(n) => Ok(buf[..n].to_vec()),
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if sender.blocking_send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+impl AsyncRead for UringReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        if self.offset == self.pending.len() {
+            match self.chunks.poll_recv(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    self.pending = chunk;
+                    self.offset = 0;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let n = buf.remaining().min(self.pending.len() - self.offset);
+        let start = self.offset;
+        buf.put_slice(&self.pending[start..start + n]);
+        self.offset += n;
+        Poll::Ready(Ok(()))
+    }
+}
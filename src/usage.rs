@@ -0,0 +1,165 @@
+// Accounting of hosted terminal time, for billing and chargeback. With
+// `ServerConfig::accounting`, every session whose command exits gets a
+// `SessionRecord`: who ran it, with which profile, for how long, the bytes
+// of WebSocket traffic both ways and how it exited. Records are appended to
+// a CSV file, with a header line when the file is created, or to a JSON
+// lines file, or handed to a callback. Sessions migrated or handed over to
+// another server are accounted for there.
+
+use crate::SessionHandle;
+use log::error;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CSV_HEADER: &str =
+    "session_id,identity,profile,started,ended,duration,bytes_in,bytes_out,exit_code,signal\n";
+
+// Sessions finishing together append one at a time.
+static APPENDING: Mutex<()> = Mutex::new(());
+
+#[derive(Clone)]
+pub enum AccountingExport {
+    Csv(PathBuf),
+    Jsonl(PathBuf),
+    Callback(Arc<dyn Fn(&SessionRecord) + Send + Sync>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct SessionRecord {
+    pub session_id: String,
+    pub identity: Option<String>,
+    pub profile: Option<String>,
+    // In seconds since the epoch.
+    pub started: u64,
+    pub ended: u64,
+    // In milliseconds.
+    pub duration: u64,
+    // From the clients, and to them.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Quoted if need be, as RFC 4180 has it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+impl SessionRecord {
+    pub(crate) fn new(handle: &SessionHandle, status: ExitStatus, ended: SystemTime) -> Self {
+        let throughput = handle.throughput().unwrap_or_default();
+        let duration = ended.duration_since(handle.started).unwrap_or_default();
+        SessionRecord {
+            session_id: handle.session_id().to_owned(),
+            identity: handle.identity.clone(),
+            profile: handle.profile.clone(),
+            started: epoch_secs(handle.started),
+            ended: epoch_secs(ended),
+            duration: duration.as_millis() as u64,
+            bytes_in: throughput.bytes_in,
+            bytes_out: throughput.bytes_out,
+            exit_code: status.code(),
+            signal: status.signal(),
+        }
+    }
+
+    fn csv(&self) -> String {
+        let optional = |value: Option<i32>| value.map(|v| v.to_string()).unwrap_or_default();
+        let fields = [
+            csv_field(&self.session_id),
+            csv_field(self.identity.as_deref().unwrap_or_default()),
+            csv_field(self.profile.as_deref().unwrap_or_default()),
+            self.started.to_string(),
+            self.ended.to_string(),
+            self.duration.to_string(),
+            self.bytes_in.to_string(),
+            self.bytes_out.to_string(),
+            optional(self.exit_code),
+            optional(self.signal),
+        ];
+        fields.join(",") + "\n"
+    }
+}
+
+fn append(path: &Path, line: &str, header: Option<&str>) -> Result<(), std::io::Error> {
+    let _appending = APPENDING.lock().unwrap();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if let Some(header) = header.filter(|_| file.metadata().is_ok_and(|meta| meta.len() == 0)) {
+        file.write_all(header.as_bytes())?;
+    }
+    file.write_all(line.as_bytes())
+}
+
+impl AccountingExport {
+    pub(crate) fn export(&self, record: SessionRecord) {
+        let (path, line, header) = match self {
+            AccountingExport::Callback(callback) => return callback(&record),
+            AccountingExport::Csv(path) => (path.clone(), record.csv(), Some(CSV_HEADER)),
+            AccountingExport::Jsonl(path) => {
+                let line = serde_json::to_string(&record).unwrap_or_default() + "\n";
+                (path.clone(), line, None)
+            }
+        };
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = append(&path, &line, header) {
+                error!(
+                    "failed to account for session {} in {:?}: {}",
+                    record.session_id, path, e
+                );
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_lines() {
+        let mut record = SessionRecord {
+            session_id: "01HZX".to_owned(),
+            identity: Some("Doe, \"Jo\"".to_owned()),
+            profile: None,
+            started: 1700000000,
+            ended: 1700000061,
+            duration: 61250,
+            bytes_in: 120,
+            bytes_out: 48000,
+            exit_code: Some(0),
+            signal: None,
+        };
+        assert_eq!(
+            record.csv(),
+            "01HZX,\"Doe, \"\"Jo\"\"\",,1700000000,1700000061,61250,120,48000,0,\n"
+        );
+        assert_eq!(
+            record.csv().matches(',').count(),
+            CSV_HEADER.matches(',').count() + 1
+        );
+        record.identity = None;
+        record.exit_code = None;
+        record.signal = Some(9);
+        assert_eq!(
+            record.csv(),
+            "01HZX,,,1700000000,1700000061,61250,120,48000,,9\n"
+        );
+    }
+}
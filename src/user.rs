@@ -0,0 +1,79 @@
+// Commands running as another account than the server's, usually a root
+// server handing sessions to unprivileged users: `ServerConfig::run_as` for
+// all of them, or `ServerConfig::user_mapping` for each client identity.
+// Accounts are looked up when spawning, their supplementary groups too since
+// `initgroups` reads the group database and can't run between fork and
+// exec. The child then sets the groups, the gid and the uid, in that order,
+// after everything else needing the server's privileges. Commands get the
+// account's `HOME`, `USER`, `LOGNAME` and `SHELL`, start in its home unless
+// `ServerConfig::working_dir` is set, and clients asking for the default
+// shell get the account's login shell unless `default_command` is set.
+
+use nix::unistd::{Gid, Uid, User};
+use std::ffi::CString;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::process::Command;
+
+// The account to run the commands of a client as, from its identity if
+// authenticated. `None` falls back to `ServerConfig::run_as`.
+pub type UserMapping = Arc<dyn Fn(Option<&str>) -> Option<String> + Send + Sync>;
+
+// As in the password and group databases.
+#[derive(Clone, Debug)]
+pub(crate) struct Account {
+    name: String,
+    pub(crate) uid: Uid,
+    pub(crate) gid: Gid,
+    groups: Vec<libc::gid_t>,
+    pub(crate) home: PathBuf,
+    pub(crate) shell: String,
+}
+
+impl Account {
+    pub(crate) fn lookup(name: &str) -> Result<Self, IoError> {
+        let user = User::from_name(name)?
+            .ok_or_else(|| IoError::new(ErrorKind::NotFound, format!("no such user: {}", name)))?;
+        let groups = nix::unistd::getgrouplist(&CString::new(name)?, user.gid)?;
+        let shell = match user.shell.to_string_lossy() {
+            // As login(1) does.
+            shell if shell.is_empty() => "/bin/sh".to_owned(),
+            shell => shell.into_owned(),
+        };
+        Ok(Account {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            groups: groups.iter().map(|gid| gid.as_raw()).collect(),
+            home: user.dir,
+            shell,
+        })
+    }
+
+    // The login shell, `-bash` for `/bin/bash`.
+    pub(crate) fn login_name(&self) -> String {
+        let name = self.shell.rsplit('/').next().unwrap_or_default();
+        format!("-{}", name)
+    }
+
+    pub(crate) fn env(&self, cmd: &mut Command) {
+        cmd.env("HOME", &self.home)
+            .env("USER", &self.name)
+            .env("LOGNAME", &self.name)
+            .env("SHELL", &self.shell);
+    }
+
+    // Runs in the child, so it must not allocate.
+    pub(crate) fn switch(&self) -> Result<(), IoError> {
+        unsafe {
+            if libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) != 0
+                || libc::setgid(self.gid.as_raw()) != 0
+                || libc::setuid(self.uid.as_raw()) != 0
+            {
+                return Err(IoError::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+// Keeps output frames on codepoint boundaries, for frontends decoding each
+// frame on its own (with a non-streaming `TextDecoder` for instance): pty
+// reads end wherever the buffer is full, possibly in the middle of a
+// multi-byte sequence. Its first bytes are held back until the rest comes
+// with the next read. Bytes that can't start or continue a sequence go
+// through as they are, it is up to the frontend to replace them.
+
+#[derive(Default)]
+pub(crate) struct Utf8Framer {
+    held: Vec<u8>,
+}
+
+impl Utf8Framer {
+    // `data` after what was held back, up to the last complete codepoint.
+    pub(crate) fn frame(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = std::mem::take(&mut self.held);
+        out.extend_from_slice(data);
+        let incomplete = incomplete_tail(&out);
+        self.held = out.split_off(out.len() - incomplete);
+        out
+    }
+
+    // What is still held back, once no more output will complete it.
+    pub(crate) fn flush(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.held)
+    }
+}
+
+// Length of the sequence `data` ends with, if it is cut short.
+fn incomplete_tail(data: &[u8]) -> usize {
+    for back in 1..=data.len().min(3) {
+        let b = data[data.len() - back];
+        if b & 0xc0 == 0x80 {
+            continue;
+        }
+        let len = match b {
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => 1,
+        };
+        return if len > back { back } else { 0 };
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feeds `data` cut at `at`, returns the frames.
+    fn split(data: &[u8], at: &[usize]) -> Vec<Vec<u8>> {
+        let mut framer = Utf8Framer::default();
+        let mut frames = vec![];
+        let mut start = 0;
+        for &end in at.iter().chain(std::iter::once(&data.len())) {
+            frames.push(framer.frame(&data[start..end]));
+            start = end;
+        }
+        frames.push(framer.flush());
+        frames
+    }
+
+    #[test]
+    fn ascii_goes_through() {
+        assert_eq!(split(b"ls -l\r\n", &[3]), [&b"ls "[..], b"-l\r\n", b""]);
+    }
+
+    #[test]
+    fn split_sequences_are_held_back() {
+        // 2, 3 and 4 byte sequences cut after each of their bytes.
+        for text in ["é", "日", "🦀"] {
+            let data = format!("a{}b", text).into_bytes();
+            for cut in 2..data.len() - 1 {
+                let frames = split(&data, &[cut]);
+                assert_eq!(frames[0], b"a", "{} cut at {}", text, cut);
+                assert_eq!(frames[1], &data[1..], "{} cut at {}", text, cut);
+                assert!(frames[2].is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn every_frame_is_valid() {
+        let data = "über 日本語 🦀🦀 ok".as_bytes();
+        let at: Vec<usize> = (1..data.len()).collect();
+        let frames = split(data, &at);
+        for frame in &frames {
+            assert!(std::str::from_utf8(frame).is_ok(), "{:?}", frame);
+        }
+        assert_eq!(frames.concat(), data);
+    }
+
+    #[test]
+    fn sequence_split_over_several_reads() {
+        let data = "🦀".as_bytes();
+        let frames = split(data, &[1, 2, 3]);
+        assert_eq!(frames, [&b""[..], b"", b"", data, b""]);
+    }
+
+    #[test]
+    fn invalid_bytes_go_through() {
+        // A lone continuation byte, and leads that are never valid.
+        for data in [&b"a\x80"[..], b"a\xc0", b"a\xff", b"\x80\x80\x80"] {
+            assert_eq!(split(data, &[]), [data, b""]);
+        }
+    }
+
+    #[test]
+    fn cut_short_sequence_is_flushed() {
+        assert_eq!(split(b"ab\xe6\x97", &[]), [&b"ab"[..], b"\xe6\x97"]);
+    }
+}
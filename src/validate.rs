@@ -0,0 +1,452 @@
+// Startup checks for `ServerConfig::validate`, for deployments to fail
+// before the first client does: commands that can't be spawned, TLS files
+// that don't load, addresses already in use and settings that contradict
+// each other. Policies are opaque, so they are only asked about the
+// commands the configuration itself runs.
+
+use crate::env::valid_name;
+use crate::user::Account;
+use crate::{BindOptions, Mount, ServerConfig};
+use std::fmt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+// A problem with a `ServerConfig`, `field` is the path to the setting, e.g.
+// `listeners[1].addr`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn add<F: Into<String>, M: Into<String>>(&mut self, field: F, message: M) {
+        self.0.push(ConfigProblem {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+}
+
+// Whether `program` can be spawned, looking it up in `PATH` as `Command`
+// does when it has no slash.
+fn executable(program: &str) -> bool {
+    let runnable = |path: &Path| {
+        path.metadata()
+            .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+    };
+    if program.contains('/') {
+        return runnable(Path::new(program));
+    }
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| runnable(&dir.join(program))))
+}
+
+fn check_devices(problems: &mut Problems, field: &str, devices: Option<&[String]>) {
+    let devices = match devices {
+        Some(devices) => devices,
+        None => return,
+    };
+    if !nix::unistd::geteuid().is_root() {
+        problems.add(field, "a private /dev needs the server to run as root");
+    }
+    for pattern in devices {
+        if let Err(e) = crate::devices::expand(pattern) {
+            problems.add(field, e.to_string());
+        }
+    }
+}
+
+// Sockets get replaced when the server starts, anything else at their path
+// would be lost.
+fn check_socket(problems: &mut Problems, field: &str, path: &Path) {
+    match path.symlink_metadata() {
+        Ok(meta) if !meta.file_type().is_socket() => problems.add(
+            field,
+            format!("{} exists and is not a socket", path.display()),
+        ),
+        Ok(_) => (),
+        Err(_) => {
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty());
+            if parent.is_some_and(|parent| !parent.is_dir()) {
+                problems.add(field, format!("no directory for {}", path.display()));
+            }
+        }
+    }
+}
+
+pub(crate) fn check(config: &ServerConfig) -> Vec<ConfigProblem> {
+    let mut problems = Problems(vec![]);
+
+    let default_command = config.resolve_command("");
+    if !executable(default_command) {
+        problems.add(
+            "default_command",
+            format!("{} is not an executable", default_command),
+        );
+    }
+    if let Some(ref fallback) = config.fallback_shell {
+        if !executable(&fallback.command) {
+            problems.add(
+                "fallback_shell.command",
+                format!("{} is not an executable", fallback.command),
+            );
+        }
+    }
+    for (i, command) in config.output_only.iter().enumerate() {
+        if !executable(command) {
+            problems.add(
+                format!("output_only[{}]", i),
+                format!("{} is not an executable", command),
+            );
+        }
+    }
+    if let Some(ref pool) = config.pool {
+        let command = pool.command();
+        let program = config.resolve_command(&command);
+        if !executable(program) {
+            problems.add("pool", format!("{} is not an executable", program));
+        }
+        if let Some(ref policy) = config.command_policy {
+            if let Err(reason) = policy(&command, None) {
+                problems.add(
+                    "pool",
+                    format!("the command policy refuses {:?}: {}", command, reason),
+                );
+            }
+        }
+    }
+    if config.pool.is_some() && config.user_mapping.is_some() {
+        problems.add("pool", "pooled shells are not used with a user_mapping");
+    }
+    if let Some(ref name) = config.run_as {
+        let euid = nix::unistd::geteuid();
+        match Account::lookup(name) {
+            Ok(account) if account.uid != euid && !euid.is_root() => problems.add(
+                "run_as",
+                format!("the server must run as root to run commands as {}", name),
+            ),
+            Ok(_) => (),
+            Err(e) => problems.add("run_as", e.to_string()),
+        }
+    }
+    if let Some(ref dir) = config.working_dir {
+        if !dir.is_dir() {
+            problems.add(
+                "working_dir",
+                format!("{} is not a directory", dir.display()),
+            );
+        }
+    }
+    check_devices(&mut problems, "devices", config.devices.as_deref());
+    if config.require_auth && !config.authenticates() {
+        let default = !config.tcp_disabled || config.unix_socket.is_some();
+        if default && config.authenticator.is_none() {
+            problems.add(
+                "authenticator",
+                "require_auth refuses every client without an authenticator",
+            );
+        }
+        for (i, listener) in config.listeners.iter().enumerate() {
+            if listener.authenticator.is_none() {
+                problems.add(
+                    format!("listeners[{}].authenticator", i),
+                    "require_auth refuses every client without an authenticator",
+                );
+            }
+        }
+    }
+    for (name, profile) in config.profiles.iter() {
+        if let Err(e) = config.resolve_profile(name) {
+            problems.add(format!("profiles[{:?}].base", name), e);
+        }
+        let field = format!("profiles[{:?}].devices", name);
+        check_devices(&mut problems, &field, profile.devices.as_deref());
+        if let Some(ref command) = profile.default_command {
+            if !executable(command) {
+                problems.add(
+                    format!("profiles[{:?}].default_command", name),
+                    format!("{} is not an executable", command),
+                );
+            }
+        }
+        if let Some(ref dir) = profile.working_dir {
+            if !dir.is_dir() {
+                problems.add(
+                    format!("profiles[{:?}].working_dir", name),
+                    format!("{} is not a directory", dir.display()),
+                );
+            }
+        }
+    }
+    for name in config.env.keys() {
+        if !valid_name(name) {
+            problems.add("env", format!("invalid variable name {:?}", name));
+        }
+    }
+    if let Some(root) = config.file_transfer.as_ref().and_then(|t| t.root.as_ref()) {
+        if !root.is_dir() {
+            problems.add(
+                "file_transfer.root",
+                format!("{} is not a directory", root.display()),
+            );
+        }
+    }
+    if let Some(ref workspace) = config.workspace {
+        if !workspace.parent.is_dir() {
+            problems.add(
+                "workspace.parent",
+                format!("{} is not a directory", workspace.parent.display()),
+            );
+        }
+        match workspace.template {
+            Some(ref template) if !template.is_dir() => problems.add(
+                "workspace.template",
+                format!("{} is not a directory", template.display()),
+            ),
+            None if workspace.mount == Mount::Overlay => {
+                problems.add("workspace.template", "overlays need a template")
+            }
+            _ => (),
+        }
+        if workspace.mount != Mount::Plain && !nix::unistd::geteuid().is_root() {
+            problems.add(
+                "workspace.mount",
+                "mounting needs the server to run as root",
+            );
+        }
+        match workspace.quota {
+            Some(_) if matches!(workspace.mount, Mount::Tmpfs { .. }) => problems.add(
+                "workspace.quota",
+                "tmpfs workspaces are limited by their size",
+            ),
+            Some(ref quota) if quota.projects.is_empty() => {
+                problems.add("workspace.quota.projects", "no project ids")
+            }
+            Some(_) if !nix::unistd::geteuid().is_root() => {
+                problems.add("workspace.quota", "quotas need the server to run as root")
+            }
+            _ => (),
+        }
+    }
+    if let Some(ref recording) = config.recording {
+        if recording.dir.exists() && !recording.dir.is_dir() {
+            problems.add(
+                "recording.dir",
+                format!("{} is not a directory", recording.dir.display()),
+            );
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    {
+        if let Some(Err(e)) = config.tls.as_ref().map(|tls| tls.load()) {
+            problems.add("tls", format!("{:#}", e));
+        }
+        for (i, listener) in config.listeners.iter().enumerate() {
+            if let Some(Err(e)) = listener.tls.as_ref().map(|tls| tls.load()) {
+                problems.add(format!("listeners[{}].tls", i), format!("{:#}", e));
+            }
+        }
+    }
+    #[cfg(feature = "fault-injection")]
+    if let Some(ref faults) = config.faults {
+        if faults.drop_output > 100 {
+            problems.add(
+                "faults.drop_output",
+                format!("{} is not a percentage", faults.drop_output),
+            );
+        }
+    }
+    #[cfg(feature = "quic")]
+    if let Some(ref quic) = config.quic {
+        if let Err(e) = quic.load() {
+            problems.add("quic", format!("{:#}", e));
+        }
+        if let Err(e) = std::net::UdpSocket::bind(quic.addr) {
+            problems.add("quic.addr", format!("can't listen on {}: {}", quic.addr, e));
+        }
+    }
+    #[cfg(feature = "resume-routing")]
+    if let Some(ref routing) = config.resume_routing {
+        if routing.key.is_empty() {
+            problems.add("resume_routing.key", "must not be empty");
+        }
+        if routing
+            .instance
+            .as_ref()
+            .is_some_and(|instance| instance.is_empty() || instance.contains('.'))
+        {
+            problems.add("resume_routing.instance", "must be non empty, without dots");
+        }
+        for (instance, upstream) in &routing.upstreams {
+            if !upstream.starts_with("ws://") && !upstream.starts_with("wss://") {
+                problems.add(
+                    format!("resume_routing.upstreams[{:?}]", instance),
+                    format!("{:?} is not a WebSocket URL", upstream),
+                );
+            }
+        }
+    }
+
+    // Held until all are bound, so that listeners sharing addresses are
+    // caught too.
+    let mut bound = vec![];
+    let mut bind = |problems: &mut Problems, field: String, addr, options: &BindOptions| {
+        if crate::handover::inherits(addr) {
+            return;
+        }
+        match crate::bind::bind(addr, options) {
+            Ok(listener) => bound.push(listener),
+            Err(e) => problems.add(field, format!("can't listen on {}: {}", addr, e)),
+        }
+    };
+    if !config.tcp_disabled {
+        bind(
+            &mut problems,
+            "addr".to_owned(),
+            config.addr(),
+            &config.bind,
+        );
+    }
+    for (i, listener) in config.listeners.iter().enumerate() {
+        bind(
+            &mut problems,
+            format!("listeners[{}].addr", i),
+            listener.addr,
+            &listener.bind,
+        );
+    }
+    match config.unix_socket {
+        Some(ref path) => check_socket(&mut problems, "unix_socket", path),
+        None if config.tcp_disabled && config.agent.is_none() => problems.add(
+            "tcp_disabled",
+            "no listener left without a unix_socket or an agent",
+        ),
+        None => (),
+    }
+    if let Some(ref path) = config.migration_socket {
+        check_socket(&mut problems, "migration_socket", path);
+    }
+
+    if let (Some((cols, rows)), Some((max_cols, max_rows))) = (config.default_size, config.max_size)
+    {
+        if cols > max_cols || rows > max_rows {
+            problems.add("default_size", "larger than max_size");
+        }
+    }
+    if let Some(ref metrics) = config.command_metrics {
+        if !config.command_sentinels {
+            problems.add("command_metrics", "only collected with command_sentinels");
+        }
+        if let Some(Err(e)) = metrics
+            .webhook
+            .as_deref()
+            .map(crate::commands::parse_webhook)
+        {
+            problems.add("command_metrics.webhook", e);
+        }
+    }
+    if let Some(Err(e)) = config
+        .alerts
+        .as_ref()
+        .and_then(|alerts| alerts.webhook.as_deref())
+        .map(crate::commands::parse_webhook)
+    {
+        problems.add("alerts.webhook", e);
+    }
+    if config.gc.as_ref().is_some_and(|gc| gc.interval.is_zero()) {
+        problems.add("gc.interval", "must not be 0");
+    }
+    if config.compression.as_ref().is_some_and(|c| c.level > 9) {
+        problems.add("compression.level", "must be 9 at most");
+    }
+    if config.message_limits.max_fragment == Some(0) {
+        problems.add("message_limits.max_fragment", "must not be 0");
+    }
+    if let Some(ref agent) = config.agent {
+        if !agent.broker.starts_with("ws://") && !agent.broker.starts_with("wss://") {
+            problems.add(
+                "agent.broker",
+                format!("{:?} is not a WebSocket URL", agent.broker),
+            );
+        }
+        if agent.device_id.is_empty() || agent.device_id.contains('/') {
+            problems.add("agent.device_id", "must be non empty, without slashes");
+        }
+    }
+    for (i, route) in config.proxy_routes.iter().enumerate() {
+        if !route.upstream.starts_with("ws://") && !route.upstream.starts_with("wss://") {
+            problems.add(
+                format!("proxy_routes[{}].upstream", i),
+                format!("{:?} is not a WebSocket URL", route.upstream),
+            );
+        }
+        let shadowed = config.proxy_routes[..i].iter().any(|earlier| {
+            route.prefix.starts_with(&earlier.prefix)
+                && (earlier.identity.is_none() || earlier.identity == route.identity)
+        });
+        if shadowed {
+            problems.add(
+                format!("proxy_routes[{}]", i),
+                "never used, an earlier route matches the same commands",
+            );
+        }
+    }
+    if let Some(ref exec) = config.container_exec {
+        let program = exec.program();
+        if !executable(&program.to_string_lossy()) {
+            problems.add(
+                "container_exec.program",
+                format!("{} is not an executable", program.display()),
+            );
+        }
+        if exec.access.is_none() {
+            problems.add(
+                "container_exec.access",
+                "unset, every container request is refused",
+            );
+        }
+    }
+    for (i, route) in config.ssh_routes.iter().enumerate() {
+        if route.host.is_empty() {
+            problems.add(format!("ssh_routes[{}].host", i), "not a host");
+        }
+        let program = route.program.as_deref().unwrap_or("ssh".as_ref());
+        if !executable(&program.to_string_lossy()) {
+            problems.add(
+                format!("ssh_routes[{}].program", i),
+                format!("{} is not an executable", program.display()),
+            );
+        }
+        if let Some(ref key) = route.key {
+            if !key.is_file() {
+                problems.add(
+                    format!("ssh_routes[{}].key", i),
+                    format!("no such file: {}", key.display()),
+                );
+            }
+        }
+        let shadowed = config.ssh_routes[..i].iter().any(|earlier| {
+            route.prefix.starts_with(&earlier.prefix)
+                && (earlier.identity.is_none() || earlier.identity == route.identity)
+        });
+        if shadowed {
+            problems.add(
+                format!("ssh_routes[{}]", i),
+                "never used, an earlier route matches the same commands",
+            );
+        }
+    }
+    problems.0
+}
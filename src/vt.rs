@@ -0,0 +1,237 @@
+use crate::keyboard::KeyboardModes;
+use crate::sentinel::{CommandSentinels, Finished};
+use crate::sequencer::Sequencer;
+use std::time::Duration;
+use vt100::{MouseProtocolEncoding, MouseProtocolMode};
+use wspty_proto::{
+    self as proto, AlternateScreen, KeyboardFlags, MouseEncoding, MouseMode, MouseTracking,
+    ServerMessage,
+};
+
+pub(crate) const DEFAULT_FRAME_INTERVAL: Duration = Duration::from_millis(50);
+
+// Server side view of the terminal, fed with everything the child writes.
+// In frame mode the client gets periodic diffs of this screen instead of
+// the raw output.
+pub(crate) struct VtState {
+    parser: vt100::Parser,
+    // Screen as last sent to the client, only set in frame mode.
+    sent: Option<vt100::Screen>,
+    redraw: bool,
+    frame_interval: Duration,
+    keyboard: KeyboardModes,
+    // Last flags notified to the client, if it asked for them.
+    keyboard_flags: Option<u32>,
+    mouse: (MouseProtocolMode, MouseProtocolEncoding),
+    alternate_screen: bool,
+    commands: Option<CommandSentinels>,
+    sequencer: Sequencer,
+    // Set during raw passthrough, see the `toggles` module.
+    raw: bool,
+}
+
+impl VtState {
+    pub(crate) fn new(rows: u16, cols: u16) -> Self {
+        VtState {
+            parser: vt100::Parser::new(rows, cols, 0),
+            sent: None,
+            redraw: false,
+            frame_interval: DEFAULT_FRAME_INTERVAL,
+            keyboard: KeyboardModes::default(),
+            keyboard_flags: None,
+            mouse: Default::default(),
+            alternate_screen: false,
+            commands: None,
+            sequencer: Sequencer::default(),
+            raw: false,
+        }
+    }
+
+    pub(crate) fn process(&mut self, data: &[u8]) {
+        match self.commands {
+            Some(ref mut commands) => commands.process(data, &mut self.parser),
+            None => self.parser.process(data),
+        }
+        self.keyboard.process(data);
+    }
+
+    pub(crate) fn track_commands(&mut self) {
+        self.commands.get_or_insert_with(CommandSentinels::default);
+    }
+
+    pub(crate) fn set_keyboard_protocol(&mut self, kitty: bool) {
+        // Forces a first notification.
+        self.keyboard_flags = kitty.then(|| !self.keyboard.flags());
+    }
+
+    // Control frames about mode changes since the last call. In frame mode
+    // this includes the keyboard protocol sequences the diffs don't carry.
+    pub(crate) fn take_notifications(&mut self) -> Vec<Vec<u8>> {
+        let mut frames = vec![];
+        let sequences = self.keyboard.take_sequences();
+        if self.frame_mode() && !sequences.is_empty() {
+            frames.push(proto::frame(proto::OUTPUT, &sequences));
+        }
+        let flags = self.keyboard.flags();
+        if let Some(ref mut notified) = self.keyboard_flags {
+            if *notified != flags {
+                *notified = flags;
+                frames.push(ServerMessage::KeyboardFlags(KeyboardFlags { flags }).encode());
+            }
+        }
+        let screen = self.parser.screen();
+        let mouse = (
+            screen.mouse_protocol_mode(),
+            screen.mouse_protocol_encoding(),
+        );
+        if mouse != self.mouse {
+            self.mouse = mouse;
+            frames.push(ServerMessage::MouseMode(mouse_mode(mouse)).encode());
+        }
+        let active = screen.alternate_screen();
+        if active != self.alternate_screen {
+            self.alternate_screen = active;
+            frames.push(ServerMessage::AlternateScreen(AlternateScreen { active }).encode());
+        }
+        if let Some(ref mut commands) = self.commands {
+            for event in commands.take_events() {
+                frames.push(ServerMessage::Command(event).encode());
+            }
+        }
+        frames
+    }
+
+    // Commands done since the last call, see the `commands` module.
+    pub(crate) fn take_finished(&mut self) -> Vec<Finished> {
+        match self.commands {
+            Some(ref mut commands) => commands.take_finished(),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn resize(&mut self, rows: u16, cols: u16) {
+        self.parser.screen_mut().set_size(rows, cols);
+        self.redraw = true;
+    }
+
+    // As (rows, cols).
+    pub(crate) fn size(&self) -> (u16, u16) {
+        self.parser.screen().size()
+    }
+
+    pub(crate) fn frame_mode(&self) -> bool {
+        self.sent.is_some()
+    }
+
+    pub(crate) fn frame_interval(&self) -> Duration {
+        self.frame_interval
+    }
+
+    // The raw stream got the client in sync with the parser so far, so
+    // diffs are computed from the current screen on. When leaving frame
+    // mode the pending diff is returned so the client catches up before raw
+    // output resumes. There is no frame mode during raw passthrough.
+    pub(crate) fn set_frame_mode(
+        &mut self,
+        enabled: bool,
+        interval: Option<Duration>,
+    ) -> Option<Vec<u8>> {
+        if enabled && !self.raw {
+            if self.sent.is_none() {
+                self.sent = Some(self.parser.screen().clone());
+                self.redraw = false;
+            }
+            self.frame_interval = interval.unwrap_or(DEFAULT_FRAME_INTERVAL);
+            None
+        } else {
+            let frame = self.take_frame();
+            self.sent = None;
+            self.sequencer.resync();
+            frame
+        }
+    }
+
+    pub(crate) fn raw(&self) -> bool {
+        self.raw
+    }
+
+    // Raw passthrough leaves frame mode, the pending diff being returned as
+    // when the client does. Output isn't processed while it lasts, so the
+    // screen misses it and the sequencer starts over after.
+    pub(crate) fn set_raw(&mut self, enabled: bool) -> Option<Vec<u8>> {
+        let frame = if enabled {
+            self.set_frame_mode(false, None)
+        } else {
+            self.sequencer.resync();
+            None
+        };
+        self.raw = enabled;
+        frame
+    }
+
+    // Where the raw output sent leaves off, see the `sequencer` module.
+    pub(crate) fn sequencer(&mut self) -> &mut Sequencer {
+        &mut self.sequencer
+    }
+
+    // Escape sequences redrawing the whole screen.
+    pub(crate) fn snapshot(&mut self) -> Vec<u8> {
+        let screen = self.parser.screen();
+        if let Some(sent) = self.sent.as_mut() {
+            *sent = screen.clone();
+            self.redraw = false;
+        }
+        screen.state_formatted()
+    }
+
+    // What the screen shows as text, a line per row.
+    pub(crate) fn text(&self) -> String {
+        self.parser.screen().contents()
+    }
+
+    // The text of the cursor's row, up to the cursor.
+    pub(crate) fn cursor_line(&self) -> String {
+        let screen = self.parser.screen();
+        let (row, col) = screen.cursor_position();
+        screen.contents_between(row, 0, row, col)
+    }
+
+    // See the `html` module.
+    pub(crate) fn html(&mut self) -> String {
+        crate::html::render(self.parser.screen_mut())
+    }
+
+    // Escape sequences turning the last sent screen into the current one.
+    pub(crate) fn take_frame(&mut self) -> Option<Vec<u8>> {
+        let sent = self.sent.as_mut()?;
+        let screen = self.parser.screen();
+        let diff = if self.redraw {
+            self.redraw = false;
+            screen.state_formatted()
+        } else {
+            screen.state_diff(sent)
+        };
+        if diff.is_empty() {
+            return None;
+        }
+        *sent = screen.clone();
+        Some(diff)
+    }
+}
+
+fn mouse_mode((mode, encoding): (MouseProtocolMode, MouseProtocolEncoding)) -> MouseMode {
+    MouseMode {
+        tracking: match mode {
+            MouseProtocolMode::None => MouseTracking::None,
+            MouseProtocolMode::Press => MouseTracking::Press,
+            MouseProtocolMode::PressRelease => MouseTracking::PressRelease,
+            MouseProtocolMode::ButtonMotion => MouseTracking::ButtonMotion,
+            MouseProtocolMode::AnyMotion => MouseTracking::AnyMotion,
+        },
+        encoding: match encoding {
+            MouseProtocolEncoding::Default => MouseEncoding::Default,
+            MouseProtocolEncoding::Utf8 => MouseEncoding::Utf8,
+            MouseProtocolEncoding::Sgr => MouseEncoding::Sgr,
+        },
+    }
+}
@@ -0,0 +1,258 @@
+// Throwaway directories, one per session, made its working directory and
+// `HOME`, and removed once its command exited. They start as a copy of the
+// template if any, or on a tmpfs to keep them off the disk and bound in
+// size, or as an overlay on the template to share it without copying,
+// mounts needing the server to run as root. Sessions opened over SSH or in
+// containers don't get one, nothing local of theirs would use it. What they
+// can write is bounded by the tmpfs size, or a quota otherwise, see the
+// `quota` module, and `Session::disk_usage` tells how much they did.
+
+use crate::quota::Quota;
+use crate::user::Account;
+use log::warn;
+use nix::mount::{MntFlags, MsFlags};
+use std::fs;
+use std::io::Error as IoError;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_WORKSPACE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mount {
+    // A plain directory.
+    #[default]
+    Plain,
+    // Of at most this many bytes, half the memory if unset.
+    Tmpfs {
+        size: Option<u64>,
+    },
+    // On the template, which must be set.
+    Overlay,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Workspace {
+    // Where workspaces are made.
+    pub parent: PathBuf,
+    // What new workspaces have in them.
+    pub template: Option<PathBuf>,
+    pub mount: Mount,
+    // Not for tmpfs workspaces.
+    pub quota: Option<Quota>,
+}
+
+// A workspace in use, removed with `remove`.
+pub(crate) struct Provisioned {
+    // Made for the session, where mounts are.
+    dir: PathBuf,
+    // The directory given to the session.
+    pub(crate) path: PathBuf,
+    mount: Mount,
+    // Of the quota, if any.
+    project: Option<u32>,
+}
+
+impl Workspace {
+    // A new workspace, for commands running as `account` if set.
+    pub(crate) fn provision(&self, account: Option<&Account>) -> Result<Provisioned, IoError> {
+        let name = format!(
+            "wspty-{}-{}",
+            std::process::id(),
+            NEXT_WORKSPACE.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = self.parent.join(name);
+        fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let mut provisioned = Provisioned {
+            path: dir.clone(),
+            dir,
+            mount: Mount::Plain,
+            project: None,
+        };
+        match self.set_up(&mut provisioned, account) {
+            Ok(()) => Ok(provisioned),
+            Err(e) => {
+                provisioned.remove();
+                Err(e)
+            }
+        }
+    }
+
+    fn set_up(
+        &self,
+        provisioned: &mut Provisioned,
+        account: Option<&Account>,
+    ) -> Result<(), IoError> {
+        let dir = provisioned.dir.clone();
+        match self.mount {
+            Mount::Plain => (),
+            Mount::Tmpfs { size } => {
+                let data = match size {
+                    Some(size) => format!("mode=0700,size={}", size),
+                    None => "mode=0700".to_owned(),
+                };
+                nix::mount::mount(
+                    Some("tmpfs"),
+                    &dir,
+                    Some("tmpfs"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                    Some(data.as_str()),
+                )?;
+                provisioned.mount = self.mount;
+            }
+            Mount::Overlay => {
+                let template = self.template.as_deref().ok_or_else(|| {
+                    IoError::new(std::io::ErrorKind::InvalidInput, "no template to overlay")
+                })?;
+                let (upper, work, merged) =
+                    (dir.join("upper"), dir.join("work"), dir.join("merged"));
+                for dir in [&upper, &work, &merged] {
+                    fs::create_dir(dir)?;
+                }
+                let data = format!(
+                    "lowerdir={},upperdir={},workdir={}",
+                    template.display(),
+                    upper.display(),
+                    work.display()
+                );
+                nix::mount::mount(
+                    Some("overlay"),
+                    &merged,
+                    Some("overlay"),
+                    MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                    Some(data.as_str()),
+                )?;
+                provisioned.path = merged;
+                provisioned.mount = self.mount;
+            }
+        }
+        // Before the copy, for the template to count.
+        if let Some(ref quota) = self.quota {
+            provisioned.project = Some(quota.apply(&provisioned.written())?);
+        }
+        if let (Some(template), false) = (&self.template, self.mount == Mount::Overlay) {
+            copy_tree(template, &provisioned.path)?;
+        }
+        match account {
+            // Going through the overlay would copy the whole template up,
+            // its files keep their owners.
+            Some(account) if self.mount == Mount::Overlay => {
+                for path in [dir.clone(), dir.join("upper"), dir.join("work")] {
+                    chown(&path, account)?;
+                }
+                chown(&provisioned.path, account)?;
+            }
+            Some(account) => chown_tree(&dir, account)?,
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+impl Provisioned {
+    // Where the session's writes end up.
+    fn written(&self) -> PathBuf {
+        match self.mount {
+            Mount::Overlay => self.dir.join("upper"),
+            _ => self.path.clone(),
+        }
+    }
+
+    // Bytes written by the session, the copy of the template included.
+    pub(crate) fn usage(&self) -> Result<u64, IoError> {
+        if let Some(project) = self.project {
+            return crate::quota::usage(&self.dir, project);
+        }
+        match self.mount {
+            Mount::Tmpfs { .. } => {
+                let stat = nix::sys::statvfs::statvfs(&self.dir)?;
+                let used = stat.blocks() - stat.blocks_free();
+                Ok(used as u64 * stat.fragment_size() as u64)
+            }
+            _ => tree_size(&self.written()),
+        }
+    }
+
+    // Unmounts and deletes the workspace. What was mounted and can't be
+    // unmounted is left alone, not to delete through the mount.
+    pub(crate) fn remove(&self) {
+        let mounted = match self.mount {
+            Mount::Plain => None,
+            Mount::Tmpfs { .. } => Some(&self.dir),
+            Mount::Overlay => Some(&self.path),
+        };
+        if let Some(mounted) = mounted {
+            if let Err(e) = nix::mount::umount2(mounted, MntFlags::MNT_DETACH) {
+                warn!("failed to unmount workspace {}: {}", mounted.display(), e);
+                return;
+            }
+        }
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("failed to remove workspace {}: {}", self.dir.display(), e);
+        }
+        if let Some(project) = self.project {
+            if let Err(e) = crate::quota::release(&self.parent(), project) {
+                warn!("failed to lift the quota of project {}: {}", project, e);
+            }
+        }
+    }
+
+    fn parent(&self) -> PathBuf {
+        self.dir.parent().unwrap_or(&self.dir).to_owned()
+    }
+}
+
+// Copies the files, directories and symlinks of `from` into `to`, with
+// their permissions.
+fn copy_tree(from: &Path, to: &Path) -> Result<(), IoError> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            fs::create_dir(&target)?;
+            copy_tree(&source, &target)?;
+            fs::set_permissions(&target, entry.metadata()?.permissions())?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&source)?, &target)?;
+        } else if file_type.is_file() {
+            fs::copy(&source, &target)?;
+        }
+    }
+    Ok(())
+}
+
+// Of its files, as `du --apparent-size` would count.
+fn tree_size(path: &Path) -> Result<u64, IoError> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += tree_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+fn chown(path: &Path, account: &Account) -> Result<(), IoError> {
+    nix::unistd::fchownat(
+        None,
+        path,
+        Some(account.uid),
+        Some(account.gid),
+        nix::unistd::FchownatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+fn chown_tree(path: &Path, account: &Account) -> Result<(), IoError> {
+    chown(path, account)?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_tree(&entry?.path(), account)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,221 @@
+// Round trips between the client and the server halves, over a loopback
+// listener of the test's own.
+
+use futures::StreamExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use wspty::{Clock, Profile, RepeatLimit, ServerConfig, Sleep, Timeouts, WsPtyClient};
+use wspty_proto::{Exit, Signal, SpawnRequest};
+
+async fn serve(config: ServerConfig) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let config = Arc::new(config);
+    tokio::spawn(async move {
+        while let Ok((stream, peer)) = listener.accept().await {
+            let config = config.clone();
+            tokio::spawn(async move {
+                let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let _ = wspty::serve_pty(ws, peer, None, config).await;
+            });
+        }
+    });
+    url
+}
+
+fn command(cmd: &[&str]) -> SpawnRequest {
+    SpawnRequest {
+        cmd: cmd.iter().map(|word| word.to_string()).collect(),
+        ..Default::default()
+    }
+}
+
+// Everything the session wrote, and how it ended.
+async fn finish(client: &mut WsPtyClient) -> (String, std::io::Result<Exit>) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let output: Vec<Vec<u8>> = client.on_output().collect().await;
+        let output = String::from_utf8_lossy(&output.concat()).into_owned();
+        (output, client.wait_exit().await)
+    })
+    .await
+    .expect("the session didn't end")
+}
+
+// Appends the output to `output` until it contains `until`.
+async fn read_until(client: &mut WsPtyClient, output: &mut String, until: &str) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !output.contains(until) {
+            let data = client.on_output().next().await.expect("the session ended");
+            output.push_str(&String::from_utf8_lossy(&data));
+        }
+    })
+    .await
+    .expect("no such output");
+}
+
+#[tokio::test]
+async fn output_and_exit_code() {
+    let url = serve(ServerConfig::default()).await;
+    let mut client = WsPtyClient::connect(url).await.unwrap();
+    client
+        .spawn(&command(&["sh", "-c", "echo hello; exit 3"]))
+        .await
+        .unwrap();
+    let (output, exit) = finish(&mut client).await;
+    assert!(output.contains("hello"), "{:?}", output);
+    assert_eq!(exit.unwrap().code, Some(3));
+}
+
+#[tokio::test]
+async fn input() {
+    let url = serve(ServerConfig::default()).await;
+    let mut client = WsPtyClient::connect(url).await.unwrap();
+    client.spawn(&command(&["/bin/sh"])).await.unwrap();
+    client.write(b"echo $((6 * 7))\r").await.unwrap();
+    client.write(b"exit\r").await.unwrap();
+    let (output, exit) = finish(&mut client).await;
+    assert!(output.contains("\r\n42\r\n"), "{:?}", output);
+    assert_eq!(exit.unwrap().code, Some(0));
+}
+
+#[tokio::test]
+async fn resize() {
+    let url = serve(ServerConfig::default()).await;
+    let mut client = WsPtyClient::connect(url).await.unwrap();
+    client.spawn(&command(&["/bin/sh"])).await.unwrap();
+    client.resize(100, 30).await.unwrap();
+    client.write(b"stty size; exit\r").await.unwrap();
+    let (output, _) = finish(&mut client).await;
+    assert!(output.contains("30 100"), "{:?}", output);
+}
+
+#[tokio::test]
+async fn signal() {
+    let url = serve(ServerConfig::default()).await;
+    let mut client = WsPtyClient::connect(url).await.unwrap();
+    client.spawn(&command(&["sleep", "30"])).await.unwrap();
+    client.signal(Signal::Term).await.unwrap();
+    let (_, exit) = finish(&mut client).await;
+    assert_eq!(exit.unwrap().signal, Some(libc::SIGTERM));
+}
+
+#[tokio::test]
+async fn refused_command() {
+    let config = ServerConfig {
+        command_policy: Some(Arc::new(|command: &str, _: Option<&str>| {
+            Err(format!("{} is not allowed", command))
+        })),
+        ..Default::default()
+    };
+    let url = serve(config).await;
+    let mut client = WsPtyClient::connect(url).await.unwrap();
+    client.spawn(&command(&["top"])).await.unwrap();
+    let (output, exit) = finish(&mut client).await;
+    assert!(output.is_empty(), "{:?}", output);
+    let error = exit.unwrap_err();
+    assert!(
+        error.to_string().contains("top is not allowed"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn self_test() {
+    let mut config = ServerConfig {
+        default_command: Some("/bin/sh".to_owned()),
+        ..Default::default()
+    };
+    let refusing = Profile {
+        default_command: Some("/bin/false".to_owned()),
+        ..Default::default()
+    };
+    config.profiles.insert("refusing".to_owned(), refusing);
+    wspty::self_test(&config, None).await.unwrap();
+    assert!(wspty::self_test(&config, Some("refusing")).await.is_err());
+    assert!(wspty::self_test(&config, Some("missing")).await.is_err());
+}
+
+// Driven by the test, for minutes of idling not to take minutes.
+struct ManualClock {
+    start: Instant,
+    elapsed: watch::Sender<Duration>,
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let start = self.start;
+        let mut elapsed = self.elapsed.subscribe();
+        Box::pin(async move {
+            let _ = elapsed
+                .wait_for(|elapsed| start + *elapsed >= deadline)
+                .await;
+        })
+    }
+}
+
+#[tokio::test]
+async fn idle_timeout() {
+    let clock = Arc::new(ManualClock {
+        start: Instant::now(),
+        elapsed: watch::channel(Duration::ZERO).0,
+    });
+    let config = ServerConfig {
+        timeouts: Timeouts {
+            idle: Some(Duration::from_secs(300)),
+            ..Default::default()
+        },
+        clock: Some(clock.clone()),
+        ..Default::default()
+    };
+    let url = serve(config).await;
+    let mut client = WsPtyClient::connect(url).await.unwrap();
+    client
+        .spawn(&command(&["sh", "-c", "echo ready; sleep 1000"]))
+        .await
+        .unwrap();
+    client.on_output().next().await.unwrap();
+    clock.elapsed.send_replace(Duration::from_secs(301));
+    let (_, exit) = finish(&mut client).await;
+    let error = exit.unwrap_err();
+    assert!(error.to_string().contains("idle_timeout"), "{}", error);
+}
+
+#[tokio::test]
+async fn repeats_end_on_the_clock() {
+    let clock = Arc::new(ManualClock {
+        start: Instant::now(),
+        elapsed: watch::channel(Duration::ZERO).0,
+    });
+    let config = ServerConfig {
+        repeated_lines: Some(RepeatLimit {
+            after: 2,
+            within: Duration::from_secs(60),
+        }),
+        clock: Some(clock.clone()),
+        ..Default::default()
+    };
+    let url = serve(config).await;
+    let mut client = WsPtyClient::connect(url).await.unwrap();
+    client
+        .spawn(&command(&[
+            "sh",
+            "-c",
+            "printf 'err\\n%.0s' 1 2 3 4 5; sleep 1000",
+        ]))
+        .await
+        .unwrap();
+    // The lines come in one write, so all of them are seen at the start.
+    let mut output = String::new();
+    read_until(&mut client, &mut output, "err\r\nerr\r\n").await;
+    // The run is over once no repeat came for `within`.
+    clock.elapsed.send_replace(Duration::from_secs(61));
+    read_until(&mut client, &mut output, "repeated 3 times").await;
+}
@@ -0,0 +1,1239 @@
+// Opcodes, message payloads and framing of the wspty protocol.
+//
+// Every WebSocket binary message starts with an opcode byte. Control
+// payloads are JSON, terminal data is sent as is. This crate is `no_std`
+// (it only needs `alloc`) so that clients compiled to WASM can share it.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+pub mod predict;
+pub mod protocol;
+pub mod reconnect;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+// Client to server opcodes.
+pub const INPUT: u8 = 0;
+pub const RESIZE: u8 = 1;
+pub const PING: u8 = 2;
+pub const FRAME_MODE: u8 = 3;
+pub const INTEGRITY_MODE: u8 = 4;
+pub const RETRANSMIT: u8 = 5;
+// Text committed by an input method, as UTF-8.
+pub const COMPOSITION: u8 = 6;
+pub const KEYBOARD_PROTOCOL: u8 = 7;
+pub const THEME: u8 = 8;
+pub const PAUSE: u8 = 9;
+// Only valid in place of the command message, see `DryRun`.
+pub const DRY_RUN: u8 = 10;
+pub const TRACE: u8 = 11;
+pub const ENVIRONMENT: u8 = 12;
+// Only valid before the command message, see `Auth`.
+pub const AUTH: u8 = 13;
+// Only valid in place of the command message, see `Resume`.
+pub const RESUME: u8 = 14;
+// Multiplexing, see `Open`.
+pub const OPEN: u8 = 15;
+pub const CLOSE_CHANNEL: u8 = 16;
+pub const LIST_CHANNELS: u8 = 17;
+pub const CLIENT_CHANNEL: u8 = 18;
+// A resize without JSON, see `encode_raw_resize`.
+pub const RAW_RESIZE: u8 = 19;
+pub const SIGNAL: u8 = 20;
+// Shared sessions, see `Participants`.
+pub const GRANT: u8 = 21;
+pub const COMPRESSION: u8 = 22;
+// Recent output, answered with `SCROLLBACK`, see `Replay`.
+pub const REPLAY: u8 = 23;
+pub const LINE_INPUT: u8 = 24;
+// Files sent beside the terminal, see `Transfer`.
+pub const TRANSFER: u8 = 25;
+pub const CLIENT_CHUNK: u8 = 26;
+pub const CANCEL_TRANSFER: u8 = 27;
+// Only valid in place of the command message, see `Attach`.
+pub const ATTACH: u8 = 28;
+// Session features switched at runtime, answered with `FEATURES`.
+pub const TOGGLE: u8 = 29;
+// Only valid before the command message, see `Challenge`.
+pub const CHALLENGE_RESPONSE: u8 = 30;
+// Only valid in place of the command message, see `Control`.
+pub const CONTROL: u8 = 31;
+// Only valid from the session's client, see `Handoff`.
+pub const HANDOFF: u8 = 32;
+
+// Server to client opcodes.
+pub const OUTPUT: u8 = 0;
+pub const PONG: u8 = 1;
+pub const CHECKED_OUTPUT: u8 = 2;
+pub const KEYBOARD_FLAGS: u8 = 3;
+pub const MOUSE_MODE: u8 = 4;
+pub const ALTERNATE_SCREEN: u8 = 5;
+pub const DECISION: u8 = 6;
+pub const QUEUE_POSITION: u8 = 7;
+pub const COMMAND: u8 = 8;
+pub const TRACE_REPORT: u8 = 9;
+pub const CPU_USAGE: u8 = 10;
+pub const RESIZED: u8 = 11;
+pub const EXIT: u8 = 12;
+pub const SESSION: u8 = 13;
+pub const SERVER_CHANNEL: u8 = 14;
+pub const CHANNELS: u8 = 15;
+pub const CHANNEL_CLOSED: u8 = 16;
+pub const JOB: u8 = 17;
+pub const PARTICIPANTS: u8 = 18;
+// Output as part of a raw DEFLATE stream, see `Compression`.
+pub const COMPRESSED_OUTPUT: u8 = 19;
+pub const PRESENCE: u8 = 20;
+pub const SCROLLBACK: u8 = 21;
+// Output of one of the command's streams, for commands run without a pty on
+// servers keeping their stderr apart. The payload is the stream id, `STDOUT`
+// or `STDERR`, then the data.
+pub const STREAM_OUTPUT: u8 = 22;
+pub const PROGRESS: u8 = 23;
+pub const TRANSFER_STATUS: u8 = 24;
+pub const SERVER_CHUNK: u8 = 25;
+pub const SHARED: u8 = 26;
+pub const FEATURES: u8 = 27;
+pub const CHALLENGE: u8 = 28;
+pub const DENIED: u8 = 29;
+pub const NOTIFICATION: u8 = 30;
+pub const PROBE_FAILED: u8 = 31;
+pub const CONTROL_CHANNEL: u8 = 32;
+pub const MALFORMED: u8 = 33;
+
+pub const STDOUT: u8 = 1;
+pub const STDERR: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowSize {
+    pub cols: u16,
+    pub rows: u16,
+    #[serde(default)]
+    pub xpixel: u16,
+    #[serde(default)]
+    pub ypixel: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameMode {
+    pub enabled: bool,
+    // Milliseconds between two screen diffs.
+    #[serde(default)]
+    pub interval: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntegrityMode {
+    pub enabled: bool,
+}
+
+// Inclusive range of checked output sequence numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retransmit {
+    pub from: u64,
+    pub to: u64,
+}
+
+// Sent by clients able to encode keys following the kitty keyboard
+// protocol flags, to get `KeyboardFlags` updates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyboardProtocol {
+    pub kitty: bool,
+}
+
+// Asks for the output in `COMPRESSED_OUTPUT` messages from then on, on
+// servers allowing it. Their payloads make up one raw DEFLATE stream
+// (RFC 1951), each ending with a sync flush so that it can be fed as is to
+// a streaming inflater, like `DecompressionStream("deflate-raw")` in
+// browsers. Enabling it again starts a new stream. Frames under the
+// server's minimum size still come as `OUTPUT`, and are not part of the
+// stream. Output is left uncompressed in integrity mode, and for clients
+// using the `protocol` envelope.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compression {
+    pub enabled: bool,
+}
+
+// While paused the server stops reading the pty, so the child blocks once
+// the kernel buffer is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pause {
+    pub paused: bool,
+}
+
+// Has the server hold input back until a line is complete, for line based
+// clients and links too slow for remote echo. The held line is edited with
+// backspace (DEL or ^H) and erased with ^U, which aren't written to the pty,
+// the client echoing it as it sees fit. Other control characters, tabs and
+// escape sequences included, are written right away with the line held
+// before them, disabling it writes the line too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineInput {
+    pub enabled: bool,
+}
+
+// Signals clients can send to what runs in the foreground of the terminal,
+// named as in `kill -l`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Signal {
+    #[serde(rename = "SIGINT")]
+    Int,
+    #[serde(rename = "SIGTERM")]
+    Term,
+    #[serde(rename = "SIGHUP")]
+    Hup,
+    #[serde(rename = "SIGQUIT")]
+    Quit,
+    #[serde(rename = "SIGKILL")]
+    Kill,
+    #[serde(rename = "SIGTSTP")]
+    Tstp,
+    #[serde(rename = "SIGCONT")]
+    Cont,
+    #[serde(rename = "SIGUSR1")]
+    Usr1,
+    #[serde(rename = "SIGUSR2")]
+    Usr2,
+}
+
+impl Signal {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "SIGINT" => Signal::Int,
+            "SIGTERM" => Signal::Term,
+            "SIGHUP" => Signal::Hup,
+            "SIGQUIT" => Signal::Quit,
+            "SIGKILL" => Signal::Kill,
+            "SIGTSTP" => Signal::Tstp,
+            "SIGCONT" => Signal::Cont,
+            "SIGUSR1" => Signal::Usr1,
+            "SIGUSR2" => Signal::Usr2,
+            _ => return None,
+        })
+    }
+}
+
+// `{"signal": "SIGINT"}`, delivered to the foreground process group as if
+// the key for it was typed, without going through the line discipline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignalRequest {
+    pub signal: Signal,
+}
+
+// Gives or takes back the write access of a participant in the session,
+// the client's own included to hand the keyboard over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    pub participant: u64,
+    pub write: bool,
+}
+
+// Asks for the output the session kept, on servers keeping it, e.g. for a
+// client attaching late to show what the terminal had before. The server
+// answers with one `ServerMessage::Scrollback`, of the last `lines` lines
+// and at most the last `bytes` bytes, everything kept if neither is set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replay {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lines: Option<u64>,
+}
+
+// Starts copying a file to or from the server, on servers allowing it. The
+// data goes in chunks tagged with `id`, `CLIENT_CHUNK` messages for uploads
+// and `SERVER_CHUNK` ones for downloads, whose payload is the id as 4 big
+// endian bytes then the data, the server telling how it goes with
+// `TransferStatus` messages. Uploads give their `size`, they are done once
+// that many bytes were sent. Transfers go on beside the terminal, its
+// output is never held back by them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transfer {
+    // Chosen by the client, unique among its transfers in progress.
+    pub id: u32,
+    pub direction: TransferDirection,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CancelTransfer {
+    pub id: u32,
+}
+
+// `started` once the server accepted a transfer, with the size of the file
+// for downloads, then `progress` after each chunk of an upload was written,
+// and `done` or `failed` with the `error`, refusals included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferState {
+    Started,
+    Progress,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferStatus {
+    pub id: u32,
+    pub state: TransferState,
+    pub transferred: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Sent as the command message, as JSON text, to run a program with
+// arguments, in a given directory or with more environment variables.
+// `{"cmd": ["python3", "-i"], "cwd": "/srv", "env": {"LANG": "C.UTF-8"}}`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpawnRequest {
+    // The program and its arguments, the default shell if empty.
+    #[serde(default)]
+    pub cmd: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    // Run in this running container instead, if the server lets the
+    // client exec into it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+}
+
+impl SpawnRequest {
+    // From a command message: a JSON object, or a bare program name as
+    // older clients send.
+    pub fn parse(text: &str) -> Result<Self, DecodeError> {
+        if text.trim_start().starts_with('{') {
+            return Ok(serde_json::from_str(text)?);
+        }
+        let cmd = match text {
+            "" => Vec::new(),
+            program => alloc::vec![program.into()],
+        };
+        Ok(SpawnRequest {
+            cmd,
+            ..Default::default()
+        })
+    }
+
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn program(&self) -> &str {
+        self.cmd.first().map_or("", String::as_str)
+    }
+
+    // Whether it is just a program name, as older clients send.
+    pub fn is_plain(&self) -> bool {
+        self.cmd.len() <= 1 && self.cwd.is_none() && self.env.is_empty() && self.container.is_none()
+    }
+}
+
+// Sent instead of the command to check whether the server would accept the
+// session, without spawning anything. The server answers with a `Decision`
+// and closes the connection.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DryRun {
+    pub command: String,
+}
+
+// Bearer token sent before the command, for servers requiring one and
+// clients that couldn't pass it with the upgrade request (e.g. browsers,
+// which can't set headers on WebSocket connections).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Auth {
+    pub token: String,
+}
+
+// Sent first by servers authenticating clients with a pre-shared key, for
+// deployments where secrets can't go in URLs or headers, which proxies may
+// log. The client answers with a `ChallengeResponse` before the command:
+// the HMAC-SHA256 of `nonce`, as sent, under its key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Challenge {
+    // Hex encoded, different for each connection.
+    pub nonce: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    // Which of the server's keys was used.
+    pub key_id: String,
+    // Hex encoded.
+    pub mac: String,
+}
+
+// Sent back for a message the client's token doesn't allow, which was
+// dropped: its opcode and the scope it needs (`input`, `resize` or
+// `signal`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Denied {
+    pub opcode: u8,
+    pub scope: String,
+}
+
+// Sent back for a message the server couldn't make sense of, which was
+// dropped: its opcode, unless it was empty, and what is wrong with it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Malformed {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opcode: Option<u8>,
+    pub error: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+// A message for the user from the server, `session will be terminated in 5
+// minutes` for instance, for frontends to show outside the terminal, as a
+// banner or a toast styled after its `level`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Notification {
+    pub level: Level,
+    pub text: String,
+}
+
+// A startup probe of the session's profile failed, so its command wasn't
+// started: the server closes the connection next with a `probe_failed`
+// `protocol::CloseReason`. `output` is what the probe printed, cut short,
+// and `expected` the pattern it didn't match, if it ran that far.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeFailure {
+    pub name: String,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub output: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    // Why it failed, e.g. `timed out after 5s`.
+    pub error: String,
+}
+
+// Sent instead of the command to get back a session left by a lost
+// connection, with the token from its `Session` message. The server closes
+// the connection with a policy violation if it doesn't know the session
+// (anymore), the client should start a new one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resume {
+    pub token: String,
+}
+
+// Sent instead of the command to make the connection the control one of a
+// session split in two, with the token from its `ControlChannel` message.
+// Everything but the pty's output and the client's input goes over it then,
+// both ways. The server closes the connection with a policy violation if it
+// doesn't know the session (anymore).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Control {
+    pub token: String,
+}
+
+// Sent by the client of a session to hand it to the authenticated user
+// `to`, e.g. an engineer taking over a customer's debugging shell. The
+// server answers with a `Decision`. Once allowed, `to` owns the session:
+// the connection is closed with `reassigned`, and the session waits for
+// `to` to `Resume` it with its token, which the client passes along, or
+// to `Attach` to it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handoff {
+    pub to: String,
+}
+
+// Sent instead of the command to follow a session started by another
+// client, with the id from its `Shared` message. Viewers get the same
+// output and `Resized` messages as its client, starting with what the
+// screen shows, and their input is dropped. Writers can type alongside the
+// client too, as far as input arbitration lets them. The server closes the
+// connection with a policy violation if it doesn't know the session or
+// won't share it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attach {
+    pub session_id: String,
+    #[serde(default)]
+    pub role: Role,
+    // For the output as text messages without escape sequences, the screen
+    // as its text to start with, for logs or chat messages.
+    #[serde(default)]
+    pub plain: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Writer,
+}
+
+// Opens a channel running `command`, the default shell if empty. Sent in
+// place of the command, it makes the connection multiplexed: each channel
+// is a session of its own, its messages wrapped in `Channel` frames both
+// ways (a big endian u32 channel id after the opcode, then the message as
+// sent on a connection of its own). `CloseChannel` ends one, and the
+// server answers `ListChannels` with `Channels`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Open {
+    pub channel: u32,
+    #[serde(default)]
+    pub command: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CloseChannel {
+    pub channel: u32,
+}
+
+// Marks the next input message for latency tracing, when enabled on the
+// server. `origin` is a client timestamp, echoed back as is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trace {
+    pub id: u64,
+    pub origin: u64,
+}
+
+// Variables to export in the session's shell, for the commands it starts
+// next. Only applied if the server's policy allows each name.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Environment {
+    pub vars: Vec<EnvVar>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvVar {
+    pub name: String,
+    pub value: String,
+}
+
+// Asks for one of the color themes configured on the server.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThemeRequest {
+    pub name: String,
+}
+
+// Kitty keyboard protocol flags currently requested by the application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyboardFlags {
+    pub flags: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseTracking {
+    None,
+    Press,
+    PressRelease,
+    ButtonMotion,
+    AnyMotion,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseEncoding {
+    Default,
+    Utf8,
+    Sgr,
+}
+
+// Mouse reporting requested by the application, sent when it changes so
+// frontends know whether to capture mouse events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MouseMode {
+    pub tracking: MouseTracking,
+    pub encoding: MouseEncoding,
+}
+
+// Sent when a full screen application switches to the alternate screen or
+// back, frontends may want to hide their own scrollback meanwhile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlternateScreen {
+    pub active: bool,
+}
+
+// Whether a session would be admitted, and why not.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    pub allowed: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+// Sent while waiting for a free session slot, `position` is 1 for the
+// next client to be let in and 0 once admitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuePosition {
+    pub position: u32,
+}
+
+// How far the setup of a session got, for clients to show something while
+// the command isn't up yet: `probing` while the server checks its
+// environment, `connecting` while ssh reaches the host, `attaching` while
+// the runtime gets into the container, all with the name of the probe,
+// host or container as `detail`, then `ready` right before the first
+// output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Connecting,
+    Attaching,
+    // Running the startup probes, see `ProbeFailure`.
+    Probing,
+    Ready,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    pub stage: Stage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+// Semantic prompt marks from the shell, when the server tracks them:
+// `prompt` when it starts printing the prompt, `input` once the user can
+// type, then `started` and `finished` around the command output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum CommandEvent {
+    Prompt,
+    Input,
+    Started,
+    Finished {
+        exit_code: Option<i32>,
+        // Milliseconds since the command started.
+        #[serde(default)]
+        duration: Option<u64>,
+    },
+}
+
+// Time spent on the server by a traced input, in microseconds: until it
+// was written to the pty, until the first output read after that, and
+// until that output was handed to the socket. The rest of the client round
+// trip is network and client side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceReport {
+    pub id: u64,
+    pub origin: u64,
+    pub write: u64,
+    pub echo: u64,
+    pub send: u64,
+}
+
+// Sent once a session used most of its CPU time budget, in milliseconds.
+// Its processes are killed when `used` reaches `limit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuUsage {
+    pub used: u64,
+    pub limit: u64,
+}
+
+// Job control in the session, when the server watches it: a process group
+// got stopped, by ^Z or a background job reading or writing the terminal,
+// then it was continued (`fg`, `bg`) or it is done.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobEvent {
+    pub pgid: i32,
+    pub command: String,
+    pub state: JobState,
+}
+
+// Who shares the session and who can type, on servers arbitrating input.
+// Sent when the session starts and then on each change: a participant
+// attaching or leaving, a `Grant`. The client is participant 0.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Participants {
+    pub participants: Vec<Participant>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Participant {
+    pub id: u64,
+    pub write: bool,
+}
+
+// Who follows the session and what they are doing, for frontends to show
+// the others, on servers tracking it. Sent when the session starts and
+// then on each change. The client is participant 0, named after its
+// authenticated identity if any.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+    pub participants: Vec<Member>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub activity: Activity,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Activity {
+    // Sent input in the last couple of seconds.
+    Typing,
+    Active,
+    // No input for a minute.
+    Idle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Stopped,
+    Continued,
+    Done,
+}
+
+// Answer to a `Resize` once the pty size is set, with the size applied
+// after clamping to the server's maximum and settling with the session's
+// other clients. When that failed `error` says why and the pty kept its
+// previous size. Also sent when the pty gets resized by someone else, see
+// `ResizeSource`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resized {
+    pub size: WindowSize,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub source: ResizeSource,
+}
+
+// Who set the size of a `Resized`. Clients fit their view to sizes they
+// didn't ask for, and may answer with a `Resize` of that size as their
+// window changes: the server takes it for the echo it is, acknowledged
+// without resizing again, so that they don't keep resizing each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResizeSource {
+    // The client's own `Resize`.
+    #[default]
+    Client,
+    // The program embedding the server.
+    Api,
+    // Another client following the session.
+    Participant,
+}
+
+// Sent when a session starts or is resumed, on servers keeping sessions
+// alive across connection losses. `token` goes in a `Resume` message to get
+// the session back.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub token: String,
+}
+
+// The only message of a data connection split from its control one, first
+// thing when the session starts: binary messages are only the pty's output
+// and the client's input, without opcodes, after it. `token` goes in a
+// `Control` message on the other connection.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControlChannel {
+    pub token: String,
+}
+
+// Sent when a session starts on servers sharing them, with the id other
+// clients `Attach` to it with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shared {
+    pub session_id: String,
+}
+
+// Switches features of the session as far as the server's policy lets the
+// client, those unset are left as they are: recording it, dropping the
+// client's input, software flow control (Ctrl-S and Ctrl-Q stopping and
+// restarting the output), and raw passthrough, the pty's output and the
+// client's input going through unfiltered, uncoalesced and untracked for
+// debugging rendering issues. The server answers with `Features`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Toggle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_only: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flow_control: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<bool>,
+}
+
+// What each feature is after a `Toggle`, whether it changed or not.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features {
+    pub recording: bool,
+    pub read_only: bool,
+    pub flow_control: bool,
+    // Unset by older servers.
+    #[serde(default)]
+    pub raw: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Channels {
+    pub channels: Vec<ChannelInfo>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    pub channel: u32,
+    pub command: String,
+}
+
+// Sent once a channel's session is over, in place of the close frame of a
+// connection of its own.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelClosed {
+    pub channel: u32,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+// Sent when the session's child exits, after its last output and before
+// the connection is closed. `signal` is set if it was killed by one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exit {
+    #[serde(default)]
+    pub code: Option<i32>,
+    #[serde(default)]
+    pub signal: Option<i32>,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Empty,
+    Truncated,
+    Utf8,
+    Json(serde_json::Error),
+    // Of a `protocol::Envelope`.
+    Version(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty message"),
+            DecodeError::Truncated => write!(f, "truncated message"),
+            DecodeError::Utf8 => write!(f, "invalid UTF-8 text"),
+            DecodeError::Json(e) => write!(f, "invalid payload: {}", e),
+            DecodeError::Version(v) => write!(f, "unsupported protocol version {}", v),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(e: serde_json::Error) -> Self {
+        DecodeError::Json(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientMessage<'a> {
+    Input(&'a [u8]),
+    Resize(WindowSize),
+    Ping,
+    FrameMode(FrameMode),
+    IntegrityMode(IntegrityMode),
+    Retransmit(Retransmit),
+    // Unlike input, always a whole composition, so never split in the
+    // middle of a character.
+    Composition(&'a str),
+    KeyboardProtocol(KeyboardProtocol),
+    Theme(ThemeRequest),
+    Pause(Pause),
+    DryRun(DryRun),
+    Trace(Trace),
+    Environment(Environment),
+    Auth(Auth),
+    Resume(Resume),
+    Open(Open),
+    CloseChannel(CloseChannel),
+    ListChannels,
+    // A channel id and the message for its session.
+    Channel(u32, &'a [u8]),
+    Signal(SignalRequest),
+    Grant(Grant),
+    Compression(Compression),
+    Replay(Replay),
+    LineInput(LineInput),
+    Transfer(Transfer),
+    // A transfer id and the next chunk of its upload.
+    TransferChunk(u32, &'a [u8]),
+    CancelTransfer(CancelTransfer),
+    Attach(Attach),
+    Toggle(Toggle),
+    ChallengeResponse(ChallengeResponse),
+    Control(Control),
+    Handoff(Handoff),
+    // Left for the caller to ignore or reject.
+    Unknown(u8, &'a [u8]),
+}
+
+impl<'a> ClientMessage<'a> {
+    pub fn decode(data: &'a [u8]) -> Result<Self, DecodeError> {
+        let (&opcode, payload) = data.split_first().ok_or(DecodeError::Empty)?;
+        Ok(match opcode {
+            INPUT => ClientMessage::Input(payload),
+            RESIZE => ClientMessage::Resize(serde_json::from_slice(payload)?),
+            RAW_RESIZE => ClientMessage::Resize(decode_raw_resize(payload)?),
+            PING => ClientMessage::Ping,
+            FRAME_MODE => ClientMessage::FrameMode(serde_json::from_slice(payload)?),
+            INTEGRITY_MODE => ClientMessage::IntegrityMode(serde_json::from_slice(payload)?),
+            RETRANSMIT => ClientMessage::Retransmit(serde_json::from_slice(payload)?),
+            COMPOSITION => ClientMessage::Composition(
+                core::str::from_utf8(payload).map_err(|_| DecodeError::Utf8)?,
+            ),
+            KEYBOARD_PROTOCOL => ClientMessage::KeyboardProtocol(serde_json::from_slice(payload)?),
+            THEME => ClientMessage::Theme(serde_json::from_slice(payload)?),
+            PAUSE => ClientMessage::Pause(serde_json::from_slice(payload)?),
+            DRY_RUN => ClientMessage::DryRun(serde_json::from_slice(payload)?),
+            TRACE => ClientMessage::Trace(serde_json::from_slice(payload)?),
+            ENVIRONMENT => ClientMessage::Environment(serde_json::from_slice(payload)?),
+            AUTH => ClientMessage::Auth(serde_json::from_slice(payload)?),
+            RESUME => ClientMessage::Resume(serde_json::from_slice(payload)?),
+            OPEN => ClientMessage::Open(serde_json::from_slice(payload)?),
+            CLOSE_CHANNEL => ClientMessage::CloseChannel(serde_json::from_slice(payload)?),
+            LIST_CHANNELS => ClientMessage::ListChannels,
+            CLIENT_CHANNEL => {
+                let (channel, message) = split_channel(payload)?;
+                ClientMessage::Channel(channel, message)
+            }
+            SIGNAL => ClientMessage::Signal(serde_json::from_slice(payload)?),
+            GRANT => ClientMessage::Grant(serde_json::from_slice(payload)?),
+            COMPRESSION => ClientMessage::Compression(serde_json::from_slice(payload)?),
+            REPLAY => ClientMessage::Replay(serde_json::from_slice(payload)?),
+            LINE_INPUT => ClientMessage::LineInput(serde_json::from_slice(payload)?),
+            TRANSFER => ClientMessage::Transfer(serde_json::from_slice(payload)?),
+            CLIENT_CHUNK => {
+                let (id, data) = split_channel(payload)?;
+                ClientMessage::TransferChunk(id, data)
+            }
+            CANCEL_TRANSFER => ClientMessage::CancelTransfer(serde_json::from_slice(payload)?),
+            ATTACH => ClientMessage::Attach(serde_json::from_slice(payload)?),
+            TOGGLE => ClientMessage::Toggle(serde_json::from_slice(payload)?),
+            CHALLENGE_RESPONSE => {
+                ClientMessage::ChallengeResponse(serde_json::from_slice(payload)?)
+            }
+            CONTROL => ClientMessage::Control(serde_json::from_slice(payload)?),
+            HANDOFF => ClientMessage::Handoff(serde_json::from_slice(payload)?),
+            _ => ClientMessage::Unknown(opcode, payload),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ClientMessage::Input(data) => frame(INPUT, data),
+            ClientMessage::Resize(size) => json_frame(RESIZE, size),
+            ClientMessage::Ping => frame(PING, &[]),
+            ClientMessage::FrameMode(mode) => json_frame(FRAME_MODE, mode),
+            ClientMessage::IntegrityMode(mode) => json_frame(INTEGRITY_MODE, mode),
+            ClientMessage::Retransmit(range) => json_frame(RETRANSMIT, range),
+            ClientMessage::Composition(text) => frame(COMPOSITION, text.as_bytes()),
+            ClientMessage::KeyboardProtocol(protocol) => json_frame(KEYBOARD_PROTOCOL, protocol),
+            ClientMessage::Theme(request) => json_frame(THEME, request),
+            ClientMessage::Pause(pause) => json_frame(PAUSE, pause),
+            ClientMessage::DryRun(dry_run) => json_frame(DRY_RUN, dry_run),
+            ClientMessage::Trace(trace) => json_frame(TRACE, trace),
+            ClientMessage::Environment(environment) => json_frame(ENVIRONMENT, environment),
+            ClientMessage::Auth(auth) => json_frame(AUTH, auth),
+            ClientMessage::Resume(resume) => json_frame(RESUME, resume),
+            ClientMessage::Open(open) => json_frame(OPEN, open),
+            ClientMessage::CloseChannel(close) => json_frame(CLOSE_CHANNEL, close),
+            ClientMessage::ListChannels => frame(LIST_CHANNELS, &[]),
+            ClientMessage::Channel(channel, message) => {
+                channel_frame(CLIENT_CHANNEL, *channel, message)
+            }
+            ClientMessage::Signal(request) => json_frame(SIGNAL, request),
+            ClientMessage::Grant(grant) => json_frame(GRANT, grant),
+            ClientMessage::Compression(compression) => json_frame(COMPRESSION, compression),
+            ClientMessage::Replay(replay) => json_frame(REPLAY, replay),
+            ClientMessage::LineInput(line_input) => json_frame(LINE_INPUT, line_input),
+            ClientMessage::Transfer(transfer) => json_frame(TRANSFER, transfer),
+            ClientMessage::TransferChunk(id, data) => channel_frame(CLIENT_CHUNK, *id, data),
+            ClientMessage::CancelTransfer(cancel) => json_frame(CANCEL_TRANSFER, cancel),
+            ClientMessage::Attach(attach) => json_frame(ATTACH, attach),
+            ClientMessage::Toggle(toggle) => json_frame(TOGGLE, toggle),
+            ClientMessage::ChallengeResponse(response) => json_frame(CHALLENGE_RESPONSE, response),
+            ClientMessage::Control(control) => json_frame(CONTROL, control),
+            ClientMessage::Handoff(handoff) => json_frame(HANDOFF, handoff),
+            ClientMessage::Unknown(opcode, payload) => frame(*opcode, payload),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerMessage<'a> {
+    Output(&'a [u8]),
+    Pong,
+    // `crc` is the CRC32 of `data` seeded with the previous frame's crc.
+    CheckedOutput { seq: u64, crc: u32, data: &'a [u8] },
+    KeyboardFlags(KeyboardFlags),
+    MouseMode(MouseMode),
+    AlternateScreen(AlternateScreen),
+    Decision(Decision),
+    QueuePosition(QueuePosition),
+    Command(CommandEvent),
+    TraceReport(TraceReport),
+    CpuUsage(CpuUsage),
+    Resized(Resized),
+    Exit(Exit),
+    Session(Session),
+    // A channel id and the message from its session.
+    Channel(u32, &'a [u8]),
+    Channels(Channels),
+    ChannelClosed(ChannelClosed),
+    Job(JobEvent),
+    Participants(Participants),
+    // Still compressed.
+    CompressedOutput(&'a [u8]),
+    Presence(Presence),
+    Progress(Progress),
+    Shared(Shared),
+    Features(Features),
+    Challenge(Challenge),
+    Denied(Denied),
+    Notification(Notification),
+    ProbeFailed(ProbeFailure),
+    ControlChannel(ControlChannel),
+    Malformed(Malformed),
+    // Past output, as asked with a `Replay`.
+    Scrollback(&'a [u8]),
+    StreamOutput { stream: u8, data: &'a [u8] },
+    TransferStatus(TransferStatus),
+    // A transfer id and the next chunk of its download.
+    TransferChunk(u32, &'a [u8]),
+    Unknown(u8, &'a [u8]),
+}
+
+impl<'a> ServerMessage<'a> {
+    pub fn decode(data: &'a [u8]) -> Result<Self, DecodeError> {
+        let (&opcode, payload) = data.split_first().ok_or(DecodeError::Empty)?;
+        Ok(match opcode {
+            OUTPUT => ServerMessage::Output(payload),
+            PONG => ServerMessage::Pong,
+            CHECKED_OUTPUT => {
+                if payload.len() < 12 {
+                    return Err(DecodeError::Truncated);
+                }
+                let (seq, rest) = payload.split_at(8);
+                let (crc, data) = rest.split_at(4);
+                ServerMessage::CheckedOutput {
+                    seq: u64::from_be_bytes(seq.try_into().unwrap()),
+                    crc: u32::from_be_bytes(crc.try_into().unwrap()),
+                    data,
+                }
+            }
+            KEYBOARD_FLAGS => ServerMessage::KeyboardFlags(serde_json::from_slice(payload)?),
+            MOUSE_MODE => ServerMessage::MouseMode(serde_json::from_slice(payload)?),
+            ALTERNATE_SCREEN => ServerMessage::AlternateScreen(serde_json::from_slice(payload)?),
+            DECISION => ServerMessage::Decision(serde_json::from_slice(payload)?),
+            QUEUE_POSITION => ServerMessage::QueuePosition(serde_json::from_slice(payload)?),
+            COMMAND => ServerMessage::Command(serde_json::from_slice(payload)?),
+            TRACE_REPORT => ServerMessage::TraceReport(serde_json::from_slice(payload)?),
+            CPU_USAGE => ServerMessage::CpuUsage(serde_json::from_slice(payload)?),
+            RESIZED => ServerMessage::Resized(serde_json::from_slice(payload)?),
+            EXIT => ServerMessage::Exit(serde_json::from_slice(payload)?),
+            SESSION => ServerMessage::Session(serde_json::from_slice(payload)?),
+            SERVER_CHANNEL => {
+                let (channel, message) = split_channel(payload)?;
+                ServerMessage::Channel(channel, message)
+            }
+            CHANNELS => ServerMessage::Channels(serde_json::from_slice(payload)?),
+            JOB => ServerMessage::Job(serde_json::from_slice(payload)?),
+            PARTICIPANTS => ServerMessage::Participants(serde_json::from_slice(payload)?),
+            COMPRESSED_OUTPUT => ServerMessage::CompressedOutput(payload),
+            PRESENCE => ServerMessage::Presence(serde_json::from_slice(payload)?),
+            PROGRESS => ServerMessage::Progress(serde_json::from_slice(payload)?),
+            SHARED => ServerMessage::Shared(serde_json::from_slice(payload)?),
+            FEATURES => ServerMessage::Features(serde_json::from_slice(payload)?),
+            CHALLENGE => ServerMessage::Challenge(serde_json::from_slice(payload)?),
+            DENIED => ServerMessage::Denied(serde_json::from_slice(payload)?),
+            NOTIFICATION => ServerMessage::Notification(serde_json::from_slice(payload)?),
+            PROBE_FAILED => ServerMessage::ProbeFailed(serde_json::from_slice(payload)?),
+            CONTROL_CHANNEL => ServerMessage::ControlChannel(serde_json::from_slice(payload)?),
+            MALFORMED => ServerMessage::Malformed(serde_json::from_slice(payload)?),
+            TRANSFER_STATUS => ServerMessage::TransferStatus(serde_json::from_slice(payload)?),
+            SERVER_CHUNK => {
+                let (id, data) = split_channel(payload)?;
+                ServerMessage::TransferChunk(id, data)
+            }
+            SCROLLBACK => ServerMessage::Scrollback(payload),
+            STREAM_OUTPUT => {
+                let (&stream, data) = payload.split_first().ok_or(DecodeError::Truncated)?;
+                ServerMessage::StreamOutput { stream, data }
+            }
+            CHANNEL_CLOSED => ServerMessage::ChannelClosed(serde_json::from_slice(payload)?),
+            _ => ServerMessage::Unknown(opcode, payload),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            ServerMessage::Output(data) => frame(OUTPUT, data),
+            ServerMessage::Pong => frame(PONG, &[]),
+            ServerMessage::CheckedOutput { seq, crc, data } => {
+                let mut msg = Vec::with_capacity(data.len() + 13);
+                msg.push(CHECKED_OUTPUT);
+                msg.extend_from_slice(&seq.to_be_bytes());
+                msg.extend_from_slice(&crc.to_be_bytes());
+                msg.extend_from_slice(data);
+                msg
+            }
+            ServerMessage::KeyboardFlags(flags) => json_frame(KEYBOARD_FLAGS, flags),
+            ServerMessage::MouseMode(mode) => json_frame(MOUSE_MODE, mode),
+            ServerMessage::AlternateScreen(screen) => json_frame(ALTERNATE_SCREEN, screen),
+            ServerMessage::Decision(decision) => json_frame(DECISION, decision),
+            ServerMessage::QueuePosition(queue) => json_frame(QUEUE_POSITION, queue),
+            ServerMessage::Command(event) => json_frame(COMMAND, event),
+            ServerMessage::TraceReport(report) => json_frame(TRACE_REPORT, report),
+            ServerMessage::CpuUsage(usage) => json_frame(CPU_USAGE, usage),
+            ServerMessage::Resized(resized) => json_frame(RESIZED, resized),
+            ServerMessage::Exit(exit) => json_frame(EXIT, exit),
+            ServerMessage::Session(session) => json_frame(SESSION, session),
+            ServerMessage::Channel(channel, message) => {
+                channel_frame(SERVER_CHANNEL, *channel, message)
+            }
+            ServerMessage::Channels(channels) => json_frame(CHANNELS, channels),
+            ServerMessage::Job(job) => json_frame(JOB, job),
+            ServerMessage::Participants(participants) => json_frame(PARTICIPANTS, participants),
+            ServerMessage::CompressedOutput(data) => frame(COMPRESSED_OUTPUT, data),
+            ServerMessage::Presence(presence) => json_frame(PRESENCE, presence),
+            ServerMessage::Progress(progress) => json_frame(PROGRESS, progress),
+            ServerMessage::Shared(shared) => json_frame(SHARED, shared),
+            ServerMessage::Features(features) => json_frame(FEATURES, features),
+            ServerMessage::Challenge(challenge) => json_frame(CHALLENGE, challenge),
+            ServerMessage::Denied(denied) => json_frame(DENIED, denied),
+            ServerMessage::Notification(notification) => json_frame(NOTIFICATION, notification),
+            ServerMessage::ProbeFailed(failure) => json_frame(PROBE_FAILED, failure),
+            ServerMessage::ControlChannel(channel) => json_frame(CONTROL_CHANNEL, channel),
+            ServerMessage::Malformed(malformed) => json_frame(MALFORMED, malformed),
+            ServerMessage::TransferStatus(status) => json_frame(TRANSFER_STATUS, status),
+            ServerMessage::TransferChunk(id, data) => channel_frame(SERVER_CHUNK, *id, data),
+            ServerMessage::Scrollback(data) => frame(SCROLLBACK, data),
+            ServerMessage::StreamOutput { stream, data } => {
+                let mut msg = Vec::with_capacity(data.len() + 2);
+                msg.extend_from_slice(&[STREAM_OUTPUT, *stream]);
+                msg.extend_from_slice(data);
+                msg
+            }
+            ServerMessage::ChannelClosed(closed) => json_frame(CHANNEL_CLOSED, closed),
+            ServerMessage::Unknown(opcode, payload) => frame(*opcode, payload),
+        }
+    }
+}
+
+pub fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(payload.len() + 1);
+    msg.push(opcode);
+    msg.extend_from_slice(payload);
+    msg
+}
+
+fn channel_frame(opcode: u8, channel: u32, message: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(message.len() + 5);
+    msg.push(opcode);
+    msg.extend_from_slice(&channel.to_be_bytes());
+    msg.extend_from_slice(message);
+    msg
+}
+
+fn split_channel(payload: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    if payload.len() < 4 {
+        return Err(DecodeError::Truncated);
+    }
+    let (channel, message) = payload.split_at(4);
+    Ok((u32::from_be_bytes(channel.try_into().unwrap()), message))
+}
+
+// A `Resize` with the size as four big endian u16 (cols, rows, xpixel and
+// ypixel), to measure what parsing JSON costs. Decoded as a `Resize`.
+pub fn encode_raw_resize(size: WindowSize) -> Vec<u8> {
+    let mut msg = alloc::vec![RAW_RESIZE];
+    for value in [size.cols, size.rows, size.xpixel, size.ypixel] {
+        msg.extend_from_slice(&value.to_be_bytes());
+    }
+    msg
+}
+
+fn decode_raw_resize(payload: &[u8]) -> Result<WindowSize, DecodeError> {
+    if payload.len() < 8 {
+        return Err(DecodeError::Truncated);
+    }
+    let value = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+    Ok(WindowSize {
+        cols: value(0),
+        rows: value(2),
+        xpixel: value(4),
+        ypixel: value(6),
+    })
+}
+
+fn json_frame<T: Serialize>(opcode: u8, payload: &T) -> Vec<u8> {
+    let mut msg = alloc::vec![opcode];
+    // Serializing these plain structs can't fail.
+    msg.extend(serde_json::to_vec(payload).unwrap_or_default());
+    msg
+}
+
+// Rolling checksum of checked output frames.
+pub fn checksum(previous: u32, data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(previous);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+// Length prefixed framing used on stream transports (QUIC).
+pub fn length_prefixed(data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(data.len() + 4);
+    msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    msg.extend_from_slice(data);
+    msg
+}
+
+// QUIC keystroke datagrams: a big endian u32 sequence number followed by
+// the input bytes.
+pub fn encode_keystrokes(seq: u32, data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(data.len() + 4);
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend_from_slice(data);
+    msg
+}
+
+pub fn decode_keystrokes(datagram: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    if datagram.len() < 4 {
+        return Err(DecodeError::Truncated);
+    }
+    let (seq, data) = datagram.split_at(4);
+    Ok((u32::from_be_bytes(seq.try_into().unwrap()), data))
+}
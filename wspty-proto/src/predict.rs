@@ -0,0 +1,149 @@
+// Local echo prediction, mosh style: printable keystrokes are displayed
+// right away instead of waiting for the server to echo them, and the echo
+// is then dropped from the output as it arrives. Output not matching the
+// predictions erases them, the server's output being the truth.
+//
+// After anything but printable input (Enter, arrows, ^C...) the cursor may
+// be anywhere, so predictions are tracked without being displayed until
+// one gets confirmed. Those left unconfirmed for `timeout` milliseconds,
+// e.g. at a password prompt, are given up the same way. Full screen
+// applications redraw too much to be predicted, see `set_alternate_screen`.
+//
+// The terminal isn't modeled: predicted characters are assumed to take a
+// cell each and to be typed at the end of the line.
+
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::vec::Vec;
+
+#[derive(Clone, Copy, Debug)]
+struct Prediction {
+    byte: u8,
+    // Whether it is on screen.
+    displayed: bool,
+    at: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Predictor {
+    enabled: bool,
+    timeout: u64,
+    pending: VecDeque<Prediction>,
+    // Cleared when the cursor position can't be guessed anymore, until a
+    // prediction gets confirmed.
+    trusted: bool,
+    alternate_screen: bool,
+}
+
+impl Predictor {
+    pub fn new(timeout: u64) -> Self {
+        Predictor {
+            enabled: true,
+            timeout,
+            pending: VecDeque::new(),
+            trusted: false,
+            alternate_screen: false,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Returns what to write to the terminal to erase the predictions shown.
+    pub fn set_enabled(&mut self, enabled: bool) -> Vec<u8> {
+        self.enabled = enabled;
+        if enabled {
+            Vec::new()
+        } else {
+            self.reset()
+        }
+    }
+
+    // From `AlternateScreen` messages.
+    pub fn set_alternate_screen(&mut self, active: bool) {
+        self.alternate_screen = active;
+        self.trusted = false;
+        self.pending.clear();
+    }
+
+    // Records `input` sent at `now`, returns what to display right away.
+    pub fn on_input(&mut self, input: &[u8], now: u64) -> Vec<u8> {
+        if !self.enabled || self.alternate_screen {
+            return Vec::new();
+        }
+        let text = match core::str::from_utf8(input) {
+            Ok(text) if !text.chars().any(char::is_control) => text,
+            _ => {
+                self.trusted = false;
+                return Vec::new();
+            }
+        };
+        let displayed = self.trusted;
+        for c in text.chars() {
+            let mut bytes = [0u8; 4];
+            for &byte in c.encode_utf8(&mut bytes).as_bytes() {
+                self.pending.push_back(Prediction {
+                    byte,
+                    displayed,
+                    at: now,
+                });
+            }
+        }
+        if displayed {
+            input.to_vec()
+        } else {
+            Vec::new()
+        }
+    }
+
+    // Returns what to write to the terminal for `output` from the server.
+    pub fn on_output(&mut self, output: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(output.len());
+        let mut confirmed = 0;
+        while let Some(&byte) = output.get(confirmed) {
+            match self.pending.front() {
+                Some(prediction) if prediction.byte == byte => {
+                    // Already on screen otherwise.
+                    if !prediction.displayed {
+                        data.push(byte);
+                    }
+                    self.pending.pop_front();
+                    self.trusted = true;
+                    confirmed += 1;
+                }
+                _ => break,
+            }
+        }
+        if confirmed < output.len() {
+            data.extend(self.reset());
+            data.extend_from_slice(&output[confirmed..]);
+        }
+        data
+    }
+
+    // Gives up on the predictions left unconfirmed past the timeout at
+    // `now`, returns what to write to the terminal to erase them.
+    pub fn poll(&mut self, now: u64) -> Vec<u8> {
+        match self.pending.front() {
+            Some(prediction) if now.saturating_sub(prediction.at) >= self.timeout => self.reset(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn reset(&mut self) -> Vec<u8> {
+        // One cell per character, continuation bytes left out.
+        let cells = self
+            .pending
+            .iter()
+            .filter(|p| p.displayed && p.byte & 0xc0 != 0x80)
+            .count();
+        self.pending.clear();
+        self.trusted = false;
+        if cells == 0 {
+            return Vec::new();
+        }
+        // Back over the predictions, then erase them in place.
+        format!("\x1b[{0}D\x1b[{0}X", cells).into_bytes()
+    }
+}
@@ -0,0 +1,823 @@
+// Versioned framing, for clients negotiating the `SUBPROTOCOL` WebSocket
+// subprotocol. Terminal data goes in binary messages as is both ways, all
+// the rest in text messages holding a JSON `Envelope`, e.g.
+// `{"v":1,"type":"resize","cols":80,"rows":24}`. Sessions start with a
+// `spawn` (or `auth`, `dry_run`, `resume`) instead of the command text.
+//
+// Clients not negotiating it get the legacy framing, a leading opcode byte
+// in binary messages, which the server keeps handling internally. This
+// module converts between the two. Multiplexing is only available with the
+// legacy framing.
+
+use crate::{
+    AlternateScreen, Attach, Auth, CancelTransfer, Challenge, ChallengeResponse, ClientMessage,
+    CommandEvent, Control, ControlChannel, CpuUsage, Decision, DecodeError, Denied, DryRun,
+    Environment, Exit, Features, FrameMode, Grant, Handoff, IntegrityMode, JobEvent, KeyboardFlags,
+    KeyboardProtocol, LineInput, Malformed, MouseMode, Notification, Participants, Pause, Presence,
+    ProbeFailure, Progress, QueuePosition, Replay, Resized, Resume, Retransmit, ServerMessage,
+    Session, Shared, SignalRequest, ThemeRequest, Toggle, Trace, TraceReport, Transfer,
+    TransferStatus, WindowSize,
+};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+pub const VERSION: u32 = 1;
+pub const SUBPROTOCOL: &str = "wspty.v1";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub v: u32,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientControl {
+    // The command to run, the default shell if empty.
+    Spawn {
+        #[serde(default)]
+        command: String,
+    },
+    Resize(WindowSize),
+    Ping,
+    FrameMode(FrameMode),
+    IntegrityMode(IntegrityMode),
+    Retransmit(Retransmit),
+    Composition {
+        text: String,
+    },
+    KeyboardProtocol(KeyboardProtocol),
+    Theme(ThemeRequest),
+    Pause(Pause),
+    DryRun(DryRun),
+    Trace(Trace),
+    Environment(Environment),
+    Auth(Auth),
+    Resume(Resume),
+    Signal(SignalRequest),
+    Grant(Grant),
+    Replay(Replay),
+    LineInput(LineInput),
+    Transfer(Transfer),
+    TransferChunk {
+        id: u32,
+        data: Vec<u8>,
+    },
+    CancelTransfer(CancelTransfer),
+    Attach(Attach),
+    Toggle(Toggle),
+    ChallengeResponse(ChallengeResponse),
+    Control(Control),
+    Handoff(Handoff),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerControl {
+    Pong,
+    CheckedOutput { seq: u64, crc: u32, data: Vec<u8> },
+    KeyboardFlags(KeyboardFlags),
+    MouseMode(MouseMode),
+    AlternateScreen(AlternateScreen),
+    Decision(Decision),
+    QueuePosition(QueuePosition),
+    Command(CommandEvent),
+    TraceReport(TraceReport),
+    CpuUsage(CpuUsage),
+    Resized(Resized),
+    Exit(Exit),
+    Session(Session),
+    Job(JobEvent),
+    Participants(Participants),
+    Presence(Presence),
+    Progress(Progress),
+    Shared(Shared),
+    Features(Features),
+    Challenge(Challenge),
+    Denied(Denied),
+    Notification(Notification),
+    ProbeFailed(ProbeFailure),
+    ControlChannel(ControlChannel),
+    Malformed(Malformed),
+    Scrollback { data: Vec<u8> },
+    StreamOutput { stream: u8, data: Vec<u8> },
+    TransferStatus(TransferStatus),
+    TransferChunk { id: u32, data: Vec<u8> },
+}
+
+fn encode<T: Serialize>(message: &T) -> String {
+    let envelope = Envelope {
+        v: VERSION,
+        message,
+    };
+    // Serializing these plain types can't fail.
+    serde_json::to_string(&envelope).unwrap_or_default()
+}
+
+fn decode<'a, T: Deserialize<'a>>(text: &'a str) -> Result<T, DecodeError> {
+    #[derive(Deserialize)]
+    struct Version {
+        v: u32,
+    }
+    let version: Version = serde_json::from_str(text)?;
+    if version.v != VERSION {
+        return Err(DecodeError::Version(version.v));
+    }
+    let envelope: Envelope<T> = serde_json::from_str(text)?;
+    Ok(envelope.message)
+}
+
+impl ClientControl {
+    pub fn encode(&self) -> String {
+        encode(self)
+    }
+
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        decode(text)
+    }
+
+    // `None` for input, sent as is, and what has no envelope form.
+    pub fn from_legacy(message: &ClientMessage<'_>) -> Option<Self> {
+        Some(match message {
+            ClientMessage::Resize(size) => ClientControl::Resize(*size),
+            ClientMessage::Ping => ClientControl::Ping,
+            ClientMessage::FrameMode(mode) => ClientControl::FrameMode(*mode),
+            ClientMessage::IntegrityMode(mode) => ClientControl::IntegrityMode(*mode),
+            ClientMessage::Retransmit(range) => ClientControl::Retransmit(*range),
+            ClientMessage::Composition(text) => ClientControl::Composition {
+                text: (*text).into(),
+            },
+            ClientMessage::KeyboardProtocol(protocol) => ClientControl::KeyboardProtocol(*protocol),
+            ClientMessage::Theme(request) => ClientControl::Theme(request.clone()),
+            ClientMessage::Pause(pause) => ClientControl::Pause(*pause),
+            ClientMessage::DryRun(dry_run) => ClientControl::DryRun(dry_run.clone()),
+            ClientMessage::Trace(trace) => ClientControl::Trace(*trace),
+            ClientMessage::Environment(environment) => {
+                ClientControl::Environment(environment.clone())
+            }
+            ClientMessage::Auth(auth) => ClientControl::Auth(auth.clone()),
+            ClientMessage::Resume(resume) => ClientControl::Resume(resume.clone()),
+            ClientMessage::Signal(request) => ClientControl::Signal(*request),
+            ClientMessage::Grant(grant) => ClientControl::Grant(*grant),
+            ClientMessage::Replay(replay) => ClientControl::Replay(*replay),
+            ClientMessage::LineInput(line_input) => ClientControl::LineInput(*line_input),
+            ClientMessage::Transfer(transfer) => ClientControl::Transfer(transfer.clone()),
+            ClientMessage::TransferChunk(id, data) => ClientControl::TransferChunk {
+                id: *id,
+                data: data.to_vec(),
+            },
+            ClientMessage::CancelTransfer(cancel) => ClientControl::CancelTransfer(*cancel),
+            ClientMessage::Attach(attach) => ClientControl::Attach(attach.clone()),
+            ClientMessage::Toggle(toggle) => ClientControl::Toggle(*toggle),
+            ClientMessage::ChallengeResponse(response) => {
+                ClientControl::ChallengeResponse(response.clone())
+            }
+            ClientMessage::Control(control) => ClientControl::Control(control.clone()),
+            ClientMessage::Handoff(handoff) => ClientControl::Handoff(handoff.clone()),
+            ClientMessage::Input(_)
+            | ClientMessage::Compression(_)
+            | ClientMessage::Open(_)
+            | ClientMessage::CloseChannel(_)
+            | ClientMessage::ListChannels
+            | ClientMessage::Channel(..)
+            | ClientMessage::Unknown(..) => return None,
+        })
+    }
+
+    // The legacy binary message, `None` for `spawn` which was a text
+    // message holding the command.
+    pub fn to_legacy(&self) -> Option<Vec<u8>> {
+        let message = match self {
+            ClientControl::Spawn { .. } => return None,
+            ClientControl::Resize(size) => ClientMessage::Resize(*size),
+            ClientControl::Ping => ClientMessage::Ping,
+            ClientControl::FrameMode(mode) => ClientMessage::FrameMode(*mode),
+            ClientControl::IntegrityMode(mode) => ClientMessage::IntegrityMode(*mode),
+            ClientControl::Retransmit(range) => ClientMessage::Retransmit(*range),
+            ClientControl::Composition { text } => ClientMessage::Composition(text),
+            ClientControl::KeyboardProtocol(protocol) => ClientMessage::KeyboardProtocol(*protocol),
+            ClientControl::Theme(request) => ClientMessage::Theme(request.clone()),
+            ClientControl::Pause(pause) => ClientMessage::Pause(*pause),
+            ClientControl::DryRun(dry_run) => ClientMessage::DryRun(dry_run.clone()),
+            ClientControl::Trace(trace) => ClientMessage::Trace(*trace),
+            ClientControl::Environment(environment) => {
+                ClientMessage::Environment(environment.clone())
+            }
+            ClientControl::Auth(auth) => ClientMessage::Auth(auth.clone()),
+            ClientControl::Resume(resume) => ClientMessage::Resume(resume.clone()),
+            ClientControl::Signal(request) => ClientMessage::Signal(*request),
+            ClientControl::Grant(grant) => ClientMessage::Grant(*grant),
+            ClientControl::Replay(replay) => ClientMessage::Replay(*replay),
+            ClientControl::LineInput(line_input) => ClientMessage::LineInput(*line_input),
+            ClientControl::Transfer(transfer) => ClientMessage::Transfer(transfer.clone()),
+            ClientControl::TransferChunk { id, data } => ClientMessage::TransferChunk(*id, data),
+            ClientControl::CancelTransfer(cancel) => ClientMessage::CancelTransfer(*cancel),
+            ClientControl::Attach(attach) => ClientMessage::Attach(attach.clone()),
+            ClientControl::Toggle(toggle) => ClientMessage::Toggle(*toggle),
+            ClientControl::ChallengeResponse(response) => {
+                ClientMessage::ChallengeResponse(response.clone())
+            }
+            ClientControl::Control(control) => ClientMessage::Control(control.clone()),
+            ClientControl::Handoff(handoff) => ClientMessage::Handoff(handoff.clone()),
+        };
+        Some(message.encode())
+    }
+}
+
+impl ServerControl {
+    pub fn encode(&self) -> String {
+        encode(self)
+    }
+
+    pub fn decode(text: &str) -> Result<Self, DecodeError> {
+        decode(text)
+    }
+
+    // `None` for output, sent as is, and what has no envelope form.
+    pub fn from_legacy(message: &ServerMessage<'_>) -> Option<Self> {
+        Some(match message {
+            ServerMessage::Pong => ServerControl::Pong,
+            ServerMessage::CheckedOutput { seq, crc, data } => ServerControl::CheckedOutput {
+                seq: *seq,
+                crc: *crc,
+                data: data.to_vec(),
+            },
+            ServerMessage::KeyboardFlags(flags) => ServerControl::KeyboardFlags(*flags),
+            ServerMessage::MouseMode(mode) => ServerControl::MouseMode(*mode),
+            ServerMessage::AlternateScreen(screen) => ServerControl::AlternateScreen(*screen),
+            ServerMessage::Decision(decision) => ServerControl::Decision(decision.clone()),
+            ServerMessage::QueuePosition(queue) => ServerControl::QueuePosition(*queue),
+            ServerMessage::Command(event) => ServerControl::Command(*event),
+            ServerMessage::TraceReport(report) => ServerControl::TraceReport(*report),
+            ServerMessage::CpuUsage(usage) => ServerControl::CpuUsage(*usage),
+            ServerMessage::Resized(resized) => ServerControl::Resized(resized.clone()),
+            ServerMessage::Exit(exit) => ServerControl::Exit(*exit),
+            ServerMessage::Session(session) => ServerControl::Session(session.clone()),
+            ServerMessage::Job(job) => ServerControl::Job(job.clone()),
+            ServerMessage::Participants(participants) => {
+                ServerControl::Participants(participants.clone())
+            }
+            ServerMessage::Presence(presence) => ServerControl::Presence(presence.clone()),
+            ServerMessage::Progress(progress) => ServerControl::Progress(progress.clone()),
+            ServerMessage::Shared(shared) => ServerControl::Shared(shared.clone()),
+            ServerMessage::Features(features) => ServerControl::Features(*features),
+            ServerMessage::Challenge(challenge) => ServerControl::Challenge(challenge.clone()),
+            ServerMessage::Denied(denied) => ServerControl::Denied(denied.clone()),
+            ServerMessage::Notification(notification) => {
+                ServerControl::Notification(notification.clone())
+            }
+            ServerMessage::ProbeFailed(failure) => ServerControl::ProbeFailed(failure.clone()),
+            ServerMessage::ControlChannel(channel) => {
+                ServerControl::ControlChannel(channel.clone())
+            }
+            ServerMessage::Malformed(malformed) => ServerControl::Malformed(malformed.clone()),
+            ServerMessage::Scrollback(data) => ServerControl::Scrollback {
+                data: data.to_vec(),
+            },
+            ServerMessage::StreamOutput { stream, data } => ServerControl::StreamOutput {
+                stream: *stream,
+                data: data.to_vec(),
+            },
+            ServerMessage::TransferStatus(status) => ServerControl::TransferStatus(status.clone()),
+            ServerMessage::TransferChunk(id, data) => ServerControl::TransferChunk {
+                id: *id,
+                data: data.to_vec(),
+            },
+            ServerMessage::Output(_)
+            | ServerMessage::CompressedOutput(_)
+            | ServerMessage::Channel(..)
+            | ServerMessage::Channels(_)
+            | ServerMessage::ChannelClosed(_)
+            | ServerMessage::Unknown(..) => return None,
+        })
+    }
+
+    pub fn to_legacy(&self) -> Vec<u8> {
+        let message = match self {
+            ServerControl::Pong => ServerMessage::Pong,
+            ServerControl::CheckedOutput { seq, crc, data } => ServerMessage::CheckedOutput {
+                seq: *seq,
+                crc: *crc,
+                data,
+            },
+            ServerControl::KeyboardFlags(flags) => ServerMessage::KeyboardFlags(*flags),
+            ServerControl::MouseMode(mode) => ServerMessage::MouseMode(*mode),
+            ServerControl::AlternateScreen(screen) => ServerMessage::AlternateScreen(*screen),
+            ServerControl::Decision(decision) => ServerMessage::Decision(decision.clone()),
+            ServerControl::QueuePosition(queue) => ServerMessage::QueuePosition(*queue),
+            ServerControl::Command(event) => ServerMessage::Command(*event),
+            ServerControl::TraceReport(report) => ServerMessage::TraceReport(*report),
+            ServerControl::CpuUsage(usage) => ServerMessage::CpuUsage(*usage),
+            ServerControl::Resized(resized) => ServerMessage::Resized(resized.clone()),
+            ServerControl::Exit(exit) => ServerMessage::Exit(*exit),
+            ServerControl::Session(session) => ServerMessage::Session(session.clone()),
+            ServerControl::Job(job) => ServerMessage::Job(job.clone()),
+            ServerControl::Participants(participants) => {
+                ServerMessage::Participants(participants.clone())
+            }
+            ServerControl::Presence(presence) => ServerMessage::Presence(presence.clone()),
+            ServerControl::Progress(progress) => ServerMessage::Progress(progress.clone()),
+            ServerControl::Shared(shared) => ServerMessage::Shared(shared.clone()),
+            ServerControl::Features(features) => ServerMessage::Features(*features),
+            ServerControl::Challenge(challenge) => ServerMessage::Challenge(challenge.clone()),
+            ServerControl::Denied(denied) => ServerMessage::Denied(denied.clone()),
+            ServerControl::Notification(notification) => {
+                ServerMessage::Notification(notification.clone())
+            }
+            ServerControl::ProbeFailed(failure) => ServerMessage::ProbeFailed(failure.clone()),
+            ServerControl::ControlChannel(channel) => {
+                ServerMessage::ControlChannel(channel.clone())
+            }
+            ServerControl::Malformed(malformed) => ServerMessage::Malformed(malformed.clone()),
+            ServerControl::Scrollback { data } => ServerMessage::Scrollback(data),
+            ServerControl::StreamOutput { stream, data } => ServerMessage::StreamOutput {
+                stream: *stream,
+                data,
+            },
+            ServerControl::TransferStatus(status) => ServerMessage::TransferStatus(status.clone()),
+            ServerControl::TransferChunk { id, data } => ServerMessage::TransferChunk(*id, data),
+        };
+        message.encode()
+    }
+}
+
+// Why the server closed the connection, as the reason of its close frame,
+// with either framing: a code, then for some `:` and a detail in English, e.g. `idle_timeout` or
+// `forbidden:no such directory: /srv`. Frontends show their own, localized,
+// message for each code, the detail being for logs or as a fallback. Codes
+// this crate doesn't know yet parse as `Other`. Reasons are cut to fit the
+// 123 bytes of a close frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    // The command exited, see `Exit`.
+    Exited,
+    IdleTimeout,
+    TimeLimit,
+    CpuBudget,
+    MemoryBudget,
+    Banned,
+    Unauthorized(String),
+    // By the server's policies, or the client's token.
+    Forbidden(String),
+    Quota(String),
+    // No room left for the session, or for the client in it.
+    Capacity(String),
+    NoPty,
+    // Resumed or attached to, but not running (anymore).
+    UnknownSession,
+    // Followed from another connection and ended without an exit status.
+    SessionEnded,
+    // Went to another process, see `SessionHandle::hand_over`.
+    HandedOver,
+    // Handed to another user, by identity, see `Handoff`.
+    Reassigned(String),
+    // By the server's operator, with their reason.
+    Terminated(String),
+    Shutdown(String),
+    InternalError,
+    // A startup probe, by name, see `ProbeFailure`.
+    ProbeFailed(String),
+    // A message of the client, by opcode, see `Malformed`.
+    Malformed(String),
+    // Of proxied sessions and devices behind a broker.
+    UpstreamUnavailable,
+    DeviceOffline,
+    DeviceUnresponsive,
+    DeviceLost,
+    KeyNotProven,
+    Other(String),
+}
+
+pub const MAX_CLOSE_REASON: usize = 123;
+
+impl CloseReason {
+    pub fn code(&self) -> &str {
+        match self {
+            CloseReason::Exited => "exited",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::TimeLimit => "time_limit",
+            CloseReason::CpuBudget => "cpu_budget",
+            CloseReason::MemoryBudget => "memory_budget",
+            CloseReason::Banned => "banned",
+            CloseReason::Unauthorized(_) => "unauthorized",
+            CloseReason::Forbidden(_) => "forbidden",
+            CloseReason::Quota(_) => "quota",
+            CloseReason::Capacity(_) => "capacity",
+            CloseReason::NoPty => "no_pty",
+            CloseReason::UnknownSession => "unknown_session",
+            CloseReason::SessionEnded => "session_ended",
+            CloseReason::HandedOver => "handed_over",
+            CloseReason::Reassigned(_) => "reassigned",
+            CloseReason::Terminated(_) => "terminated",
+            CloseReason::Shutdown(_) => "shutdown",
+            CloseReason::ProbeFailed(_) => "probe_failed",
+            CloseReason::Malformed(_) => "malformed",
+            CloseReason::InternalError => "internal_error",
+            CloseReason::UpstreamUnavailable => "upstream_unavailable",
+            CloseReason::DeviceOffline => "device_offline",
+            CloseReason::DeviceUnresponsive => "device_unresponsive",
+            CloseReason::DeviceLost => "device_lost",
+            CloseReason::KeyNotProven => "key_not_proven",
+            CloseReason::Other(reason) => reason,
+        }
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            CloseReason::Unauthorized(detail)
+            | CloseReason::Forbidden(detail)
+            | CloseReason::Quota(detail)
+            | CloseReason::Capacity(detail)
+            | CloseReason::Reassigned(detail)
+            | CloseReason::Terminated(detail)
+            | CloseReason::Shutdown(detail)
+            | CloseReason::ProbeFailed(detail)
+            | CloseReason::Malformed(detail) => Some(detail),
+            _ => None,
+        }
+    }
+
+    pub fn parse(reason: &str) -> Self {
+        let (code, detail) = match reason.split_once(':') {
+            Some((code, detail)) => (code, detail.to_owned()),
+            None => (reason, String::new()),
+        };
+        match code {
+            "exited" => CloseReason::Exited,
+            "idle_timeout" => CloseReason::IdleTimeout,
+            "time_limit" => CloseReason::TimeLimit,
+            "cpu_budget" => CloseReason::CpuBudget,
+            "memory_budget" => CloseReason::MemoryBudget,
+            "banned" => CloseReason::Banned,
+            "unauthorized" => CloseReason::Unauthorized(detail),
+            "forbidden" => CloseReason::Forbidden(detail),
+            "quota" => CloseReason::Quota(detail),
+            "capacity" => CloseReason::Capacity(detail),
+            "no_pty" => CloseReason::NoPty,
+            "unknown_session" => CloseReason::UnknownSession,
+            "session_ended" => CloseReason::SessionEnded,
+            "handed_over" => CloseReason::HandedOver,
+            "reassigned" => CloseReason::Reassigned(detail),
+            "terminated" => CloseReason::Terminated(detail),
+            "shutdown" => CloseReason::Shutdown(detail),
+            "probe_failed" => CloseReason::ProbeFailed(detail),
+            "malformed" => CloseReason::Malformed(detail),
+            "internal_error" => CloseReason::InternalError,
+            "upstream_unavailable" => CloseReason::UpstreamUnavailable,
+            "device_offline" => CloseReason::DeviceOffline,
+            "device_unresponsive" => CloseReason::DeviceUnresponsive,
+            "device_lost" => CloseReason::DeviceLost,
+            "key_not_proven" => CloseReason::KeyNotProven,
+            _ => CloseReason::Other(reason.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let detail = match self.detail() {
+            Some(detail) if !detail.is_empty() => detail,
+            _ => return write!(f, "{}", self.code()),
+        };
+        let mut room = MAX_CLOSE_REASON.saturating_sub(self.code().len() + 1);
+        while !detail.is_char_boundary(room.min(detail.len())) {
+            room -= 1;
+        }
+        write!(f, "{}:{}", self.code(), &detail[..room.min(detail.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Activity, EnvVar, JobState, Level, Member, MouseEncoding, MouseTracking, Participant,
+        ResizeSource, Role, Signal, Stage, TransferDirection, TransferState,
+    };
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn client_controls() -> Vec<ClientControl> {
+        vec![
+            ClientControl::Resize(WindowSize {
+                cols: 120,
+                rows: 40,
+                xpixel: 960,
+                ypixel: 640,
+            }),
+            ClientControl::Ping,
+            ClientControl::FrameMode(FrameMode {
+                enabled: true,
+                interval: Some(16),
+            }),
+            ClientControl::IntegrityMode(IntegrityMode { enabled: true }),
+            ClientControl::Retransmit(Retransmit { from: 3, to: 7 }),
+            ClientControl::Composition {
+                text: "日本語".into(),
+            },
+            ClientControl::KeyboardProtocol(KeyboardProtocol { kitty: true }),
+            ClientControl::Theme(ThemeRequest {
+                name: "solarized".into(),
+            }),
+            ClientControl::Pause(Pause { paused: true }),
+            ClientControl::DryRun(DryRun {
+                command: "htop".into(),
+            }),
+            ClientControl::Trace(Trace { id: 1, origin: 42 }),
+            ClientControl::Environment(Environment {
+                vars: vec![EnvVar {
+                    name: "AWS_TOKEN".into(),
+                    value: "secret".into(),
+                }],
+            }),
+            ClientControl::Auth(Auth {
+                token: "abc".into(),
+            }),
+            ClientControl::Resume(Resume {
+                token: "0123".into(),
+            }),
+            ClientControl::Signal(SignalRequest {
+                signal: Signal::Int,
+            }),
+            ClientControl::Grant(Grant {
+                participant: 2,
+                write: true,
+            }),
+            ClientControl::Replay(Replay {
+                bytes: Some(4096),
+                lines: None,
+            }),
+            ClientControl::LineInput(LineInput { enabled: true }),
+            ClientControl::Transfer(Transfer {
+                id: 1,
+                direction: TransferDirection::Upload,
+                path: "notes.txt".into(),
+                size: Some(5),
+            }),
+            ClientControl::TransferChunk {
+                id: 1,
+                data: b"notes".to_vec(),
+            },
+            ClientControl::CancelTransfer(CancelTransfer { id: 1 }),
+            ClientControl::Attach(Attach {
+                session_id: "42".into(),
+                role: Role::Writer,
+                plain: true,
+            }),
+            ClientControl::Toggle(Toggle {
+                recording: Some(true),
+                read_only: None,
+                flow_control: Some(false),
+                raw: Some(true),
+            }),
+            ClientControl::ChallengeResponse(ChallengeResponse {
+                key_id: "ci".into(),
+                mac: "9f86d081".into(),
+            }),
+            ClientControl::Control(Control {
+                token: "c2VjcmV0".into(),
+            }),
+            ClientControl::Handoff(Handoff {
+                to: "oncall".into(),
+            }),
+        ]
+    }
+
+    fn server_controls() -> Vec<ServerControl> {
+        vec![
+            ServerControl::Pong,
+            ServerControl::CheckedOutput {
+                seq: 9,
+                crc: 0xdead_beef,
+                data: b"ls\r\n".to_vec(),
+            },
+            ServerControl::KeyboardFlags(KeyboardFlags { flags: 5 }),
+            ServerControl::MouseMode(MouseMode {
+                tracking: MouseTracking::ButtonMotion,
+                encoding: MouseEncoding::Sgr,
+            }),
+            ServerControl::AlternateScreen(AlternateScreen { active: true }),
+            ServerControl::Decision(Decision {
+                allowed: false,
+                reason: Some("banned".into()),
+            }),
+            ServerControl::QueuePosition(QueuePosition { position: 2 }),
+            ServerControl::Command(CommandEvent::Finished {
+                exit_code: Some(1),
+                duration: Some(250),
+            }),
+            ServerControl::TraceReport(TraceReport {
+                id: 1,
+                origin: 2,
+                write: 3,
+                echo: 4,
+                send: 5,
+            }),
+            ServerControl::CpuUsage(CpuUsage {
+                used: 900,
+                limit: 1000,
+            }),
+            ServerControl::Resized(Resized {
+                size: WindowSize {
+                    cols: 80,
+                    rows: 24,
+                    xpixel: 0,
+                    ypixel: 0,
+                },
+                error: None,
+                source: ResizeSource::Participant,
+            }),
+            ServerControl::Exit(Exit {
+                code: None,
+                signal: Some(9),
+            }),
+            ServerControl::Session(Session {
+                token: "0123".into(),
+            }),
+            ServerControl::Job(JobEvent {
+                pgid: 42,
+                command: "vim".into(),
+                state: JobState::Stopped,
+            }),
+            ServerControl::Participants(Participants {
+                participants: vec![
+                    Participant {
+                        id: 0,
+                        write: false,
+                    },
+                    Participant { id: 2, write: true },
+                ],
+            }),
+            ServerControl::Presence(Presence {
+                participants: vec![
+                    Member {
+                        id: 0,
+                        name: Some("alice".into()),
+                        activity: Activity::Typing,
+                    },
+                    Member {
+                        id: 1,
+                        name: None,
+                        activity: Activity::Idle,
+                    },
+                ],
+            }),
+            ServerControl::Progress(Progress {
+                stage: Stage::Connecting,
+                detail: Some("db.example".into()),
+            }),
+            ServerControl::Shared(Shared {
+                session_id: "42".into(),
+            }),
+            ServerControl::Features(Features {
+                recording: true,
+                read_only: false,
+                flow_control: true,
+                raw: false,
+            }),
+            ServerControl::Challenge(Challenge {
+                nonce: "c0ffee".into(),
+            }),
+            ServerControl::Denied(Denied {
+                opcode: crate::INPUT,
+                scope: "input".into(),
+            }),
+            ServerControl::Notification(Notification {
+                level: Level::Warn,
+                text: "session will be terminated in 5 minutes".into(),
+            }),
+            ServerControl::ProbeFailed(ProbeFailure {
+                name: "home".into(),
+                command: "test -w ~".into(),
+                exit_code: Some(1),
+                output: String::new(),
+                expected: None,
+                error: "exited with 1".into(),
+            }),
+            ServerControl::ControlChannel(ControlChannel {
+                token: "c2VjcmV0".into(),
+            }),
+            ServerControl::Malformed(Malformed {
+                opcode: Some(1),
+                error: "payload too large".into(),
+            }),
+            ServerControl::Scrollback {
+                data: b"$ ls\r\n".to_vec(),
+            },
+            ServerControl::StreamOutput {
+                stream: crate::STDERR,
+                data: b"no such file\n".to_vec(),
+            },
+            ServerControl::TransferStatus(TransferStatus {
+                id: 2,
+                state: TransferState::Failed,
+                transferred: 0,
+                size: None,
+                error: Some("no such file".into()),
+            }),
+            ServerControl::TransferChunk {
+                id: 2,
+                data: b"data".to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn client_envelope_round_trip() {
+        let spawn = ClientControl::Spawn {
+            command: "/bin/sh".into(),
+        };
+        for control in client_controls().into_iter().chain([spawn]) {
+            let text = control.encode();
+            assert_eq!(ClientControl::decode(&text).unwrap(), control, "{}", text);
+        }
+    }
+
+    #[test]
+    fn client_legacy_round_trip() {
+        for control in client_controls() {
+            let legacy = control.to_legacy().unwrap();
+            let message = ClientMessage::decode(&legacy).unwrap();
+            assert_eq!(ClientControl::from_legacy(&message), Some(control));
+        }
+    }
+
+    #[test]
+    fn server_envelope_round_trip() {
+        for control in server_controls() {
+            let text = control.encode();
+            assert_eq!(ServerControl::decode(&text).unwrap(), control, "{}", text);
+        }
+    }
+
+    #[test]
+    fn server_legacy_round_trip() {
+        for control in server_controls() {
+            let legacy = control.to_legacy();
+            let message = ServerMessage::decode(&legacy).unwrap();
+            assert_eq!(ServerControl::from_legacy(&message), Some(control));
+        }
+    }
+
+    #[test]
+    fn envelope_format() {
+        let text = ClientControl::Resize(WindowSize {
+            cols: 80,
+            rows: 24,
+            xpixel: 0,
+            ypixel: 0,
+        })
+        .encode();
+        assert_eq!(
+            text,
+            r#"{"v":1,"type":"resize","cols":80,"rows":24,"xpixel":0,"ypixel":0}"#
+        );
+        let spawn = ClientControl::decode(r#"{"v":1,"type":"spawn"}"#).unwrap();
+        assert_eq!(
+            spawn,
+            ClientControl::Spawn {
+                command: String::new()
+            }
+        );
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let decoded = ClientControl::decode(r#"{"v":2,"type":"ping"}"#);
+        assert!(matches!(decoded, Err(DecodeError::Version(2))));
+    }
+
+    #[test]
+    fn close_reasons() {
+        let reasons = [
+            CloseReason::IdleTimeout,
+            CloseReason::Forbidden("no such directory: /srv".into()),
+            CloseReason::Shutdown(String::new()),
+            CloseReason::Reassigned("oncall".into()),
+            CloseReason::Other("going away".into()),
+        ];
+        for reason in reasons {
+            assert_eq!(CloseReason::parse(&reason.to_string()), reason);
+        }
+        assert_eq!(CloseReason::IdleTimeout.to_string(), "idle_timeout");
+        assert_eq!(
+            CloseReason::Quota("3 sessions".into()).to_string(),
+            "quota:3 sessions"
+        );
+        let long = CloseReason::Terminated("€".repeat(50)).to_string();
+        assert_eq!(long.len(), MAX_CLOSE_REASON - 1);
+        assert!(long.starts_with("terminated:€"));
+    }
+
+    #[test]
+    fn no_envelope_for_data() {
+        let input = ClientMessage::Input(b"ls\n");
+        assert_eq!(ClientControl::from_legacy(&input), None);
+        let output = ServerMessage::Output(b"total 0\r\n");
+        assert_eq!(ServerControl::from_legacy(&output), None);
+    }
+}
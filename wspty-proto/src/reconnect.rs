@@ -0,0 +1,143 @@
+// Sans-io reconnection logic: the caller owns the socket and the clock and
+// reports events, the state machine says when to (re)connect. The resume
+// token handed by the server is kept across reconnections, for the caller
+// to present it and get the same session back. State changes are queued
+// for the caller to forward, see `take_changes`.
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// Changes not taken yet beyond this are dropped, oldest first.
+const MAX_QUEUED_CHANGES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Idle,
+    Connecting { attempt: u32 },
+    Connected,
+    // Waiting until `at` (in caller milliseconds) before the next attempt.
+    Backoff { attempt: u32, at: u64 },
+    Closed,
+}
+
+#[derive(Clone, Debug)]
+pub struct Reconnect {
+    state: ConnectionState,
+    base_delay: u64,
+    max_delay: u64,
+    max_attempts: Option<u32>,
+    resume_token: Option<String>,
+    changes: VecDeque<ConnectionState>,
+}
+
+impl Reconnect {
+    // Delays are in milliseconds, doubling from `base_delay` up to
+    // `max_delay` on consecutive failures.
+    pub fn new(base_delay: u64, max_delay: u64) -> Self {
+        Reconnect {
+            state: ConnectionState::Idle,
+            base_delay,
+            max_delay,
+            max_attempts: None,
+            resume_token: None,
+            changes: VecDeque::new(),
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if state == self.state {
+            return;
+        }
+        self.state = state;
+        if self.changes.len() == MAX_QUEUED_CHANGES {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(state);
+    }
+
+    // The states entered since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<ConnectionState> {
+        self.changes.drain(..).collect()
+    }
+
+    // To present when connecting, if the server handed one.
+    pub fn resume_token(&self) -> Option<&str> {
+        self.resume_token.as_deref()
+    }
+
+    pub fn on_resume_token(&mut self, token: String) {
+        self.resume_token = Some(token);
+    }
+
+    // The server doesn't know the session anymore, the next connection
+    // starts a new one.
+    pub fn on_resume_rejected(&mut self) {
+        self.resume_token = None;
+    }
+
+    // Returns whether the caller should open a connection now.
+    pub fn connect(&mut self) -> bool {
+        match self.state {
+            ConnectionState::Idle | ConnectionState::Closed => {
+                self.set_state(ConnectionState::Connecting { attempt: 0 });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn on_open(&mut self) {
+        if let ConnectionState::Connecting { .. } = self.state {
+            self.set_state(ConnectionState::Connected);
+        }
+    }
+
+    // The connection failed or dropped. Returns the time of the next
+    // attempt, or None if we gave up.
+    pub fn on_close(&mut self, now: u64) -> Option<u64> {
+        let attempt = match self.state {
+            ConnectionState::Connecting { attempt } => attempt + 1,
+            ConnectionState::Connected => 0,
+            _ => return None,
+        };
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            self.close();
+            return None;
+        }
+        let delay = self
+            .base_delay
+            .saturating_mul(1u64 << attempt.min(32))
+            .min(self.max_delay);
+        let at = now.saturating_add(delay);
+        self.set_state(ConnectionState::Backoff { attempt, at });
+        Some(at)
+    }
+
+    // Returns whether the caller should open a connection now.
+    pub fn poll(&mut self, now: u64) -> bool {
+        match self.state {
+            ConnectionState::Backoff { attempt, at } if now >= at => {
+                self.set_state(ConnectionState::Connecting { attempt });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Closed on purpose, don't reconnect. The session is over, so is its
+    // resume token.
+    pub fn close(&mut self) {
+        self.resume_token = None;
+        self.set_state(ConnectionState::Closed);
+    }
+}
@@ -0,0 +1,575 @@
+// JavaScript bindings, built with `wasm-pack build -- --features wasm`.
+
+use crate::predict::Predictor;
+use crate::protocol::CloseReason;
+use crate::reconnect::{ConnectionState, Reconnect};
+use crate::{
+    Attach, Auth, CancelTransfer, ChallengeResponse, ClientMessage, CloseChannel, Compression,
+    Control, DryRun, EnvVar, Environment, FrameMode, Grant, Handoff, IntegrityMode,
+    KeyboardProtocol, LineInput, Open, Pause, Replay, Resume, Retransmit, Role, ServerMessage,
+    Signal, SignalRequest, ThemeRequest, Toggle, Trace, Transfer, TransferDirection, WindowSize,
+};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(js_name = encodeInput)]
+pub fn encode_input(data: &[u8]) -> Vec<u8> {
+    ClientMessage::Input(data).encode()
+}
+
+#[wasm_bindgen(js_name = encodeComposition)]
+pub fn encode_composition(text: &str) -> Vec<u8> {
+    ClientMessage::Composition(text).encode()
+}
+
+#[wasm_bindgen(js_name = encodeKeyboardProtocol)]
+pub fn encode_keyboard_protocol(kitty: bool) -> Vec<u8> {
+    ClientMessage::KeyboardProtocol(KeyboardProtocol { kitty }).encode()
+}
+
+#[wasm_bindgen(js_name = encodeTheme)]
+pub fn encode_theme(name: &str) -> Vec<u8> {
+    ClientMessage::Theme(ThemeRequest { name: name.into() }).encode()
+}
+
+#[wasm_bindgen(js_name = encodePause)]
+pub fn encode_pause(paused: bool) -> Vec<u8> {
+    ClientMessage::Pause(Pause { paused }).encode()
+}
+
+#[wasm_bindgen(js_name = encodeAuth)]
+pub fn encode_auth(token: &str) -> Vec<u8> {
+    ClientMessage::Auth(Auth {
+        token: token.into(),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeChallengeResponse)]
+pub fn encode_challenge_response(key_id: &str, mac: &str) -> Vec<u8> {
+    ClientMessage::ChallengeResponse(ChallengeResponse {
+        key_id: key_id.into(),
+        mac: mac.into(),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeResume)]
+pub fn encode_resume(token: &str) -> Vec<u8> {
+    ClientMessage::Resume(Resume {
+        token: token.into(),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeControl)]
+pub fn encode_control(token: &str) -> Vec<u8> {
+    ClientMessage::Control(Control {
+        token: token.into(),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeHandoff)]
+pub fn encode_handoff(to: &str) -> Vec<u8> {
+    ClientMessage::Handoff(Handoff { to: to.into() }).encode()
+}
+
+#[wasm_bindgen(js_name = encodeAttach)]
+pub fn encode_attach(session_id: &str, writer: bool, plain: bool) -> Vec<u8> {
+    let role = if writer { Role::Writer } else { Role::Viewer };
+    ClientMessage::Attach(Attach {
+        session_id: session_id.into(),
+        role,
+        plain,
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeToggle)]
+pub fn encode_toggle(
+    recording: Option<bool>,
+    read_only: Option<bool>,
+    flow_control: Option<bool>,
+    raw: Option<bool>,
+) -> Vec<u8> {
+    ClientMessage::Toggle(Toggle {
+        recording,
+        read_only,
+        flow_control,
+        raw,
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeOpen)]
+pub fn encode_open(channel: u32, command: &str) -> Vec<u8> {
+    ClientMessage::Open(Open {
+        channel,
+        command: command.into(),
+    })
+    .encode()
+}
+
+// `undefined` for names other than those of `Signal`.
+#[wasm_bindgen(js_name = encodeSignal)]
+pub fn encode_signal(name: &str) -> Option<Vec<u8>> {
+    let signal = Signal::from_name(name)?;
+    Some(ClientMessage::Signal(SignalRequest { signal }).encode())
+}
+
+#[wasm_bindgen(js_name = encodeGrant)]
+pub fn encode_grant(participant: u32, write: bool) -> Vec<u8> {
+    ClientMessage::Grant(Grant {
+        participant: participant.into(),
+        write,
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeCompression)]
+pub fn encode_compression(enabled: bool) -> Vec<u8> {
+    ClientMessage::Compression(Compression { enabled }).encode()
+}
+
+// Everything the server kept when both are `undefined`.
+#[wasm_bindgen(js_name = encodeReplay)]
+pub fn encode_replay(bytes: Option<u32>, lines: Option<u32>) -> Vec<u8> {
+    ClientMessage::Replay(Replay {
+        bytes: bytes.map(u64::from),
+        lines: lines.map(u64::from),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeLineInput)]
+pub fn encode_line_input(enabled: bool) -> Vec<u8> {
+    ClientMessage::LineInput(LineInput { enabled }).encode()
+}
+
+// The chunks then go with `encodeTransferChunk`.
+#[wasm_bindgen(js_name = encodeUpload)]
+pub fn encode_upload(id: u32, path: &str, size: u64) -> Vec<u8> {
+    ClientMessage::Transfer(Transfer {
+        id,
+        direction: TransferDirection::Upload,
+        path: path.into(),
+        size: Some(size),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeDownload)]
+pub fn encode_download(id: u32, path: &str) -> Vec<u8> {
+    ClientMessage::Transfer(Transfer {
+        id,
+        direction: TransferDirection::Download,
+        path: path.into(),
+        size: None,
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeTransferChunk)]
+pub fn encode_transfer_chunk(id: u32, data: &[u8]) -> Vec<u8> {
+    ClientMessage::TransferChunk(id, data).encode()
+}
+
+#[wasm_bindgen(js_name = encodeCancelTransfer)]
+pub fn encode_cancel_transfer(id: u32) -> Vec<u8> {
+    ClientMessage::CancelTransfer(CancelTransfer { id }).encode()
+}
+
+#[wasm_bindgen(js_name = encodeCloseChannel)]
+pub fn encode_close_channel(channel: u32) -> Vec<u8> {
+    ClientMessage::CloseChannel(CloseChannel { channel }).encode()
+}
+
+#[wasm_bindgen(js_name = encodeListChannels)]
+pub fn encode_list_channels() -> Vec<u8> {
+    ClientMessage::ListChannels.encode()
+}
+
+// Wraps a message encoded by the functions above for `channel`.
+#[wasm_bindgen(js_name = encodeChannel)]
+pub fn encode_channel(channel: u32, message: &[u8]) -> Vec<u8> {
+    ClientMessage::Channel(channel, message).encode()
+}
+
+#[wasm_bindgen(js_name = encodeDryRun)]
+pub fn encode_dry_run(command: &str) -> Vec<u8> {
+    ClientMessage::DryRun(DryRun {
+        command: command.into(),
+    })
+    .encode()
+}
+
+// `origin` should be a `performance.now()` reading in microseconds.
+#[wasm_bindgen(js_name = encodeTrace)]
+pub fn encode_trace(id: f64, origin: f64) -> Vec<u8> {
+    ClientMessage::Trace(Trace {
+        id: id as u64,
+        origin: origin as u64,
+    })
+    .encode()
+}
+
+// `names` and `values` are matched by index.
+#[wasm_bindgen(js_name = encodeEnvironment)]
+pub fn encode_environment(names: Vec<String>, values: Vec<String>) -> Vec<u8> {
+    let vars = names
+        .into_iter()
+        .zip(values)
+        .map(|(name, value)| EnvVar { name, value })
+        .collect();
+    ClientMessage::Environment(Environment { vars }).encode()
+}
+
+#[wasm_bindgen(js_name = encodeResize)]
+pub fn encode_resize(cols: u16, rows: u16, xpixel: Option<u16>, ypixel: Option<u16>) -> Vec<u8> {
+    ClientMessage::Resize(WindowSize {
+        cols,
+        rows,
+        xpixel: xpixel.unwrap_or(0),
+        ypixel: ypixel.unwrap_or(0),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodePing)]
+pub fn encode_ping() -> Vec<u8> {
+    ClientMessage::Ping.encode()
+}
+
+#[wasm_bindgen(js_name = encodeFrameMode)]
+pub fn encode_frame_mode(enabled: bool, interval: Option<u32>) -> Vec<u8> {
+    ClientMessage::FrameMode(FrameMode {
+        enabled,
+        interval: interval.map(u64::from),
+    })
+    .encode()
+}
+
+#[wasm_bindgen(js_name = encodeIntegrityMode)]
+pub fn encode_integrity_mode(enabled: bool) -> Vec<u8> {
+    ClientMessage::IntegrityMode(IntegrityMode { enabled }).encode()
+}
+
+#[wasm_bindgen(js_name = encodeRetransmit)]
+pub fn encode_retransmit(from: u64, to: u64) -> Vec<u8> {
+    ClientMessage::Retransmit(Retransmit { from, to }).encode()
+}
+
+// Of the reason of a close frame, see `CloseReason`, to pick a message.
+#[wasm_bindgen(js_name = closeReasonCode)]
+pub fn close_reason_code(reason: &str) -> String {
+    CloseReason::parse(reason).code().into()
+}
+
+#[wasm_bindgen(js_name = closeReasonDetail)]
+pub fn close_reason_detail(reason: &str) -> Option<String> {
+    CloseReason::parse(reason).detail().map(Into::into)
+}
+
+#[wasm_bindgen]
+pub struct Decoded {
+    opcode: u8,
+    data: Vec<u8>,
+    json: Option<String>,
+    corrupted: bool,
+}
+
+#[wasm_bindgen]
+impl Decoded {
+    #[wasm_bindgen(getter)]
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    // Terminal output, empty for control messages.
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    // Payload of control messages, as JSON.
+    #[wasm_bindgen(getter)]
+    pub fn json(&self) -> Option<String> {
+        self.json.clone()
+    }
+
+    // A checked output frame failed verification, or some were missing:
+    // ask for `expectedSeq` onwards to be sent again.
+    #[wasm_bindgen(getter)]
+    pub fn corrupted(&self) -> bool {
+        self.corrupted
+    }
+}
+
+// Decodes server messages, verifying checked output on the way.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Decoder {
+    next_seq: u64,
+    crc: u32,
+}
+
+#[wasm_bindgen]
+impl Decoder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    #[wasm_bindgen(getter, js_name = expectedSeq)]
+    pub fn expected_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn decode(&mut self, msg: &[u8]) -> Result<Decoded, JsValue> {
+        let decoded = ServerMessage::decode(msg).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(match decoded {
+            ServerMessage::Output(data) => Decoded {
+                opcode: crate::OUTPUT,
+                data: data.to_vec(),
+                json: None,
+                corrupted: false,
+            },
+            ServerMessage::CheckedOutput { seq, crc, data } => {
+                let valid = seq == self.next_seq && crate::checksum(self.crc, data) == crc;
+                if valid {
+                    self.next_seq += 1;
+                    self.crc = crc;
+                }
+                Decoded {
+                    opcode: crate::CHECKED_OUTPUT,
+                    data: if valid { data.to_vec() } else { Vec::new() },
+                    json: None,
+                    corrupted: !valid,
+                }
+            }
+            // For the frontend to inflate.
+            ServerMessage::CompressedOutput(data) => Decoded {
+                opcode: crate::COMPRESSED_OUTPUT,
+                data: data.to_vec(),
+                json: None,
+                corrupted: false,
+            },
+            ServerMessage::Scrollback(data) => Decoded {
+                opcode: crate::SCROLLBACK,
+                data: data.to_vec(),
+                json: None,
+                corrupted: false,
+            },
+            ServerMessage::Pong => Decoded {
+                opcode: crate::PONG,
+                data: Vec::new(),
+                json: None,
+                corrupted: false,
+            },
+            ServerMessage::KeyboardFlags(_)
+            | ServerMessage::MouseMode(_)
+            | ServerMessage::AlternateScreen(_)
+            | ServerMessage::Decision(_)
+            | ServerMessage::QueuePosition(_)
+            | ServerMessage::Command(_)
+            | ServerMessage::TraceReport(_)
+            | ServerMessage::CpuUsage(_)
+            | ServerMessage::Resized(_)
+            | ServerMessage::Exit(_)
+            | ServerMessage::Session(_)
+            | ServerMessage::Channels(_)
+            | ServerMessage::ChannelClosed(_)
+            | ServerMessage::Job(_)
+            | ServerMessage::Participants(_)
+            | ServerMessage::Presence(_)
+            | ServerMessage::Progress(_)
+            | ServerMessage::Shared(_)
+            | ServerMessage::Features(_)
+            | ServerMessage::Challenge(_)
+            | ServerMessage::Denied(_)
+            | ServerMessage::Notification(_)
+            | ServerMessage::ProbeFailed(_)
+            | ServerMessage::ControlChannel(_)
+            | ServerMessage::Malformed(_)
+            | ServerMessage::TransferStatus(_) => Decoded {
+                opcode: msg[0],
+                data: Vec::new(),
+                json: control_json(msg),
+                corrupted: false,
+            },
+            ServerMessage::StreamOutput { stream, data } => Decoded {
+                opcode: crate::STREAM_OUTPUT,
+                data: data.to_vec(),
+                json: Some(alloc::format!("{{\"stream\":{}}}", stream)),
+                corrupted: false,
+            },
+            ServerMessage::TransferChunk(id, data) => Decoded {
+                opcode: crate::SERVER_CHUNK,
+                data: data.to_vec(),
+                json: Some(alloc::format!("{{\"id\":{}}}", id)),
+                corrupted: false,
+            },
+            // The message is left for the channel's own decoder.
+            ServerMessage::Channel(channel, message) => Decoded {
+                opcode: crate::SERVER_CHANNEL,
+                data: message.to_vec(),
+                json: Some(alloc::format!("{{\"channel\":{}}}", channel)),
+                corrupted: false,
+            },
+            ServerMessage::Unknown(opcode, _) => Decoded {
+                opcode,
+                data: Vec::new(),
+                json: None,
+                corrupted: false,
+            },
+        })
+    }
+}
+
+fn control_json(msg: &[u8]) -> Option<String> {
+    core::str::from_utf8(&msg[1..]).ok().map(String::from)
+}
+
+// Reconnection state machine driven from JavaScript, times are
+// milliseconds as returned by `Date.now()`.
+#[wasm_bindgen]
+pub struct Reconnector {
+    inner: Reconnect,
+    on_state_change: Option<js_sys::Function>,
+}
+
+// One of "idle", "connecting", "connected", "backoff" or "closed".
+fn state_name(state: ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Idle => "idle",
+        ConnectionState::Connecting { .. } => "connecting",
+        ConnectionState::Connected => "connected",
+        ConnectionState::Backoff { .. } => "backoff",
+        ConnectionState::Closed => "closed",
+    }
+}
+
+#[wasm_bindgen]
+impl Reconnector {
+    #[wasm_bindgen(constructor)]
+    pub fn new(base_delay: f64, max_delay: f64) -> Self {
+        Reconnector {
+            inner: Reconnect::new(base_delay as u64, max_delay as u64),
+            on_state_change: None,
+        }
+    }
+
+    // Called with the name of each new state.
+    #[wasm_bindgen(setter, js_name = onStateChange)]
+    pub fn set_on_state_change(&mut self, callback: Option<js_sys::Function>) {
+        self.on_state_change = callback;
+    }
+
+    fn notify(&mut self) {
+        let changes = self.inner.take_changes();
+        if let Some(ref callback) = self.on_state_change {
+            for state in changes {
+                let _ = callback.call1(&JsValue::NULL, &JsValue::from_str(state_name(state)));
+            }
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn state(&self) -> String {
+        state_name(self.inner.state()).into()
+    }
+
+    #[wasm_bindgen(getter, js_name = resumeToken)]
+    pub fn resume_token(&self) -> Option<String> {
+        self.inner.resume_token().map(String::from)
+    }
+
+    #[wasm_bindgen(js_name = onResumeToken)]
+    pub fn on_resume_token(&mut self, token: String) {
+        self.inner.on_resume_token(token)
+    }
+
+    #[wasm_bindgen(js_name = onResumeRejected)]
+    pub fn on_resume_rejected(&mut self) {
+        self.inner.on_resume_rejected()
+    }
+
+    pub fn connect(&mut self) -> bool {
+        let connect = self.inner.connect();
+        self.notify();
+        connect
+    }
+
+    #[wasm_bindgen(js_name = onOpen)]
+    pub fn on_open(&mut self) {
+        self.inner.on_open();
+        self.notify();
+    }
+
+    // Time of the next attempt, if any.
+    #[wasm_bindgen(js_name = onClose)]
+    pub fn on_close(&mut self, now: f64) -> Option<f64> {
+        let at = self.inner.on_close(now as u64).map(|at| at as f64);
+        self.notify();
+        at
+    }
+
+    pub fn poll(&mut self, now: f64) -> bool {
+        let connect = self.inner.poll(now as u64);
+        self.notify();
+        connect
+    }
+
+    pub fn close(&mut self) {
+        self.inner.close();
+        self.notify();
+    }
+}
+
+// Local echo prediction driven from JavaScript, see the `predict` module.
+// Whatever the methods return is to be written to the terminal.
+#[wasm_bindgen]
+pub struct LocalEcho {
+    inner: Predictor,
+}
+
+#[wasm_bindgen]
+impl LocalEcho {
+    #[wasm_bindgen(constructor)]
+    pub fn new(timeout: f64) -> Self {
+        LocalEcho {
+            inner: Predictor::new(timeout as u64),
+        }
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn enabled(&self) -> bool {
+        self.inner.enabled()
+    }
+
+    #[wasm_bindgen(js_name = setEnabled)]
+    pub fn set_enabled(&mut self, enabled: bool) -> Vec<u8> {
+        self.inner.set_enabled(enabled)
+    }
+
+    #[wasm_bindgen(js_name = setAlternateScreen)]
+    pub fn set_alternate_screen(&mut self, active: bool) {
+        self.inner.set_alternate_screen(active)
+    }
+
+    #[wasm_bindgen(js_name = onInput)]
+    pub fn on_input(&mut self, input: &[u8], now: f64) -> Vec<u8> {
+        self.inner.on_input(input, now as u64)
+    }
+
+    #[wasm_bindgen(js_name = onOutput)]
+    pub fn on_output(&mut self, output: &[u8]) -> Vec<u8> {
+        self.inner.on_output(output)
+    }
+
+    pub fn poll(&mut self, now: f64) -> Vec<u8> {
+        self.inner.poll(now as u64)
+    }
+}